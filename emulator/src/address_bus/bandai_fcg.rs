@@ -0,0 +1,605 @@
+use super::i2c_eeprom::I2cEeprom;
+use super::nametable_layout::NametableLayout;
+use super::prg_ram::PrgRam;
+use super::{CpuAddressBus, CpuAddressBusBase, FromRom, PpuAddressBus};
+use crate::{apu, controller as ctrl, cpu, parse, ppu, serialize};
+
+use std::cell::Cell;
+use std::{fs, io};
+
+// bandai fcg-1/fcg-2 and lz93d50 boards. the variants differ mostly in what they
+// save to: mapper 16 boards have a 24c02 eeprom (or nothing), mapper 159 boards a
+// 24c01 eeprom and mapper 153 boards 8 KB of battery-backed prg ram, with the chr
+// registers selecting the 256 KB prg rom half instead of chr banks (which are ram)
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum BandaiFcgBoard {
+    // mapper 16
+    Eeprom24c02,
+    // mapper 153
+    PrgRam,
+    // mapper 159
+    Eeprom24c01,
+}
+
+pub struct BandaiFcgCpuAddressBus<'a> {
+    base: CpuAddressBusBase<'a>,
+    ppu_bus: BandaiFcgPpuAddressBus,
+    internal_ram: [u8; 0x800],
+    prg_rom: Box<[u8]>,
+    board: BandaiFcgBoard,
+    // only present on 'BandaiFcgBoard::PrgRam'
+    prg_ram: PrgRam,
+    eeprom: I2cEeprom,
+    // register 8 (16 KB bank at 0x8000)
+    prg_bank: u8,
+    // registers 0xb and 0xc
+    irq_latch: u16,
+    // decremented once per cpu cycle
+    irq_counter: u16,
+    // the number of cpu cycles the irq counter has
+    // been clocked for (see 'CpuAddressBus::catch_up()')
+    cycle_count: i32,
+    bits: BandaiFcgCpuBits::BitField,
+}
+
+bitfield!(BandaiFcgCpuBits<u8>(
+    // register 0xa (bit 0)
+    irq_enable: 0..0,
+    irq_asserted: 1..1,
+    // register 0xd (bit 5 on 'BandaiFcgBoard::PrgRam')
+    prg_ram_enable: 2..2,
+));
+
+pub struct BandaiFcgPpuAddressBus {
+    chr: Box<[u8]>,
+    // the chr is 8 KB of unbanked ram on 'BandaiFcgBoard::PrgRam'
+    chr_is_ram: bool,
+    nametables: [u8; 0x800],
+    palettes: [u8; 32],
+    // registers 0-7 (1 KB banks)
+    chr_banks: [u8; 8],
+    // register 9
+    layout: NametableLayout,
+}
+
+impl<'a> BandaiFcgCpuAddressBus<'a> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        prg_rom: &[u8],
+        chr_rom: &[u8],
+        chr_ram_size: usize,
+        prg_ram: PrgRam,
+        mirroring: parse::MirroringType,
+        board: BandaiFcgBoard,
+        ppu: ppu::Ppu,
+        apu: apu::Apu,
+        controller: ctrl::Controller,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> Self {
+        let max_prg_size = if board == BandaiFcgBoard::PrgRam {
+            0x80000
+        } else {
+            0x40000
+        };
+
+        if prg_rom.is_empty()
+            || prg_rom.len() > max_prg_size
+            || !prg_rom.len().is_multiple_of(0x4000)
+        {
+            error_exit!(
+                "Failed to load rom file: prg rom must be a multiple of 16 KB and at \
+                 most {} KB for bandai fcg (mapper 16/153/159)",
+                max_prg_size / 0x400
+            );
+        }
+
+        if chr_rom.len() > 0x40000 {
+            error_exit!(
+                "Failed to load rom file: chr rom must be at most 256 KB \
+                 for bandai fcg (mapper 16/153/159)"
+            );
+        }
+
+        let (chr, chr_is_ram) = super::new_chr(chr_rom, chr_ram_size);
+
+        let ppu_bus = BandaiFcgPpuAddressBus {
+            chr,
+            chr_is_ram,
+            nametables: [0; 0x800],
+            palettes: [0; 32],
+            chr_banks: [0; 8],
+            layout: match mirroring {
+                parse::MirroringType::Hor => NametableLayout::HORIZONTAL,
+                _ => NametableLayout::VERTICAL,
+            },
+        };
+
+        Self {
+            base: CpuAddressBusBase::new(ppu, apu, controller, framebuffer),
+            ppu_bus,
+            internal_ram: [0; 0x800],
+            prg_rom: prg_rom.to_vec().into_boxed_slice(),
+            board,
+            prg_ram,
+            eeprom: match board {
+                BandaiFcgBoard::Eeprom24c01 => I2cEeprom::new_24c01(),
+                _ => I2cEeprom::new_24c02(),
+            },
+            prg_bank: 0,
+            irq_latch: 0,
+            irq_counter: 0,
+            cycle_count: 0,
+            bits: BandaiFcgCpuBits::BitField::zeroed(),
+        }
+    }
+
+    fn calc_prg_rom_addr(&self, addr: u16) -> usize {
+        let bank = if addr & 0x4000 == 0 {
+            self.prg_bank as usize & 0xf
+        } else {
+            0xf
+        };
+
+        // the outer 256 KB bank, selected by bit 0 of any chr register
+        let outer_bank = if self.board == BandaiFcgBoard::PrgRam {
+            self.ppu_bus
+                .chr_banks
+                .iter()
+                .fold(0, |acc, &bank| acc | (bank & 1) as usize)
+        } else {
+            0
+        };
+
+        ((outer_bank << 4 | bank) * 0x4000) % self.prg_rom.len() + (addr & 0x3fff) as usize
+    }
+
+    // asserts or acknowledges the irq on the cpu
+    fn set_irq(&mut self, assert: bool, cpu: &mut cpu::Cpu) {
+        if assert != self.bits.irq_asserted.is_true() {
+            self.bits.irq_asserted.set(assert as u8);
+            if assert {
+                cpu.irq += 1;
+            } else {
+                cpu.irq = cpu.irq.saturating_sub(1);
+            }
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8, cpu: &mut cpu::Cpu) {
+        match addr & 0xf {
+            0x0..=0x7 => self.ppu_bus.chr_banks[(addr & 0xf) as usize] = val,
+            0x8 => self.prg_bank = val,
+            0x9 => self.ppu_bus.layout = NametableLayout::from_mirroring_bits(val),
+            0xa => {
+                // the lz93d50 reloads the counter from the latch, and the irq is acknowledged
+                self.catch_up(cpu);
+                self.bits.irq_enable.set(val & 1);
+                self.irq_counter = self.irq_latch;
+                self.set_irq(false, cpu);
+            }
+            // NOTE: fcg-1/fcg-2 write the counter directly, while the lz93d50 writes the
+            // latch. writing both is compatible with both, since games write these
+            // before enabling the irq through register 0xa
+            0xb => {
+                self.catch_up(cpu);
+                self.irq_latch = (self.irq_latch & 0xff00) | val as u16;
+                self.irq_counter = self.irq_latch;
+            }
+            0xc => {
+                self.catch_up(cpu);
+                self.irq_latch = (self.irq_latch & 0xff) | (val as u16) << 8;
+                self.irq_counter = self.irq_latch;
+            }
+            0xd => {
+                if self.board == BandaiFcgBoard::PrgRam {
+                    self.bits.prg_ram_enable.set((val >> 5) & 1);
+                } else {
+                    // bit 5 is scl and bit 6 is sda
+                    self.eeprom.write_lines(val & 0x20 != 0, val & 0x40 != 0);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl<'a> FromRom<'a> for BandaiFcgCpuAddressBus<'a> {
+    fn from_rom(
+        rom: &parse::RomDescription,
+        ppu: ppu::Ppu,
+        apu: apu::Apu,
+        controller: ctrl::Controller,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> Self {
+        let board = match rom.header.mapper {
+            153 => BandaiFcgBoard::PrgRam,
+            159 => BandaiFcgBoard::Eeprom24c01,
+            _ => BandaiFcgBoard::Eeprom24c02,
+        };
+
+        // only mapper 153 boards have prg ram
+        let prg_ram = if board == BandaiFcgBoard::PrgRam {
+            PrgRam::from_header(&rom.header, 0x2000)
+        } else {
+            PrgRam::new(0, 0)
+        };
+
+        Self::new(
+            &rom.prg_rom,
+            &rom.chr_rom,
+            rom.header.total_chr_ram_size(),
+            prg_ram,
+            rom.header.mirroring,
+            board,
+            ppu,
+            apu,
+            controller,
+            framebuffer,
+        )
+    }
+}
+
+impl<'a> CpuAddressBus<'a> for BandaiFcgCpuAddressBus<'a> {
+    fn read(&mut self, mut addr: u16, cpu: &mut cpu::Cpu) -> u8 {
+        // internal ram
+        if super::is_0_to_1fff(addr) {
+            addr &= !0b1_1000_0000_0000;
+            return unsafe { *self.internal_ram.get_unchecked(addr as usize) };
+        }
+
+        // ppu registers
+        if super::is_2000_to_3fff(addr) {
+            self.base
+                .ppu
+                .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+
+            addr &= 0b111;
+            return self
+                .base
+                .ppu
+                .read_register_by_index(addr as u8, &mut self.ppu_bus, cpu);
+        }
+
+        // prg ram or the eeprom data line (bit 4)
+        if super::is_6000_to_7fff(addr) {
+            return match self.board {
+                BandaiFcgBoard::PrgRam if self.bits.prg_ram_enable.is_true() => {
+                    self.prg_ram.read((addr & 0x1fff) as usize)
+                }
+                BandaiFcgBoard::PrgRam => 0,
+                _ => (self.eeprom.read_sda() as u8) << 4,
+            };
+        }
+
+        // prg rom
+        if addr & 0x8000 != 0 {
+            return self.prg_rom[self.calc_prg_rom_addr(addr)];
+        }
+
+        if addr == 0x4016 {
+            return self.base.controller.read();
+        }
+
+        0
+    }
+
+    fn write(&mut self, mut addr: u16, val: u8, cpu: &mut cpu::Cpu) {
+        if super::is_0_to_1fff(addr) {
+            addr &= !0b1_1000_0000_0000;
+            unsafe { *self.internal_ram.get_unchecked_mut(addr as usize) = val };
+            return;
+        }
+
+        if super::is_2000_to_3fff(addr) {
+            self.base
+                .ppu
+                .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+
+            self.base
+                .ppu
+                .write_register_by_index(addr as u8 & 0b111, val, cpu, &mut self.ppu_bus);
+
+            return;
+        }
+
+        if super::is_6000_to_7fff(addr) {
+            match self.board {
+                BandaiFcgBoard::PrgRam => {
+                    if self.bits.prg_ram_enable.is_true() {
+                        self.prg_ram.write((addr & 0x1fff) as usize, val);
+                    }
+                }
+                // fcg-1/fcg-2 boards have their registers here instead of at 0x8000
+                BandaiFcgBoard::Eeprom24c02 => self.write_register(addr, val, cpu),
+                BandaiFcgBoard::Eeprom24c01 => (),
+            }
+
+            return;
+        }
+
+        match addr {
+            // oamdma
+            0x4014 => {
+                self.base
+                    .ppu
+                    .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+                super::write_oamdma(self, val, cpu);
+            }
+            // standard controller 1
+            0x4016 => self.base.controller.write(val),
+            0x8000..=0xffff => self.write_register(addr, val, cpu),
+            _ => (),
+        }
+    }
+
+    fn base(&mut self) -> (&mut CpuAddressBusBase<'a>, &mut dyn PpuAddressBus) {
+        (&mut self.base, &mut self.ppu_bus)
+    }
+
+    fn catch_up(&mut self, cpu: &mut cpu::Cpu) {
+        while self.cycle_count < cpu.cycle_count {
+            if self.bits.irq_enable.is_true() {
+                if self.irq_counter == 0 {
+                    self.set_irq(true, cpu);
+                }
+                self.irq_counter = self.irq_counter.wrapping_sub(1);
+            }
+
+            self.cycle_count += 1;
+        }
+    }
+
+    fn sub_cycle_count(&mut self, sub: i32) {
+        self.cycle_count -= sub;
+    }
+
+    fn battery_backed_ram(&mut self) -> Vec<&mut [u8]> {
+        match self.board {
+            BandaiFcgBoard::PrgRam => vec![self.prg_ram.battery_backed()],
+            _ => vec![self.eeprom.data_mut()],
+        }
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        match self.board {
+            BandaiFcgBoard::PrgRam => self.prg_ram.window(),
+            _ => None,
+        }
+    }
+}
+
+impl BandaiFcgPpuAddressBus {
+    fn calc_chr_addr(&self, addr: u16) -> usize {
+        if self.chr_is_ram {
+            return addr as usize;
+        }
+
+        let bank = self.chr_banks[(addr >> 10) as usize] as usize;
+        (bank * 0x400) % self.chr.len() + (addr & 0x3ff) as usize
+    }
+}
+
+impl PpuAddressBus for BandaiFcgPpuAddressBus {
+    fn read(&mut self, addr: u16, _: i32, _: &mut cpu::Cpu) -> u8 {
+        if addr >= 0x3f00 {
+            let addr = super::calc_ppu_palette_addr(addr);
+            return unsafe { *self.palettes.get_unchecked(addr as usize) };
+        }
+
+        if addr >= 0x2000 {
+            return self.nametables[self.layout.calc_vram_addr(addr)];
+        }
+
+        self.chr[self.calc_chr_addr(addr)]
+    }
+
+    fn write(&mut self, addr: u16, val: u8, _: i32, _: &mut cpu::Cpu) {
+        if addr >= 0x3f00 {
+            let addr = super::calc_ppu_palette_addr(addr);
+            unsafe { *self.palettes.get_unchecked_mut(addr as usize) = val };
+            return;
+        }
+
+        if addr >= 0x2000 {
+            let addr = self.layout.calc_vram_addr(addr);
+            self.nametables[addr] = val;
+        } else if self.chr_is_ram {
+            let addr = self.calc_chr_addr(addr);
+            self.chr[addr] = val;
+        }
+    }
+
+    fn set_address(&mut self, _: u16, _: i32, _: &mut cpu::Cpu) {}
+
+    fn read_palette_memory(&self, color_idx: u8) -> u8 {
+        self.palettes[super::calc_ppu_palette_addr(color_idx as u16) as usize]
+    }
+}
+
+// NOTE: 'Serialize' is implemented manually to avoid serializing rom
+impl serialize::Serialize for BandaiFcgPpuAddressBus {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        if self.chr_is_ram {
+            self.chr.serialize(file)?;
+        }
+        self.nametables.serialize(file)?;
+        self.palettes.serialize(file)?;
+        self.chr_banks.serialize(file)?;
+        self.layout.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        if self.chr_is_ram {
+            self.chr.deserialize(file)?;
+        }
+        self.nametables.deserialize(file)?;
+        self.palettes.deserialize(file)?;
+        self.chr_banks.deserialize(file)?;
+        self.layout.deserialize(file)
+    }
+}
+
+impl<'a> serialize::Serialize for BandaiFcgCpuAddressBus<'a> {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        self.base.serialize(file)?;
+        self.ppu_bus.serialize(file)?;
+        self.internal_ram.serialize(file)?;
+        self.prg_ram.serialize(file)?;
+        self.eeprom.serialize(file)?;
+        self.prg_bank.serialize(file)?;
+        self.irq_latch.serialize(file)?;
+        self.irq_counter.serialize(file)?;
+        self.cycle_count.serialize(file)?;
+        self.bits.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        self.base.deserialize(file)?;
+        self.ppu_bus.deserialize(file)?;
+        self.internal_ram.deserialize(file)?;
+        self.prg_ram.deserialize(file)?;
+        self.eeprom.deserialize(file)?;
+        self.prg_bank.deserialize(file)?;
+        self.irq_latch.deserialize(file)?;
+        self.irq_counter.deserialize(file)?;
+        self.cycle_count.deserialize(file)?;
+        self.bits.deserialize(file)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_test_bus<'a>(
+        board: BandaiFcgBoard,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> BandaiFcgCpuAddressBus<'a> {
+        let prg_rom = (0..0x80000).map(|i| (i / 0x4000) as u8).collect::<Vec<_>>();
+        let chr_rom = (0..0x20000).map(|i| (i / 0x400) as u8).collect::<Vec<_>>();
+
+        BandaiFcgCpuAddressBus::new(
+            &prg_rom[..if board == BandaiFcgBoard::PrgRam {
+                0x80000
+            } else {
+                0x40000
+            }],
+            if board == BandaiFcgBoard::PrgRam {
+                &[]
+            } else {
+                &chr_rom
+            },
+            0,
+            PrgRam::new(0x2000, 0x2000),
+            parse::MirroringType::Vert,
+            board,
+            ppu::Ppu::new(),
+            apu::Apu::new(),
+            ctrl::Controller::default(),
+            framebuffer,
+        )
+    }
+
+    #[test]
+    fn test_banking() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(BandaiFcgBoard::Eeprom24c02, unsafe {
+            &*(&framebuffer as *const _ as *const _)
+        });
+        let mut cpu = cpu::Cpu::default();
+
+        bus.write(0x8008, 3, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 3);
+        assert_eq!(bus.read(0xc000, &mut cpu), 15);
+
+        // fcg-1/fcg-2 registers
+        bus.write(0x6008, 5, &mut cpu);
+        bus.write(0x6003, 0x22, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 5);
+        assert_eq!(bus.ppu_bus.read(0x0c00, 0, &mut cpu), 0x22);
+
+        // horizontal mirroring
+        bus.write(0x8009, 1, &mut cpu);
+        bus.ppu_bus.write(0x2005, 0x55, 0, &mut cpu);
+        assert_eq!(bus.ppu_bus.read(0x2405, 0, &mut cpu), 0x55);
+        assert_eq!(bus.ppu_bus.read(0x2805, 0, &mut cpu), 0);
+    }
+
+    #[test]
+    fn test_prg_ram_board() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(BandaiFcgBoard::PrgRam, unsafe {
+            &*(&framebuffer as *const _ as *const _)
+        });
+        let mut cpu = cpu::Cpu::default();
+
+        // the chr registers select the 256 KB half of prg rom
+        bus.write(0x8008, 2, &mut cpu);
+        bus.write(0x8000, 1, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 18);
+        assert_eq!(bus.read(0xc000, &mut cpu), 31);
+
+        bus.write(0x6000, 0xaa, &mut cpu);
+        assert_eq!(bus.read(0x6000, &mut cpu), 0);
+
+        bus.write(0x800d, 0x20, &mut cpu);
+        bus.write(0x6000, 0xaa, &mut cpu);
+        assert_eq!(bus.read(0x6000, &mut cpu), 0xaa);
+
+        // chr ram
+        bus.ppu_bus.write(0x1234, 0x66, 0, &mut cpu);
+        assert_eq!(bus.ppu_bus.read(0x1234, 0, &mut cpu), 0x66);
+    }
+
+    #[test]
+    fn test_irq_counter() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(BandaiFcgBoard::Eeprom24c02, unsafe {
+            &*(&framebuffer as *const _ as *const _)
+        });
+        let mut cpu = cpu::Cpu::default();
+
+        bus.write(0x800b, 2, &mut cpu);
+        bus.write(0x800c, 0, &mut cpu);
+        bus.write(0x800a, 1, &mut cpu);
+
+        cpu.cycle_count += 2;
+        bus.catch_up(&mut cpu);
+        assert_eq!(cpu.irq, 0);
+
+        cpu.cycle_count += 1;
+        bus.catch_up(&mut cpu);
+        assert_eq!(cpu.irq, 1);
+
+        // writing to the irq control register acknowledges the irq
+        bus.write(0x800a, 0, &mut cpu);
+        assert_eq!(cpu.irq, 0);
+    }
+
+    #[test]
+    fn test_eeprom_data_line() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(BandaiFcgBoard::Eeprom24c02, unsafe {
+            &*(&framebuffer as *const _ as *const _)
+        });
+        let mut cpu = cpu::Cpu::default();
+
+        // start condition, then select the eeprom for writing (0xa0)
+        bus.write(0x800d, 0x60, &mut cpu);
+        bus.write(0x800d, 0x20, &mut cpu);
+        bus.write(0x800d, 0x00, &mut cpu);
+        for i in (0..8).rev() {
+            let sda = ((0xa0 >> i) & 1) << 6;
+            bus.write(0x800d, sda, &mut cpu);
+            bus.write(0x800d, sda | 0x20, &mut cpu);
+            bus.write(0x800d, sda, &mut cpu);
+        }
+
+        // the eeprom acknowledges by pulling sda low
+        bus.write(0x800d, 0x60, &mut cpu);
+        assert_eq!(bus.read(0x6000, &mut cpu) & 0x10, 0);
+        bus.write(0x800d, 0x40, &mut cpu);
+        assert_eq!(bus.read(0x6000, &mut cpu) & 0x10, 0x10);
+    }
+}
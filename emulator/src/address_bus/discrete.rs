@@ -0,0 +1,596 @@
+use super::nametable_layout::NametableLayout;
+use super::prg_ram::PrgRam;
+use super::{CpuAddressBus, CpuAddressBusBase, FromRom, PpuAddressBus};
+use crate::{apu, controller as ctrl, cpu, parse, ppu, serialize};
+
+use std::cell::Cell;
+use std::{fs, io};
+
+// small boards built mostly from discrete logic, which only differ in where their
+// bank registers live and how the written value is split into prg and chr banks.
+// banks are tracked in 8 KB (prg) and 1 KB (chr) units regardless of the board
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DiscreteBoard {
+    // mapper 11
+    ColorDreams,
+    // mapper 34 (submapper 2, or 8 KB of chr or less)
+    Bnrom,
+    // mapper 34 (submapper 1, or more than 8 KB of chr)
+    Nina001,
+    // mapper 66
+    Gxrom,
+    // mapper 71. single-screen mirroring control is enabled by the first write
+    // to 0x9000-0x9fff, since only fire hawk writes there
+    Camerica,
+    // mapper 71 (submapper 1)
+    CamericaFireHawk,
+    // mapper 79
+    Nina03,
+    // mapper 206
+    Namco108,
+}
+
+pub struct DiscreteCpuAddressBus<'a> {
+    base: CpuAddressBusBase<'a>,
+    ppu_bus: DiscretePpuAddressBus,
+    internal_ram: [u8; 0x800],
+    prg_rom: Box<[u8]>,
+    board: DiscreteBoard,
+    // only present on 'DiscreteBoard::Nina001'
+    prg_ram: PrgRam,
+    // 8 KB banks at 0x8000, 0xa000, 0xc000 and 0xe000
+    prg_banks: [u8; 4],
+    // the register selected through 0x8000 on 'DiscreteBoard::Namco108'
+    bank_select: u8,
+    // set on 'DiscreteBoard::CamericaFireHawk', or once a 'DiscreteBoard::Camerica'
+    // game writes to 0x9000-0x9fff
+    has_mirroring_control: bool,
+}
+
+pub struct DiscretePpuAddressBus {
+    chr: Box<[u8]>,
+    // boards without chr rom have chr ram instead
+    chr_is_ram: bool,
+    // the second half is only used with four-screen mirroring
+    nametables: [u8; 0x1000],
+    palettes: [u8; 32],
+    // 1 KB banks
+    chr_banks: [u8; 8],
+    layout: NametableLayout,
+}
+
+impl<'a> DiscreteCpuAddressBus<'a> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        prg_rom: &[u8],
+        chr_rom: &[u8],
+        chr_ram_size: usize,
+        prg_ram: PrgRam,
+        mirroring: parse::MirroringType,
+        board: DiscreteBoard,
+        ppu: ppu::Ppu,
+        apu: apu::Apu,
+        controller: ctrl::Controller,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> Self {
+        if prg_rom.is_empty() || prg_rom.len() > 0x80000 || !prg_rom.len().is_multiple_of(0x2000) {
+            error_exit!(
+                "Failed to load rom file: prg rom must be a multiple of 8 KB \
+                 and at most 512 KB for discrete logic mappers"
+            );
+        }
+
+        if chr_rom.len() > 0x40000 {
+            error_exit!(
+                "Failed to load rom file: chr rom must be at most 256 KB for discrete logic mappers"
+            );
+        }
+
+        let (chr, chr_is_ram) = super::new_chr(chr_rom, chr_ram_size);
+
+        let ppu_bus = DiscretePpuAddressBus {
+            chr,
+            chr_is_ram,
+            nametables: [0; 0x1000],
+            palettes: [0; 32],
+            chr_banks: [0, 1, 2, 3, 4, 5, 6, 7],
+            layout: NametableLayout::from_mirroring(mirroring),
+        };
+
+        // boards with 16 or 8 KB prg banks have the last banks fixed at 0xc000/0xe000
+        let last_bank = (prg_rom.len() / 0x2000 - 1) as u8;
+        let prg_banks = match board {
+            DiscreteBoard::Camerica | DiscreteBoard::CamericaFireHawk | DiscreteBoard::Namco108 => {
+                [0, 1, last_bank.saturating_sub(1), last_bank]
+            }
+            _ => [0, 1, 2, 3],
+        };
+
+        Self {
+            base: CpuAddressBusBase::new(ppu, apu, controller, framebuffer),
+            ppu_bus,
+            internal_ram: [0; 0x800],
+            prg_rom: prg_rom.to_vec().into_boxed_slice(),
+            board,
+            prg_ram,
+            prg_banks,
+            bank_select: 0,
+            has_mirroring_control: board == DiscreteBoard::CamericaFireHawk,
+        }
+    }
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        let bank = self.prg_banks[((addr >> 13) & 0b11) as usize] as usize;
+        self.prg_rom[(bank * 0x2000) % self.prg_rom.len() + (addr & 0x1fff) as usize]
+    }
+
+    fn set_prg_bank_32k(&mut self, bank: u8) {
+        for (i, prg_bank) in self.prg_banks.iter_mut().enumerate() {
+            *prg_bank = bank * 4 + i as u8;
+        }
+    }
+
+    fn set_chr_bank_8k(&mut self, bank: u8) {
+        self.set_chr_bank_4k(0, bank * 2);
+        self.set_chr_bank_4k(1, bank * 2 + 1);
+    }
+
+    fn set_chr_bank_4k(&mut self, window: usize, bank: u8) {
+        for i in 0..4 {
+            self.ppu_bus.chr_banks[window * 4 + i] = bank * 4 + i as u8;
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match self.board {
+            DiscreteBoard::ColorDreams if addr >= 0x8000 => {
+                // bus conflicts: the written value is anded with the rom contents
+                let val = val & self.read_prg_rom(addr);
+                self.set_prg_bank_32k(val & 0b11);
+                self.set_chr_bank_8k(val >> 4);
+            }
+            DiscreteBoard::Bnrom if addr >= 0x8000 => {
+                let val = val & self.read_prg_rom(addr);
+                self.set_prg_bank_32k(val & 0xf);
+            }
+            DiscreteBoard::Nina001 => match addr {
+                0x7ffd => self.set_prg_bank_32k(val & 1),
+                0x7ffe => self.set_chr_bank_4k(0, val & 0xf),
+                0x7fff => self.set_chr_bank_4k(1, val & 0xf),
+                _ => (),
+            },
+            DiscreteBoard::Gxrom if addr >= 0x8000 => {
+                let val = val & self.read_prg_rom(addr);
+                self.set_prg_bank_32k((val >> 4) & 0b11);
+                self.set_chr_bank_8k(val & 0b11);
+            }
+            DiscreteBoard::Camerica | DiscreteBoard::CamericaFireHawk => match addr {
+                0x9000..=0x9fff => {
+                    self.has_mirroring_control = true;
+                    self.ppu_bus.layout = NametableLayout::from_mirroring_bits(2 | (val >> 4));
+                }
+                0x8000..=0x8fff if self.has_mirroring_control => {
+                    self.ppu_bus.layout = NametableLayout::from_mirroring_bits(2 | (val >> 4));
+                }
+                0xc000..=0xffff => {
+                    self.prg_banks[0] = (val & 0xf) * 2;
+                    self.prg_banks[1] = (val & 0xf) * 2 + 1;
+                }
+                _ => (),
+            },
+            // the registers are at 0x4100-0x5fff, wherever a8 is set
+            DiscreteBoard::Nina03 if addr & 0xe100 == 0x4100 => {
+                self.set_prg_bank_32k((val >> 3) & 1);
+                self.set_chr_bank_8k(val & 0b111);
+            }
+            DiscreteBoard::Namco108 if matches!(addr, 0x8000..=0x9fff) => {
+                if addr & 1 == 0 {
+                    self.bank_select = val & 0b111;
+                    return;
+                }
+
+                // registers 0 and 1 select 2 KB chr banks (ignoring the lowest bit),
+                // registers 2-5 select 1 KB chr banks and registers 6-7 select prg banks
+                let chr_banks = &mut self.ppu_bus.chr_banks;
+                match self.bank_select {
+                    reg @ 0..=1 => {
+                        let reg = reg as usize;
+                        chr_banks[reg * 2] = val & 0x3e;
+                        chr_banks[reg * 2 + 1] = (val & 0x3e) | 1;
+                    }
+                    reg @ 2..=5 => chr_banks[reg as usize + 2] = val & 0x3f,
+                    reg => self.prg_banks[reg as usize - 6] = val & 0xf,
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl<'a> FromRom<'a> for DiscreteCpuAddressBus<'a> {
+    fn from_rom(
+        rom: &parse::RomDescription,
+        ppu: ppu::Ppu,
+        apu: apu::Apu,
+        controller: ctrl::Controller,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> Self {
+        let board = match (rom.header.mapper, rom.header.submapper) {
+            (11, _) => DiscreteBoard::ColorDreams,
+            (34, 1) => DiscreteBoard::Nina001,
+            (34, 2) => DiscreteBoard::Bnrom,
+            (34, _) if rom.chr_rom.len() > 0x2000 => DiscreteBoard::Nina001,
+            (34, _) => DiscreteBoard::Bnrom,
+            (66, _) => DiscreteBoard::Gxrom,
+            (71, 1) => DiscreteBoard::CamericaFireHawk,
+            (71, _) => DiscreteBoard::Camerica,
+            (79, _) => DiscreteBoard::Nina03,
+            (206, _) => DiscreteBoard::Namco108,
+            (n, _) => error_exit!(
+                "Failed to load rom file: mapper {} is not a discrete logic mapper",
+                n
+            ),
+        };
+
+        // only nina-001 boards have prg ram
+        let prg_ram = if board == DiscreteBoard::Nina001 {
+            PrgRam::from_header(&rom.header, 0x2000)
+        } else {
+            PrgRam::new(0, 0)
+        };
+
+        Self::new(
+            &rom.prg_rom,
+            &rom.chr_rom,
+            rom.header.total_chr_ram_size(),
+            prg_ram,
+            rom.header.mirroring,
+            board,
+            ppu,
+            apu,
+            controller,
+            framebuffer,
+        )
+    }
+}
+
+impl<'a> CpuAddressBus<'a> for DiscreteCpuAddressBus<'a> {
+    fn read(&mut self, mut addr: u16, cpu: &mut cpu::Cpu) -> u8 {
+        // internal ram
+        if super::is_0_to_1fff(addr) {
+            addr &= !0b1_1000_0000_0000;
+            return unsafe { *self.internal_ram.get_unchecked(addr as usize) };
+        }
+
+        // ppu registers
+        if super::is_2000_to_3fff(addr) {
+            self.base
+                .ppu
+                .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+
+            addr &= 0b111;
+            return self
+                .base
+                .ppu
+                .read_register_by_index(addr as u8, &mut self.ppu_bus, cpu);
+        }
+
+        // prg ram
+        if super::is_6000_to_7fff(addr) {
+            return if self.board == DiscreteBoard::Nina001 {
+                self.prg_ram.read((addr & 0x1fff) as usize)
+            } else {
+                0
+            };
+        }
+
+        // prg rom
+        if addr & 0x8000 != 0 {
+            return self.read_prg_rom(addr);
+        }
+
+        if addr == 0x4016 {
+            return self.base.controller.read();
+        }
+
+        0
+    }
+
+    fn write(&mut self, mut addr: u16, val: u8, cpu: &mut cpu::Cpu) {
+        if super::is_0_to_1fff(addr) {
+            addr &= !0b1_1000_0000_0000;
+            unsafe { *self.internal_ram.get_unchecked_mut(addr as usize) = val };
+            return;
+        }
+
+        if super::is_2000_to_3fff(addr) {
+            self.base
+                .ppu
+                .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+
+            self.base
+                .ppu
+                .write_register_by_index(addr as u8 & 0b111, val, cpu, &mut self.ppu_bus);
+
+            return;
+        }
+
+        // NOTE: the nina-001 registers at 0x7ffd-0x7fff also write through to prg ram
+        if super::is_6000_to_7fff(addr) && self.board == DiscreteBoard::Nina001 {
+            self.prg_ram.write((addr & 0x1fff) as usize, val);
+        }
+
+        match addr {
+            // oamdma
+            0x4014 => {
+                self.base
+                    .ppu
+                    .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+                super::write_oamdma(self, val, cpu);
+            }
+            // standard controller 1
+            0x4016 => self.base.controller.write(val),
+            0x4100..=0xffff => {
+                // the ppu must be caught up before chr banks or mirroring change
+                self.base
+                    .ppu
+                    .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+                self.write_register(addr, val);
+            }
+            _ => (),
+        }
+    }
+
+    fn base(&mut self) -> (&mut CpuAddressBusBase<'a>, &mut dyn PpuAddressBus) {
+        (&mut self.base, &mut self.ppu_bus)
+    }
+
+    fn battery_backed_ram(&mut self) -> Vec<&mut [u8]> {
+        if self.board == DiscreteBoard::Nina001 {
+            vec![self.prg_ram.battery_backed()]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        if self.board == DiscreteBoard::Nina001 {
+            self.prg_ram.window()
+        } else {
+            None
+        }
+    }
+}
+
+impl DiscretePpuAddressBus {
+    fn calc_chr_addr(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr >> 10) as usize] as usize;
+        (bank * 0x400) % self.chr.len() + (addr & 0x3ff) as usize
+    }
+}
+
+impl PpuAddressBus for DiscretePpuAddressBus {
+    fn read(&mut self, addr: u16, _: i32, _: &mut cpu::Cpu) -> u8 {
+        if addr >= 0x3f00 {
+            let addr = super::calc_ppu_palette_addr(addr);
+            return unsafe { *self.palettes.get_unchecked(addr as usize) };
+        }
+
+        if addr >= 0x2000 {
+            return self.nametables[self.layout.calc_vram_addr(addr)];
+        }
+
+        self.chr[self.calc_chr_addr(addr)]
+    }
+
+    fn write(&mut self, addr: u16, val: u8, _: i32, _: &mut cpu::Cpu) {
+        if addr >= 0x3f00 {
+            let addr = super::calc_ppu_palette_addr(addr);
+            unsafe { *self.palettes.get_unchecked_mut(addr as usize) = val };
+            return;
+        }
+
+        if addr >= 0x2000 {
+            let addr = self.layout.calc_vram_addr(addr);
+            self.nametables[addr] = val;
+        } else if self.chr_is_ram {
+            let addr = self.calc_chr_addr(addr);
+            self.chr[addr] = val;
+        }
+    }
+
+    fn set_address(&mut self, _: u16, _: i32, _: &mut cpu::Cpu) {}
+
+    fn read_palette_memory(&self, color_idx: u8) -> u8 {
+        self.palettes[super::calc_ppu_palette_addr(color_idx as u16) as usize]
+    }
+}
+
+// NOTE: 'Serialize' is implemented manually to avoid serializing rom
+impl serialize::Serialize for DiscretePpuAddressBus {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        if self.chr_is_ram {
+            self.chr.serialize(file)?;
+        }
+        self.nametables.serialize(file)?;
+        self.palettes.serialize(file)?;
+        self.chr_banks.serialize(file)?;
+        self.layout.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        if self.chr_is_ram {
+            self.chr.deserialize(file)?;
+        }
+        self.nametables.deserialize(file)?;
+        self.palettes.deserialize(file)?;
+        self.chr_banks.deserialize(file)?;
+        self.layout.deserialize(file)
+    }
+}
+
+impl<'a> serialize::Serialize for DiscreteCpuAddressBus<'a> {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        self.base.serialize(file)?;
+        self.ppu_bus.serialize(file)?;
+        self.internal_ram.serialize(file)?;
+        self.prg_ram.serialize(file)?;
+        self.prg_banks.serialize(file)?;
+        self.bank_select.serialize(file)?;
+        self.has_mirroring_control.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        self.base.deserialize(file)?;
+        self.ppu_bus.deserialize(file)?;
+        self.internal_ram.deserialize(file)?;
+        self.prg_ram.deserialize(file)?;
+        self.prg_banks.deserialize(file)?;
+        self.bank_select.deserialize(file)?;
+        self.has_mirroring_control.deserialize(file)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // prg bytes hold their 8 KB bank number and chr bytes their 1 KB bank number
+    fn new_test_bus<'a>(
+        board: DiscreteBoard,
+        chr_size: usize,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> DiscreteCpuAddressBus<'a> {
+        let prg_rom = (0..0x20000).map(|i| (i / 0x2000) as u8).collect::<Vec<_>>();
+        let chr_rom = (0..chr_size).map(|i| (i / 0x400) as u8).collect::<Vec<_>>();
+
+        DiscreteCpuAddressBus::new(
+            &prg_rom,
+            &chr_rom,
+            0,
+            PrgRam::new(0x2000, 0x2000),
+            parse::MirroringType::Vert,
+            board,
+            ppu::Ppu::new(),
+            apu::Apu::new(),
+            ctrl::Controller::default(),
+            framebuffer,
+        )
+    }
+
+    #[test]
+    fn test_color_dreams_and_gxrom() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut cpu = cpu::Cpu::default();
+
+        let mut bus = new_test_bus(DiscreteBoard::ColorDreams, 0x20000, unsafe {
+            &*(&framebuffer as *const _ as *const _)
+        });
+        // the written value is anded with the rom byte at 0xffff (bank 3)
+        bus.write(0xffff, 0x31, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 4);
+        assert_eq!(bus.read(0xe000, &mut cpu), 7);
+        assert_eq!(bus.ppu_bus.read(0x0000, 0, &mut cpu), 0);
+
+        bus.prg_rom[0xffff] = 0xff;
+        bus.write(0xffff, 0x23, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 12);
+        assert_eq!(bus.ppu_bus.read(0x1c00, 0, &mut cpu), 0x17);
+
+        let mut bus = new_test_bus(DiscreteBoard::Gxrom, 0x8000, unsafe {
+            &*(&framebuffer as *const _ as *const _)
+        });
+        bus.prg_rom[0x7fff] = 0xff;
+        bus.write(0xffff, 0x13, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 4);
+        assert_eq!(bus.ppu_bus.read(0x0400, 0, &mut cpu), 0x19);
+    }
+
+    #[test]
+    fn test_bnrom_and_nina001() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut cpu = cpu::Cpu::default();
+
+        let mut bus = new_test_bus(DiscreteBoard::Bnrom, 0, unsafe {
+            &*(&framebuffer as *const _ as *const _)
+        });
+        bus.write(0xe000, 3, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 12);
+        // chr ram
+        bus.ppu_bus.write(0x1234, 0x66, 0, &mut cpu);
+        assert_eq!(bus.ppu_bus.read(0x1234, 0, &mut cpu), 0x66);
+
+        let mut bus = new_test_bus(DiscreteBoard::Nina001, 0x10000, unsafe {
+            &*(&framebuffer as *const _ as *const _)
+        });
+        bus.write(0x7ffd, 1, &mut cpu);
+        bus.write(0x7ffe, 2, &mut cpu);
+        bus.write(0x7fff, 0xf, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 4);
+        assert_eq!(bus.ppu_bus.read(0x0000, 0, &mut cpu), 8);
+        assert_eq!(bus.ppu_bus.read(0x1fff, 0, &mut cpu), 0x3f);
+        assert_eq!(bus.read(0x7fff, &mut cpu), 0xf);
+    }
+
+    #[test]
+    fn test_camerica() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut cpu = cpu::Cpu::default();
+
+        let mut bus = new_test_bus(DiscreteBoard::Camerica, 0, unsafe {
+            &*(&framebuffer as *const _ as *const _)
+        });
+        assert_eq!(bus.read(0xc000, &mut cpu), 14);
+        bus.write(0xc000, 5, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 10);
+        assert_eq!(bus.read(0xa000, &mut cpu), 11);
+
+        // fire hawk's single-screen mirroring
+        bus.write(0x8000, 0x10, &mut cpu);
+        assert_eq!(bus.ppu_bus.layout, NametableLayout::VERTICAL);
+        bus.write(0x9000, 0x10, &mut cpu);
+        assert_eq!(bus.ppu_bus.layout, NametableLayout::SINGLE_SCREEN_B);
+        bus.write(0x8000, 0x00, &mut cpu);
+        assert_eq!(bus.ppu_bus.layout, NametableLayout::SINGLE_SCREEN_A);
+    }
+
+    #[test]
+    fn test_nina03() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(DiscreteBoard::Nina03, 0x10000, unsafe {
+            &*(&framebuffer as *const _ as *const _)
+        });
+        let mut cpu = cpu::Cpu::default();
+
+        // a8 must be set
+        bus.write(0x4000, 0x0f, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 0);
+
+        bus.write(0x5f00, 0x0f, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 4);
+        assert_eq!(bus.ppu_bus.read(0x0000, 0, &mut cpu), 0x38);
+    }
+
+    #[test]
+    fn test_namco108() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(DiscreteBoard::Namco108, 0x10000, unsafe {
+            &*(&framebuffer as *const _ as *const _)
+        });
+        let mut cpu = cpu::Cpu::default();
+
+        assert_eq!(bus.read(0xc000, &mut cpu), 14);
+        assert_eq!(bus.read(0xe000, &mut cpu), 15);
+
+        for (reg, val) in [5, 7, 20, 21, 22, 23, 3, 9].iter().enumerate() {
+            bus.write(0x8000, reg as u8, &mut cpu);
+            bus.write(0x8001, *val, &mut cpu);
+        }
+
+        assert_eq!(bus.read(0x8000, &mut cpu), 3);
+        assert_eq!(bus.read(0xa000, &mut cpu), 9);
+        assert_eq!(bus.ppu_bus.chr_banks, [4, 5, 6, 7, 20, 21, 22, 23]);
+        assert_eq!(bus.ppu_bus.read(0x0400, 0, &mut cpu), 5);
+    }
+}
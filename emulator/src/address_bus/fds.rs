@@ -0,0 +1,865 @@
+use super::nametable_layout::NametableLayout;
+use super::{CpuAddressBus, CpuAddressBusBase, PpuAddressBus};
+use crate::apu::ExpansionAudio;
+use crate::{apu, controller as ctrl, cpu, fds, ppu, serialize};
+
+use std::cell::Cell;
+use std::{fs, io};
+
+// the number of cpu cycles it takes the drive to transfer a byte (at ~96.4 kbit/s)
+const BYTE_TRANSFER_CYCLES: u32 = 150;
+// the number of cpu cycles it takes the drive to return the head to the start of the disk
+const REWIND_CYCLES: u32 = 50000;
+// how long the disk stays out of the drive when switching sides (about a second), so
+// that the bios notices the disk being ejected
+const SWITCH_SIDE_CYCLES: u32 = 1_789_773;
+
+// bits of 0x4025 (the disk control register)
+const CONTROL_MOTOR_ON: u8 = 0x01;
+const CONTROL_TRANSFER_RESET: u8 = 0x02;
+const CONTROL_READ_MODE: u8 = 0x04;
+const CONTROL_HORIZONTAL_MIRRORING: u8 = 0x08;
+const CONTROL_CRC: u8 = 0x10;
+const CONTROL_DISK_READY: u8 = 0x40;
+const CONTROL_IRQ_ENABLE: u8 = 0x80;
+
+// the famicom disk system's ram adapter, which plugs into the cartridge slot. it holds
+// the bios (0xe000-0xffff), 32 KB of prg ram (0x6000-0xdfff), 8 KB of chr ram, a timer
+// irq, the sound hardware and the interface to the disk drive
+pub struct FdsCpuAddressBus<'a> {
+    base: CpuAddressBusBase<'a>,
+    ppu_bus: FdsPpuAddressBus,
+    internal_ram: [u8; 0x800],
+    bios: Box<[u8]>,
+    prg_ram: Box<[u8]>,
+    drive: DiskDrive,
+    audio: apu::FdsAudio,
+    // 0x4020-0x4021
+    timer_reload: u16,
+    // counts down once per cpu cycle, and triggers the timer irq when it hits 0
+    timer_counter: u16,
+    // 0x4023. bit 0 enables the disk registers (and the timer), and bit 1 the sound registers
+    io_enable: u8,
+    // the number of cpu cycles the timer, the drive and 'audio'
+    // have been clocked for (see 'CpuAddressBus::catch_up()')
+    cycle_count: i32,
+    bits: FdsCpuBits::BitField,
+}
+
+bitfield!(FdsCpuBits<u8>(
+    timer_enabled: 0..0,
+    timer_repeat: 1..1,
+    timer_irq_asserted: 2..2,
+    disk_irq_asserted: 3..3
+));
+
+pub struct FdsPpuAddressBus {
+    chr_ram: Box<[u8]>,
+    nametables: [u8; 0x800],
+    palettes: [u8; 32],
+    // set through bit 3 of 0x4025
+    layout: NametableLayout,
+}
+
+// the disk drive, which streams the inserted disk side past the head a byte at a time.
+// sides are stored as they'd be laid out on an actual disk (see 'fds::add_gaps()')
+pub struct DiskDrive {
+    sides: Vec<Box<[u8]>>,
+    // the side in the drive (or the side that was in the drive, if the disk is ejected)
+    side: u8,
+    // counts down while a disk is being switched, after which 'side' is inserted
+    insert_delay: u32,
+    // the position of the head on the side
+    position: usize,
+    // the number of cpu cycles until the head reaches the next byte
+    transfer_delay: u32,
+    // 0x4031 and 0x4024
+    read_data: u8,
+    write_data: u8,
+    // 0x4025
+    control: u8,
+    crc: u16,
+    bits: DiskDriveBits::BitField,
+}
+
+bitfield!(DiskDriveBits<u8>(
+    disk_inserted: 0..0,
+    // set while the head moves over the disk (and cleared while it's rewinding)
+    scanning: 1..1,
+    // set once the head reaches the end of the disk (or the motor is stopped)
+    end_of_head: 2..2,
+    // set once the start mark of a block is read
+    gap_ended: 3..3,
+    transfer_complete: 4..4,
+    previous_crc_control: 5..5
+));
+
+impl DiskDrive {
+    fn new(sides: &[&[u8]]) -> Self {
+        let mut bits = DiskDriveBits::BitField::zeroed();
+        // start out with the first side inserted
+        bits.disk_inserted.set(1);
+        bits.end_of_head.set(1);
+
+        Self {
+            sides: sides
+                .iter()
+                .map(|side| fds::add_gaps(side).into_boxed_slice())
+                .collect(),
+            side: 0,
+            insert_delay: 0,
+            position: 0,
+            transfer_delay: 0,
+            read_data: 0,
+            write_data: 0,
+            control: 0,
+            crc: 0,
+            bits,
+        }
+    }
+
+    // the side in the drive, if any
+    pub fn inserted_side(&self) -> Option<usize> {
+        if self.bits.disk_inserted.is_true() {
+            Some(self.side as usize)
+        } else {
+            None
+        }
+    }
+
+    pub fn eject(&mut self) {
+        self.bits.disk_inserted.set(0);
+        self.insert_delay = 0;
+    }
+
+    // inserts the side that was last in the drive
+    pub fn insert(&mut self) {
+        self.bits.disk_inserted.set(1);
+        self.insert_delay = 0;
+    }
+
+    // ejects the disk, and inserts the next side (wrapping around to the first side
+    // of the first disk) once the bios has had time to notice
+    pub fn switch_side(&mut self) {
+        self.side = ((self.side as usize + 1) % self.sides.len()) as u8;
+        self.bits.disk_inserted.set(0);
+        self.insert_delay = SWITCH_SIDE_CYCLES;
+    }
+
+    // the contents of the disk in the .fds format, including any writes
+    pub fn image(&self) -> Vec<u8> {
+        self.sides
+            .iter()
+            .map(|side| fds::remove_gaps(side))
+            .collect::<Vec<_>>()
+            .concat()
+    }
+
+    fn is_ready(&self) -> bool {
+        self.bits.disk_inserted.is_true() && self.bits.scanning.is_true()
+    }
+
+    // advances the drive by a cpu cycle. returns whether a byte was transferred
+    // that should trigger an irq (if enabled through 0x4025)
+    fn clock(&mut self) -> bool {
+        if self.insert_delay > 0 {
+            self.insert_delay -= 1;
+            if self.insert_delay == 0 {
+                self.bits.disk_inserted.set(1);
+            }
+        }
+
+        if !self.bits.disk_inserted.is_true() || self.control & CONTROL_MOTOR_ON == 0 {
+            self.bits.end_of_head.set(1);
+            self.bits.scanning.set(0);
+            return false;
+        }
+
+        if self.control & CONTROL_TRANSFER_RESET != 0 && !self.bits.scanning.is_true() {
+            return false;
+        }
+
+        if self.bits.end_of_head.is_true() {
+            self.bits.end_of_head.set(0);
+            self.bits.gap_ended.set(0);
+            self.position = 0;
+            self.transfer_delay = REWIND_CYCLES;
+            return false;
+        }
+
+        if self.transfer_delay > 0 {
+            self.transfer_delay -= 1;
+            return false;
+        }
+
+        self.bits.scanning.set(1);
+        let is_ready = self.control & CONTROL_DISK_READY != 0;
+        let is_crc_control = self.control & CONTROL_CRC != 0;
+        if !is_ready {
+            self.crc = 0;
+        }
+
+        let side = &mut self.sides[self.side as usize];
+        let mut transferred = false;
+
+        if self.control & CONTROL_READ_MODE != 0 {
+            let data = side[self.position];
+
+            if !is_ready {
+                self.bits.gap_ended.set(0);
+            } else if data != 0 && !self.bits.gap_ended.is_true() {
+                // the start mark of the block is latched, but doesn't trigger an irq
+                self.bits.gap_ended.set(1);
+                self.bits.transfer_complete.set(1);
+                self.read_data = data;
+            } else if self.bits.gap_ended.is_true() {
+                self.bits.transfer_complete.set(1);
+                self.read_data = data;
+                transferred = true;
+            }
+        } else {
+            let mut data = 0;
+            if !is_crc_control {
+                self.bits.transfer_complete.set(1);
+                data = self.write_data;
+                transferred = true;
+            }
+
+            // the gap before a block is written while the drive isn't ready
+            if !is_ready {
+                data = 0;
+            }
+
+            if !is_crc_control {
+                self.crc = fds::update_crc(self.crc, data);
+            } else {
+                if !self.bits.previous_crc_control.is_true() {
+                    // shift the crc out
+                    self.crc = fds::update_crc(fds::update_crc(self.crc, 0), 0);
+                }
+
+                data = self.crc as u8;
+                self.crc >>= 8;
+            }
+
+            side[self.position] = data;
+            self.bits.gap_ended.set(0);
+        }
+
+        self.bits.previous_crc_control.set(is_crc_control as u8);
+
+        self.position += 1;
+        if self.position >= side.len() {
+            // the motor stops at the end of the disk
+            self.control &= !CONTROL_MOTOR_ON;
+            self.bits.end_of_head.set(1);
+        } else {
+            self.transfer_delay = BYTE_TRANSFER_CYCLES;
+        }
+
+        transferred && self.control & CONTROL_IRQ_ENABLE != 0
+    }
+}
+
+impl<'a> FdsCpuAddressBus<'a> {
+    pub fn new(
+        bios: &[u8],
+        sides: &[&[u8]],
+        ppu: ppu::Ppu,
+        apu: apu::Apu,
+        controller: ctrl::Controller,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> Self {
+        if bios.len() != 0x2000 {
+            error_exit!(
+                "Failed to load fds bios: the bios was the wrong size ({}, expected 8 KB)",
+                bios.len()
+            );
+        }
+
+        if sides.is_empty() || sides.len() > 0xff {
+            error_exit!(
+                "Failed to load rom file: fds images must have between 1 and 255 disk sides"
+            );
+        }
+
+        Self {
+            base: CpuAddressBusBase::new(ppu, apu, controller, framebuffer),
+            ppu_bus: FdsPpuAddressBus {
+                chr_ram: vec![0; 0x2000].into_boxed_slice(),
+                nametables: [0; 0x800],
+                palettes: [0; 32],
+                layout: NametableLayout::VERTICAL,
+            },
+            internal_ram: [0; 0x800],
+            bios: bios.to_vec().into_boxed_slice(),
+            prg_ram: vec![0; 0x8000].into_boxed_slice(),
+            drive: DiskDrive::new(sides),
+            audio: apu::FdsAudio::default(),
+            timer_reload: 0,
+            timer_counter: 0,
+            io_enable: 0,
+            cycle_count: 0,
+            bits: FdsCpuBits::BitField::zeroed(),
+        }
+    }
+
+    fn set_timer_irq(&mut self, assert: bool, cpu: &mut cpu::Cpu) {
+        if assert != self.bits.timer_irq_asserted.is_true() {
+            self.bits.timer_irq_asserted.set(assert as u8);
+            Self::update_irq_line(assert, cpu);
+        }
+    }
+
+    fn set_disk_irq(&mut self, assert: bool, cpu: &mut cpu::Cpu) {
+        if assert != self.bits.disk_irq_asserted.is_true() {
+            self.bits.disk_irq_asserted.set(assert as u8);
+            Self::update_irq_line(assert, cpu);
+        }
+    }
+
+    // the timer and the drive are separate irq sources
+    fn update_irq_line(assert: bool, cpu: &mut cpu::Cpu) {
+        if assert {
+            cpu.irq += 1;
+        } else {
+            cpu.irq = cpu.irq.saturating_sub(1);
+        }
+    }
+
+    fn read_register(&mut self, addr: u16, cpu: &mut cpu::Cpu) -> u8 {
+        self.catch_up(cpu);
+
+        match addr {
+            // disk status. reading acknowledges both irqs
+            0x4030 => {
+                let val = self.bits.timer_irq_asserted.get()
+                    | self.drive.bits.transfer_complete.get() << 1;
+
+                self.drive.bits.transfer_complete.set(0);
+                self.set_timer_irq(false, cpu);
+                self.set_disk_irq(false, cpu);
+
+                val
+            }
+            0x4031 => {
+                self.drive.bits.transfer_complete.set(0);
+                self.set_disk_irq(false, cpu);
+                self.drive.read_data
+            }
+            // drive status (bit 0 = no disk, bit 1 = not ready, bit 2 = write-protected)
+            0x4032 => {
+                let is_inserted = self.drive.bits.disk_inserted.is_true();
+                (!is_inserted as u8)
+                    | (!self.drive.is_ready() as u8) << 1
+                    | (!is_inserted as u8) << 2
+            }
+            // bit 7 of the expansion port is the battery status (1 = good)
+            0x4033 => 0x80,
+            0x4040..=0x4092 => self.audio.read_register(addr),
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8, cpu: &mut cpu::Cpu) {
+        self.catch_up(cpu);
+
+        if (self.io_enable & 0b01 == 0 && matches!(addr, 0x4024..=0x4026))
+            || (self.io_enable & 0b10 == 0 && addr >= 0x4040)
+        {
+            return;
+        }
+
+        match addr {
+            0x4020 => self.timer_reload = (self.timer_reload & 0xff00) | val as u16,
+            0x4021 => self.timer_reload = (self.timer_reload & 0xff) | (val as u16) << 8,
+            0x4022 => {
+                self.bits.timer_repeat.set(val & 1);
+                let is_enabled = val & 0b10 != 0 && self.io_enable & 0b01 != 0;
+                self.bits.timer_enabled.set(is_enabled as u8);
+
+                if is_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.set_timer_irq(false, cpu);
+                }
+            }
+            0x4023 => {
+                self.io_enable = val;
+                if val & 0b01 == 0 {
+                    self.bits.timer_enabled.set(0);
+                    self.set_timer_irq(false, cpu);
+                    self.set_disk_irq(false, cpu);
+                }
+            }
+            0x4024 => {
+                self.drive.write_data = val;
+                self.drive.bits.transfer_complete.set(0);
+                self.set_disk_irq(false, cpu);
+            }
+            0x4025 => {
+                self.drive.control = val;
+                self.ppu_bus.layout = if val & CONTROL_HORIZONTAL_MIRRORING != 0 {
+                    NametableLayout::HORIZONTAL
+                } else {
+                    NametableLayout::VERTICAL
+                };
+                self.set_disk_irq(false, cpu);
+            }
+            0x4040..=0x408a => self.audio.write_register(addr, val),
+            _ => (),
+        }
+    }
+}
+
+impl<'a> CpuAddressBus<'a> for FdsCpuAddressBus<'a> {
+    fn read(&mut self, mut addr: u16, cpu: &mut cpu::Cpu) -> u8 {
+        // internal ram
+        if super::is_0_to_1fff(addr) {
+            addr &= !0b1_1000_0000_0000;
+            return unsafe { *self.internal_ram.get_unchecked(addr as usize) };
+        }
+
+        // ppu registers
+        if super::is_2000_to_3fff(addr) {
+            self.base
+                .ppu
+                .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+
+            addr &= 0b111;
+            return self
+                .base
+                .ppu
+                .read_register_by_index(addr as u8, &mut self.ppu_bus, cpu);
+        }
+
+        match addr {
+            0x4016 => self.base.controller.read(),
+            0x4030..=0x4092 => self.read_register(addr, cpu),
+            0x6000..=0xdfff => self.prg_ram[(addr - 0x6000) as usize],
+            0xe000..=0xffff => self.bios[(addr - 0xe000) as usize],
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, mut addr: u16, val: u8, cpu: &mut cpu::Cpu) {
+        if super::is_0_to_1fff(addr) {
+            addr &= !0b1_1000_0000_0000;
+            unsafe { *self.internal_ram.get_unchecked_mut(addr as usize) = val };
+            return;
+        }
+
+        if super::is_2000_to_3fff(addr) {
+            self.base
+                .ppu
+                .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+
+            self.base
+                .ppu
+                .write_register_by_index(addr as u8 & 0b111, val, cpu, &mut self.ppu_bus);
+
+            return;
+        }
+
+        match addr {
+            // oamdma
+            0x4014 => {
+                self.base
+                    .ppu
+                    .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+                super::write_oamdma(self, val, cpu);
+            }
+            // standard controller 1
+            0x4016 => self.base.controller.write(val),
+            0x4020..=0x408a => self.write_register(addr, val, cpu),
+            0x6000..=0xdfff => self.prg_ram[(addr - 0x6000) as usize] = val,
+            _ => (),
+        }
+    }
+
+    fn base(&mut self) -> (&mut CpuAddressBusBase<'a>, &mut dyn PpuAddressBus) {
+        (&mut self.base, &mut self.ppu_bus)
+    }
+
+    fn catch_up(&mut self, cpu: &mut cpu::Cpu) {
+        while self.cycle_count < cpu.cycle_count {
+            self.audio.clock();
+
+            if self.bits.timer_enabled.is_true() {
+                if self.timer_counter == 0 {
+                    self.set_timer_irq(true, cpu);
+                    self.timer_counter = self.timer_reload;
+                    if !self.bits.timer_repeat.is_true() {
+                        self.bits.timer_enabled.set(0);
+                    }
+                } else {
+                    self.timer_counter -= 1;
+                }
+            }
+
+            if self.drive.clock() {
+                self.set_disk_irq(true, cpu);
+            }
+
+            self.cycle_count += 1;
+        }
+    }
+
+    fn sub_cycle_count(&mut self, sub: i32) {
+        self.cycle_count -= sub;
+    }
+
+    fn expansion_audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram[..0x2000])
+    }
+
+    fn disk_drive(&mut self) -> Option<&mut DiskDrive> {
+        Some(&mut self.drive)
+    }
+}
+
+impl PpuAddressBus for FdsPpuAddressBus {
+    fn read(&mut self, addr: u16, _: i32, _: &mut cpu::Cpu) -> u8 {
+        if addr >= 0x3f00 {
+            let addr = super::calc_ppu_palette_addr(addr);
+            return unsafe { *self.palettes.get_unchecked(addr as usize) };
+        }
+
+        if addr >= 0x2000 {
+            return self.nametables[self.layout.calc_vram_addr(addr)];
+        }
+
+        self.chr_ram[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, val: u8, _: i32, _: &mut cpu::Cpu) {
+        if addr >= 0x3f00 {
+            let addr = super::calc_ppu_palette_addr(addr);
+            unsafe { *self.palettes.get_unchecked_mut(addr as usize) = val };
+            return;
+        }
+
+        if addr >= 0x2000 {
+            let addr = self.layout.calc_vram_addr(addr);
+            self.nametables[addr] = val;
+            return;
+        }
+
+        self.chr_ram[addr as usize] = val;
+    }
+
+    fn set_address(&mut self, _: u16, _: i32, _: &mut cpu::Cpu) {}
+
+    fn read_palette_memory(&self, color_idx: u8) -> u8 {
+        self.palettes[super::calc_ppu_palette_addr(color_idx as u16) as usize]
+    }
+}
+
+impl serialize::Serialize for FdsPpuAddressBus {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        self.chr_ram.serialize(file)?;
+        self.nametables.serialize(file)?;
+        self.palettes.serialize(file)?;
+        self.layout.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        self.chr_ram.deserialize(file)?;
+        self.nametables.deserialize(file)?;
+        self.palettes.deserialize(file)?;
+        self.layout.deserialize(file)
+    }
+}
+
+// NOTE: the disk contents are part of the save state, as the game may have written to them
+impl serialize::Serialize for DiskDrive {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        self.sides.serialize(file)?;
+        self.side.serialize(file)?;
+        self.insert_delay.serialize(file)?;
+        self.position.serialize(file)?;
+        self.transfer_delay.serialize(file)?;
+        self.read_data.serialize(file)?;
+        self.write_data.serialize(file)?;
+        self.control.serialize(file)?;
+        self.crc.serialize(file)?;
+        self.bits.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        self.sides.deserialize(file)?;
+        self.side.deserialize(file)?;
+        self.insert_delay.deserialize(file)?;
+        self.position.deserialize(file)?;
+        self.transfer_delay.deserialize(file)?;
+        self.read_data.deserialize(file)?;
+        self.write_data.deserialize(file)?;
+        self.control.deserialize(file)?;
+        self.crc.deserialize(file)?;
+        self.bits.deserialize(file)
+    }
+}
+
+// NOTE: 'Serialize' is implemented manually to avoid serializing the bios
+impl<'a> serialize::Serialize for FdsCpuAddressBus<'a> {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        self.base.serialize(file)?;
+        self.ppu_bus.serialize(file)?;
+        self.internal_ram.serialize(file)?;
+        self.prg_ram.serialize(file)?;
+        self.drive.serialize(file)?;
+        self.audio.serialize(file)?;
+        self.timer_reload.serialize(file)?;
+        self.timer_counter.serialize(file)?;
+        self.io_enable.serialize(file)?;
+        self.cycle_count.serialize(file)?;
+        self.bits.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        self.base.deserialize(file)?;
+        self.ppu_bus.deserialize(file)?;
+        self.internal_ram.deserialize(file)?;
+        self.prg_ram.deserialize(file)?;
+        self.drive.deserialize(file)?;
+        self.audio.deserialize(file)?;
+        self.timer_reload.deserialize(file)?;
+        self.timer_counter.deserialize(file)?;
+        self.io_enable.deserialize(file)?;
+        self.cycle_count.deserialize(file)?;
+        self.bits.deserialize(file)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_test_bus<'a>(framebuffer: &'a [Cell<u32>; 256 * 240]) -> FdsCpuAddressBus<'a> {
+        let bios = (0..0x2000).map(|i| (i >> 8) as u8).collect::<Vec<_>>();
+        let side_a = fds::test::new_side(&[0x11, 0x22, 0x33]);
+        let side_b = fds::test::new_side(&[0x44]);
+
+        FdsCpuAddressBus::new(
+            &bios,
+            &[&side_a, &side_b],
+            ppu::Ppu::new(),
+            apu::Apu::new(),
+            ctrl::Controller::default(),
+            framebuffer,
+        )
+    }
+
+    // runs the bus for 'cycles' cpu cycles, resetting the cycle counts every
+    // now and then (like at the end of each frame)
+    fn run(bus: &mut FdsCpuAddressBus, cpu: &mut cpu::Cpu, cycles: u32) {
+        for _ in 0..cycles {
+            cpu.cycle_count += 1;
+            bus.catch_up(cpu);
+
+            if cpu.cycle_count == 0x4000 {
+                bus.sub_cycle_count(cpu.cycle_count);
+                cpu.cycle_count = 0;
+            }
+        }
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        bus.write(0x6000, 0x12, &mut cpu);
+        bus.write(0xdfff, 0x34, &mut cpu);
+        assert_eq!(bus.read(0x6000, &mut cpu), 0x12);
+        assert_eq!(bus.read(0xdfff, &mut cpu), 0x34);
+
+        // the bios is read-only
+        bus.write(0xe100, 0xff, &mut cpu);
+        assert_eq!(bus.read(0xe100, &mut cpu), 0x01);
+        assert_eq!(bus.read(0xfffc, &mut cpu), 0x1f);
+
+        // mirroring is controlled through 0x4025 (once the disk registers are enabled)
+        bus.write(0x4025, CONTROL_HORIZONTAL_MIRRORING, &mut cpu);
+        assert_eq!(bus.ppu_bus.layout, NametableLayout::VERTICAL);
+        bus.write(0x4023, 0b11, &mut cpu);
+        bus.write(0x4025, CONTROL_HORIZONTAL_MIRRORING, &mut cpu);
+        assert_eq!(bus.ppu_bus.layout, NametableLayout::HORIZONTAL);
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        bus.write(0x4023, 0b01, &mut cpu);
+        bus.write(0x4020, 10, &mut cpu);
+        bus.write(0x4021, 0, &mut cpu);
+        // repeating
+        bus.write(0x4022, 0b11, &mut cpu);
+
+        run(&mut bus, &mut cpu, 10);
+        assert_eq!(cpu.irq, 0);
+        run(&mut bus, &mut cpu, 1);
+        assert_eq!(cpu.irq, 1);
+
+        // reading the status acknowledges the irq
+        assert_eq!(bus.read(0x4030, &mut cpu) & 1, 1);
+        assert_eq!(cpu.irq, 0);
+
+        run(&mut bus, &mut cpu, 11);
+        assert_eq!(cpu.irq, 1);
+        bus.write(0x4022, 0, &mut cpu);
+        assert_eq!(cpu.irq, 0);
+    }
+
+    #[test]
+    fn test_disk_read() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        bus.write(0x4023, 0b01, &mut cpu);
+        // start the motor in read mode. the drive only reports ready once it has rewound
+        bus.write(0x4025, CONTROL_MOTOR_ON | CONTROL_READ_MODE, &mut cpu);
+        assert_eq!(bus.read(0x4032, &mut cpu), 0b010);
+        run(&mut bus, &mut cpu, REWIND_CYCLES + 2);
+        assert_eq!(bus.read(0x4032, &mut cpu), 0b000);
+
+        // wait for the first block, with byte transfer irqs enabled
+        bus.write(
+            0x4025,
+            CONTROL_MOTOR_ON | CONTROL_READ_MODE | CONTROL_DISK_READY | CONTROL_IRQ_ENABLE,
+            &mut cpu,
+        );
+        let mut data = Vec::new();
+        while data.len() < 15 {
+            run(&mut bus, &mut cpu, 1);
+            if cpu.irq != 0 {
+                data.push(bus.read(0x4031, &mut cpu));
+                assert_eq!(cpu.irq, 0);
+            }
+        }
+        assert_eq!(&data[..], b"\x01*NINTENDO-HVC*");
+    }
+
+    #[test]
+    fn test_disk_write() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        bus.write(0x4023, 0b01, &mut cpu);
+        bus.write(0x4025, CONTROL_MOTOR_ON, &mut cpu);
+        run(
+            &mut bus,
+            &mut cpu,
+            REWIND_CYCLES + 2 + BYTE_TRANSFER_CYCLES * 10,
+        );
+
+        // write a block (start mark, file count block) followed by its crc
+        let start = bus.drive.position;
+        bus.write(0x4024, 0x80, &mut cpu);
+        bus.write(
+            0x4025,
+            CONTROL_MOTOR_ON | CONTROL_DISK_READY | CONTROL_IRQ_ENABLE,
+            &mut cpu,
+        );
+        for &val in [2, 7].iter() {
+            while cpu.irq == 0 {
+                run(&mut bus, &mut cpu, 1);
+            }
+            bus.write(0x4024, val, &mut cpu);
+        }
+        while cpu.irq == 0 {
+            run(&mut bus, &mut cpu, 1);
+        }
+        bus.write(
+            0x4025,
+            CONTROL_MOTOR_ON | CONTROL_DISK_READY | CONTROL_CRC,
+            &mut cpu,
+        );
+        run(&mut bus, &mut cpu, (BYTE_TRANSFER_CYCLES + 1) * 2);
+
+        let written = &bus.drive.sides[0][start..start + 5];
+        assert_eq!(written[..3], [0x80, 2, 7]);
+        assert_eq!(
+            written
+                .iter()
+                .fold(0, |crc, &byte| fds::update_crc(crc, byte)),
+            0
+        );
+    }
+
+    #[test]
+    fn test_switch_side() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        let drive = bus.disk_drive().unwrap();
+        assert_eq!(drive.sides.len(), 2);
+        assert_eq!(drive.inserted_side(), Some(0));
+
+        drive.switch_side();
+        assert_eq!(drive.inserted_side(), None);
+        assert_eq!(bus.read(0x4032, &mut cpu), 0b111);
+
+        run(&mut bus, &mut cpu, SWITCH_SIDE_CYCLES);
+        assert_eq!(bus.disk_drive().unwrap().inserted_side(), Some(1));
+
+        // ejecting and inserting takes effect immediately
+        let drive = bus.disk_drive().unwrap();
+        drive.eject();
+        assert_eq!(drive.inserted_side(), None);
+        drive.insert();
+        assert_eq!(drive.inserted_side(), Some(1));
+    }
+
+    #[test]
+    fn test_audio() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        // a square wave at full gain, stepped every 32 cpu cycles
+        bus.write(0x4023, 0b11, &mut cpu);
+        bus.write(0x4089, 0x80, &mut cpu);
+        for i in 0..64 {
+            bus.write(0x4040 + i, if i < 32 { 0 } else { 63 }, &mut cpu);
+        }
+        bus.write(0x4080, 0x80 | 32, &mut cpu);
+        bus.write(0x4082, 0x00, &mut cpu);
+        bus.write(0x4083, 0x08, &mut cpu);
+
+        // the output is mixed into the apu's samples (as in the main loop). returns the
+        // peak-to-peak amplitude of the samples with the given master volume
+        bus.base.apu.set_recording(true);
+        let mut record = |master_volume: u8| {
+            bus.write(0x4089, master_volume, &mut cpu);
+            for _ in 0..0x1000 {
+                cpu.cycle_count += 4;
+                bus.catch_up(&mut cpu);
+                let output = bus.expansion_audio_output();
+                bus.base.apu.catch_up(cpu.cycle_count, output);
+            }
+
+            let samples = bus.base.apu.take_samples();
+            let max = samples.iter().copied().max().unwrap() as i32;
+            let min = samples.iter().copied().min().unwrap() as i32;
+            max - min
+        };
+
+        let full_volume = record(0);
+        let lowest_volume = record(3);
+        assert!(full_volume > 10_000);
+        // the lowest master volume is 2/5 of the full volume
+        assert!((lowest_volume as f32 / full_volume as f32 - 0.4).abs() < 0.05);
+    }
+}
@@ -0,0 +1,551 @@
+use super::nametable_layout::NametableLayout;
+use super::prg_ram::PrgRam;
+use super::{CpuAddressBus, CpuAddressBusBase, FromRom, PpuAddressBus};
+use crate::apu::ExpansionAudio;
+use crate::{apu, controller as ctrl, cpu, parse, ppu, serialize};
+
+use std::cell::Cell;
+use std::{fs, io};
+
+// sunsoft fme-7 and its sunsoft 5b variant (which adds expansion audio). the
+// audio registers are emulated for both, since fme-7 games never write to them
+
+pub struct Fme7CpuAddressBus<'a> {
+    base: CpuAddressBusBase<'a>,
+    ppu_bus: Fme7PpuAddressBus,
+    internal_ram: [u8; 0x800],
+    prg_rom: Box<[u8]>,
+    prg_ram: PrgRam,
+    // the command register (0x8000-0x9fff), selecting what
+    // writes to the parameter register (0xa000-0xbfff) do
+    command: u8,
+    // command 8. bits 0-5 select the bank at 0x6000, bit 6 selects
+    // ram instead of rom and bit 7 enables ram
+    prg_bank_6000: u8,
+    // commands 9-0xb (8 KB banks at 0x8000, 0xa000 and 0xc000)
+    prg_banks: [u8; 3],
+    // commands 0xe and 0xf. decremented once per cpu cycle
+    irq_counter: u16,
+    audio: apu::Sunsoft5bAudio,
+    // the number of cpu cycles the irq counter and 'audio'
+    // have been clocked for (see 'CpuAddressBus::catch_up()')
+    cycle_count: i32,
+    bits: Fme7CpuBits::BitField,
+}
+
+bitfield!(Fme7CpuBits<u8>(
+    // command 0xd (bit 0 and bit 7)
+    irq_enable: 0..0,
+    irq_counter_enable: 1..1,
+    irq_asserted: 2..2,
+));
+
+pub struct Fme7PpuAddressBus {
+    chr: Box<[u8]>,
+    chr_is_ram: bool,
+    nametables: [u8; 0x800],
+    palettes: [u8; 32],
+    // commands 0-7 (1 KB banks)
+    chr_banks: [u8; 8],
+    // command 0xc
+    layout: NametableLayout,
+}
+
+impl<'a> Fme7CpuAddressBus<'a> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        prg_rom: &[u8],
+        chr_rom: &[u8],
+        chr_ram_size: usize,
+        prg_ram: PrgRam,
+        mirroring: parse::MirroringType,
+        ppu: ppu::Ppu,
+        apu: apu::Apu,
+        controller: ctrl::Controller,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> Self {
+        if prg_rom.is_empty() || prg_rom.len() > 0x80000 || !prg_rom.len().is_multiple_of(0x2000) {
+            error_exit!(
+                "Failed to load rom file: prg rom must be a multiple of \
+                 8 KB and at most 512 KB for fme-7 (mapper 69)"
+            );
+        }
+
+        if chr_rom.len() > 0x40000 {
+            error_exit!(
+                "Failed to load rom file: chr rom must be at \
+                 most 256 KB for fme-7 (mapper 69)"
+            );
+        }
+
+        let (chr, chr_is_ram) = super::new_chr(chr_rom, chr_ram_size);
+
+        let ppu_bus = Fme7PpuAddressBus {
+            chr,
+            chr_is_ram,
+            nametables: [0; 0x800],
+            palettes: [0; 32],
+            chr_banks: [0; 8],
+            layout: match mirroring {
+                parse::MirroringType::Hor => NametableLayout::HORIZONTAL,
+                _ => NametableLayout::VERTICAL,
+            },
+        };
+
+        Self {
+            base: CpuAddressBusBase::new(ppu, apu, controller, framebuffer),
+            ppu_bus,
+            internal_ram: [0; 0x800],
+            prg_rom: prg_rom.to_vec().into_boxed_slice(),
+            prg_ram,
+            command: 0,
+            prg_bank_6000: 0,
+            prg_banks: [0; 3],
+            irq_counter: 0,
+            audio: apu::Sunsoft5bAudio::default(),
+            cycle_count: 0,
+            bits: Fme7CpuBits::BitField::zeroed(),
+        }
+    }
+
+    fn calc_prg_rom_addr(&self, bank: usize, addr: u16) -> usize {
+        (bank * 0x2000) % self.prg_rom.len() + (addr & 0x1fff) as usize
+    }
+
+    fn is_prg_ram_mapped(&self) -> bool {
+        self.prg_bank_6000 & 0x40 != 0
+    }
+
+    fn is_prg_ram_enabled(&self) -> bool {
+        self.prg_bank_6000 & 0xc0 == 0xc0
+    }
+
+    // asserts or acknowledges the irq on the cpu
+    fn set_irq(&mut self, assert: bool, cpu: &mut cpu::Cpu) {
+        if assert != self.bits.irq_asserted.is_true() {
+            self.bits.irq_asserted.set(assert as u8);
+            if assert {
+                cpu.irq += 1;
+            } else {
+                cpu.irq = cpu.irq.saturating_sub(1);
+            }
+        }
+    }
+
+    fn write_parameter(&mut self, val: u8, cpu: &mut cpu::Cpu) {
+        match self.command {
+            0x0..=0x7 => self.ppu_bus.chr_banks[self.command as usize] = val,
+            0x8 => self.prg_bank_6000 = val,
+            0x9..=0xb => self.prg_banks[self.command as usize - 9] = val & 0x3f,
+            0xc => self.ppu_bus.layout = NametableLayout::from_mirroring_bits(val),
+            0xd => {
+                // writing to the irq control register acknowledges the irq
+                self.catch_up(cpu);
+                self.bits.irq_enable.set(val & 1);
+                self.bits.irq_counter_enable.set(val >> 7);
+                self.set_irq(false, cpu);
+            }
+            0xe => {
+                self.catch_up(cpu);
+                self.irq_counter = (self.irq_counter & 0xff00) | val as u16;
+            }
+            0xf => {
+                self.catch_up(cpu);
+                self.irq_counter = (self.irq_counter & 0xff) | (val as u16) << 8;
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<'a> FromRom<'a> for Fme7CpuAddressBus<'a> {
+    fn from_rom(
+        rom: &parse::RomDescription,
+        ppu: ppu::Ppu,
+        apu: apu::Apu,
+        controller: ctrl::Controller,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> Self {
+        Self::new(
+            &rom.prg_rom,
+            &rom.chr_rom,
+            rom.header.total_chr_ram_size(),
+            PrgRam::from_header(&rom.header, 0x2000),
+            rom.header.mirroring,
+            ppu,
+            apu,
+            controller,
+            framebuffer,
+        )
+    }
+}
+
+impl<'a> CpuAddressBus<'a> for Fme7CpuAddressBus<'a> {
+    fn read(&mut self, mut addr: u16, cpu: &mut cpu::Cpu) -> u8 {
+        // internal ram
+        if super::is_0_to_1fff(addr) {
+            addr &= !0b1_1000_0000_0000;
+            return unsafe { *self.internal_ram.get_unchecked(addr as usize) };
+        }
+
+        // ppu registers
+        if super::is_2000_to_3fff(addr) {
+            self.base
+                .ppu
+                .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+
+            addr &= 0b111;
+            return self
+                .base
+                .ppu
+                .read_register_by_index(addr as u8, &mut self.ppu_bus, cpu);
+        }
+
+        // prg ram/rom
+        if super::is_6000_to_7fff(addr) {
+            if !self.is_prg_ram_mapped() {
+                let bank = (self.prg_bank_6000 & 0x3f) as usize;
+                return self.prg_rom[self.calc_prg_rom_addr(bank, addr)];
+            }
+
+            return if self.is_prg_ram_enabled() {
+                self.prg_ram.read((addr & 0x1fff) as usize)
+            } else {
+                0
+            };
+        }
+
+        // prg rom
+        if addr & 0x8000 != 0 {
+            let bank = match (addr >> 13) & 0b11 {
+                3 => self.prg_rom.len() / 0x2000 - 1,
+                window => self.prg_banks[window as usize] as usize,
+            };
+
+            return self.prg_rom[self.calc_prg_rom_addr(bank, addr)];
+        }
+
+        if addr == 0x4016 {
+            return self.base.controller.read();
+        }
+
+        0
+    }
+
+    fn write(&mut self, mut addr: u16, val: u8, cpu: &mut cpu::Cpu) {
+        if super::is_0_to_1fff(addr) {
+            addr &= !0b1_1000_0000_0000;
+            unsafe { *self.internal_ram.get_unchecked_mut(addr as usize) = val };
+            return;
+        }
+
+        if super::is_2000_to_3fff(addr) {
+            self.base
+                .ppu
+                .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+
+            self.base
+                .ppu
+                .write_register_by_index(addr as u8 & 0b111, val, cpu, &mut self.ppu_bus);
+
+            return;
+        }
+
+        if super::is_6000_to_7fff(addr) {
+            if self.is_prg_ram_mapped() && self.is_prg_ram_enabled() {
+                self.prg_ram.write((addr & 0x1fff) as usize, val);
+            }
+
+            return;
+        }
+
+        match addr {
+            // oamdma
+            0x4014 => {
+                self.base
+                    .ppu
+                    .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+                super::write_oamdma(self, val, cpu);
+            }
+            // standard controller 1
+            0x4016 => self.base.controller.write(val),
+            0x8000..=0x9fff => self.command = val & 0xf,
+            0xa000..=0xbfff => self.write_parameter(val, cpu),
+            // sunsoft 5b audio
+            0xc000..=0xdfff => self.audio.select_register(val),
+            0xe000..=0xffff => {
+                self.catch_up(cpu);
+                self.audio.write_register(val);
+            }
+            _ => (),
+        }
+    }
+
+    fn base(&mut self) -> (&mut CpuAddressBusBase<'a>, &mut dyn PpuAddressBus) {
+        (&mut self.base, &mut self.ppu_bus)
+    }
+
+    fn catch_up(&mut self, cpu: &mut cpu::Cpu) {
+        while self.cycle_count < cpu.cycle_count {
+            self.audio.clock();
+
+            if self.bits.irq_counter_enable.is_true() {
+                self.irq_counter = self.irq_counter.wrapping_sub(1);
+                if self.irq_counter == 0xffff && self.bits.irq_enable.is_true() {
+                    self.set_irq(true, cpu);
+                }
+            }
+
+            self.cycle_count += 1;
+        }
+    }
+
+    fn sub_cycle_count(&mut self, sub: i32) {
+        self.cycle_count -= sub;
+    }
+
+    fn expansion_audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn battery_backed_ram(&mut self) -> Vec<&mut [u8]> {
+        vec![self.prg_ram.battery_backed()]
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        self.prg_ram.window()
+    }
+}
+
+impl Fme7PpuAddressBus {
+    fn calc_chr_addr(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr >> 10) as usize] as usize;
+        (bank * 0x400) % self.chr.len() + (addr & 0x3ff) as usize
+    }
+}
+
+impl PpuAddressBus for Fme7PpuAddressBus {
+    fn read(&mut self, addr: u16, _: i32, _: &mut cpu::Cpu) -> u8 {
+        if addr >= 0x3f00 {
+            let addr = super::calc_ppu_palette_addr(addr);
+            return unsafe { *self.palettes.get_unchecked(addr as usize) };
+        }
+
+        if addr >= 0x2000 {
+            return self.nametables[self.layout.calc_vram_addr(addr)];
+        }
+
+        self.chr[self.calc_chr_addr(addr)]
+    }
+
+    fn write(&mut self, addr: u16, val: u8, _: i32, _: &mut cpu::Cpu) {
+        if addr >= 0x3f00 {
+            let addr = super::calc_ppu_palette_addr(addr);
+            unsafe { *self.palettes.get_unchecked_mut(addr as usize) = val };
+            return;
+        }
+
+        if addr >= 0x2000 {
+            let addr = self.layout.calc_vram_addr(addr);
+            self.nametables[addr] = val;
+        } else if self.chr_is_ram {
+            let addr = self.calc_chr_addr(addr);
+            self.chr[addr] = val;
+        }
+    }
+
+    fn set_address(&mut self, _: u16, _: i32, _: &mut cpu::Cpu) {}
+
+    fn read_palette_memory(&self, color_idx: u8) -> u8 {
+        self.palettes[super::calc_ppu_palette_addr(color_idx as u16) as usize]
+    }
+}
+
+// NOTE: 'Serialize' is implemented manually to avoid serializing rom
+impl serialize::Serialize for Fme7PpuAddressBus {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        if self.chr_is_ram {
+            self.chr.serialize(file)?;
+        }
+        self.nametables.serialize(file)?;
+        self.palettes.serialize(file)?;
+        self.chr_banks.serialize(file)?;
+        self.layout.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        if self.chr_is_ram {
+            self.chr.deserialize(file)?;
+        }
+        self.nametables.deserialize(file)?;
+        self.palettes.deserialize(file)?;
+        self.chr_banks.deserialize(file)?;
+        self.layout.deserialize(file)
+    }
+}
+
+impl<'a> serialize::Serialize for Fme7CpuAddressBus<'a> {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        self.base.serialize(file)?;
+        self.ppu_bus.serialize(file)?;
+        self.internal_ram.serialize(file)?;
+        self.prg_ram.serialize(file)?;
+        self.command.serialize(file)?;
+        self.prg_bank_6000.serialize(file)?;
+        self.prg_banks.serialize(file)?;
+        self.irq_counter.serialize(file)?;
+        self.audio.serialize(file)?;
+        self.cycle_count.serialize(file)?;
+        self.bits.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        self.base.deserialize(file)?;
+        self.ppu_bus.deserialize(file)?;
+        self.internal_ram.deserialize(file)?;
+        self.prg_ram.deserialize(file)?;
+        self.command.deserialize(file)?;
+        self.prg_bank_6000.deserialize(file)?;
+        self.prg_banks.deserialize(file)?;
+        self.irq_counter.deserialize(file)?;
+        self.audio.deserialize(file)?;
+        self.cycle_count.deserialize(file)?;
+        self.bits.deserialize(file)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_test_bus<'a>(framebuffer: &'a [Cell<u32>; 256 * 240]) -> Fme7CpuAddressBus<'a> {
+        let prg_rom = (0..0x20000).map(|i| (i / 0x2000) as u8).collect::<Vec<_>>();
+        let chr_rom = (0..0x20000).map(|i| (i / 0x400) as u8).collect::<Vec<_>>();
+
+        Fme7CpuAddressBus::new(
+            &prg_rom,
+            &chr_rom,
+            0,
+            PrgRam::new(0x2000, 0x2000),
+            parse::MirroringType::Vert,
+            ppu::Ppu::new(),
+            apu::Apu::new(),
+            ctrl::Controller::default(),
+            framebuffer,
+        )
+    }
+
+    fn write_command(bus: &mut Fme7CpuAddressBus, command: u8, val: u8, cpu: &mut cpu::Cpu) {
+        bus.write(0x8000, command, cpu);
+        bus.write(0xa000, val, cpu);
+    }
+
+    #[test]
+    fn test_prg_banking() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        write_command(&mut bus, 0x9, 3, &mut cpu);
+        write_command(&mut bus, 0xa, 5, &mut cpu);
+        write_command(&mut bus, 0xb, 7, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 3);
+        assert_eq!(bus.read(0xa000, &mut cpu), 5);
+        assert_eq!(bus.read(0xc000, &mut cpu), 7);
+        assert_eq!(bus.read(0xe000, &mut cpu), 15);
+
+        // rom at 0x6000
+        write_command(&mut bus, 0x8, 9, &mut cpu);
+        assert_eq!(bus.read(0x6000, &mut cpu), 9);
+
+        // ram at 0x6000, disabled and then enabled
+        write_command(&mut bus, 0x8, 0x40, &mut cpu);
+        bus.write(0x6000, 0xaa, &mut cpu);
+        assert_eq!(bus.read(0x6000, &mut cpu), 0);
+
+        write_command(&mut bus, 0x8, 0xc0, &mut cpu);
+        bus.write(0x6000, 0xaa, &mut cpu);
+        assert_eq!(bus.read(0x6000, &mut cpu), 0xaa);
+    }
+
+    #[test]
+    fn test_chr_banking_and_mirroring() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        write_command(&mut bus, 0x2, 0x33, &mut cpu);
+        write_command(&mut bus, 0x7, 0x44, &mut cpu);
+        assert_eq!(bus.ppu_bus.read(0x0800, 0, &mut cpu), 0x33);
+        assert_eq!(bus.ppu_bus.read(0x1fff, 0, &mut cpu), 0x44);
+
+        // single-screen using the second nametable
+        write_command(&mut bus, 0xc, 3, &mut cpu);
+        bus.ppu_bus.write(0x2005, 0x55, 0, &mut cpu);
+        assert_eq!(bus.ppu_bus.read(0x2c05, 0, &mut cpu), 0x55);
+        assert_eq!(bus.ppu_bus.nametables[0x405], 0x55);
+
+        // horizontal
+        write_command(&mut bus, 0xc, 1, &mut cpu);
+        assert_eq!(bus.ppu_bus.read(0x2805, 0, &mut cpu), 0x55);
+        assert_eq!(bus.ppu_bus.read(0x2005, 0, &mut cpu), 0);
+    }
+
+    #[test]
+    fn test_irq_counter() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        write_command(&mut bus, 0xe, 2, &mut cpu);
+        write_command(&mut bus, 0xf, 0, &mut cpu);
+        write_command(&mut bus, 0xd, 0x81, &mut cpu);
+
+        // the irq fires when the counter wraps around from 0 to 0xffff
+        cpu.cycle_count += 2;
+        bus.catch_up(&mut cpu);
+        assert_eq!(cpu.irq, 0);
+
+        cpu.cycle_count += 1;
+        bus.catch_up(&mut cpu);
+        assert_eq!(cpu.irq, 1);
+
+        // writing to the irq control register acknowledges the irq
+        write_command(&mut bus, 0xd, 0x80, &mut cpu);
+        assert_eq!(cpu.irq, 0);
+
+        // the counter keeps counting with irqs disabled
+        cpu.cycle_count += 10;
+        bus.catch_up(&mut cpu);
+        assert_eq!(cpu.irq, 0);
+        assert_eq!(bus.irq_counter, 0xfff5);
+    }
+
+    #[test]
+    fn test_audio() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        // channel a: period 0x100, volume 15, tone only
+        for &(register, val) in [(0x0, 0x00), (0x1, 0x01), (0x7, 0b111_110), (0x8, 15)].iter() {
+            bus.write(0xc000, register, &mut cpu);
+            bus.write(0xe000, val, &mut cpu);
+        }
+
+        // the output is mixed into the apu's samples (as in the main loop)
+        bus.base.apu.set_recording(true);
+        for _ in 0..0x1000 {
+            cpu.cycle_count += 4;
+            bus.catch_up(&mut cpu);
+            let output = bus.expansion_audio_output();
+            bus.base.apu.catch_up(cpu.cycle_count, output);
+        }
+
+        // at full volume, the square wave swings by about as much as an apu pulse channel
+        let samples = bus.base.apu.take_samples();
+        let max = samples.iter().copied().max().unwrap();
+        let min = samples.iter().copied().min().unwrap();
+        assert!(max > 3000 && min < -3000);
+    }
+}
@@ -0,0 +1,290 @@
+#[macro_use]
+use derive_serialize::Serialize;
+
+// the serial eeproms used for saving on some bandai boards, driven bit by bit
+// through their scl (clock) and sda (data) lines. the 24c02 (256 bytes) uses the
+// standard i2c protocol, with a device select byte preceding the word address and
+// all bytes sent msb first. the 24c01 (128 bytes) skips the device select byte,
+// instead sending a 7-bit address followed by the read/write bit, all lsb first
+#[derive(Serialize)]
+pub struct I2cEeprom {
+    data: Box<[u8]>,
+    is_24c01: bool,
+    state: u8,
+    // the state to enter after the acknowledge clock
+    next_state: u8,
+    // the last levels written to the scl and sda lines
+    scl: bool,
+    sda: bool,
+    // the level the eeprom drives sda to (true = released/high)
+    output: bool,
+    addr: u8,
+    // the byte being received, and the number of bits received or sent so far
+    shift: u8,
+    bit_count: u8,
+}
+
+// eeprom states
+const IDLE: u8 = 0;
+const DEVICE_SELECT: u8 = 1;
+const ADDRESS: u8 = 2;
+const WRITE: u8 = 3;
+const READ: u8 = 4;
+// the eeprom acknowledges a received byte
+const ACK: u8 = 5;
+// the cpu acknowledges a sent byte (or ends the read)
+const MASTER_ACK: u8 = 6;
+
+impl I2cEeprom {
+    pub fn new_24c01() -> Self {
+        Self::new(0x80, true)
+    }
+
+    pub fn new_24c02() -> Self {
+        Self::new(0x100, false)
+    }
+
+    fn new(size: usize, is_24c01: bool) -> Self {
+        Self {
+            data: vec![0; size].into_boxed_slice(),
+            is_24c01,
+            state: IDLE,
+            next_state: IDLE,
+            scl: false,
+            sda: false,
+            output: true,
+            addr: 0,
+            shift: 0,
+            bit_count: 0,
+        }
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    // the level of the sda line as seen by the cpu
+    pub fn read_sda(&self) -> bool {
+        self.output
+    }
+
+    pub fn write_lines(&mut self, scl: bool, sda: bool) {
+        if scl && self.scl {
+            // sda changing while scl is high signals a start or stop condition
+            if self.sda && !sda {
+                self.state = if self.is_24c01 {
+                    ADDRESS
+                } else {
+                    DEVICE_SELECT
+                };
+                self.bit_count = 0;
+                self.output = true;
+            } else if !self.sda && sda {
+                self.state = IDLE;
+                self.output = true;
+            }
+        } else if scl && !self.scl {
+            self.clock_rising_edge(sda);
+        } else if !scl && self.scl {
+            self.clock_falling_edge();
+        }
+
+        self.scl = scl;
+        self.sda = sda;
+    }
+
+    // data is sampled while scl is high
+    fn clock_rising_edge(&mut self, sda: bool) {
+        match self.state {
+            DEVICE_SELECT | ADDRESS | WRITE if self.bit_count < 8 => {
+                self.shift = if self.is_24c01 {
+                    (self.shift >> 1) | (sda as u8) << 7
+                } else {
+                    (self.shift << 1) | sda as u8
+                };
+                self.bit_count += 1;
+            }
+            READ if self.bit_count < 8 => self.bit_count += 1,
+            MASTER_ACK => {
+                if sda {
+                    // no acknowledge ends the read
+                    self.state = IDLE;
+                } else {
+                    self.addr = self.addr.wrapping_add(1) & (self.data.len() - 1) as u8;
+                    self.state = READ;
+                    self.bit_count = 0;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // the eeprom changes its output while scl is low
+    fn clock_falling_edge(&mut self) {
+        match self.state {
+            DEVICE_SELECT | ADDRESS | WRITE if self.bit_count == 8 => self.receive_byte(),
+            READ if self.bit_count < 8 => self.output = self.calc_output_bit(),
+            READ => {
+                self.output = true;
+                self.state = MASTER_ACK;
+            }
+            ACK => {
+                self.state = self.next_state;
+                self.bit_count = 0;
+                self.output = if self.state == READ {
+                    self.calc_output_bit()
+                } else {
+                    true
+                };
+            }
+            _ => (),
+        }
+    }
+
+    fn calc_output_bit(&self) -> bool {
+        let byte = self.data[self.addr as usize];
+        let bit = if self.is_24c01 {
+            self.bit_count
+        } else {
+            7 - self.bit_count
+        };
+
+        (byte >> bit) & 1 != 0
+    }
+
+    fn receive_byte(&mut self) {
+        let byte = self.shift;
+
+        self.next_state = match self.state {
+            DEVICE_SELECT => {
+                if byte & 0xf0 != 0xa0 {
+                    // not addressed to the eeprom
+                    self.state = IDLE;
+                    return;
+                }
+
+                if byte & 1 != 0 {
+                    READ
+                } else {
+                    ADDRESS
+                }
+            }
+            ADDRESS if self.is_24c01 => {
+                self.addr = byte & 0x7f;
+                if byte & 0x80 != 0 {
+                    READ
+                } else {
+                    WRITE
+                }
+            }
+            ADDRESS => {
+                self.addr = byte;
+                WRITE
+            }
+            _ => {
+                self.data[self.addr as usize] = byte;
+
+                // writes wrap around within a page (4 bytes on 24c01 and 8 bytes on 24c02)
+                let page_mask = if self.is_24c01 { 0b11 } else { 0b111 };
+                self.addr = (self.addr & !page_mask) | (self.addr.wrapping_add(1) & page_mask);
+                WRITE
+            }
+        };
+
+        self.state = ACK;
+        self.output = false;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn start(eeprom: &mut I2cEeprom) {
+        eeprom.write_lines(false, true);
+        eeprom.write_lines(true, true);
+        eeprom.write_lines(true, false);
+        eeprom.write_lines(false, false);
+    }
+
+    fn stop(eeprom: &mut I2cEeprom) {
+        eeprom.write_lines(false, false);
+        eeprom.write_lines(true, false);
+        eeprom.write_lines(true, true);
+    }
+
+    fn clock_bit(eeprom: &mut I2cEeprom, sda: bool) -> bool {
+        eeprom.write_lines(false, sda);
+        eeprom.write_lines(true, sda);
+        let bit = eeprom.read_sda();
+        eeprom.write_lines(false, sda);
+        bit
+    }
+
+    // returns whether the byte was acknowledged
+    fn send_byte(eeprom: &mut I2cEeprom, byte: u8, lsb_first: bool) -> bool {
+        for i in 0..8 {
+            let bit = if lsb_first { i } else { 7 - i };
+            clock_bit(eeprom, (byte >> bit) & 1 != 0);
+        }
+        !clock_bit(eeprom, true)
+    }
+
+    fn receive_byte(eeprom: &mut I2cEeprom, lsb_first: bool, ack: bool) -> u8 {
+        let mut byte = 0;
+        for i in 0..8 {
+            let bit = if lsb_first { i } else { 7 - i };
+            byte |= (clock_bit(eeprom, true) as u8) << bit;
+        }
+        clock_bit(eeprom, !ack);
+        byte
+    }
+
+    #[test]
+    fn test_24c02() {
+        let mut eeprom = I2cEeprom::new_24c02();
+
+        // write two bytes starting at 0x10
+        start(&mut eeprom);
+        assert!(send_byte(&mut eeprom, 0xa0, false));
+        assert!(send_byte(&mut eeprom, 0x10, false));
+        assert!(send_byte(&mut eeprom, 0x12, false));
+        assert!(send_byte(&mut eeprom, 0x34, false));
+        stop(&mut eeprom);
+        assert_eq!(eeprom.data[0x10..0x12], [0x12, 0x34]);
+
+        // random read: set the address, then restart as a read
+        start(&mut eeprom);
+        assert!(send_byte(&mut eeprom, 0xa0, false));
+        assert!(send_byte(&mut eeprom, 0x10, false));
+        start(&mut eeprom);
+        assert!(send_byte(&mut eeprom, 0xa1, false));
+        assert_eq!(receive_byte(&mut eeprom, false, true), 0x12);
+        assert_eq!(receive_byte(&mut eeprom, false, false), 0x34);
+        stop(&mut eeprom);
+
+        // other devices are ignored
+        start(&mut eeprom);
+        assert!(!send_byte(&mut eeprom, 0x50, false));
+    }
+
+    #[test]
+    fn test_24c01() {
+        let mut eeprom = I2cEeprom::new_24c01();
+
+        // page writes wrap around within 4 bytes
+        start(&mut eeprom);
+        assert!(send_byte(&mut eeprom, 0x02, true));
+        assert!(send_byte(&mut eeprom, 0xaa, true));
+        assert!(send_byte(&mut eeprom, 0xbb, true));
+        assert!(send_byte(&mut eeprom, 0xcc, true));
+        stop(&mut eeprom);
+        assert_eq!(eeprom.data[0..4], [0xcc, 0, 0xaa, 0xbb]);
+
+        start(&mut eeprom);
+        assert!(send_byte(&mut eeprom, 0x80 | 0x02, true));
+        assert_eq!(receive_byte(&mut eeprom, true, true), 0xaa);
+        assert_eq!(receive_byte(&mut eeprom, true, false), 0xbb);
+        stop(&mut eeprom);
+    }
+}
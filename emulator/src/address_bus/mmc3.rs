@@ -1,7 +1,9 @@
 use crate::{apu, controller as ctrl, cpu, parse, ppu, serialize};
 #[macro_use]
 use crate::bitfield;
-use super::{CpuAddressBus, CpuAddressBusBase, PpuAddressBus};
+use super::nametable_layout::{NametableLayout, NametablePage};
+use super::prg_ram::PrgRam;
+use super::{CpuAddressBus, CpuAddressBusBase, FromRom, PpuAddressBus};
 
 #[macro_use]
 use derive_serialize::Serialize;
@@ -10,13 +12,41 @@ use std::cell::Cell;
 use std::{fs, io};
 
 // NOTE: the current implementation ignores open bus behavior
-// and compatibility with mmc6 or any non-mapper-4 cartridges,
+
+// the boards built around the mmc3 (and the closely related mmc6). they all share
+// the same banking and irq logic, but differ in how chr and prg ram are wired up
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mmc3Board {
+    // mapper 4
+    Txrom,
+    // mapper 118. bit 7 of the chr bank registers drives ciram a10, selecting the
+    // nametable for each 1 KB section of 0x2000-0x2fff (the mirroring bit is ignored)
+    Txsrom,
+    // mapper 119. bit 6 of the chr bank registers selects 8 KB of chr ram
+    // instead of the (up to 64 KB) chr rom
+    Tqrom,
+    // mapper 4, submapper 1. only has 1 KB of internal prg ram, split into two
+    // 512 byte halves with separate enable and protect bits
+    Mmc6,
+}
+
+// the mmc3 revisions differ in when the irq counter triggers an irq. both trigger
+// one when the counter is decremented to 0, but only revision b (the sharp-made
+// mmc3b/mmc3c) also triggers one every time the counter is reloaded with a latch
+// of 0. revision a (mmc3a and the nec-made chips) only does so when the reload was
+// requested through 0xc001. selected by submapper 4 on mapper 4
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mmc3IrqRevision {
+    A,
+    B,
+}
 
 pub struct Mmc3CpuAddressBus<'a> {
     base: CpuAddressBusBase<'a>,
     ppu_bus: Mmc3PpuAddressBus,
     internal_ram: [u8; 0x800],
-    prg_ram: [u8; 0x2000],
+    // 1 KB on the mmc6 (inside the chip itself)
+    prg_ram: PrgRam,
     // up to 64 banks
     prg_banks: Box<[[u8; 0x2000]]>,
     // the bank register to update on the next write
@@ -36,6 +66,13 @@ bitfield!(Mmc3CpuBits<u8>(
     prg_banks_swapped: 0..0,
     prg_ram_enable: 1..1,
     prg_ram_protect: 2..2,
+    // bit 5 of 0x8000 on mmc6. clearing it also clears the bits below
+    mmc6_prg_ram_enable: 3..3,
+    // 0xa001 on mmc6 (the low half is 0x7000-0x71ff, the high half 0x7200-0x73ff)
+    mmc6_low_write_enable: 4..4,
+    mmc6_low_read_enable: 5..5,
+    mmc6_high_write_enable: 6..6,
+    mmc6_high_read_enable: 7..7,
 ));
 
 pub struct Mmc3PpuAddressBus {
@@ -44,31 +81,42 @@ pub struct Mmc3PpuAddressBus {
     // 'Mmc3PpuAddressBus' allows both the ppu and the cpu address buses
     // access to it while keeping the borrow checker happy
     r: [u8; 8],
-    // up to 256 banks
+    // up to 256 banks (of either chr rom or chr ram)
     chr_banks: Box<[[u8; 0x400]]>,
+    chr_is_ram: bool,
+    // 8 KB on 'Mmc3Board::Tqrom', empty otherwise
+    chr_ram: Box<[u8]>,
+    // 4 KB with four-screen mirroring, 2 KB otherwise
     nametables: Box<[u8]>,
+    // set through 0xa000 (unless the board has four-screen vram). ignored on txsrom
+    layout: NametableLayout,
     palettes: [u8; 32],
     irq_counter: u8,
     irq_latch: u8,
     cycle_count_at_prev_a12_high: i32,
+    board: Mmc3Board,
+    irq_revision: Mmc3IrqRevision,
     bits: Mmc3PpuBits::BitField,
 }
 
 bitfield!(Mmc3PpuBits<u8>(
-    hor_mirroring: 0..0,
-    no_mirroring: 1..1,
-    a12_invert: 2..2,
-    prev_a12: 3..3,
-    irq_reload: 4..4,
-    irq_enable: 5..5,
-    trigger_irq: 6..6,
+    a12_invert: 0..0,
+    prev_a12: 1..1,
+    irq_reload: 2..2,
+    irq_enable: 3..3,
+    trigger_irq: 4..4,
 ));
 
 impl<'a> Mmc3CpuAddressBus<'a> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         prg_rom: &[u8],
         chr_rom: &[u8],
+        chr_ram_size: usize,
+        prg_ram: PrgRam,
         mirroring: parse::MirroringType,
+        board: Mmc3Board,
+        irq_revision: Mmc3IrqRevision,
         ppu: ppu::Ppu,
         apu: apu::Apu,
         controller: ctrl::Controller,
@@ -78,29 +126,41 @@ impl<'a> Mmc3CpuAddressBus<'a> {
             0x4000..=0x80000 => (),
             _ => error_exit!(
                 "Failed to load rom file: prg rom must be \
-                 between 16 and 512 KB for mmc3 (mapper 4)"
+                 between 16 and 512 KB for mmc3 (mapper 4/118/119)"
             ),
         }
 
         if !prg_rom.len().is_power_of_two() {
             error_exit!(
                 "Failed to load rom file: prg rom size must be \
-                 a power of two for mmc3 (mapper 4)"
+                 a power of two for mmc3 (mapper 4/118/119)"
             );
         }
 
-        match chr_rom.len() {
-            0x2000..=0x40000 => (),
-            _ => error_exit!(
+        // only 6 bits of the chr bank registers select chr rom on tqrom
+        let max_chr_size = if board == Mmc3Board::Tqrom {
+            0x10000
+        } else {
+            0x40000
+        };
+
+        // boards without chr rom use chr ram instead (except for tqrom, which has both)
+        let has_chr_rom = !chr_rom.is_empty() || board == Mmc3Board::Tqrom;
+
+        if has_chr_rom && (chr_rom.len() < 0x2000 || chr_rom.len() > max_chr_size) {
+            error_exit!(
                 "Failed to load rom file: chr rom must be between \
-                 8 and 256 KB for mmc3 (mapper 4)"
-            ),
+                 8 and {} KB for mmc3 (mapper 4/118/119)",
+                max_chr_size / 0x400
+            );
         }
 
-        if !chr_rom.len().is_power_of_two() {
+        let (chr, chr_is_ram) = super::new_chr(chr_rom, chr_ram_size);
+
+        if !chr.len().is_power_of_two() {
             error_exit!(
-                "Failed to load rom file: chr rom size must be a \
-                 power of two for mmc3 (mapper 4)"
+                "Failed to load rom file: chr rom/ram size must be a \
+                 power of two for mmc3 (mapper 4/118/119)"
             );
         }
 
@@ -117,9 +177,8 @@ impl<'a> Mmc3CpuAddressBus<'a> {
         };
 
         let chr_banks = {
-            let slice = chr_rom.to_vec().into_boxed_slice();
-            let raw = Box::into_raw(slice) as *mut [u8; 0x400];
-            let n_banks = chr_rom.len() >> 10;
+            let n_banks = chr.len() >> 10;
+            let raw = Box::into_raw(chr) as *mut [u8; 0x400];
 
             unsafe {
                 let slice = std::slice::from_raw_parts_mut(raw, n_banks);
@@ -127,38 +186,37 @@ impl<'a> Mmc3CpuAddressBus<'a> {
             }
         };
 
-        let (n_nametables, hor_mirroring, no_mirroring) = match mirroring {
-            parse::MirroringType::Hor => (2, true, false),
-            parse::MirroringType::Vert => (2, false, false),
-            parse::MirroringType::FourScreen => (4, false, true),
+        let layout = NametableLayout::from_mirroring(mirroring);
+        let n_nametables = if layout == NametableLayout::FOUR_SCREEN {
+            4
+        } else {
+            2
         };
-
         let nametables = vec![0u8; 0x400 * n_nametables].into_boxed_slice();
 
+        let tqrom_chr_ram_size = if board == Mmc3Board::Tqrom { 0x2000 } else { 0 };
+
         let ppu_bus = Mmc3PpuAddressBus {
             chr_banks,
+            chr_is_ram,
+            chr_ram: vec![0; tqrom_chr_ram_size].into_boxed_slice(),
             nametables,
+            layout,
             palettes: [0; 32],
             r: [0; 8],
             irq_latch: 0,
             irq_counter: 0,
             cycle_count_at_prev_a12_high: 0,
-            bits: Mmc3PpuBits::BitField::new(
-                hor_mirroring as u8,
-                no_mirroring as u8,
-                0,
-                0,
-                0,
-                0,
-                0,
-            ),
+            board,
+            irq_revision,
+            bits: Mmc3PpuBits::BitField::zeroed(),
         };
 
         Self {
             base: CpuAddressBusBase::new(ppu, apu, controller, framebuffer),
             ppu_bus,
             internal_ram: [0; 0x800],
-            prg_ram: [0; 0x2000],
+            prg_ram,
             prg_banks,
             bank_register_to_update: 0,
             bits: Mmc3CpuBits::BitField::zeroed(),
@@ -166,6 +224,89 @@ impl<'a> Mmc3CpuAddressBus<'a> {
     }
 }
 
+impl<'a> Mmc3CpuAddressBus<'a> {
+    // the 1 KB of mmc6 prg ram is mirrored throughout 0x7000-0x7fff. reading from a
+    // disabled half returns 0 as long as the other half is enabled for reading
+    fn read_mmc6_prg_ram(&self, addr: u16) -> u8 {
+        if addr < 0x7000 {
+            return 0;
+        }
+
+        let readable = if addr & 0x200 != 0 {
+            self.bits.mmc6_high_read_enable.is_true()
+        } else {
+            self.bits.mmc6_low_read_enable.is_true()
+        };
+
+        // FIXME: return open bus when neither half is enabled for reading
+        if readable {
+            self.prg_ram.read((addr & 0x3ff) as usize)
+        } else {
+            0
+        }
+    }
+
+    // a half can only be written to while it is also enabled for reading
+    fn write_mmc6_prg_ram(&mut self, addr: u16, val: u8) {
+        if addr < 0x7000 {
+            return;
+        }
+
+        let writable = if addr & 0x200 != 0 {
+            self.bits.mmc6_high_read_enable.is_true() && self.bits.mmc6_high_write_enable.is_true()
+        } else {
+            self.bits.mmc6_low_read_enable.is_true() && self.bits.mmc6_low_write_enable.is_true()
+        };
+
+        if writable {
+            self.prg_ram.write((addr & 0x3ff) as usize, val);
+        }
+    }
+}
+
+impl<'a> FromRom<'a> for Mmc3CpuAddressBus<'a> {
+    fn from_rom(
+        rom: &parse::RomDescription,
+        ppu: ppu::Ppu,
+        apu: apu::Apu,
+        controller: ctrl::Controller,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> Self {
+        let board = match (rom.header.mapper, rom.header.submapper) {
+            (118, _) => Mmc3Board::Txsrom,
+            (119, _) => Mmc3Board::Tqrom,
+            (_, 1) => Mmc3Board::Mmc6,
+            _ => Mmc3Board::Txrom,
+        };
+
+        let irq_revision = if rom.header.mapper == 4 && rom.header.submapper == 4 {
+            Mmc3IrqRevision::A
+        } else {
+            Mmc3IrqRevision::B
+        };
+
+        let max_prg_ram_size = if board == Mmc3Board::Mmc6 {
+            0x400
+        } else {
+            0x2000
+        };
+
+        Self::new(
+            &rom.prg_rom,
+            &rom.chr_rom,
+            rom.header.total_chr_ram_size(),
+            PrgRam::from_header(&rom.header, max_prg_ram_size),
+            rom.header.mirroring,
+            board,
+            irq_revision,
+            ppu,
+            apu,
+            controller,
+            framebuffer,
+        )
+    }
+}
+
 impl<'a> CpuAddressBus<'a> for Mmc3CpuAddressBus<'a> {
     fn read(&mut self, mut addr: u16, cpu: &mut cpu::Cpu) -> u8 {
         // internal ram
@@ -187,10 +328,13 @@ impl<'a> CpuAddressBus<'a> for Mmc3CpuAddressBus<'a> {
                 .read_register_by_index(addr as u8, &mut self.ppu_bus, cpu);
         }
 
+        if super::is_6000_to_7fff(addr) && self.ppu_bus.board == Mmc3Board::Mmc6 {
+            return self.read_mmc6_prg_ram(addr);
+        }
+
         // prg ram
         if super::is_6000_to_7fff(addr) && self.bits.prg_ram_enable.is_true() {
-            addr &= !0b110_0000_0000_0000;
-            return unsafe { *self.prg_ram.get_unchecked(addr as usize) };
+            return self.prg_ram.read((addr & 0x1fff) as usize);
         }
 
         // FIXME: return open bus when ram is read from but is disabled
@@ -265,12 +409,16 @@ impl<'a> CpuAddressBus<'a> for Mmc3CpuAddressBus<'a> {
             return;
         }
 
+        if super::is_6000_to_7fff(addr) && self.ppu_bus.board == Mmc3Board::Mmc6 {
+            self.write_mmc6_prg_ram(addr, val);
+            return;
+        }
+
         if super::is_6000_to_7fff(addr)
             && self.bits.prg_ram_enable.is_true()
             && !self.bits.prg_ram_protect.is_true()
         {
-            addr &= !0b110_0000_0000_0000;
-            unsafe { *self.prg_ram.get_unchecked_mut(addr as usize) = val };
+            self.prg_ram.write((addr & 0x1fff) as usize, val);
             return;
         }
 
@@ -280,7 +428,16 @@ impl<'a> CpuAddressBus<'a> for Mmc3CpuAddressBus<'a> {
                 self.bank_register_to_update = val & 0b111;
                 self.bits.prg_banks_swapped.set((val & 0b100_0000) >> 6);
                 self.ppu_bus.bits.a12_invert.set((val & 0b1000_0000) >> 7);
-            // TODO: mmc6 stuff
+
+                if self.ppu_bus.board == Mmc3Board::Mmc6 {
+                    self.bits.mmc6_prg_ram_enable.set((val & 0b10_0000) >> 5);
+                    if val & 0b10_0000 == 0 {
+                        self.bits.mmc6_low_write_enable.set(0);
+                        self.bits.mmc6_low_read_enable.set(0);
+                        self.bits.mmc6_high_write_enable.set(0);
+                        self.bits.mmc6_high_read_enable.set(0);
+                    }
+                }
             } else {
                 // NOTE: 'val' is not %'d with the number of banks - this
                 // is instead done when reading from the bank registers
@@ -293,11 +450,24 @@ impl<'a> CpuAddressBus<'a> for Mmc3CpuAddressBus<'a> {
         // mirroring/prg ram enable and protect
         if super::is_a000_to_bfff(addr) {
             if addr & 1 == 0 {
-                self.ppu_bus.bits.hor_mirroring.set(val & 1);
+                if self.ppu_bus.layout != NametableLayout::FOUR_SCREEN {
+                    self.ppu_bus.layout = NametableLayout::from_mirroring_bits(val & 1);
+                }
+            } else if self.ppu_bus.board == Mmc3Board::Mmc6 {
+                // ignored while the prg ram is disabled through 0x8000
+                if self.bits.mmc6_prg_ram_enable.is_true() {
+                    self.bits.mmc6_low_write_enable.set((val & 0b1_0000) >> 4);
+                    self.bits.mmc6_low_read_enable.set((val & 0b10_0000) >> 5);
+                    self.bits
+                        .mmc6_high_write_enable
+                        .set((val & 0b100_0000) >> 6);
+                    self.bits
+                        .mmc6_high_read_enable
+                        .set((val & 0b1000_0000) >> 7);
+                }
             } else {
                 self.bits.prg_ram_protect.set((val & 0b100_0000) >> 6);
                 self.bits.prg_ram_enable.set((val & 0b1000_0000) >> 7);
-                // TODO: mmc6 stuff
             }
 
             return;
@@ -348,6 +518,19 @@ impl<'a> CpuAddressBus<'a> for Mmc3CpuAddressBus<'a> {
     fn base(&mut self) -> (&mut CpuAddressBusBase<'a>, &mut dyn PpuAddressBus) {
         (&mut self.base, &mut self.ppu_bus)
     }
+
+    fn battery_backed_ram(&mut self) -> Vec<&mut [u8]> {
+        vec![self.prg_ram.battery_backed()]
+    }
+
+    // NOTE: the mmc6 only has ram at 0x7000-0x7fff
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        if self.ppu_bus.board == Mmc3Board::Mmc6 {
+            None
+        } else {
+            self.prg_ram.window()
+        }
+    }
 }
 
 impl Mmc3PpuAddressBus {
@@ -360,7 +543,10 @@ impl Mmc3PpuAddressBus {
             if !self.bits.prev_a12.is_true() {
                 // ignore a12 rise if there was another a12 rise 6 or fewer cycles ago
                 if (cycle_count - self.cycle_count_at_prev_a12_high) as u32 > 6 {
-                    if self.bits.irq_reload.is_true() || self.irq_counter == 0 {
+                    let prev_irq_counter = self.irq_counter;
+                    let irq_reload = self.bits.irq_reload.is_true();
+
+                    if irq_reload || self.irq_counter == 0 {
                         // reload counter
                         self.irq_counter = self.irq_latch;
                         self.bits.irq_reload.set(0);
@@ -369,7 +555,17 @@ impl Mmc3PpuAddressBus {
                         self.irq_counter -= 1;
                     }
 
-                    if self.irq_counter == 0 && self.bits.irq_enable.is_true() {
+                    // revision a ignores the counter being reloaded with 0 when
+                    // it's already 0 (unless the reload was requested)
+                    let counter_reached_zero = match self.irq_revision {
+                        Mmc3IrqRevision::A => prev_irq_counter != 0 || irq_reload,
+                        Mmc3IrqRevision::B => true,
+                    };
+
+                    if self.irq_counter == 0
+                        && counter_reached_zero
+                        && self.bits.irq_enable.is_true()
+                    {
                         // if not already triggering cpu irq
                         if !self.bits.trigger_irq.is_true() {
                             self.bits.trigger_irq.set(1);
@@ -385,10 +581,62 @@ impl Mmc3PpuAddressBus {
 
         self.bits.prev_a12.set(a12 as u8);
     }
+
+    // returns the (unmasked) 1 KB chr bank that 'addr' (0-0x1fff) is mapped to
+    fn calc_chr_bank_idx(&self, addr: u16) -> u8 {
+        let a12 = (addr & 0b1_0000_0000_0000) != 0;
+        let a12_invert = self.bits.a12_invert.is_true();
+
+        if a12 == a12_invert {
+            // if this is reached, addr points to one of the 2kb chr banks (r0 or r1)
+            let bank_register_idx = ((addr >> 11) & 1) as u8;
+
+            // the lowest bit of the register is ignored, and is instead set
+            // when addr points to the upper section of the 2kb bank
+            let upper = ((addr >> 10) & 1) as u8;
+            (self.r[bank_register_idx as usize] & !1) | upper
+        } else {
+            // if this is reached, addr points to any of the 1kb chr banks (r2-r5)
+            let bank_register_idx = if a12_invert {
+                ((addr >> 10) + 2) as u8
+            } else {
+                ((addr >> 10) - 2) as u8
+            };
+
+            assert!(bank_register_idx >= 2);
+            self.r[bank_register_idx as usize]
+        }
+    }
+
+    fn calc_chr_ram_addr(&self, bank_idx: u8, addr: u16) -> usize {
+        ((bank_idx as usize & 0b111) << 10) | (addr as usize & 0x3ff)
+    }
+
+    // maps 'addr' (0x2000-0x3eff) to an index into 'nametables'
+    fn calc_nametable_addr(&self, addr: u16) -> usize {
+        if self.board == Mmc3Board::Txsrom {
+            return self.calc_txsrom_layout().calc_vram_addr(addr);
+        }
+
+        self.layout.calc_vram_addr(addr)
+    }
+
+    // on txsrom, the page for each nametable is selected by bit 7 of the
+    // chr bank that the corresponding 1 KB section of 0-0xfff is mapped to
+    fn calc_txsrom_layout(&self) -> NametableLayout {
+        let mut layout = NametableLayout::SINGLE_SCREEN_A;
+        for nametable_idx in 0..4 {
+            if self.calc_chr_bank_idx((nametable_idx as u16) << 10) & 0x80 != 0 {
+                layout.set_page(nametable_idx, NametablePage::CiramB);
+            }
+        }
+
+        layout
+    }
 }
 
 impl PpuAddressBus for Mmc3PpuAddressBus {
-    fn read(&mut self, mut addr: u16, cycle_count: i32, cpu: &mut cpu::Cpu) -> u8 {
+    fn read(&mut self, addr: u16, cycle_count: i32, cpu: &mut cpu::Cpu) -> u8 {
         assert!(addr <= 0x3fff);
 
         let a12 = (addr & 0b1_0000_0000_0000) != 0;
@@ -402,71 +650,28 @@ impl PpuAddressBus for Mmc3PpuAddressBus {
 
         // nametables (0x2000-0x3eff)
         if addr >= 0x2000 {
-            // apply horizontal or vertical mirroring
-            if !self.bits.no_mirroring.is_true() {
-                addr = super::calc_ppu_nametable_addr_with_mirroring(
-                    addr,
-                    self.bits.hor_mirroring.is_true(),
-                );
-            } else {
-                addr &= !0x3000;
-            }
-
-            return unsafe { *self.nametables.get_unchecked(addr as usize) };
+            let addr = self.calc_nametable_addr(addr);
+            return unsafe { *self.nametables.get_unchecked(addr) };
         }
 
         // pattern tables (0-0x1fff)
 
-        let a12_invert = self.bits.a12_invert.is_true();
-        let n_banks = self.chr_banks.len() as u16;
-        assert!(n_banks.is_power_of_two());
-
-        if a12 == a12_invert {
-            // if this is reached, addr points to one of the 2kb chr banks
+        let bank_idx = self.calc_chr_bank_idx(addr);
 
-            // calculate bank register index (should point to either r1 or r0)
-            let bank_register_idx = ((addr >> 11) & 1) as u8;
-
-            let bank_idx = if addr & 0b0100_0000_0000 != 0 {
-                // addr points to upper section of 2kb bank
-                assert!(matches!(
-                    addr,
-                    (0x400..=0x7ff) | (0xc00..=0xfff) | (0x1400..=0x17ff) | (0x1c00..=0x1fff)
-                ));
-
-                // - set lowest to bit to make 'bank_idx' reflect this
-                (self.r[bank_register_idx as usize] & ((n_banks - 1) as u8)) | 1
-            } else {
-                // addr points to lower section of 2kb bank
-                assert!(matches!(
-                    addr,
-                    (0..=0x3ff) | (0x800..=0xbff) | (0x1000..=0x13ff) | (0x1800..=0x1bff)
-                ));
-
-                // - clear lowest bit
-                // NOTE: the value in 'r[idx]' is %'d with the number of banks
-                (self.r[bank_register_idx as usize] & ((n_banks - 1) as u8)) & !1
-            };
-
-            let bank = &self.chr_banks[bank_idx as usize];
-            unsafe { *bank.get_unchecked(addr as usize & (bank.len() - 1)) }
-        } else {
-            // if this is reached, addr points to any of the 2kb chr banks (r2-r5)
-            let bank_register_idx = if a12_invert {
-                ((addr >> 10) + 2) as u8
-            } else {
-                ((addr >> 10) - 2) as u8
-            };
+        // on tqrom, bit 6 selects one of the 8 chr ram banks
+        if self.board == Mmc3Board::Tqrom && bank_idx & 0b100_0000 != 0 {
+            return self.chr_ram[self.calc_chr_ram_addr(bank_idx, addr)];
+        }
 
-            assert!(bank_register_idx >= 2);
+        let n_banks = self.chr_banks.len() as u16;
+        assert!(n_banks.is_power_of_two());
 
-            let bank_idx = self.r[bank_register_idx as usize] & ((n_banks - 1) as u8);
-            let bank = &self.chr_banks[bank_idx as usize];
-            unsafe { *bank.get_unchecked(addr as usize & (bank.len() - 1)) }
-        }
+        // NOTE: the value in 'r[idx]' is %'d with the number of banks
+        let bank = &self.chr_banks[(bank_idx & ((n_banks - 1) as u8)) as usize];
+        unsafe { *bank.get_unchecked(addr as usize & (bank.len() - 1)) }
     }
 
-    fn write(&mut self, mut addr: u16, val: u8, cycle_count: i32, cpu: &mut cpu::Cpu) {
+    fn write(&mut self, addr: u16, val: u8, cycle_count: i32, cpu: &mut cpu::Cpu) {
         let a12 = (addr & 0b1_0000_0000_0000) != 0;
         self.clock_irq_counter(a12, cycle_count, cpu);
 
@@ -477,14 +682,20 @@ impl PpuAddressBus for Mmc3PpuAddressBus {
         }
 
         if addr >= 0x2000 {
-            if !self.bits.no_mirroring.is_true() {
-                addr = super::calc_ppu_nametable_addr_with_mirroring(
-                    addr, //
-                    self.bits.hor_mirroring.is_true(),
-                );
-            }
+            let addr = self.calc_nametable_addr(addr);
+            unsafe { *self.nametables.get_unchecked_mut(addr) = val };
+            return;
+        }
 
-            unsafe { *self.nametables.get_unchecked_mut((addr & !0x3000) as usize) = val };
+        // chr rom is read-only, but tqrom can also bank in chr ram
+        let bank_idx = self.calc_chr_bank_idx(addr);
+        if self.board == Mmc3Board::Tqrom && bank_idx & 0b100_0000 != 0 {
+            let addr = self.calc_chr_ram_addr(bank_idx, addr);
+            self.chr_ram[addr] = val;
+        } else if self.chr_is_ram {
+            let n_banks = self.chr_banks.len() as u16;
+            let bank = &mut self.chr_banks[(bank_idx & ((n_banks - 1) as u8)) as usize];
+            bank[addr as usize & 0x3ff] = val;
         }
     }
 
@@ -502,7 +713,12 @@ impl PpuAddressBus for Mmc3PpuAddressBus {
 impl serialize::Serialize for Mmc3PpuAddressBus {
     fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
         self.r.serialize(file)?;
+        if self.chr_is_ram {
+            self.chr_banks.serialize(file)?;
+        }
+        self.chr_ram.serialize(file)?;
         self.nametables.serialize(file)?;
+        self.layout.serialize(file)?;
         self.palettes.serialize(file)?;
         self.irq_counter.serialize(file)?;
         self.irq_latch.serialize(file)?;
@@ -512,7 +728,12 @@ impl serialize::Serialize for Mmc3PpuAddressBus {
 
     fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
         self.r.deserialize(file)?;
+        if self.chr_is_ram {
+            self.chr_banks.deserialize(file)?;
+        }
+        self.chr_ram.deserialize(file)?;
         self.nametables.deserialize(file)?;
+        self.layout.deserialize(file)?;
         self.palettes.deserialize(file)?;
         self.irq_counter.deserialize(file)?;
         self.irq_latch.deserialize(file)?;
@@ -526,13 +747,7 @@ impl<'a> serialize::Serialize for Mmc3CpuAddressBus<'a> {
         self.base.serialize(file)?;
         self.ppu_bus.serialize(file)?;
         self.internal_ram.serialize(file)?;
-        // NOTE: we use fully qualified trait syntax to avoid accidentally going
-        // through 'CoerceUnsized' and picking the wrong 'Serialize' implementation
-        // (the 'Serialize' impl I wrote for [u8; 0x2000] should be faster than the
-        // implementation I wrote for [T]). This is probably not needed, as Rust
-        // should pick the right impl regardless (it should prefer not going
-        // through 'CoerceUnsized'), but I'm keeping it anyway
-        serialize::Serialize::serialize(&self.prg_ram, file)?;
+        self.prg_ram.serialize(file)?;
         self.bank_register_to_update.serialize(file)?;
         self.bits.serialize(file)
     }
@@ -541,7 +756,7 @@ impl<'a> serialize::Serialize for Mmc3CpuAddressBus<'a> {
         self.base.deserialize(file)?;
         self.ppu_bus.deserialize(file)?;
         self.internal_ram.deserialize(file)?;
-        serialize::Serialize::deserialize(&mut self.prg_ram, file)?;
+        self.prg_ram.deserialize(file)?;
         self.bank_register_to_update.deserialize(file)?;
         self.bits.deserialize(file)
     }
@@ -554,7 +769,7 @@ mod test {
     #[test]
     fn test_ppu_calc_addr() {
         let ppu = ppu::Ppu::new();
-        let apu = apu::Apu {};
+        let apu = apu::Apu::new();
         let controller = ctrl::Controller::default();
         let framebuffer = Cell::new([0u32; 256 * 240]);
 
@@ -565,7 +780,11 @@ mod test {
         let mut cpu_bus = Mmc3CpuAddressBus::new(
             &prg_rom,
             &chr_rom,
+            0,
+            PrgRam::new(0x2000, 0),
             parse::MirroringType::Hor,
+            Mmc3Board::Txrom,
+            Mmc3IrqRevision::B,
             ppu,
             apu,
             controller,
@@ -767,4 +986,203 @@ mod test {
         assert_eq!(calc_chr_bank_register_idx(0x03ff, true), 2);
         assert_eq!(calc_chr_bank_register_idx(0x0000, true), 2);
     }
+
+    fn new_cpu_bus(
+        board: Mmc3Board,
+        chr_size: usize,
+        framebuffer: &Cell<[u32; 256 * 240]>,
+    ) -> Mmc3CpuAddressBus<'_> {
+        Mmc3CpuAddressBus::new(
+            &vec![0; 1024 * 128],
+            &vec![0; chr_size],
+            0,
+            PrgRam::new(
+                if board == Mmc3Board::Mmc6 {
+                    0x400
+                } else {
+                    0x2000
+                },
+                0,
+            ),
+            parse::MirroringType::Vert,
+            board,
+            Mmc3IrqRevision::B,
+            ppu::Ppu::new(),
+            apu::Apu::new(),
+            ctrl::Controller::default(),
+            unsafe { &*(framebuffer as *const _ as *const _) },
+        )
+    }
+
+    #[test]
+    fn test_txsrom_nametables() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut cpu = cpu::Cpu::default();
+        let mut cpu_bus = new_cpu_bus(Mmc3Board::Txsrom, 1024 * 128, &framebuffer);
+
+        // r0 (0-0x7ff) selects the first nametable, r1 (0x800-0xfff) the second
+        cpu_bus.write(0x8000, 0, &mut cpu);
+        cpu_bus.write(0x8001, 0, &mut cpu);
+        cpu_bus.write(0x8000, 1, &mut cpu);
+        cpu_bus.write(0x8001, 0x80, &mut cpu);
+
+        cpu_bus.ppu_bus.write(0x2005, 0xaa, 0, &mut cpu);
+        cpu_bus.ppu_bus.write(0x2805, 0xbb, 0, &mut cpu);
+        assert_eq!(cpu_bus.ppu_bus.nametables[0x005], 0xaa);
+        assert_eq!(cpu_bus.ppu_bus.nametables[0x405], 0xbb);
+        assert_eq!(cpu_bus.ppu_bus.read(0x2405, 0, &mut cpu), 0xaa);
+        assert_eq!(cpu_bus.ppu_bus.read(0x2c05, 0, &mut cpu), 0xbb);
+
+        // the mirroring register is ignored
+        cpu_bus.write(0xa000, 1, &mut cpu);
+        assert_eq!(cpu_bus.ppu_bus.read(0x2405, 0, &mut cpu), 0xaa);
+
+        // with a12 inverted, r2-r5 select the nametables instead
+        cpu_bus.write(0x8000, 0x80 | 3, &mut cpu);
+        cpu_bus.write(0x8001, 0x80, &mut cpu);
+        assert_eq!(cpu_bus.ppu_bus.read(0x2005, 0, &mut cpu), 0xaa);
+        assert_eq!(cpu_bus.ppu_bus.read(0x2405, 0, &mut cpu), 0xbb);
+        assert_eq!(cpu_bus.ppu_bus.read(0x2805, 0, &mut cpu), 0xaa);
+    }
+
+    #[test]
+    fn test_tqrom_chr_ram() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut cpu = cpu::Cpu::default();
+        let mut cpu_bus = new_cpu_bus(Mmc3Board::Tqrom, 1024 * 64, &framebuffer);
+        cpu_bus.ppu_bus.chr_banks[5][0x10] = 0x11;
+
+        // r2 (0x1000-0x13ff) points to chr rom, r3 (0x1400-0x17ff) to chr ram bank 5
+        cpu_bus.write(0x8000, 2, &mut cpu);
+        cpu_bus.write(0x8001, 5, &mut cpu);
+        cpu_bus.write(0x8000, 3, &mut cpu);
+        cpu_bus.write(0x8001, 0x40 | 5, &mut cpu);
+
+        // writes to chr rom are ignored
+        cpu_bus.ppu_bus.write(0x1010, 0x22, 0, &mut cpu);
+        assert_eq!(cpu_bus.ppu_bus.read(0x1010, 0, &mut cpu), 0x11);
+
+        cpu_bus.ppu_bus.write(0x1410, 0x33, 0, &mut cpu);
+        assert_eq!(cpu_bus.ppu_bus.read(0x1410, 0, &mut cpu), 0x33);
+        assert_eq!(cpu_bus.ppu_bus.chr_ram[0x1410], 0x33);
+        assert_eq!(cpu_bus.ppu_bus.chr_banks[5][0x10], 0x11);
+
+        // chr ram can also be mapped through the 2kb banks
+        cpu_bus.write(0x8000, 0, &mut cpu);
+        cpu_bus.write(0x8001, 0x40 | 4, &mut cpu);
+        assert_eq!(cpu_bus.ppu_bus.read(0x0410, 0, &mut cpu), 0x33);
+    }
+
+    #[test]
+    fn test_mmc6_prg_ram() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut cpu = cpu::Cpu::default();
+        let mut cpu_bus = new_cpu_bus(Mmc3Board::Mmc6, 1024 * 128, &framebuffer);
+
+        // 0xa001 is ignored while prg ram is disabled through 0x8000
+        cpu_bus.write(0xa001, 0xf0, &mut cpu);
+        cpu_bus.write(0x7000, 0x11, &mut cpu);
+        assert_eq!(cpu_bus.read(0x7000, &mut cpu), 0);
+
+        // enable both halves for reading and writing
+        cpu_bus.write(0x8000, 0x20, &mut cpu);
+        cpu_bus.write(0xa001, 0xf0, &mut cpu);
+        cpu_bus.write(0x7000, 0x11, &mut cpu);
+        cpu_bus.write(0x7200, 0x22, &mut cpu);
+
+        // mirrored every 1 KB, but not below 0x7000
+        assert_eq!(cpu_bus.read(0x7c00, &mut cpu), 0x11);
+        assert_eq!(cpu_bus.read(0x7e00, &mut cpu), 0x22);
+        assert_eq!(cpu_bus.read(0x6000, &mut cpu), 0);
+
+        // protect the low half and disable reading the high half
+        cpu_bus.write(0xa001, 0x60, &mut cpu);
+        cpu_bus.write(0x7000, 0x33, &mut cpu);
+        cpu_bus.write(0x7200, 0x44, &mut cpu);
+        assert_eq!(cpu_bus.read(0x7000, &mut cpu), 0x11);
+        // writes to a half require it to be enabled for reading as well
+        assert_eq!(cpu_bus.read(0x7200, &mut cpu), 0);
+        assert_eq!(cpu_bus.prg_ram.as_mut_slice()[0x200], 0x22);
+
+        // disabling prg ram through 0x8000 also clears the 0xa001 bits
+        cpu_bus.write(0x8000, 0, &mut cpu);
+        cpu_bus.write(0x8000, 0x20, &mut cpu);
+        assert_eq!(cpu_bus.read(0x7000, &mut cpu), 0);
+    }
+
+    // clocks the irq counter by raising a12 'n' times (far enough apart to not be filtered)
+    fn clock_a12(ppu_bus: &mut Mmc3PpuAddressBus, n: usize, cpu: &mut cpu::Cpu) {
+        for _ in 0..n {
+            let cycle_count = ppu_bus.cycle_count_at_prev_a12_high + 10;
+            ppu_bus.set_address(0, cycle_count, cpu);
+            ppu_bus.set_address(0x1000, cycle_count, cpu);
+        }
+    }
+
+    #[test]
+    fn test_irq_revisions() {
+        for revision in [Mmc3IrqRevision::A, Mmc3IrqRevision::B] {
+            let framebuffer = Cell::new([0u32; 256 * 240]);
+            let mut cpu = cpu::Cpu::default();
+            let mut cpu_bus = new_cpu_bus(Mmc3Board::Txrom, 1024 * 128, &framebuffer);
+            cpu_bus.ppu_bus.irq_revision = revision;
+
+            // both revisions trigger an irq when decrementing to 0
+            cpu_bus.write(0xc000, 2, &mut cpu);
+            cpu_bus.write(0xc001, 0, &mut cpu);
+            cpu_bus.write(0xe001, 0, &mut cpu);
+            clock_a12(&mut cpu_bus.ppu_bus, 2, &mut cpu);
+            assert_eq!(cpu.irq, 0);
+            clock_a12(&mut cpu_bus.ppu_bus, 1, &mut cpu);
+            assert_eq!(cpu.irq, 1);
+
+            // .. and when a reload with 0 was requested through 0xc001
+            cpu_bus.write(0xe000, 0, &mut cpu);
+            cpu_bus.write(0xe001, 0, &mut cpu);
+            cpu_bus.write(0xc000, 0, &mut cpu);
+            cpu_bus.write(0xc001, 0, &mut cpu);
+            clock_a12(&mut cpu_bus.ppu_bus, 1, &mut cpu);
+            assert_eq!(cpu.irq, 1);
+
+            // but only revision b keeps triggering irqs while reloading a latch of 0
+            cpu_bus.write(0xe000, 0, &mut cpu);
+            cpu_bus.write(0xe001, 0, &mut cpu);
+            clock_a12(&mut cpu_bus.ppu_bus, 1, &mut cpu);
+            let expected_irq = if revision == Mmc3IrqRevision::B { 1 } else { 0 };
+            assert_eq!(cpu.irq, expected_irq);
+        }
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        use serialize::Serialize;
+
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut cpu = cpu::Cpu::default();
+        let mut cpu_bus = new_cpu_bus(Mmc3Board::Txrom, 0, &framebuffer);
+        assert!(cpu_bus.ppu_bus.chr_is_ram);
+        assert_eq!(cpu_bus.ppu_bus.chr_banks.len(), 8);
+
+        // chr ram is banked like chr rom. point r2 (0x1000-0x13ff) to bank 6
+        cpu_bus.write(0x8000, 2, &mut cpu);
+        cpu_bus.write(0x8001, 6, &mut cpu);
+        cpu_bus.ppu_bus.write(0x1010, 0xaa, 0, &mut cpu);
+        assert_eq!(cpu_bus.ppu_bus.chr_banks[6][0x10], 0xaa);
+        assert_eq!(cpu_bus.ppu_bus.read(0x1010, 0, &mut cpu), 0xaa);
+
+        // chr ram is included in save states
+        let path = std::env::temp_dir().join("nees_mmc3_chr_ram_test");
+        {
+            let file = fs::File::create(&path).unwrap();
+            let mut writer = io::BufWriter::new(file);
+            cpu_bus.ppu_bus.serialize(&mut writer).unwrap();
+        }
+
+        cpu_bus.ppu_bus.chr_banks[6][0x10] = 0;
+        let mut reader = io::BufReader::new(fs::File::open(&path).unwrap());
+        cpu_bus.ppu_bus.deserialize(&mut reader).unwrap();
+        let _ = fs::remove_file(path);
+
+        assert_eq!(cpu_bus.ppu_bus.chr_banks[6][0x10], 0xaa);
+    }
 }
@@ -0,0 +1,1118 @@
+use super::nametable_layout::{NametableLayout, NametablePage};
+use super::prg_ram::PrgRam;
+use super::{CpuAddressBus, CpuAddressBusBase, FromRom, PpuAddressBus, PpuFetchKind};
+use crate::apu::ExpansionAudio;
+use crate::{apu, controller as ctrl, cpu, parse, ppu, serialize};
+
+use std::cell::Cell;
+use std::{fs, io};
+
+// NOTE: the current implementation ignores open bus behavior, as well as
+// the mmc5a-only registers (0x5207-0x5209 and the cl3/sl3 pins)
+
+pub struct Mmc5CpuAddressBus<'a> {
+    base: CpuAddressBusBase<'a>,
+    ppu_bus: Mmc5PpuAddressBus,
+    internal_ram: [u8; 0x800],
+    prg_rom: Box<[u8]>,
+    // up to 64 KB (eight 8 KB banks)
+    prg_ram: PrgRam,
+    // prg bank registers 0x5113-0x5117
+    prg_banks: [u8; 5],
+    // prg ram protect registers 0x5102 and 0x5103
+    prg_ram_protect: [u8; 2],
+    prg_mode: u8,
+    // the two values written to 0x5205 and 0x5206
+    multiplicands: [u8; 2],
+    audio: apu::Mmc5Audio,
+    // the number of cpu cycles 'audio' has been clocked for
+    // (see 'CpuAddressBus::catch_up()')
+    cycle_count: i32,
+    bits: Mmc5CpuBits::BitField,
+}
+
+bitfield!(Mmc5CpuBits<u8>(
+    pcm_irq_asserted: 0..0,
+));
+
+pub struct Mmc5PpuAddressBus {
+    chr: Box<[u8]>,
+    chr_is_ram: bool,
+    // the two nametables in console vram (ciram)
+    nametables: [u8; 0x800],
+    exram: [u8; 0x400],
+    palettes: [u8; 32],
+    // chr bank registers 0x5120-0x5127 (used for sprites) and 0x5128-0x512b
+    // (used for backgrounds when sprites are 8x16). the upper 2 bits of each
+    // register come from 0x5130 at the time of the write
+    sprite_chr_banks: [u16; 8],
+    bg_chr_banks: [u16; 4],
+    chr_upper_bits: u8,
+    chr_mode: u8,
+    exram_mode: u8,
+    // 0x5105. exram is mapped as cartridge vram page 0
+    layout: NametableLayout,
+    fill_tile: u8,
+    fill_attribute: u8,
+    // vertical split registers 0x5200-0x5202
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+    // the y coordinate within the split region of the current scanline
+    split_y: u8,
+    irq_scanline: u8,
+    scanline_counter: u8,
+    // scanlines are detected by watching for three consecutive reads
+    // from the same nametable address (see 'detect_scanline()')
+    prev_nametable_addr: u16,
+    nametable_match_count: u8,
+    // the horizontal position of the background tile currently being
+    // fetched, counted from the start of the sprite fetches
+    tile_counter: u8,
+    current_tile: u8,
+    // the exram byte belonging to the current tile (when in extended attribute mode)
+    ext_attribute: u8,
+    bits: Mmc5PpuBits::BitField,
+}
+
+bitfield!(Mmc5PpuBits<u16>(
+    sprites_8x16: 0..0,
+    // whether 0x5128-0x512b were written to more recently than 0x5120-0x5127
+    bg_chr_banks_last_written: 1..1,
+    sprite_fetch: 2..2,
+    ppudata_access: 3..3,
+    // set while tiles for the next scanline are fetched at dots 321-336,
+    // before that scanline has been detected
+    prefetch: 4..4,
+    in_split: 5..5,
+    in_frame: 6..6,
+    irq_enable: 7..7,
+    irq_pending: 8..8,
+    irq_asserted: 9..9,
+));
+
+impl<'a> Mmc5CpuAddressBus<'a> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        prg_rom: &[u8],
+        chr_rom: &[u8],
+        chr_ram_size: usize,
+        prg_ram: PrgRam,
+        mirroring: parse::MirroringType,
+        ppu: ppu::Ppu,
+        apu: apu::Apu,
+        controller: ctrl::Controller,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> Self {
+        if prg_rom.is_empty() || prg_rom.len() > 0x100000 || !prg_rom.len().is_multiple_of(0x2000) {
+            error_exit!(
+                "Failed to load rom file: prg rom must be a multiple of \
+                 8 KB and at most 1024 KB for mmc5 (mapper 5)"
+            );
+        }
+
+        if chr_rom.len() > 0x100000 || !chr_rom.len().is_multiple_of(0x400) {
+            error_exit!(
+                "Failed to load rom file: chr rom must be a multiple of \
+                 1 KB and at most 1024 KB for mmc5 (mapper 5)"
+            );
+        }
+
+        // MMC5 controls mirroring itself (through 0x5105), but start out
+        // with whatever the header specifies
+        let layout = match mirroring {
+            parse::MirroringType::Hor => NametableLayout::HORIZONTAL,
+            _ => NametableLayout::VERTICAL,
+        };
+
+        let (chr, chr_is_ram) = super::new_chr(chr_rom, chr_ram_size);
+
+        let ppu_bus = Mmc5PpuAddressBus {
+            chr,
+            chr_is_ram,
+            nametables: [0; 0x800],
+            exram: [0; 0x400],
+            palettes: [0; 32],
+            sprite_chr_banks: [0; 8],
+            bg_chr_banks: [0; 4],
+            chr_upper_bits: 0,
+            chr_mode: 0,
+            exram_mode: 0,
+            layout,
+            fill_tile: 0,
+            fill_attribute: 0,
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            split_y: 0,
+            irq_scanline: 0,
+            scanline_counter: 0,
+            prev_nametable_addr: 0,
+            nametable_match_count: 0,
+            tile_counter: 0,
+            current_tile: 0,
+            ext_attribute: 0,
+            bits: Mmc5PpuBits::BitField::zeroed(),
+        };
+
+        Self {
+            base: CpuAddressBusBase::new(ppu, apu, controller, framebuffer),
+            ppu_bus,
+            internal_ram: [0; 0x800],
+            prg_rom: prg_rom.to_vec().into_boxed_slice(),
+            prg_ram,
+            // 0x5117 is set to the last bank on power-up
+            prg_banks: [0, 0, 0, 0, 0xff],
+            prg_ram_protect: [0; 2],
+            prg_mode: 3,
+            multiplicands: [0; 2],
+            audio: apu::Mmc5Audio::default(),
+            cycle_count: 0,
+            bits: Mmc5CpuBits::BitField::zeroed(),
+        }
+    }
+
+    // returns the bank register (as an index into 'prg_banks') that
+    // maps the 8 KB window at 'addr' (0x8000-0xffff), along with the
+    // offset of the window within the bank it selects (in 8 KB units)
+    fn calc_prg_bank_register(&self, addr: u16) -> (usize, u8) {
+        let window = ((addr >> 13) & 0b11) as u8;
+
+        match (self.prg_mode, window) {
+            // one 32 KB bank (0x5117)
+            (0, w) => (4, w),
+            // two 16 KB banks (0x5115 and 0x5117)
+            (1, 0..=1) => (2, window),
+            (1, _) => (4, window & 1),
+            // one 16 KB bank (0x5115) and two 8 KB banks (0x5116 and 0x5117)
+            (2, 0..=1) => (2, window),
+            (2, w) => (w as usize + 1, 0),
+            // four 8 KB banks (0x5114-0x5117)
+            (_, w) => (w as usize + 1, 0),
+        }
+    }
+
+    // returns the offset into either 'prg_ram' or 'prg_rom' for 'addr'
+    // (0x8000-0xffff), and whether the offset is into 'prg_ram'
+    // NOTE: offsets into 'prg_ram' are mirrored by 'PrgRam' itself
+    fn calc_prg_addr(&self, addr: u16) -> (usize, bool) {
+        let (register_idx, window_offset) = self.calc_prg_bank_register(addr);
+        let bank = self.prg_banks[register_idx];
+
+        // clear the low bits of the bank number for 16 and 32 KB banks
+        let bank_mask = match (self.prg_mode, register_idx) {
+            (0, _) => !0b11,
+            (1, _) | (2, 2) => !0b1,
+            _ => !0,
+        };
+
+        // bit 7 selects rom, except for 0x5117, which can only map rom
+        let is_ram = register_idx != 4 && bank & 0x80 == 0;
+        let offset = (addr & 0x1fff) as usize;
+
+        if is_ram {
+            let bank = ((bank & bank_mask & 0b111) | window_offset) as usize;
+            (bank * 0x2000 + offset, true)
+        } else {
+            let bank = ((bank & bank_mask & 0x7f) | window_offset) as usize;
+            ((bank * 0x2000) % self.prg_rom.len() + offset, false)
+        }
+    }
+
+    fn is_prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect[0] & 0b11 == 0b10 && self.prg_ram_protect[1] & 0b11 == 0b01
+    }
+
+    fn calc_prg_ram_addr(&self, addr: u16) -> usize {
+        let bank = (self.prg_banks[0] & 0b111) as usize;
+        bank * 0x2000 + (addr & 0x1fff) as usize
+    }
+
+    // asserts or acknowledges the pcm irq on the cpu to reflect the state of 'audio'
+    fn update_pcm_irq(&mut self, cpu: &mut cpu::Cpu) {
+        let assert = self.audio.is_irq_asserted();
+        if assert != self.bits.pcm_irq_asserted.is_true() {
+            self.bits.pcm_irq_asserted.set(assert as u8);
+            if assert {
+                cpu.irq += 1;
+            } else {
+                cpu.irq = cpu.irq.saturating_sub(1);
+            }
+        }
+    }
+
+    fn read_register(&mut self, addr: u16, cpu: &mut cpu::Cpu) -> u8 {
+        match addr {
+            0x5010 | 0x5015 => {
+                self.catch_up(cpu);
+                let val = self.audio.read_register(addr);
+                self.update_pcm_irq(cpu);
+                val
+            }
+            // irq status
+            0x5204 => {
+                let val = (self.ppu_bus.bits.irq_pending.get() << 7) as u8
+                    | (self.ppu_bus.bits.in_frame.get() << 6) as u8;
+
+                self.ppu_bus.bits.irq_pending.set(0);
+                self.ppu_bus.update_irq(cpu);
+                val
+            }
+            // multiplier
+            0x5205 => (self.multiplicands[0] as u16 * self.multiplicands[1] as u16) as u8,
+            0x5206 => ((self.multiplicands[0] as u16 * self.multiplicands[1] as u16) >> 8) as u8,
+            // exram (only readable in modes 2 and 3)
+            0x5c00..=0x5fff if self.ppu_bus.exram_mode >= 2 => {
+                self.ppu_bus.exram[(addr & 0x3ff) as usize]
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8, cpu: &mut cpu::Cpu) {
+        match addr {
+            0x5000..=0x5015 => {
+                self.catch_up(cpu);
+                if self.audio.write_register(addr, val) {
+                    self.update_pcm_irq(cpu);
+                }
+            }
+            0x5100 => self.prg_mode = val & 0b11,
+            0x5101 => self.ppu_bus.chr_mode = val & 0b11,
+            0x5102 | 0x5103 => self.prg_ram_protect[(addr & 1) as usize] = val,
+            0x5104 => self.ppu_bus.exram_mode = val & 0b11,
+            0x5105 => {
+                // 2 bits per nametable
+                for nametable_idx in 0..4 {
+                    let page = match (val >> (nametable_idx * 2)) & 0b11 {
+                        0 => NametablePage::CiramA,
+                        1 => NametablePage::CiramB,
+                        2 => NametablePage::CartridgeVram(0),
+                        _ => NametablePage::Fill,
+                    };
+
+                    self.ppu_bus.layout.set_page(nametable_idx, page);
+                }
+            }
+            0x5106 => self.ppu_bus.fill_tile = val,
+            0x5107 => self.ppu_bus.fill_attribute = val & 0b11,
+            0x5113..=0x5117 => self.prg_banks[addr as usize - 0x5113] = val,
+            0x5120..=0x5127 => {
+                let bank = val as u16 | ((self.ppu_bus.chr_upper_bits as u16) << 8);
+                self.ppu_bus.sprite_chr_banks[addr as usize - 0x5120] = bank;
+                self.ppu_bus.bits.bg_chr_banks_last_written.set(0);
+            }
+            0x5128..=0x512b => {
+                let bank = val as u16 | ((self.ppu_bus.chr_upper_bits as u16) << 8);
+                self.ppu_bus.bg_chr_banks[addr as usize - 0x5128] = bank;
+                self.ppu_bus.bits.bg_chr_banks_last_written.set(1);
+            }
+            0x5130 => self.ppu_bus.chr_upper_bits = val & 0b11,
+            0x5200 => self.ppu_bus.split_control = val,
+            0x5201 => self.ppu_bus.split_scroll = val,
+            0x5202 => self.ppu_bus.split_bank = val,
+            0x5203 => self.ppu_bus.irq_scanline = val,
+            0x5204 => {
+                self.ppu_bus.bits.irq_enable.set((val >> 7) as u16);
+                self.ppu_bus.update_irq(cpu);
+            }
+            0x5205 | 0x5206 => self.multiplicands[(addr & 1) as usize ^ 1] = val,
+            0x5c00..=0x5fff => {
+                let addr = (addr & 0x3ff) as usize;
+                match self.ppu_bus.exram_mode {
+                    // when exram is used for rendering, writes only go
+                    // through while the ppu is rendering (0 is written otherwise)
+                    0 | 1 if self.ppu_bus.bits.in_frame.is_true() => self.ppu_bus.exram[addr] = val,
+                    0 | 1 => self.ppu_bus.exram[addr] = 0,
+                    2 => self.ppu_bus.exram[addr] = val,
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl<'a> FromRom<'a> for Mmc5CpuAddressBus<'a> {
+    fn from_rom(
+        rom: &parse::RomDescription,
+        ppu: ppu::Ppu,
+        apu: apu::Apu,
+        controller: ctrl::Controller,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> Self {
+        // ines 1.0 headers rarely give the right amount of ram for mmc5 boards,
+        // so those get the largest amount any board has
+        let prg_ram = if rom.header.is_nes_2_format {
+            PrgRam::from_header(&rom.header, 0x10000)
+        } else {
+            PrgRam::new(0x10000, if rom.header.has_battery { 0x10000 } else { 0 })
+        };
+
+        Self::new(
+            &rom.prg_rom,
+            &rom.chr_rom,
+            rom.header.total_chr_ram_size(),
+            prg_ram,
+            rom.header.mirroring,
+            ppu,
+            apu,
+            controller,
+            framebuffer,
+        )
+    }
+}
+
+impl<'a> CpuAddressBus<'a> for Mmc5CpuAddressBus<'a> {
+    fn read(&mut self, mut addr: u16, cpu: &mut cpu::Cpu) -> u8 {
+        // internal ram
+        if super::is_0_to_1fff(addr) {
+            addr &= !0b1_1000_0000_0000;
+            return unsafe { *self.internal_ram.get_unchecked(addr as usize) };
+        }
+
+        // ppu registers
+        if super::is_2000_to_3fff(addr) {
+            self.base
+                .ppu
+                .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+
+            addr &= 0b111;
+            return self
+                .base
+                .ppu
+                .read_register_by_index(addr as u8, &mut self.ppu_bus, cpu);
+        }
+
+        // prg ram
+        if super::is_6000_to_7fff(addr) {
+            return self.prg_ram.read(self.calc_prg_ram_addr(addr));
+        }
+
+        // prg rom/ram (0x8000-0xffff)
+        if addr & 0x8000 != 0 {
+            let (offset, is_ram) = self.calc_prg_addr(addr);
+            let val = if is_ram {
+                self.prg_ram.read(offset)
+            } else {
+                self.prg_rom[offset]
+            };
+
+            // in pcm read mode, reads from 0x8000-0xbfff are fed to the pcm channel
+            if addr < 0xc000 {
+                self.catch_up(cpu);
+                self.audio.snoop_prg_read(val);
+                self.update_pcm_irq(cpu);
+            }
+
+            return val;
+        }
+
+        if addr == 0x4016 {
+            return self.base.controller.read();
+        }
+
+        if addr >= 0x5000 {
+            return self.read_register(addr, cpu);
+        }
+
+        0
+    }
+
+    fn write(&mut self, mut addr: u16, val: u8, cpu: &mut cpu::Cpu) {
+        if super::is_0_to_1fff(addr) {
+            addr &= !0b1_1000_0000_0000;
+            unsafe { *self.internal_ram.get_unchecked_mut(addr as usize) = val };
+            return;
+        }
+
+        if super::is_2000_to_3fff(addr) {
+            self.base
+                .ppu
+                .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+
+            // MMC5 snoops writes to ppuctrl to know the sprite size
+            if addr & 0b111 == 0 {
+                self.ppu_bus.bits.sprites_8x16.set(((val >> 5) & 1) as u16);
+            }
+
+            self.base
+                .ppu
+                .write_register_by_index(addr as u8 & 0b111, val, cpu, &mut self.ppu_bus);
+
+            return;
+        }
+
+        if super::is_6000_to_7fff(addr) {
+            if self.is_prg_ram_writable() {
+                let offset = self.calc_prg_ram_addr(addr);
+                self.prg_ram.write(offset, val);
+            }
+
+            return;
+        }
+
+        if addr & 0x8000 != 0 {
+            let (offset, is_ram) = self.calc_prg_addr(addr);
+            if is_ram && self.is_prg_ram_writable() {
+                self.prg_ram.write(offset, val);
+            }
+
+            return;
+        }
+
+        // oamdma
+        if addr == 0x4014 {
+            self.base
+                .ppu
+                .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+            super::write_oamdma(self, val, cpu);
+            return;
+        }
+
+        // standard controller 1
+        if addr == 0x4016 {
+            self.base.controller.write(val);
+            return;
+        }
+
+        if addr >= 0x5000 {
+            self.write_register(addr, val, cpu);
+        }
+    }
+
+    fn base(&mut self) -> (&mut CpuAddressBusBase<'a>, &mut dyn PpuAddressBus) {
+        (&mut self.base, &mut self.ppu_bus)
+    }
+
+    fn battery_backed_ram(&mut self) -> Vec<&mut [u8]> {
+        vec![self.prg_ram.battery_backed()]
+    }
+
+    // NOTE: 0x6000-0x7fff is mapped to the first bank on power-on
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        self.prg_ram.window()
+    }
+
+    fn catch_up(&mut self, cpu: &mut cpu::Cpu) {
+        while self.cycle_count < cpu.cycle_count {
+            self.audio.clock();
+            self.cycle_count += 1;
+        }
+    }
+
+    fn sub_cycle_count(&mut self, sub: i32) {
+        self.cycle_count -= sub;
+    }
+
+    fn expansion_audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+}
+
+impl Mmc5PpuAddressBus {
+    // asserts or acknowledges the scanline irq on the cpu to
+    // reflect the state of the 'irq_pending' and 'irq_enable' bits
+    fn update_irq(&mut self, cpu: &mut cpu::Cpu) {
+        let assert = self.bits.irq_pending.is_true() && self.bits.irq_enable.is_true();
+        if assert != self.bits.irq_asserted.is_true() {
+            self.bits.irq_asserted.set(assert as u16);
+            if assert {
+                cpu.irq += 1;
+            } else {
+                cpu.irq = cpu.irq.saturating_sub(1);
+            }
+        }
+    }
+
+    // MMC5 has no way of knowing the current ppu dot. instead, it detects the start of
+    // each scanline by watching for three consecutive reads from the same nametable
+    // address, which happens at dot 337, dot 339 and dot 1 of the next scanline.
+    // returns true if the read at 'addr' completes such a sequence
+    fn detect_scanline(&mut self, addr: u16, cpu: &mut cpu::Cpu) -> bool {
+        if addr == self.prev_nametable_addr {
+            self.nametable_match_count += 1;
+        } else {
+            self.nametable_match_count = 0;
+        }
+        self.prev_nametable_addr = addr;
+
+        if self.nametable_match_count != 2 {
+            return false;
+        }
+
+        if self.bits.in_frame.is_true() {
+            self.scanline_counter = self.scanline_counter.wrapping_add(1);
+            self.split_y = next_split_y(self.split_y);
+
+            if self.scanline_counter == self.irq_scanline {
+                self.bits.irq_pending.set(1);
+                self.update_irq(cpu);
+            }
+        } else {
+            // first scanline of the frame
+            self.bits.in_frame.set(1);
+            self.scanline_counter = 0;
+            self.split_y = self.split_scroll;
+            self.bits.irq_pending.set(0);
+            self.update_irq(cpu);
+        }
+
+        self.bits.prefetch.set(0);
+        true
+    }
+
+    // whether the ppu is currently fetching background tiles
+    fn is_bg_fetch(&self) -> bool {
+        !self.bits.sprite_fetch.is_true() && !self.bits.ppudata_access.is_true()
+    }
+
+    // the y coordinate within the split region that the current tile belongs to
+    fn calc_split_y(&self) -> u8 {
+        match (self.bits.prefetch.is_true(), self.bits.in_frame.is_true()) {
+            // tiles prefetched at dots 321-336 belong to the next scanline
+            (true, true) => next_split_y(self.split_y),
+            (true, false) => self.split_scroll,
+            _ => self.split_y,
+        }
+    }
+
+    fn is_tile_in_split(&self, tile: u8) -> bool {
+        if self.split_control & 0x80 == 0 || self.exram_mode > 1 {
+            return false;
+        }
+
+        let threshold = self.split_control & 0b11111;
+        if self.split_control & 0x40 != 0 {
+            // split region is to the right of the threshold
+            tile >= threshold
+        } else {
+            tile < threshold
+        }
+    }
+
+    // handles nametable reads made while fetching background tiles. returns
+    // None if the read should go through the regular nametable mapping
+    fn read_bg_nametable(&mut self, addr: u16, is_new_scanline: bool) -> Option<u8> {
+        let offset = (addr & 0x3ff) as usize;
+
+        if offset < 0x3c0 {
+            // tile index fetch
+            self.current_tile = if is_new_scanline {
+                // the two tiles before this one were prefetched on the previous scanline
+                2
+            } else {
+                self.tile_counter
+            };
+            self.tile_counter = self.current_tile + 1;
+
+            if self.exram_mode == 1 {
+                self.ext_attribute = self.exram[offset];
+            }
+
+            let in_split = self.is_tile_in_split(self.current_tile);
+            self.bits.in_split.set(in_split as u16);
+
+            if in_split {
+                let coarse_y = (self.calc_split_y() >> 3) as usize;
+                let idx = (coarse_y * 32 + (self.current_tile & 0b11111) as usize) & 0x3ff;
+                return Some(self.exram[idx]);
+            }
+
+            return None;
+        }
+
+        // attribute fetch
+        if self.bits.in_split.is_true() {
+            let coarse_y = self.calc_split_y() >> 3;
+            let tile = self.current_tile & 0b11111;
+            let attribute =
+                self.exram[0x3c0 | ((coarse_y as usize >> 2) << 3) | (tile as usize >> 2)];
+            let shift = ((coarse_y << 1) & 0b100) | (tile & 0b10);
+            return Some(((attribute >> shift) & 0b11) * 0x55);
+        }
+
+        if self.exram_mode == 1 {
+            // use the palette from exram for all four quadrants
+            return Some((self.ext_attribute >> 6) * 0x55);
+        }
+
+        None
+    }
+
+    fn read_nametable(&self, addr: u16) -> u8 {
+        match self.layout.resolve(addr) {
+            (NametablePage::CartridgeVram(_), offset) if self.exram_mode <= 1 => self.exram[offset],
+            (NametablePage::CartridgeVram(_), _) => 0,
+            (NametablePage::Fill, offset) if offset >= 0x3c0 => self.fill_attribute * 0x55,
+            (NametablePage::Fill, _) => self.fill_tile,
+            _ => self.nametables[self.layout.calc_vram_addr(addr)],
+        }
+    }
+
+    // calculates the index of the 1 KB chr bank mapped at 'addr' (0-0x1fff)
+    fn calc_chr_bank(&self, addr: u16) -> usize {
+        let use_bg_banks = self.bits.sprites_8x16.is_true()
+            && if self.bits.ppudata_access.is_true() {
+                self.bits.bg_chr_banks_last_written.is_true()
+            } else {
+                !self.bits.sprite_fetch.is_true()
+            };
+
+        let bank = if use_bg_banks {
+            // the background banks are mapped to both 0-0xfff and 0x1000-0x1fff
+            let r = &self.bg_chr_banks;
+            match self.chr_mode {
+                0 => (r[3] << 3) | ((addr >> 10) & 0b111),
+                1 => (r[3] << 2) | ((addr >> 10) & 0b11),
+                2 => (r[(((addr >> 11) & 1) << 1) as usize | 1] << 1) | ((addr >> 10) & 1),
+                _ => r[((addr >> 10) & 0b11) as usize],
+            }
+        } else {
+            let r = &self.sprite_chr_banks;
+            match self.chr_mode {
+                0 => (r[7] << 3) | ((addr >> 10) & 0b111),
+                1 => (r[((addr >> 12) << 2) as usize | 3] << 2) | ((addr >> 10) & 0b11),
+                2 => (r[((addr >> 11) << 1) as usize | 1] << 1) | ((addr >> 10) & 1),
+                _ => r[(addr >> 10) as usize],
+            }
+        };
+
+        bank as usize % (self.chr.len() >> 10)
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        if self.is_bg_fetch() {
+            if self.bits.in_split.is_true() {
+                // split region tiles come from the 4 KB bank in 0x5202, with
+                // the fine y scroll taken from the split region
+                let addr = (addr & 0xff8) | (self.calc_split_y() & 0b111) as u16;
+                let offset = (self.split_bank as usize * 0x1000 + addr as usize) % self.chr.len();
+                return self.chr[offset];
+            }
+
+            if self.exram_mode == 1 {
+                // each tile selects its own 4 KB bank in extended attribute mode
+                let bank =
+                    (self.ext_attribute & 0x3f) as usize | ((self.chr_upper_bits as usize) << 6);
+                let offset = (bank * 0x1000 + (addr & 0xfff) as usize) % self.chr.len();
+                return self.chr[offset];
+            }
+        }
+
+        self.chr[(self.calc_chr_bank(addr) << 10) | (addr & 0x3ff) as usize]
+    }
+}
+
+// advances the split region y coordinate by one scanline
+fn next_split_y(split_y: u8) -> u8 {
+    if split_y == 239 {
+        0
+    } else {
+        split_y.wrapping_add(1)
+    }
+}
+
+impl PpuAddressBus for Mmc5PpuAddressBus {
+    fn read(&mut self, addr: u16, _: i32, cpu: &mut cpu::Cpu) -> u8 {
+        // palette memory
+        if addr >= 0x3f00 {
+            let addr = super::calc_ppu_palette_addr(addr);
+            return unsafe { *self.palettes.get_unchecked(addr as usize) };
+        }
+
+        // nametables (0x2000-0x3eff)
+        if addr >= 0x2000 {
+            if self.is_bg_fetch() {
+                let is_new_scanline = self.detect_scanline(addr, cpu);
+                if let Some(val) = self.read_bg_nametable(addr, is_new_scanline) {
+                    return val;
+                }
+            }
+
+            return self.read_nametable(addr);
+        }
+
+        // pattern tables (0-0x1fff). these break up any sequence of nametable reads
+        self.nametable_match_count = 0;
+        self.prev_nametable_addr = 0;
+
+        self.read_chr(addr)
+    }
+
+    fn write(&mut self, addr: u16, val: u8, _: i32, _: &mut cpu::Cpu) {
+        if addr >= 0x3f00 {
+            let addr = super::calc_ppu_palette_addr(addr);
+            unsafe { *self.palettes.get_unchecked_mut(addr as usize) = val };
+            return;
+        }
+
+        if addr >= 0x2000 {
+            match self.layout.resolve(addr) {
+                (NametablePage::CartridgeVram(_), offset) if self.exram_mode <= 1 => {
+                    self.exram[offset] = val
+                }
+                // writes to fill mode nametables are ignored
+                (NametablePage::CartridgeVram(_), _) | (NametablePage::Fill, _) => (),
+                _ => {
+                    let addr = self.layout.calc_vram_addr(addr);
+                    self.nametables[addr] = val;
+                }
+            }
+        } else if self.chr_is_ram {
+            let offset = (self.calc_chr_bank(addr) << 10) | (addr & 0x3ff) as usize;
+            self.chr[offset] = val;
+        }
+    }
+
+    fn set_address(&mut self, _: u16, _: i32, _: &mut cpu::Cpu) {}
+
+    fn read_palette_memory(&self, color_idx: u8) -> u8 {
+        self.palettes[super::calc_ppu_palette_addr(color_idx as u16) as usize]
+    }
+
+    fn set_fetch_kind(&mut self, kind: PpuFetchKind) {
+        self.bits
+            .sprite_fetch
+            .set((kind == PpuFetchKind::Sprite) as u16);
+        self.bits
+            .ppudata_access
+            .set((kind == PpuFetchKind::Ppudata) as u16);
+
+        if kind == PpuFetchKind::Sprite {
+            // the first background tiles fetched after the sprites are
+            // the two leftmost tiles of the next scanline
+            self.tile_counter = 0;
+            self.bits.prefetch.set(1);
+            self.bits.in_split.set(0);
+        }
+    }
+
+    fn set_rendering(&mut self, rendering: bool) {
+        if !rendering {
+            self.bits.in_frame.set(0);
+            self.bits.in_split.set(0);
+            self.nametable_match_count = 0;
+            self.prev_nametable_addr = 0;
+        }
+    }
+}
+
+// NOTE: 'Serialize' is implemented manually to avoid serializing rom
+impl serialize::Serialize for Mmc5PpuAddressBus {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        if self.chr_is_ram {
+            self.chr.serialize(file)?;
+        }
+        self.nametables.serialize(file)?;
+        self.exram.serialize(file)?;
+        self.palettes.serialize(file)?;
+        self.sprite_chr_banks.serialize(file)?;
+        self.bg_chr_banks.serialize(file)?;
+        self.chr_upper_bits.serialize(file)?;
+        self.chr_mode.serialize(file)?;
+        self.exram_mode.serialize(file)?;
+        self.layout.serialize(file)?;
+        self.fill_tile.serialize(file)?;
+        self.fill_attribute.serialize(file)?;
+        self.split_control.serialize(file)?;
+        self.split_scroll.serialize(file)?;
+        self.split_bank.serialize(file)?;
+        self.split_y.serialize(file)?;
+        self.irq_scanline.serialize(file)?;
+        self.scanline_counter.serialize(file)?;
+        self.prev_nametable_addr.serialize(file)?;
+        self.nametable_match_count.serialize(file)?;
+        self.tile_counter.serialize(file)?;
+        self.current_tile.serialize(file)?;
+        self.ext_attribute.serialize(file)?;
+        self.bits.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        if self.chr_is_ram {
+            self.chr.deserialize(file)?;
+        }
+        self.nametables.deserialize(file)?;
+        self.exram.deserialize(file)?;
+        self.palettes.deserialize(file)?;
+        self.sprite_chr_banks.deserialize(file)?;
+        self.bg_chr_banks.deserialize(file)?;
+        self.chr_upper_bits.deserialize(file)?;
+        self.chr_mode.deserialize(file)?;
+        self.exram_mode.deserialize(file)?;
+        self.layout.deserialize(file)?;
+        self.fill_tile.deserialize(file)?;
+        self.fill_attribute.deserialize(file)?;
+        self.split_control.deserialize(file)?;
+        self.split_scroll.deserialize(file)?;
+        self.split_bank.deserialize(file)?;
+        self.split_y.deserialize(file)?;
+        self.irq_scanline.deserialize(file)?;
+        self.scanline_counter.deserialize(file)?;
+        self.prev_nametable_addr.deserialize(file)?;
+        self.nametable_match_count.deserialize(file)?;
+        self.tile_counter.deserialize(file)?;
+        self.current_tile.deserialize(file)?;
+        self.ext_attribute.deserialize(file)?;
+        self.bits.deserialize(file)
+    }
+}
+
+impl<'a> serialize::Serialize for Mmc5CpuAddressBus<'a> {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        self.base.serialize(file)?;
+        self.ppu_bus.serialize(file)?;
+        self.internal_ram.serialize(file)?;
+        self.prg_ram.serialize(file)?;
+        self.prg_banks.serialize(file)?;
+        self.prg_ram_protect.serialize(file)?;
+        self.prg_mode.serialize(file)?;
+        self.multiplicands.serialize(file)?;
+        self.audio.serialize(file)?;
+        self.cycle_count.serialize(file)?;
+        self.bits.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        self.base.deserialize(file)?;
+        self.ppu_bus.deserialize(file)?;
+        self.internal_ram.deserialize(file)?;
+        self.prg_ram.deserialize(file)?;
+        self.prg_banks.deserialize(file)?;
+        self.prg_ram_protect.deserialize(file)?;
+        self.prg_mode.deserialize(file)?;
+        self.multiplicands.deserialize(file)?;
+        self.audio.deserialize(file)?;
+        self.cycle_count.deserialize(file)?;
+        self.bits.deserialize(file)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_test_bus<'a>(framebuffer: &'a [Cell<u32>; 256 * 240]) -> Mmc5CpuAddressBus<'a> {
+        let prg_rom = (0..0x20000).map(|i| (i / 0x2000) as u8).collect::<Vec<_>>();
+        let chr_rom = (0..0x40000).map(|i| (i / 0x400) as u8).collect::<Vec<_>>();
+
+        Mmc5CpuAddressBus::new(
+            &prg_rom,
+            &chr_rom,
+            0,
+            PrgRam::new(0x10000, 0),
+            parse::MirroringType::Vert,
+            ppu::Ppu::new(),
+            apu::Apu::new(),
+            ctrl::Controller::default(),
+            framebuffer,
+        )
+    }
+
+    #[test]
+    fn test_prg_banking() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        // power-on state: mode 3 with the last bank at 0xe000
+        assert_eq!(bus.read(0xe000, &mut cpu), 15);
+
+        // mode 0 (one 32 KB bank)
+        bus.write(0x5100, 0, &mut cpu);
+        bus.write(0x5117, 0x80 | 5, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 4);
+        assert_eq!(bus.read(0xa000, &mut cpu), 5);
+        assert_eq!(bus.read(0xc000, &mut cpu), 6);
+        assert_eq!(bus.read(0xe000, &mut cpu), 7);
+
+        // mode 1 (two 16 KB banks)
+        bus.write(0x5100, 1, &mut cpu);
+        bus.write(0x5115, 0x80 | 3, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 2);
+        assert_eq!(bus.read(0xa000, &mut cpu), 3);
+        assert_eq!(bus.read(0xc000, &mut cpu), 4);
+        assert_eq!(bus.read(0xe000, &mut cpu), 5);
+
+        // mode 2 (16 KB + 8 KB + 8 KB)
+        bus.write(0x5100, 2, &mut cpu);
+        bus.write(0x5116, 0x80 | 9, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 2);
+        assert_eq!(bus.read(0xc000, &mut cpu), 9);
+        assert_eq!(bus.read(0xe000, &mut cpu), 5);
+
+        // mode 3 (four 8 KB banks)
+        bus.write(0x5100, 3, &mut cpu);
+        bus.write(0x5114, 0x80 | 12, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 12);
+        assert_eq!(bus.read(0xa000, &mut cpu), 3);
+
+        // ram mapped into 0x8000-0x9fff (bank 1), and write protection
+        bus.write(0x5114, 1, &mut cpu);
+        bus.write(0x8010, 0xaa, &mut cpu);
+        assert_eq!(bus.read(0x8010, &mut cpu), 0);
+
+        bus.write(0x5102, 0b10, &mut cpu);
+        bus.write(0x5103, 0b01, &mut cpu);
+        bus.write(0x8010, 0xaa, &mut cpu);
+        assert_eq!(bus.read(0x8010, &mut cpu), 0xaa);
+
+        // the same ram bank mapped into 0x6000-0x7fff
+        bus.write(0x5113, 1, &mut cpu);
+        assert_eq!(bus.read(0x6010, &mut cpu), 0xaa);
+    }
+
+    #[test]
+    fn test_chr_banking() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        // 1 KB mode
+        bus.write(0x5101, 3, &mut cpu);
+        for i in 0..8 {
+            bus.write(0x5120 + i, 0x10 + i as u8, &mut cpu);
+        }
+        bus.write(0x5128, 0x40, &mut cpu);
+        bus.write(0x5129, 0x41, &mut cpu);
+
+        // 8x8 sprites use 0x5120-0x5127 for everything
+        bus.ppu_bus.set_fetch_kind(PpuFetchKind::Background);
+        assert_eq!(bus.ppu_bus.read(0x0400, 0, &mut cpu), 0x11);
+        assert_eq!(bus.ppu_bus.read(0x1c00, 0, &mut cpu), 0x17);
+
+        // 8x16 sprites use 0x5128-0x512b for backgrounds (mirrored
+        // in both pattern tables)
+        bus.write(0x2000, 0x20, &mut cpu);
+        assert_eq!(bus.ppu_bus.read(0x0400, 0, &mut cpu), 0x41);
+        assert_eq!(bus.ppu_bus.read(0x1400, 0, &mut cpu), 0x41);
+
+        bus.ppu_bus.set_fetch_kind(PpuFetchKind::Sprite);
+        assert_eq!(bus.ppu_bus.read(0x1400, 0, &mut cpu), 0x15);
+
+        // ppudata accesses use the last written set of banks
+        bus.ppu_bus.set_fetch_kind(PpuFetchKind::Ppudata);
+        assert_eq!(bus.ppu_bus.read(0x1400, 0, &mut cpu), 0x41);
+        bus.write(0x5125, 0x22, &mut cpu);
+        assert_eq!(bus.ppu_bus.read(0x1400, 0, &mut cpu), 0x22);
+
+        // upper chr bits (with 1024 KB of chr rom, where the first two bytes
+        // of each 1 KB bank hold its number)
+        let chr_rom = (0..0x100000)
+            .map(|i| match i % 0x400 {
+                0 => (i >> 18) as u8,
+                1 => (i >> 10) as u8,
+                _ => 0,
+            })
+            .collect::<Vec<_>>();
+        let mut bus = Mmc5CpuAddressBus::new(
+            &[0; 0x8000],
+            &chr_rom,
+            0,
+            PrgRam::new(0x10000, 0),
+            parse::MirroringType::Vert,
+            ppu::Ppu::new(),
+            apu::Apu::new(),
+            ctrl::Controller::default(),
+            unsafe { &*(&framebuffer as *const _ as *const _) },
+        );
+        bus.write(0x5101, 3, &mut cpu);
+        bus.write(0x5130, 1, &mut cpu);
+        bus.write(0x5120, 0x05, &mut cpu);
+        bus.ppu_bus.set_fetch_kind(PpuFetchKind::Sprite);
+        assert_eq!(bus.ppu_bus.calc_chr_bank(0), 0x105);
+        assert_eq!(bus.ppu_bus.read(0x0000, 0, &mut cpu), 1);
+        assert_eq!(bus.ppu_bus.read(0x0001, 0, &mut cpu), 5);
+    }
+
+    #[test]
+    fn test_nametables() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        bus.ppu_bus.set_fetch_kind(PpuFetchKind::Ppudata);
+
+        // nametable 0 => ciram 0, 1 => ciram 1, 2 => exram, 3 => fill mode
+        bus.write(0x5105, 0b11_10_01_00, &mut cpu);
+        bus.write(0x5106, 0x33, &mut cpu);
+        bus.write(0x5107, 2, &mut cpu);
+
+        bus.ppu_bus.write(0x2005, 0x11, 0, &mut cpu);
+        bus.ppu_bus.write(0x2405, 0x22, 0, &mut cpu);
+        bus.ppu_bus.write(0x2805, 0x44, 0, &mut cpu);
+
+        assert_eq!(bus.ppu_bus.nametables[0x005], 0x11);
+        assert_eq!(bus.ppu_bus.nametables[0x405], 0x22);
+        assert_eq!(bus.ppu_bus.exram[0x005], 0x44);
+        assert_eq!(bus.ppu_bus.read(0x2c05, 0, &mut cpu), 0x33);
+        assert_eq!(bus.ppu_bus.read(0x2fc5, 0, &mut cpu), 0xaa);
+
+        // exram is only cpu-readable in modes 2 and 3
+        assert_eq!(bus.read(0x5c05, &mut cpu), 0);
+        bus.write(0x5104, 2, &mut cpu);
+        assert_eq!(bus.read(0x5c05, &mut cpu), 0x44);
+        bus.write(0x5c06, 0x55, &mut cpu);
+        assert_eq!(bus.read(0x5c06, &mut cpu), 0x55);
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        bus.write(0x5203, 2, &mut cpu);
+        bus.write(0x5204, 0x80, &mut cpu);
+
+        // emulates the reads the ppu makes at the end of each scanline
+        fn end_scanline(bus: &mut Mmc5PpuAddressBus, cpu: &mut cpu::Cpu) {
+            bus.set_fetch_kind(PpuFetchKind::Sprite);
+            bus.read(0x2000, 0, cpu);
+            bus.read(0x1000, 0, cpu);
+            bus.set_fetch_kind(PpuFetchKind::Background);
+            bus.read(0x2000, 0, cpu);
+            bus.read(0x0000, 0, cpu);
+            bus.read(0x23c0, 0, cpu);
+            // dots 337 and 339
+            bus.read(0x2002, 0, cpu);
+            bus.read(0x2002, 0, cpu);
+            // dot 1
+            bus.read(0x2002, 0, cpu);
+            bus.read(0x0000, 0, cpu);
+        }
+
+        bus.ppu_bus.set_rendering(true);
+        end_scanline(&mut bus.ppu_bus, &mut cpu);
+        assert!(bus.ppu_bus.bits.in_frame.is_true());
+        assert_eq!(bus.ppu_bus.scanline_counter, 0);
+        assert_eq!(bus.ppu_bus.current_tile, 2);
+
+        end_scanline(&mut bus.ppu_bus, &mut cpu);
+        assert_eq!(cpu.irq, 0);
+        end_scanline(&mut bus.ppu_bus, &mut cpu);
+        assert_eq!(cpu.irq, 1);
+
+        // reading 0x5204 acknowledges the irq
+        assert_eq!(bus.read(0x5204, &mut cpu), 0xc0);
+        assert_eq!(cpu.irq, 0);
+        assert_eq!(bus.read(0x5204, &mut cpu), 0x40);
+
+        // the in-frame flag is cleared when rendering stops
+        bus.ppu_bus.set_rendering(false);
+        assert_eq!(bus.read(0x5204, &mut cpu), 0);
+    }
+
+    #[test]
+    fn test_multiplier() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        bus.write(0x5205, 0xc8, &mut cpu);
+        bus.write(0x5206, 0x7b, &mut cpu);
+        assert_eq!(bus.read(0x5205, &mut cpu), (0xc8u16 * 0x7b) as u8);
+        assert_eq!(bus.read(0x5206, &mut cpu), ((0xc8u16 * 0x7b) >> 8) as u8);
+    }
+}
@@ -1,14 +1,126 @@
-use crate::{apu, controller as ctrl, cpu, ppu, serialize};
+use crate::{apu, controller as ctrl, cpu, parse, ppu, serialize};
 
+mod bandai_fcg;
+mod discrete;
+mod fds;
+mod fme7;
+mod i2c_eeprom;
 mod mmc3;
+mod mmc5;
+mod namco163;
+mod nametable_layout;
 mod nrom;
-
+mod nsf;
+mod prg_ram;
+mod vrc7;
+
+pub use bandai_fcg::BandaiFcgCpuAddressBus;
+pub use discrete::DiscreteCpuAddressBus;
+pub use fds::{DiskDrive, FdsCpuAddressBus};
+pub use fme7::Fme7CpuAddressBus;
 pub use mmc3::{Mmc3CpuAddressBus, Mmc3PpuAddressBus};
+pub use mmc5::Mmc5CpuAddressBus;
+pub use namco163::Namco163CpuAddressBus;
 pub use nrom::{NromCpuAddressBus, NromPpuAddressBus};
+pub use nsf::{NsfCpuAddressBus, NsfPlayer};
+#[cfg(test)]
+pub use prg_ram::PrgRam;
+pub use vrc7::Vrc7CpuAddressBus;
 
 use std::cell::Cell;
 use std::{fs, io};
 
+// creates a 'CpuAddressBus' implementation from a rom. the returned bus is boxed so
+// that it can be stored in 'MAPPERS' alongside the buses of other mappers
+type NewCpuAddressBus = for<'a> fn(
+    &parse::RomDescription,
+    ppu::Ppu,
+    apu::Apu,
+    ctrl::Controller,
+    &'a [Cell<u32>; 256 * 240],
+) -> Box<dyn CpuAddressBus<'a> + 'a>;
+
+pub struct MapperEntry {
+    pub mapper: u16,
+    // 'None' matches any submapper not registered separately
+    pub submapper: Option<u8>,
+    pub name: &'static str,
+    pub new: NewCpuAddressBus,
+}
+
+macro_rules! mapper_entry {
+    ($mapper:expr, $submapper:expr, $name:expr, $bus:ident) => {
+        MapperEntry {
+            mapper: $mapper,
+            submapper: $submapper,
+            name: $name,
+            new: |rom, ppu, apu, controller, framebuffer| {
+                Box::new($bus::from_rom(rom, ppu, apu, controller, framebuffer))
+            },
+        }
+    };
+}
+
+// all supported mappers. adding a mapper only requires implementing 'FromRom'
+// for its 'CpuAddressBus' and registering it here
+pub static MAPPERS: &[MapperEntry] = &[
+    mapper_entry!(0, None, "nrom", NromCpuAddressBus),
+    mapper_entry!(4, None, "mmc3", Mmc3CpuAddressBus),
+    mapper_entry!(4, Some(4), "mmc3 (revision a irq)", Mmc3CpuAddressBus),
+    mapper_entry!(4, Some(1), "mmc6", Mmc3CpuAddressBus),
+    mapper_entry!(5, None, "mmc5", Mmc5CpuAddressBus),
+    mapper_entry!(11, None, "color dreams", DiscreteCpuAddressBus),
+    mapper_entry!(
+        16,
+        None,
+        "bandai fcg-1/fcg-2/lz93d50 with 24c02",
+        BandaiFcgCpuAddressBus
+    ),
+    mapper_entry!(19, None, "namco 163", Namco163CpuAddressBus),
+    mapper_entry!(34, None, "bnrom/nina-001", DiscreteCpuAddressBus),
+    mapper_entry!(66, None, "gxrom", DiscreteCpuAddressBus),
+    mapper_entry!(69, None, "sunsoft fme-7/5b", Fme7CpuAddressBus),
+    mapper_entry!(71, None, "camerica/codemasters", DiscreteCpuAddressBus),
+    mapper_entry!(79, None, "nina-03/nina-06", DiscreteCpuAddressBus),
+    mapper_entry!(85, None, "konami vrc7", Vrc7CpuAddressBus),
+    mapper_entry!(118, None, "mmc3 (txsrom)", Mmc3CpuAddressBus),
+    mapper_entry!(119, None, "mmc3 (tqrom)", Mmc3CpuAddressBus),
+    mapper_entry!(
+        153,
+        None,
+        "bandai lz93d50 with prg ram",
+        BandaiFcgCpuAddressBus
+    ),
+    mapper_entry!(
+        159,
+        None,
+        "bandai lz93d50 with 24c01",
+        BandaiFcgCpuAddressBus
+    ),
+    mapper_entry!(206, None, "namco 108/dxrom", DiscreteCpuAddressBus),
+];
+
+// finds the entry in 'MAPPERS' for the given mapper and submapper, preferring
+// an entry registered for the exact submapper
+pub fn find_mapper(mapper: u16, submapper: u8) -> Option<&'static MapperEntry> {
+    let mut entries = MAPPERS.iter().filter(|entry| entry.mapper == mapper);
+    entries
+        .clone()
+        .find(|entry| entry.submapper == Some(submapper))
+        .or_else(|| entries.find(|entry| entry.submapper.is_none()))
+}
+
+// returns the chr memory of a cartridge, and whether it's ram. cartridges without chr
+// rom have chr ram instead, sized according to the header (at least 8 KB, which is
+// also what's assumed when the header doesn't specify a size)
+fn new_chr(chr_rom: &[u8], chr_ram_size: usize) -> (Box<[u8]>, bool) {
+    if chr_rom.is_empty() {
+        (vec![0; chr_ram_size.max(0x2000)].into_boxed_slice(), true)
+    } else {
+        (chr_rom.to_vec().into_boxed_slice(), false)
+    }
+}
+
 // the base struct that all 'CpuAddressBus' implementations should inherit
 // from. can be accessed through the 'CpuAddressBus::base()' trait method
 pub struct CpuAddressBusBase<'a> {
@@ -62,6 +174,61 @@ pub trait CpuAddressBus<'a>: serialize::Serialize {
     fn read(&mut self, addr: u16, cpu: &mut cpu::Cpu) -> u8;
     // called by 'Cpu' when writing to memory
     fn write(&mut self, addr: u16, val: u8, cpu: &mut cpu::Cpu);
+    // called after every instruction. catches any cpu-cycle driven hardware
+    // on the cartridge (irq counters, expansion audio, etc.) up to the cpu.
+    // implementations should also catch up before handling register writes
+    fn catch_up(&mut self, _cpu: &mut cpu::Cpu) {}
+    // called at the end of each frame, right before 'Cpu::cycle_count'
+    // is reset (see 'Ppu::sub_cpu_cycles()')
+    fn sub_cycle_count(&mut self, _sub: i32) {}
+    // the current output level of the cartridge's expansion audio (if any),
+    // to be mixed with the output of the apu's own channels
+    fn expansion_audio_output(&self) -> f32 {
+        0.0
+    }
+    // the memory on the cartridge that is kept alive by a battery (if any). its
+    // contents are persisted between sessions in a '.sav' file next to the rom
+    fn battery_backed_ram(&mut self) -> Vec<&mut [u8]> {
+        Vec::new()
+    }
+    // the prg ram (up to 8 KB) mapped to 0x6000-0x7fff on power-on (if any). trainers
+    // are copied into it before reset (see 'parse::RomDescription::trainer')
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+    // the disk drive of the famicom disk system (if the bus is its ram adapter). used
+    // to eject and switch disk sides, and to save the changes made to the disk
+    fn disk_drive(&mut self) -> Option<&mut DiskDrive> {
+        None
+    }
+    // the state of the nsf player (if the bus is playing an nsf tune). used to change
+    // tracks and to draw the track info
+    fn nsf_player(&mut self) -> Option<&mut NsfPlayer> {
+        None
+    }
+}
+
+// implemented by the 'CpuAddressBus' of every mapper in 'MAPPERS'
+pub trait FromRom<'a>: CpuAddressBus<'a> + Sized {
+    fn from_rom(
+        rom: &parse::RomDescription,
+        ppu: ppu::Ppu,
+        apu: apu::Apu,
+        controller: ctrl::Controller,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> Self;
+}
+
+// the kind of memory access the ppu is currently making. some mappers (like
+// MMC5) bank chr memory differently for sprites and backgrounds
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PpuFetchKind {
+    // background tile fetches (dots 1-256 and 321-340)
+    Background,
+    // sprite tile fetches (dots 257-320)
+    Sprite,
+    // cpu reads/writes through ppudata (0x2007)
+    Ppudata,
 }
 
 pub trait PpuAddressBus: 'static {
@@ -77,20 +244,28 @@ pub trait PpuAddressBus: 'static {
     // the address on the address bus (happens during rendering). for MMC3,
     // this should /not/ affect the irq counter
     fn read_palette_memory(&self, color_idx: u8) -> u8;
+    // called by 'Ppu' before it switches between fetching background tiles,
+    // fetching sprite tiles and accessing memory through ppudata
+    fn set_fetch_kind(&mut self, _kind: PpuFetchKind) {}
+    // called by 'Ppu' when it starts or stops fetching memory for rendering
+    // (at the start and end of each frame, and when rendering is toggled
+    // through ppumask). may be called repeatedly with the same value
+    fn set_rendering(&mut self, _rendering: bool) {}
 }
 
 // utility function for writing to the 'oamdma' register on the ppu
 // (0x4014). only requires 'CpuAddressBus::read()' to be implemented.
 // intented to be used by 'CpuAddressBus::write()' implementations.
 fn write_oamdma<'a, M: CpuAddressBus<'a>>(memory: &mut M, val: u8, cpu: &mut cpu::Cpu) {
-    memory.base().0.ppu.set_ppustatus_low_bits(val);
-
     // if 'val' is $XX, start address should be $XX00
     let start_addr = (val as u16) << 8;
 
     for (i, addr) in ((start_addr)..=(start_addr + 0xff)).enumerate() {
         let byte = memory.read(addr, cpu);
-        memory.base().0.ppu.write_to_oam_and_increment_addr(byte);
+        // each byte is written through oamdata, filling the ppu open bus
+        let ppu = &mut memory.base().0.ppu;
+        ppu.set_open_bus(byte);
+        ppu.write_to_oam_and_increment_addr(byte);
 
         cpu.cycle_count += 2;
 
@@ -155,22 +330,3 @@ fn calc_ppu_palette_addr(mut addr: u16) -> u8 {
 
     addr as u8
 }
-
-// assumes 'addr' points to a nametable in ppu memory
-// (0x2000-0x3eff) and applies proper mirroring to it.
-// returns a value in the range 0-0x7ff.
-// NOTE: this assumes either horizontal or vertical
-// mirroring, and doesn't work when both are disabled
-fn calc_ppu_nametable_addr_with_mirroring(mut addr: u16, hor_mirroring: bool) -> u16 {
-    debug_assert!(matches!(addr, 0x2000..=0x3eff));
-
-    addr &= !0x3000;
-
-    // apply horizontal nametable mirroring
-    if hor_mirroring {
-        let high_bits = addr & 0b110000000000;
-        addr -= (high_bits.count_ones() as u16) << 10;
-    }
-
-    addr & 0x7ff
-}
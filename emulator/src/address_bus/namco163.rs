@@ -0,0 +1,612 @@
+use super::nametable_layout::{NametableLayout, NametablePage};
+use super::prg_ram::PrgRam;
+use super::{CpuAddressBus, CpuAddressBusBase, FromRom, PpuAddressBus};
+use crate::apu::ExpansionAudio;
+use crate::{apu, controller as ctrl, cpu, parse, ppu, serialize};
+
+use std::cell::Cell;
+use std::{fs, io};
+
+// NOTE: the namco 129/175/340 variants sharing mapper number 19 are not
+// distinguished (they lack the expansion audio and the chr ram nametables)
+
+pub struct Namco163CpuAddressBus<'a> {
+    base: CpuAddressBusBase<'a>,
+    ppu_bus: Namco163PpuAddressBus,
+    internal_ram: [u8; 0x800],
+    prg_rom: Box<[u8]>,
+    prg_ram: PrgRam,
+    // 8 KB prg banks at 0x8000, 0xa000 and 0xc000 (0xe000 is fixed to the last bank)
+    prg_banks: [u8; 3],
+    // the value last written to 0xf800. bits 4-7 enable prg ram writes when set to
+    // 0b0100, and bits 0-3 write-protect each 2 KB quarter of prg ram
+    prg_ram_protect: u8,
+    // 15-bit counter counting up once per cpu cycle (bit 15 enables it)
+    irq_counter: u16,
+    audio: apu::Namco163Audio,
+    // the number of cpu cycles the irq counter and 'audio'
+    // have been clocked for (see 'CpuAddressBus::catch_up()')
+    cycle_count: i32,
+    bits: Namco163CpuBits::BitField,
+}
+
+bitfield!(Namco163CpuBits<u8>(
+    irq_asserted: 0..0,
+));
+
+pub struct Namco163PpuAddressBus {
+    chr: Box<[u8]>,
+    chr_is_ram: bool,
+    // the two nametables in console vram (ciram)
+    nametables: [u8; 0x800],
+    palettes: [u8; 32],
+    // 1 KB banks for 0-0x1fff (0x8000-0xbfff). values 0xe0-0xff
+    // select a ciram page instead of chr rom
+    chr_banks: [u8; 8],
+    // 0xc000-0xdfff. each nametable can be mapped to either ciram or chr rom
+    layout: NametableLayout,
+    // bit 6 and 7 of 0xe800. when set, values 0xe0-0xff select chr rom for
+    // 0-0xfff and 0x1000-0x1fff respectively
+    ciram_disable: u8,
+}
+
+impl<'a> Namco163CpuAddressBus<'a> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        prg_rom: &[u8],
+        chr_rom: &[u8],
+        chr_ram_size: usize,
+        prg_ram: PrgRam,
+        mirroring: parse::MirroringType,
+        ppu: ppu::Ppu,
+        apu: apu::Apu,
+        controller: ctrl::Controller,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> Self {
+        if prg_rom.is_empty() || prg_rom.len() > 0x80000 || !prg_rom.len().is_multiple_of(0x2000) {
+            error_exit!(
+                "Failed to load rom file: prg rom must be a multiple of \
+                 8 KB and at most 512 KB for namco 163 (mapper 19)"
+            );
+        }
+
+        if chr_rom.len() > 0x40000 {
+            error_exit!(
+                "Failed to load rom file: chr rom must be at \
+                 most 256 KB for namco 163 (mapper 19)"
+            );
+        }
+
+        let (chr, chr_is_ram) = super::new_chr(chr_rom, chr_ram_size);
+
+        let ppu_bus = Namco163PpuAddressBus {
+            chr,
+            chr_is_ram,
+            nametables: [0; 0x800],
+            palettes: [0; 32],
+            chr_banks: [0; 8],
+            // namco 163 controls mirroring itself (through 0xc000-0xdfff), but
+            // start out with whatever the header specifies
+            layout: match mirroring {
+                parse::MirroringType::Hor => NametableLayout::HORIZONTAL,
+                _ => NametableLayout::VERTICAL,
+            },
+            ciram_disable: 0,
+        };
+
+        Self {
+            base: CpuAddressBusBase::new(ppu, apu, controller, framebuffer),
+            ppu_bus,
+            internal_ram: [0; 0x800],
+            prg_rom: prg_rom.to_vec().into_boxed_slice(),
+            prg_ram,
+            prg_banks: [0; 3],
+            prg_ram_protect: 0,
+            irq_counter: 0,
+            audio: apu::Namco163Audio::default(),
+            cycle_count: 0,
+            bits: Namco163CpuBits::BitField::zeroed(),
+        }
+    }
+
+    fn calc_prg_rom_addr(&self, addr: u16) -> usize {
+        let window = ((addr >> 13) & 0b11) as usize;
+        let bank = match window {
+            3 => self.prg_rom.len() / 0x2000 - 1,
+            _ => (self.prg_banks[window] & 0x3f) as usize,
+        };
+
+        (bank * 0x2000) % self.prg_rom.len() + (addr & 0x1fff) as usize
+    }
+
+    fn is_prg_ram_writable(&self, addr: u16) -> bool {
+        let quarter = (addr >> 11) & 0b11;
+        self.prg_ram_protect & 0xf0 == 0x40 && self.prg_ram_protect & (1 << quarter) == 0
+    }
+
+    // asserts or acknowledges the irq on the cpu
+    fn set_irq(&mut self, assert: bool, cpu: &mut cpu::Cpu) {
+        if assert != self.bits.irq_asserted.is_true() {
+            self.bits.irq_asserted.set(assert as u8);
+            if assert {
+                cpu.irq += 1;
+            } else {
+                cpu.irq = cpu.irq.saturating_sub(1);
+            }
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8, cpu: &mut cpu::Cpu) {
+        match addr {
+            0x4800..=0x4fff => self.audio.write_data(val),
+            // writing to either half of the irq counter acknowledges the irq
+            0x5000..=0x57ff => {
+                self.catch_up(cpu);
+                self.irq_counter = (self.irq_counter & 0xff00) | val as u16;
+                self.set_irq(false, cpu);
+            }
+            0x5800..=0x5fff => {
+                self.catch_up(cpu);
+                self.irq_counter = (self.irq_counter & 0xff) | (val as u16) << 8;
+                self.set_irq(false, cpu);
+            }
+            0x8000..=0xbfff => self.ppu_bus.chr_banks[((addr - 0x8000) >> 11) as usize] = val,
+            0xc000..=0xdfff => {
+                let page = if val >= 0xe0 {
+                    if val & 1 == 0 {
+                        NametablePage::CiramA
+                    } else {
+                        NametablePage::CiramB
+                    }
+                } else {
+                    NametablePage::Chr(val as u16)
+                };
+
+                let nametable_idx = ((addr - 0xc000) >> 11) as usize;
+                self.ppu_bus.layout.set_page(nametable_idx, page);
+            }
+            0xe000..=0xe7ff => {
+                self.catch_up(cpu);
+                self.prg_banks[0] = val & 0x3f;
+                self.audio.set_disabled(val & 0x40 != 0);
+            }
+            0xe800..=0xefff => {
+                self.prg_banks[1] = val & 0x3f;
+                self.ppu_bus.ciram_disable = val & 0xc0;
+            }
+            0xf000..=0xf7ff => self.prg_banks[2] = val & 0x3f,
+            0xf800..=0xffff => {
+                self.audio.write_addr(val);
+                self.prg_ram_protect = val;
+            }
+            _ => (),
+        }
+    }
+}
+
+impl<'a> FromRom<'a> for Namco163CpuAddressBus<'a> {
+    fn from_rom(
+        rom: &parse::RomDescription,
+        ppu: ppu::Ppu,
+        apu: apu::Apu,
+        controller: ctrl::Controller,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> Self {
+        Self::new(
+            &rom.prg_rom,
+            &rom.chr_rom,
+            rom.header.total_chr_ram_size(),
+            PrgRam::from_header(&rom.header, 0x2000),
+            rom.header.mirroring,
+            ppu,
+            apu,
+            controller,
+            framebuffer,
+        )
+    }
+}
+
+impl<'a> CpuAddressBus<'a> for Namco163CpuAddressBus<'a> {
+    fn read(&mut self, mut addr: u16, cpu: &mut cpu::Cpu) -> u8 {
+        // internal ram
+        if super::is_0_to_1fff(addr) {
+            addr &= !0b1_1000_0000_0000;
+            return unsafe { *self.internal_ram.get_unchecked(addr as usize) };
+        }
+
+        // ppu registers
+        if super::is_2000_to_3fff(addr) {
+            self.base
+                .ppu
+                .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+
+            addr &= 0b111;
+            return self
+                .base
+                .ppu
+                .read_register_by_index(addr as u8, &mut self.ppu_bus, cpu);
+        }
+
+        // prg ram
+        if super::is_6000_to_7fff(addr) {
+            return self.prg_ram.read((addr & 0x1fff) as usize);
+        }
+
+        // prg rom
+        if addr & 0x8000 != 0 {
+            return self.prg_rom[self.calc_prg_rom_addr(addr)];
+        }
+
+        match addr {
+            0x4016 => self.base.controller.read(),
+            0x4800..=0x4fff => self.audio.read_data(),
+            0x5000..=0x57ff => {
+                self.catch_up(cpu);
+                self.irq_counter as u8
+            }
+            0x5800..=0x5fff => {
+                self.catch_up(cpu);
+                (self.irq_counter >> 8) as u8
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, mut addr: u16, val: u8, cpu: &mut cpu::Cpu) {
+        if super::is_0_to_1fff(addr) {
+            addr &= !0b1_1000_0000_0000;
+            unsafe { *self.internal_ram.get_unchecked_mut(addr as usize) = val };
+            return;
+        }
+
+        if super::is_2000_to_3fff(addr) {
+            self.base
+                .ppu
+                .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+
+            self.base
+                .ppu
+                .write_register_by_index(addr as u8 & 0b111, val, cpu, &mut self.ppu_bus);
+
+            return;
+        }
+
+        if super::is_6000_to_7fff(addr) {
+            if self.is_prg_ram_writable(addr) {
+                self.prg_ram.write((addr & 0x1fff) as usize, val);
+            }
+
+            return;
+        }
+
+        // oamdma
+        if addr == 0x4014 {
+            self.base
+                .ppu
+                .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+            super::write_oamdma(self, val, cpu);
+            return;
+        }
+
+        // standard controller 1
+        if addr == 0x4016 {
+            self.base.controller.write(val);
+            return;
+        }
+
+        self.write_register(addr, val, cpu);
+    }
+
+    fn base(&mut self) -> (&mut CpuAddressBusBase<'a>, &mut dyn PpuAddressBus) {
+        (&mut self.base, &mut self.ppu_bus)
+    }
+
+    fn catch_up(&mut self, cpu: &mut cpu::Cpu) {
+        while self.cycle_count < cpu.cycle_count {
+            self.audio.clock();
+
+            // the irq counter stops counting once it reaches 0x7fff
+            if self.irq_counter & 0x8000 != 0 && self.irq_counter & 0x7fff != 0x7fff {
+                self.irq_counter += 1;
+                if self.irq_counter & 0x7fff == 0x7fff {
+                    self.set_irq(true, cpu);
+                }
+            }
+
+            self.cycle_count += 1;
+        }
+    }
+
+    fn sub_cycle_count(&mut self, sub: i32) {
+        self.cycle_count -= sub;
+    }
+
+    fn expansion_audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn battery_backed_ram(&mut self) -> Vec<&mut [u8]> {
+        vec![self.prg_ram.battery_backed(), self.audio.ram_mut()]
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        self.prg_ram.window()
+    }
+}
+
+impl Namco163PpuAddressBus {
+    // returns the ciram page selected by bank register 'bank_idx', if any
+    fn get_ciram_page(&self, bank_idx: usize) -> Option<usize> {
+        let bank = self.chr_banks[bank_idx];
+        if bank < 0xe0 {
+            return None;
+        }
+
+        let is_disabled = match bank_idx {
+            0..=3 => self.ciram_disable & 0x40 != 0,
+            _ => self.ciram_disable & 0x80 != 0,
+        };
+
+        if is_disabled {
+            None
+        } else {
+            Some((bank & 1) as usize)
+        }
+    }
+
+    // handles reads from 0-0x1fff
+    fn read_chr(&self, addr: u16) -> u8 {
+        let bank_idx = (addr >> 10) as usize;
+        let offset = (addr & 0x3ff) as usize;
+
+        match self.get_ciram_page(bank_idx) {
+            Some(page) => self.nametables[page * 0x400 + offset],
+            None => self.chr[self.calc_chr_addr(self.chr_banks[bank_idx] as usize, offset)],
+        }
+    }
+
+    // handles reads from 0x2000-0x3eff
+    fn read_nametable(&self, addr: u16) -> u8 {
+        match self.layout.resolve(addr) {
+            (NametablePage::Chr(bank), offset) => {
+                self.chr[self.calc_chr_addr(bank as usize, offset)]
+            }
+            _ => self.nametables[self.layout.calc_vram_addr(addr)],
+        }
+    }
+
+    // NOTE: writes to chr rom are ignored
+    fn write_chr(&mut self, bank: usize, offset: usize, val: u8) {
+        if self.chr_is_ram {
+            let addr = self.calc_chr_addr(bank, offset);
+            self.chr[addr] = val;
+        }
+    }
+
+    fn calc_chr_addr(&self, bank: usize, offset: usize) -> usize {
+        (bank * 0x400) % self.chr.len() + offset
+    }
+}
+
+impl PpuAddressBus for Namco163PpuAddressBus {
+    fn read(&mut self, addr: u16, _: i32, _: &mut cpu::Cpu) -> u8 {
+        if addr >= 0x3f00 {
+            let addr = super::calc_ppu_palette_addr(addr);
+            return unsafe { *self.palettes.get_unchecked(addr as usize) };
+        }
+
+        if addr >= 0x2000 {
+            return self.read_nametable(addr);
+        }
+
+        self.read_chr(addr)
+    }
+
+    fn write(&mut self, addr: u16, val: u8, _: i32, _: &mut cpu::Cpu) {
+        if addr >= 0x3f00 {
+            let addr = super::calc_ppu_palette_addr(addr);
+            unsafe { *self.palettes.get_unchecked_mut(addr as usize) = val };
+            return;
+        }
+
+        if addr >= 0x2000 {
+            match self.layout.resolve(addr) {
+                (NametablePage::Chr(bank), offset) => self.write_chr(bank as usize, offset, val),
+                _ => {
+                    let addr = self.layout.calc_vram_addr(addr);
+                    self.nametables[addr] = val;
+                }
+            }
+
+            return;
+        }
+
+        let bank_idx = (addr >> 10) as usize;
+        let offset = (addr & 0x3ff) as usize;
+
+        match self.get_ciram_page(bank_idx) {
+            Some(page) => self.nametables[page * 0x400 + offset] = val,
+            None => self.write_chr(self.chr_banks[bank_idx] as usize, offset, val),
+        }
+    }
+
+    fn set_address(&mut self, _: u16, _: i32, _: &mut cpu::Cpu) {}
+
+    fn read_palette_memory(&self, color_idx: u8) -> u8 {
+        self.palettes[super::calc_ppu_palette_addr(color_idx as u16) as usize]
+    }
+}
+
+// NOTE: 'Serialize' is implemented manually to avoid serializing rom
+impl serialize::Serialize for Namco163PpuAddressBus {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        if self.chr_is_ram {
+            self.chr.serialize(file)?;
+        }
+        self.nametables.serialize(file)?;
+        self.palettes.serialize(file)?;
+        self.chr_banks.serialize(file)?;
+        self.layout.serialize(file)?;
+        self.ciram_disable.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        if self.chr_is_ram {
+            self.chr.deserialize(file)?;
+        }
+        self.nametables.deserialize(file)?;
+        self.palettes.deserialize(file)?;
+        self.chr_banks.deserialize(file)?;
+        self.layout.deserialize(file)?;
+        self.ciram_disable.deserialize(file)
+    }
+}
+
+impl<'a> serialize::Serialize for Namco163CpuAddressBus<'a> {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        self.base.serialize(file)?;
+        self.ppu_bus.serialize(file)?;
+        self.internal_ram.serialize(file)?;
+        self.prg_ram.serialize(file)?;
+        self.prg_banks.serialize(file)?;
+        self.prg_ram_protect.serialize(file)?;
+        self.irq_counter.serialize(file)?;
+        self.audio.serialize(file)?;
+        self.cycle_count.serialize(file)?;
+        self.bits.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        self.base.deserialize(file)?;
+        self.ppu_bus.deserialize(file)?;
+        self.internal_ram.deserialize(file)?;
+        self.prg_ram.deserialize(file)?;
+        self.prg_banks.deserialize(file)?;
+        self.prg_ram_protect.deserialize(file)?;
+        self.irq_counter.deserialize(file)?;
+        self.audio.deserialize(file)?;
+        self.cycle_count.deserialize(file)?;
+        self.bits.deserialize(file)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_test_bus<'a>(framebuffer: &'a [Cell<u32>; 256 * 240]) -> Namco163CpuAddressBus<'a> {
+        let prg_rom = (0..0x20000).map(|i| (i / 0x2000) as u8).collect::<Vec<_>>();
+        let chr_rom = (0..0x20000).map(|i| (i / 0x400) as u8).collect::<Vec<_>>();
+
+        Namco163CpuAddressBus::new(
+            &prg_rom,
+            &chr_rom,
+            0,
+            PrgRam::new(0x2000, 0x2000),
+            parse::MirroringType::Vert,
+            ppu::Ppu::new(),
+            apu::Apu::new(),
+            ctrl::Controller::default(),
+            framebuffer,
+        )
+    }
+
+    #[test]
+    fn test_prg_banking() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        bus.write(0xe000, 3, &mut cpu);
+        bus.write(0xe800, 7, &mut cpu);
+        bus.write(0xf000, 11, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 3);
+        assert_eq!(bus.read(0xa000, &mut cpu), 7);
+        assert_eq!(bus.read(0xc000, &mut cpu), 11);
+        assert_eq!(bus.read(0xe000, &mut cpu), 15);
+
+        // prg ram is write-protected unless 0xf800 is set up to allow writes
+        bus.write(0x6000, 0xaa, &mut cpu);
+        assert_eq!(bus.read(0x6000, &mut cpu), 0);
+
+        bus.write(0xf800, 0x40 | 0b0010, &mut cpu);
+        bus.write(0x6000, 0xaa, &mut cpu);
+        bus.write(0x6800, 0xbb, &mut cpu);
+        assert_eq!(bus.read(0x6000, &mut cpu), 0xaa);
+        assert_eq!(bus.read(0x6800, &mut cpu), 0);
+    }
+
+    #[test]
+    fn test_chr_banking() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        bus.write(0x8800, 0x21, &mut cpu);
+        bus.write(0xb800, 0x42, &mut cpu);
+        assert_eq!(bus.ppu_bus.read(0x0400, 0, &mut cpu), 0x21);
+        assert_eq!(bus.ppu_bus.read(0x1c00, 0, &mut cpu), 0x42);
+
+        // values 0xe0-0xff select ciram, unless disabled through 0xe800
+        bus.write(0x8000, 0xe1, &mut cpu);
+        bus.ppu_bus.write(0x0005, 0x99, 0, &mut cpu);
+        assert_eq!(bus.ppu_bus.read(0x0005, 0, &mut cpu), 0x99);
+        assert_eq!(bus.ppu_bus.nametables[0x405], 0x99);
+
+        bus.write(0xe800, 0x40, &mut cpu);
+        assert_eq!(bus.ppu_bus.read(0x0005, 0, &mut cpu), 0xe1 % 0x80);
+
+        // nametables can be mapped to chr rom
+        bus.write(0xc000, 0x13, &mut cpu);
+        assert_eq!(bus.ppu_bus.read(0x2005, 0, &mut cpu), 0x13);
+        bus.ppu_bus.write(0x2805, 0x77, 0, &mut cpu);
+        assert_eq!(bus.ppu_bus.read(0x3805, 0, &mut cpu), 0x77);
+        assert_eq!(bus.ppu_bus.nametables[0x005], 0x77);
+    }
+
+    #[test]
+    fn test_irq_counter() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        bus.write(0x5000, 0xfd, &mut cpu);
+        bus.write(0x5800, 0x80 | 0x7f, &mut cpu);
+
+        cpu.cycle_count += 1;
+        bus.catch_up(&mut cpu);
+        assert_eq!(cpu.irq, 0);
+
+        cpu.cycle_count += 1;
+        bus.catch_up(&mut cpu);
+        assert_eq!(cpu.irq, 1);
+
+        // the counter stops at 0x7fff
+        cpu.cycle_count += 10;
+        bus.catch_up(&mut cpu);
+        assert_eq!(bus.read(0x5000, &mut cpu), 0xff);
+        assert_eq!(bus.read(0x5800, &mut cpu), 0xff);
+
+        // writing to the counter acknowledges the irq
+        bus.write(0x5000, 0, &mut cpu);
+        assert_eq!(cpu.irq, 0);
+    }
+
+    #[test]
+    fn test_sound_ram() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        bus.write(0xf800, 0x80 | 0x10, &mut cpu);
+        bus.write(0x4800, 0x12, &mut cpu);
+        bus.write(0x4800, 0x34, &mut cpu);
+
+        bus.write(0xf800, 0x80 | 0x10, &mut cpu);
+        assert_eq!(bus.read(0x4800, &mut cpu), 0x12);
+        assert_eq!(bus.read(0x4800, &mut cpu), 0x34);
+
+        let battery_backed_ram = bus.battery_backed_ram();
+        assert_eq!(battery_backed_ram[1][0x10..0x12], [0x12, 0x34]);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::{parse, serialize};
+
+use std::{fs, io};
+
+// the memory backing one of the four 1 KB nametables at 0x2000-0x2fff
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NametablePage {
+    // the two pages of console vram (ciram)
+    CiramA,
+    CiramB,
+    // a page of extra vram on the cartridge (four-screen boards, mmc5 exram)
+    CartridgeVram(u8),
+    // a 1 KB page of chr rom (or ram)
+    Chr(u16),
+    // a page with fixed contents, generated by the mapper (mmc5 fill mode)
+    Fill,
+}
+
+// maps each of the four logical nametables to a 1 KB page. mappers with fixed
+// mirroring create one from the header, while mappers with mirroring control
+// swap in a new layout (or change single pages) when their registers are written
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct NametableLayout {
+    pages: [NametablePage; 4],
+}
+
+impl NametableLayout {
+    pub const HORIZONTAL: Self = Self::new([
+        NametablePage::CiramA,
+        NametablePage::CiramA,
+        NametablePage::CiramB,
+        NametablePage::CiramB,
+    ]);
+
+    pub const VERTICAL: Self = Self::new([
+        NametablePage::CiramA,
+        NametablePage::CiramB,
+        NametablePage::CiramA,
+        NametablePage::CiramB,
+    ]);
+
+    pub const SINGLE_SCREEN_A: Self = Self::new([NametablePage::CiramA; 4]);
+    pub const SINGLE_SCREEN_B: Self = Self::new([NametablePage::CiramB; 4]);
+
+    // the third and fourth nametable come from 2 KB of vram on the cartridge
+    pub const FOUR_SCREEN: Self = Self::new([
+        NametablePage::CiramA,
+        NametablePage::CiramB,
+        NametablePage::CartridgeVram(0),
+        NametablePage::CartridgeVram(1),
+    ]);
+
+    pub const fn new(pages: [NametablePage; 4]) -> Self {
+        Self { pages }
+    }
+
+    pub fn from_mirroring(mirroring: parse::MirroringType) -> Self {
+        match mirroring {
+            parse::MirroringType::Hor => Self::HORIZONTAL,
+            parse::MirroringType::Vert => Self::VERTICAL,
+            parse::MirroringType::FourScreen => Self::FOUR_SCREEN,
+            parse::MirroringType::SingleScreenA => Self::SINGLE_SCREEN_A,
+            parse::MirroringType::SingleScreenB => Self::SINGLE_SCREEN_B,
+        }
+    }
+
+    // the mirroring register layout shared by most mappers with mirroring control
+    // (0 = vertical, 1 = horizontal, 2 = single-screen a, 3 = single-screen b)
+    pub fn from_mirroring_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0 => Self::VERTICAL,
+            1 => Self::HORIZONTAL,
+            2 => Self::SINGLE_SCREEN_A,
+            _ => Self::SINGLE_SCREEN_B,
+        }
+    }
+
+    pub fn set_page(&mut self, nametable_idx: usize, page: NametablePage) {
+        self.pages[nametable_idx] = page;
+    }
+
+    // returns the page mapped at 'addr' (0x2000-0x3eff) along with the offset into it
+    pub fn resolve(&self, addr: u16) -> (NametablePage, usize) {
+        let nametable_idx = ((addr >> 10) & 0b11) as usize;
+        (self.pages[nametable_idx], (addr & 0x3ff) as usize)
+    }
+
+    // maps 'addr' (0x2000-0x3eff) to an index into the nametable vram of a mapper,
+    // which is laid out as ciram a, ciram b and then the cartridge vram pages
+    // NOTE: mappers that map chr or fill pages have to 'resolve()' those themselves
+    pub fn calc_vram_addr(&self, addr: u16) -> usize {
+        let (page, offset) = self.resolve(addr);
+        match page {
+            NametablePage::CiramA => offset,
+            NametablePage::CiramB => 0x400 | offset,
+            NametablePage::CartridgeVram(n) => 0x800 + n as usize * 0x400 + offset,
+            NametablePage::Chr(_) | NametablePage::Fill => {
+                unreachable!("nametable page {:?} isn't backed by vram", page)
+            }
+        }
+    }
+}
+
+// NOTE: each page is stored as a kind byte followed by the (chr or cartridge vram) page index
+impl serialize::Serialize for NametableLayout {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        for page in self.pages.iter() {
+            let (kind, idx): (u8, u16) = match *page {
+                NametablePage::CiramA => (0, 0),
+                NametablePage::CiramB => (1, 0),
+                NametablePage::CartridgeVram(n) => (2, n as u16),
+                NametablePage::Chr(bank) => (3, bank),
+                NametablePage::Fill => (4, 0),
+            };
+
+            kind.serialize(file)?;
+            idx.serialize(file)?;
+        }
+
+        Ok(())
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        for page in self.pages.iter_mut() {
+            let (mut kind, mut idx) = (0u8, 0u16);
+            kind.deserialize(file)?;
+            idx.deserialize(file)?;
+
+            *page = match kind {
+                0 => NametablePage::CiramA,
+                1 => NametablePage::CiramB,
+                2 => NametablePage::CartridgeVram(idx as u8),
+                3 => NametablePage::Chr(idx),
+                4 => NametablePage::Fill,
+                _ => return Err(format!("invalid nametable page kind {}", kind)),
+            };
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_calc_vram_addr() {
+        let layout = NametableLayout::HORIZONTAL;
+        assert_eq!(layout.calc_vram_addr(0x2005), 0x005);
+        assert_eq!(layout.calc_vram_addr(0x2405), 0x005);
+        assert_eq!(layout.calc_vram_addr(0x2805), 0x405);
+        assert_eq!(layout.calc_vram_addr(0x2c05), 0x405);
+
+        let layout = NametableLayout::VERTICAL;
+        assert_eq!(layout.calc_vram_addr(0x2405), 0x405);
+        assert_eq!(layout.calc_vram_addr(0x2805), 0x005);
+        // 0x3000-0x3eff mirrors 0x2000-0x2eff
+        assert_eq!(layout.calc_vram_addr(0x3c05), 0x405);
+
+        let layout = NametableLayout::SINGLE_SCREEN_B;
+        assert_eq!(layout.calc_vram_addr(0x2005), 0x405);
+        assert_eq!(layout.calc_vram_addr(0x2fff), 0x7ff);
+
+        let layout = NametableLayout::FOUR_SCREEN;
+        assert_eq!(layout.calc_vram_addr(0x2805), 0x805);
+        assert_eq!(layout.calc_vram_addr(0x2fff), 0xfff);
+    }
+
+    #[test]
+    fn test_per_page_mapping() {
+        let mut layout = NametableLayout::from_mirroring_bits(0);
+        assert_eq!(layout, NametableLayout::VERTICAL);
+
+        layout.set_page(1, NametablePage::Chr(0x12));
+        layout.set_page(3, NametablePage::CartridgeVram(0));
+        assert_eq!(layout.resolve(0x2010), (NametablePage::CiramA, 0x10));
+        assert_eq!(layout.resolve(0x27ff), (NametablePage::Chr(0x12), 0x3ff));
+        assert_eq!(
+            layout.resolve(0x2c20),
+            (NametablePage::CartridgeVram(0), 0x20)
+        );
+        assert_eq!(layout.calc_vram_addr(0x2c20), 0x820);
+    }
+}
@@ -1,4 +1,6 @@
-use super::{CpuAddressBus, CpuAddressBusBase, PpuAddressBus};
+use super::nametable_layout::NametableLayout;
+use super::prg_ram::PrgRam;
+use super::{CpuAddressBus, CpuAddressBusBase, FromRom, PpuAddressBus};
 use crate::{apu, controller as ctrl, cpu, parse, ppu, serialize};
 
 #[macro_use]
@@ -12,31 +14,37 @@ pub struct NromCpuAddressBus<'a> {
     ppu_bus: NromPpuAddressBus,
     internal_ram: [u8; 0x800],
     prg_rom: Box<[u8]>,
-    prg_ram: [u8; 0x2000],
+    prg_ram: PrgRam,
 }
 
 pub struct NromPpuAddressBus {
-    chr_ram: [u8; 0x2000],
-    nametables: [u8; 0x800],
+    // 8 KB of chr rom, or at least 8 KB of chr ram (of which only the first 8 KB is used)
+    chr: Box<[u8]>,
+    chr_is_ram: bool,
+    // the second half is only used with four-screen mirroring
+    nametables: [u8; 0x1000],
     palettes: [u8; 32],
-    hor_mirroring: bool,
+    layout: NametableLayout,
 }
 
 impl<'a> NromCpuAddressBus<'a> {
     // TODO: reduce unnecessary copying
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         prg_rom: &[u8],
-        chr_ram: &[u8],
+        chr_rom: &[u8],
+        chr_ram_size: usize,
+        prg_ram: PrgRam,
         mirroring: parse::MirroringType,
         ppu: ppu::Ppu,
         apu: apu::Apu,
         controller: ctrl::Controller,
         framebuffer: &'a [Cell<u32>; 256 * 240],
     ) -> Self {
-        if chr_ram.len() != 0x2000 {
+        if !matches!(chr_rom.len(), 0 | 0x2000) {
             error_exit!(
                 "Failed to load rom file: chr rom was the wrong size ({}) for nrom (mapper 0)",
-                chr_ram.len()
+                chr_rom.len()
             )
         }
 
@@ -47,29 +55,22 @@ impl<'a> NromCpuAddressBus<'a> {
             )
         }
 
-        let hor_mirroring = match mirroring {
-            parse::MirroringType::Hor => true,
-            parse::MirroringType::Vert => false,
-            parse::MirroringType::FourScreen => error_exit!(
-                "Failed to load rom file: nrom (mapper 0) doesn't support 4-screen vram"
-            ),
-        };
+        // TODO: avoid this copy
+        let (chr, chr_is_ram) = super::new_chr(chr_rom, chr_ram_size);
 
-        let mut ppu_bus = NromPpuAddressBus {
-            chr_ram: [0; 0x2000],
-            nametables: [0; 0x800],
+        let ppu_bus = NromPpuAddressBus {
+            chr,
+            chr_is_ram,
+            nametables: [0; 0x1000],
             palettes: [0; 32],
-            hor_mirroring,
+            layout: NametableLayout::from_mirroring(mirroring),
         };
 
-        // TODO: avoid this copy
-        ppu_bus.chr_ram.copy_from_slice(chr_ram);
-
         Self {
             base: CpuAddressBusBase::new(ppu, apu, controller, framebuffer),
             ppu_bus,
             internal_ram: [0; 0x800],
-            prg_ram: [0; 0x2000],
+            prg_ram,
             prg_rom: prg_rom.to_vec().into_boxed_slice(),
         }
     }
@@ -86,19 +87,42 @@ impl<'a> NromCpuAddressBus<'a> {
 
         Self {
             internal_ram: [0; 0x800],
-            prg_ram: [0; 0x2000],
+            prg_ram: PrgRam::new(0x2000, 0),
             prg_rom: vec![0; prg_rom_size as usize].into_boxed_slice(),
             ppu_bus: NromPpuAddressBus {
-                chr_ram: [0; 0x2000],
-                nametables: [0; 0x800],
+                chr: vec![0; 0x2000].into_boxed_slice(),
+                chr_is_ram: true,
+                nametables: [0; 0x1000],
                 palettes: [0; 32],
-                hor_mirroring: false,
+                layout: NametableLayout::VERTICAL,
             },
             base: CpuAddressBusBase::new(ppu, apu, controller, framebuffer),
         }
     }
 }
 
+impl<'a> FromRom<'a> for NromCpuAddressBus<'a> {
+    fn from_rom(
+        rom: &parse::RomDescription,
+        ppu: ppu::Ppu,
+        apu: apu::Apu,
+        controller: ctrl::Controller,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> Self {
+        Self::new(
+            &rom.prg_rom,
+            &rom.chr_rom,
+            rom.header.total_chr_ram_size(),
+            PrgRam::from_header(&rom.header, 0x2000),
+            rom.header.mirroring,
+            ppu,
+            apu,
+            controller,
+            framebuffer,
+        )
+    }
+}
+
 impl<'a> CpuAddressBus<'a> for NromCpuAddressBus<'a> {
     fn read(&mut self, mut addr: u16, cpu: &mut cpu::Cpu) -> u8 {
         // internal ram
@@ -124,8 +148,7 @@ impl<'a> CpuAddressBus<'a> for NromCpuAddressBus<'a> {
 
         // prg ram
         if super::is_6000_to_7fff(addr) {
-            addr &= !0b110_0000_0000_0000;
-            return unsafe { *self.prg_ram.get_unchecked(addr as usize) };
+            return self.prg_ram.read((addr & 0x1fff) as usize);
         }
 
         // addres line a15 = 1 (0x8000-0xffff) => prg rom
@@ -173,11 +196,7 @@ impl<'a> CpuAddressBus<'a> for NromCpuAddressBus<'a> {
         }
 
         if super::is_6000_to_7fff(addr) {
-            unsafe {
-                *self
-                    .prg_ram
-                    .get_unchecked_mut((addr & !0b110_0000_0000_0000) as usize) = val;
-            }
+            self.prg_ram.write((addr & 0x1fff) as usize, val);
             return;
         }
 
@@ -201,6 +220,14 @@ impl<'a> CpuAddressBus<'a> for NromCpuAddressBus<'a> {
     fn base(&mut self) -> (&mut CpuAddressBusBase<'a>, &mut dyn PpuAddressBus) {
         (&mut self.base, &mut self.ppu_bus)
     }
+
+    fn battery_backed_ram(&mut self) -> Vec<&mut [u8]> {
+        vec![self.prg_ram.battery_backed()]
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        self.prg_ram.window()
+    }
 }
 
 impl PpuAddressBus for NromPpuAddressBus {
@@ -218,12 +245,12 @@ impl PpuAddressBus for NromPpuAddressBus {
 
         // if address is in the range 0x2000-0x3eff
         if addr >= 0x2000 {
-            let addr = super::calc_ppu_nametable_addr_with_mirroring(addr, self.hor_mirroring);
-            return unsafe { *self.nametables.get_unchecked(addr as usize) };
+            let addr = self.layout.calc_vram_addr(addr);
+            return unsafe { *self.nametables.get_unchecked(addr) };
         }
 
-        // address is in the range 0-0x1fff (chr ram)
-        unsafe { *self.chr_ram.get_unchecked(addr as usize) }
+        // address is in the range 0-0x1fff (chr rom/ram)
+        unsafe { *self.chr.get_unchecked(addr as usize) }
     }
 
     // NOTE: passing addresses higher than 0x3fff will write to palette ram
@@ -239,12 +266,14 @@ impl PpuAddressBus for NromPpuAddressBus {
         }
 
         if addr >= 0x2000 {
-            let addr = super::calc_ppu_nametable_addr_with_mirroring(addr, self.hor_mirroring);
-            unsafe { *self.nametables.get_unchecked_mut(addr as usize) = val };
+            let addr = self.layout.calc_vram_addr(addr);
+            unsafe { *self.nametables.get_unchecked_mut(addr) = val };
             return;
         }
 
-        unsafe { *self.chr_ram.get_unchecked_mut(addr as usize) = val };
+        if self.chr_is_ram {
+            unsafe { *self.chr.get_unchecked_mut(addr as usize) = val };
+        }
     }
 
     fn set_address(&mut self, addr: u16, ppu_cycle_count: i32, cpu: &mut cpu::Cpu) {}
@@ -257,17 +286,21 @@ impl PpuAddressBus for NromPpuAddressBus {
 // NOTE: 'Serialize' is implemented manually to avoid serializing rom
 impl serialize::Serialize for NromPpuAddressBus {
     fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
-        self.chr_ram.serialize(file)?;
+        if self.chr_is_ram {
+            self.chr.serialize(file)?;
+        }
         self.nametables.serialize(file)?;
         self.palettes.serialize(file)?;
-        self.hor_mirroring.serialize(file)
+        self.layout.serialize(file)
     }
 
     fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
-        self.chr_ram.deserialize(file)?;
+        if self.chr_is_ram {
+            self.chr.deserialize(file)?;
+        }
         self.nametables.deserialize(file)?;
         self.palettes.deserialize(file)?;
-        self.hor_mirroring.deserialize(file)
+        self.layout.deserialize(file)
     }
 }
 
@@ -294,7 +327,7 @@ mod test {
     #[test]
     fn test_cpu_read_write() {
         let ppu = ppu::Ppu::new();
-        let apu = apu::Apu {};
+        let apu = apu::Apu::new();
         let controller = ctrl::Controller::default();
         let framebuffer = Cell::new([0u32; 256 * 240]);
         let mut bus = NromCpuAddressBus::new_empty(0x4000, ppu, apu, controller, unsafe {
@@ -369,10 +402,11 @@ mod test {
     #[test]
     fn test_ppu_read_write() {
         let mut bus = NromPpuAddressBus {
-            chr_ram: [0; 0x2000],
-            nametables: [0; 0x800],
+            chr: vec![0; 0x2000].into_boxed_slice(),
+            chr_is_ram: true,
+            nametables: [0; 0x1000],
             palettes: [0; 32],
-            hor_mirroring: false,
+            layout: NametableLayout::VERTICAL,
         };
         let mut cpu = cpu::Cpu::default();
 
@@ -391,7 +425,7 @@ mod test {
         }
 
         // set mirroring = horizontal
-        bus.hor_mirroring = true;
+        bus.layout = NametableLayout::HORIZONTAL;
         // test nametable writes
         bus.write(0x2fff, 0xee, 0, &mut cpu);
         assert_eq!(bus.nametables[0x7ff], 0xee);
@@ -461,4 +495,70 @@ mod test {
         // should end up in palette ram
         assert_eq!(bus.palettes[31], 0x20);
     }
+
+    #[test]
+    fn test_four_screen() {
+        let mut bus = NromPpuAddressBus {
+            chr: vec![0; 0x2000].into_boxed_slice(),
+            chr_is_ram: true,
+            nametables: [0; 0x1000],
+            palettes: [0; 32],
+            layout: NametableLayout::from_mirroring(parse::MirroringType::FourScreen),
+        };
+        let mut cpu = cpu::Cpu::default();
+
+        // every nametable has its own page
+        for (i, addr) in [0x2010u16, 0x2410, 0x2810, 0x2c10].iter().enumerate() {
+            bus.write(*addr, i as u8 + 1, 0, &mut cpu);
+        }
+
+        assert_eq!(bus.nametables[0x010], 1);
+        assert_eq!(bus.nametables[0x410], 2);
+        assert_eq!(bus.nametables[0x810], 3);
+        assert_eq!(bus.nametables[0xc10], 4);
+        // 0x3000-0x3eff still mirrors 0x2000-0x2eff
+        assert_eq!(bus.read(0x3810, 0, &mut cpu), 3);
+    }
+
+    #[test]
+    fn test_chr_rom_and_ram() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut cpu = cpu::Cpu::default();
+        let chr_rom = vec![0x11; 0x2000];
+
+        // chr rom can't be written to
+        let mut bus = NromCpuAddressBus::new(
+            &[0; 0x4000],
+            &chr_rom,
+            0,
+            PrgRam::new(0x2000, 0),
+            parse::MirroringType::Vert,
+            ppu::Ppu::new(),
+            apu::Apu::new(),
+            ctrl::Controller::default(),
+            unsafe { &*(&framebuffer as *const _ as *const _) },
+        );
+
+        assert!(!bus.ppu_bus.chr_is_ram);
+        bus.ppu_bus.write(0x1234, 0x22, 0, &mut cpu);
+        assert_eq!(bus.ppu_bus.read(0x1234, 0, &mut cpu), 0x11);
+
+        // roms without chr rom get (at least) 8 KB of chr ram
+        let mut bus = NromCpuAddressBus::new(
+            &[0; 0x4000],
+            &[],
+            0x1000,
+            PrgRam::new(0x2000, 0),
+            parse::MirroringType::Vert,
+            ppu::Ppu::new(),
+            apu::Apu::new(),
+            ctrl::Controller::default(),
+            unsafe { &*(&framebuffer as *const _ as *const _) },
+        );
+
+        assert!(bus.ppu_bus.chr_is_ram);
+        assert_eq!(bus.ppu_bus.chr.len(), 0x2000);
+        bus.ppu_bus.write(0x1fff, 0x22, 0, &mut cpu);
+        assert_eq!(bus.ppu_bus.read(0x1fff, 0, &mut cpu), 0x22);
+    }
 }
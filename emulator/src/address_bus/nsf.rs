@@ -0,0 +1,847 @@
+use super::{CpuAddressBus, CpuAddressBusBase, PpuAddressBus};
+use crate::apu::ExpansionAudio;
+use crate::{apu, controller as ctrl, cpu, nsf, ppu, serialize};
+
+use std::cell::Cell;
+use std::{fs, io};
+
+// the routine that plays the tune (see 'DRIVER'), and the registers it reads
+const DRIVER_ADDR: u16 = 0x4100;
+const TRACK_REGISTER: u16 = 0x4140;
+const REGION_REGISTER: u16 = 0x4141;
+const STATUS_REGISTER: u16 = 0x4142;
+
+// the play rate used by tunes that don't specify one (in microseconds)
+const DEFAULT_PLAY_SPEED: u16 = 16639;
+// the fade out of tracks that have a length but no fade (in milliseconds)
+const DEFAULT_FADE: u32 = 8000;
+
+// nsf tunes consist of an init routine (called once per track, with the track number in
+// 'a' and the region in 'x') and a play routine (called at the play rate). these are
+// called by a small driver, mapped to 'DRIVER_ADDR' and pointed to by the reset vector.
+// rather than relying on interrupts, the driver polls the status register, which tells
+// it when to call the play routine and when to restart for a new track
+#[rustfmt::skip]
+static DRIVER: [u8; 0x1d] = [
+    0x78,             // 4100: sei
+    0xd8,             // 4101: cld
+    0xa2, 0xff,       // 4102: ldx #$ff
+    0x9a,             // 4104: txs
+    0xad, 0x40, 0x41, // 4105: lda $4140 (resets the tune and returns the track number)
+    0xae, 0x41, 0x41, // 4108: ldx $4141
+    0x20, 0x00, 0x00, // 410b: jsr init
+    0xad, 0x42, 0x41, // 410e: lda $4142 (bit 7 = restart, bit 6 = play)
+    0x30, 0xed,       // 4111: bmi $4100
+    0x0a,             // 4113: asl a
+    0x10, 0xf8,       // 4114: bpl $410e
+    0x20, 0x00, 0x00, // 4116: jsr play
+    0x4c, 0x0e, 0x41, // 4119: jmp $410e
+    0x40,             // 411c: rti (nmi and irq handler)
+];
+const DRIVER_INIT_OFFSET: usize = 0xc;
+const DRIVER_PLAY_OFFSET: usize = 0x17;
+const DRIVER_RTI_ADDR: u16 = DRIVER_ADDR + 0x1c;
+
+// a synthetic mapper for playing nsf tunes. tunes are mapped to 0x8000-0xffff either
+// directly (at their load address) or in 4 KB banks selected through 0x5ff8-0x5fff, and
+// may use any combination of the expansion chips
+pub struct NsfCpuAddressBus<'a> {
+    base: CpuAddressBusBase<'a>,
+    ppu_bus: NsfPpuAddressBus,
+    internal_ram: [u8; 0x800],
+    // the program data. for bankswitched tunes, this is padded so that the load address
+    // is at the same offset in the first bank
+    prg: Box<[u8]>,
+    // 0x6000-0xffff. banks are copied in when selected, as tunes using the fds may write
+    // to the whole range (and select banks for 0x6000-0x7fff through 0x5ff6-0x5ff7)
+    memory: Box<[u8]>,
+    driver: [u8; 0x1d],
+    player: NsfPlayer,
+    vrc6: Option<apu::Vrc6Audio>,
+    vrc7: Option<apu::Opll>,
+    fds: Option<apu::FdsAudio>,
+    mmc5: Option<apu::Mmc5Audio>,
+    n163: Option<apu::Namco163Audio>,
+    sunsoft5b: Option<apu::Sunsoft5bAudio>,
+    // the parts of MMC5 besides its audio that tunes may use: the 8-bit
+    // multiplier (0x5205-0x5206) and the exram (0x5c00-0x5ff5)
+    multiplicand: u8,
+    multiplier: u8,
+    exram: [u8; 0x400],
+    // the number of cpu cycles the play timer and expansion
+    // chips have been clocked for (see 'CpuAddressBus::catch_up()')
+    cycle_count: i32,
+}
+
+// nsf tunes don't use the ppu, so its memory is limited to the palettes (which are
+// set to black, so that the track info can be drawn over the blank frames)
+pub struct NsfPpuAddressBus {
+    palettes: [u8; 32],
+}
+
+// the state of the player (which track is playing, and for how long)
+pub struct NsfPlayer {
+    // the tune's metadata (without its data)
+    nsf: nsf::Nsf,
+    track: u8,
+    // the number of cpu cycles per second (which depends on the region)
+    cpu_clock_rate: u32,
+    // the number of cpu cycles between calls to the play routine
+    play_period: u32,
+    play_timer: u32,
+    elapsed_ms: u32,
+    // cpu cycles not yet counted in 'elapsed_ms' (multiplied by 1000)
+    elapsed_remainder: u32,
+    bits: NsfPlayerBits::BitField,
+}
+
+bitfield!(NsfPlayerBits<u8>(
+    restart_requested: 0..0,
+    play_due: 1..1,
+    is_pal: 2..2
+));
+
+impl NsfPlayer {
+    fn new(nsf: nsf::Nsf, region: ppu::Region) -> Self {
+        // dendy runs at the pal frame rate, so tunes are played at the pal rate there too
+        let is_pal = region != ppu::Region::Ntsc;
+        let play_speed = if is_pal {
+            nsf.pal_play_speed
+        } else {
+            nsf.ntsc_play_speed
+        };
+        let play_speed = if play_speed == 0 {
+            DEFAULT_PLAY_SPEED
+        } else {
+            play_speed
+        };
+
+        let mut bits = NsfPlayerBits::BitField::zeroed();
+        bits.is_pal.set(is_pal as u8);
+
+        Self {
+            track: nsf.starting_track,
+            nsf,
+            cpu_clock_rate: region.cpu_clock_rate(),
+            play_period: (play_speed as u64 * region.cpu_clock_rate() as u64 / 1_000_000) as u32,
+            play_timer: 0,
+            elapsed_ms: 0,
+            elapsed_remainder: 0,
+            bits,
+        }
+    }
+
+    pub fn nsf(&self) -> &nsf::Nsf {
+        &self.nsf
+    }
+
+    // the current track (zero-based)
+    pub fn track(&self) -> u8 {
+        self.track
+    }
+
+    // the time since the current track started
+    pub fn elapsed_ms(&self) -> u32 {
+        self.elapsed_ms
+    }
+
+    // restarts the tune with the given track. takes effect the next time the driver polls
+    // the status register (which may be delayed by a play routine that is still running)
+    pub fn select_track(&mut self, track: u8) {
+        self.track = track % self.nsf.tracks.len() as u8;
+        self.bits.restart_requested.set(1);
+    }
+
+    pub fn next_track(&mut self) {
+        self.select_track(((self.track as usize + 1) % self.nsf.tracks.len()) as u8);
+    }
+
+    pub fn previous_track(&mut self) {
+        let track_count = self.nsf.tracks.len();
+        self.select_track(((self.track as usize + track_count - 1) % track_count) as u8);
+    }
+
+    // the length of the current track and its fade out, if known
+    fn track_time(&self) -> Option<(u32, u32)> {
+        let track = &self.nsf.tracks[self.track as usize];
+        track
+            .time
+            .map(|time| (time, track.fade.unwrap_or(DEFAULT_FADE)))
+    }
+
+    // the volume of the current track, which fades out once the track has played
+    fn fade_gain(&self) -> f32 {
+        match self.track_time() {
+            Some((time, _)) if self.elapsed_ms < time => 1.0,
+            Some((time, fade)) if fade > 0 => {
+                (1.0 - (self.elapsed_ms - time) as f32 / fade as f32).max(0.0)
+            }
+            Some(_) => 0.0,
+            None => 1.0,
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.play_timer == 0 {
+            self.play_timer = self.play_period;
+            self.bits.play_due.set(1);
+        } else {
+            self.play_timer -= 1;
+        }
+    }
+
+    // advances the elapsed time, moving on to the next track once the current one
+    // (and its fade out) has ended
+    fn add_elapsed_cycles(&mut self, cycles: u32) {
+        self.elapsed_remainder += cycles * 1000;
+        self.elapsed_ms += self.elapsed_remainder / self.cpu_clock_rate;
+        self.elapsed_remainder %= self.cpu_clock_rate;
+
+        if let Some((time, fade)) = self.track_time() {
+            if self.elapsed_ms >= time + fade && !self.bits.restart_requested.is_true() {
+                self.next_track();
+            }
+        }
+    }
+}
+
+impl<'a> NsfCpuAddressBus<'a> {
+    pub fn new(
+        mut nsf: nsf::Nsf,
+        ppu: ppu::Ppu,
+        apu: apu::Apu,
+        controller: ctrl::Controller,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> Self {
+        let has_fds = nsf.expansion & nsf::EXPANSION_FDS != 0;
+        let min_load_addr = if has_fds { 0x6000 } else { 0x8000 };
+        if nsf.load_addr < min_load_addr {
+            error_exit!(
+                "Failed to load rom file: nsf load address {:#06x} is below {:#06x}",
+                nsf.load_addr,
+                min_load_addr
+            );
+        }
+
+        let mut prg = std::mem::take(&mut nsf.data);
+        if nsf.initial_banks.is_some() {
+            let padding = (nsf.load_addr & 0xfff) as usize;
+            prg.splice(0..0, vec![0; padding]);
+            prg.resize((prg.len() + 0xfff) & !0xfff, 0);
+        }
+
+        let mut driver = DRIVER;
+        driver[DRIVER_INIT_OFFSET..DRIVER_INIT_OFFSET + 2]
+            .copy_from_slice(&nsf.init_addr.to_le_bytes());
+        driver[DRIVER_PLAY_OFFSET..DRIVER_PLAY_OFFSET + 2]
+            .copy_from_slice(&nsf.play_addr.to_le_bytes());
+
+        let has_chip = |chip: u8| nsf.expansion & chip != 0;
+        let vrc6 = has_chip(nsf::EXPANSION_VRC6).then(apu::Vrc6Audio::default);
+        let vrc7 = has_chip(nsf::EXPANSION_VRC7).then(apu::Opll::default);
+        let fds = has_chip(nsf::EXPANSION_FDS).then(apu::FdsAudio::default);
+        let mmc5 = has_chip(nsf::EXPANSION_MMC5).then(apu::Mmc5Audio::default);
+        let n163 = has_chip(nsf::EXPANSION_N163).then(apu::Namco163Audio::default);
+        let sunsoft5b = has_chip(nsf::EXPANSION_5B).then(apu::Sunsoft5bAudio::default);
+        let region = ppu.region();
+
+        Self {
+            base: CpuAddressBusBase::new(ppu, apu, controller, framebuffer),
+            ppu_bus: NsfPpuAddressBus {
+                palettes: [0x0f; 32],
+            },
+            internal_ram: [0; 0x800],
+            prg: prg.into_boxed_slice(),
+            memory: vec![0; 0xa000].into_boxed_slice(),
+            driver,
+            player: NsfPlayer::new(nsf, region),
+            vrc6,
+            vrc7,
+            fds,
+            mmc5,
+            n163,
+            sunsoft5b,
+            multiplicand: 0,
+            multiplier: 0,
+            exram: [0; 0x400],
+            cycle_count: 0,
+        }
+    }
+
+    // copies a 4 KB bank of 'prg' to 'memory' (0 = 0x6000-0x6fff, .., 9 = 0xf000-0xffff)
+    fn switch_bank(&mut self, slot: usize, bank: u8) {
+        let bank_count = self.prg.len() / 0x1000;
+        let bank = &self.prg[(bank as usize % bank_count) * 0x1000..][..0x1000];
+        self.memory[slot * 0x1000..(slot + 1) * 0x1000].copy_from_slice(bank);
+    }
+
+    // resets the memory and expansion chips for the selected track, as the
+    // tune expects before its init routine is called
+    fn start_track(&mut self) {
+        self.internal_ram = [0; 0x800];
+        self.memory.iter_mut().for_each(|byte| *byte = 0);
+        self.exram = [0; 0x400];
+
+        match self.player.nsf.initial_banks {
+            Some(banks) => {
+                for (i, &bank) in banks.iter().enumerate() {
+                    self.switch_bank(i + 2, bank);
+                }
+                // fds tunes also start with banks 6 and 7 at 0x6000-0x7fff
+                if self.fds.is_some() {
+                    self.switch_bank(0, banks[6]);
+                    self.switch_bank(1, banks[7]);
+                }
+            }
+            None => {
+                let start = (self.player.nsf.load_addr - 0x6000) as usize;
+                let len = self.prg.len().min(self.memory.len() - start);
+                self.memory[start..start + len].copy_from_slice(&self.prg[..len]);
+            }
+        }
+
+        macro_rules! reset_chip {
+            ($chip:ident) => {
+                if let Some(ref mut chip) = self.$chip {
+                    *chip = Default::default();
+                }
+            };
+        }
+        reset_chip!(vrc6);
+        reset_chip!(vrc7);
+        reset_chip!(fds);
+        reset_chip!(mmc5);
+        reset_chip!(n163);
+        reset_chip!(sunsoft5b);
+
+        let player = &mut self.player;
+        player.bits.restart_requested.set(0);
+        player.bits.play_due.set(0);
+        player.play_timer = player.play_period;
+        player.elapsed_ms = 0;
+        player.elapsed_remainder = 0;
+    }
+
+    fn read_expansion(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4040..=0x4092 if self.fds.is_some() => self.fds.as_mut().unwrap().read_register(addr),
+            0x4800..=0x4fff if self.n163.is_some() => self.n163.as_mut().unwrap().read_data(),
+            0x5015 if self.mmc5.is_some() => self.mmc5.as_mut().unwrap().read_register(addr),
+            0x5205 if self.mmc5.is_some() => {
+                (self.multiplicand as u16 * self.multiplier as u16) as u8
+            }
+            0x5206 if self.mmc5.is_some() => {
+                ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8
+            }
+            0x5c00..=0x5ff5 if self.mmc5.is_some() => self.exram[(addr - 0x5c00) as usize],
+            _ => 0,
+        }
+    }
+
+    fn write_expansion(&mut self, addr: u16, val: u8) {
+        if let Some(ref mut fds) = self.fds {
+            fds.write_register(addr, val);
+        }
+
+        if let Some(ref mut mmc5) = self.mmc5 {
+            match addr {
+                0x5000..=0x5015 => {
+                    mmc5.write_register(addr, val);
+                }
+                0x5205 => self.multiplicand = val,
+                0x5206 => self.multiplier = val,
+                0x5c00..=0x5ff5 => self.exram[(addr - 0x5c00) as usize] = val,
+                _ => (),
+            }
+        }
+
+        if let Some(ref mut n163) = self.n163 {
+            match addr {
+                0x4800..=0x4fff => n163.write_data(val),
+                0xf800..=0xffff => n163.write_addr(val),
+                _ => (),
+            }
+        }
+
+        if let Some(ref mut vrc6) = self.vrc6 {
+            vrc6.write_register(addr, val);
+        }
+
+        if let Some(ref mut vrc7) = self.vrc7 {
+            match addr {
+                0x9010 => vrc7.select_register(val),
+                0x9030 => vrc7.write_register(val),
+                _ => (),
+            }
+        }
+
+        if let Some(ref mut sunsoft5b) = self.sunsoft5b {
+            match addr {
+                0xc000..=0xdfff => sunsoft5b.select_register(val),
+                0xe000..=0xffff => sunsoft5b.write_register(val),
+                _ => (),
+            }
+        }
+    }
+}
+
+impl<'a> CpuAddressBus<'a> for NsfCpuAddressBus<'a> {
+    fn read(&mut self, mut addr: u16, cpu: &mut cpu::Cpu) -> u8 {
+        // internal ram
+        if super::is_0_to_1fff(addr) {
+            addr &= !0b1_1000_0000_0000;
+            return unsafe { *self.internal_ram.get_unchecked(addr as usize) };
+        }
+
+        // ppu registers
+        if super::is_2000_to_3fff(addr) {
+            self.base
+                .ppu
+                .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+
+            addr &= 0b111;
+            return self
+                .base
+                .ppu
+                .read_register_by_index(addr as u8, &mut self.ppu_bus, cpu);
+        }
+
+        match addr {
+            0x4016 => self.base.controller.read(),
+            DRIVER_ADDR..=DRIVER_RTI_ADDR => self.driver[(addr - DRIVER_ADDR) as usize],
+            TRACK_REGISTER => {
+                self.start_track();
+                self.player.track
+            }
+            REGION_REGISTER => self.player.bits.is_pal.get(),
+            STATUS_REGISTER => {
+                self.catch_up(cpu);
+
+                let val = self.player.bits.restart_requested.get() << 7
+                    | self.player.bits.play_due.get() << 6;
+                self.player.bits.play_due.set(0);
+                val
+            }
+            // the vectors point to the driver
+            0xfffa | 0xfffe => DRIVER_RTI_ADDR as u8,
+            0xfffb | 0xffff => (DRIVER_RTI_ADDR >> 8) as u8,
+            0xfffc => DRIVER_ADDR as u8,
+            0xfffd => (DRIVER_ADDR >> 8) as u8,
+            0x6000..=0xffff => self.memory[(addr - 0x6000) as usize],
+            _ => {
+                self.catch_up(cpu);
+                self.read_expansion(addr)
+            }
+        }
+    }
+
+    fn write(&mut self, mut addr: u16, val: u8, cpu: &mut cpu::Cpu) {
+        if super::is_0_to_1fff(addr) {
+            addr &= !0b1_1000_0000_0000;
+            unsafe { *self.internal_ram.get_unchecked_mut(addr as usize) = val };
+            return;
+        }
+
+        if super::is_2000_to_3fff(addr) {
+            self.base
+                .ppu
+                .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+
+            self.base
+                .ppu
+                .write_register_by_index(addr as u8 & 0b111, val, cpu, &mut self.ppu_bus);
+
+            return;
+        }
+
+        match addr {
+            // oamdma
+            0x4014 => {
+                self.base
+                    .ppu
+                    .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+                super::write_oamdma(self, val, cpu);
+                return;
+            }
+            // standard controller 1
+            0x4016 => self.base.controller.write(val),
+            0x5ff6..=0x5ff7 if self.fds.is_some() => {
+                self.switch_bank((addr - 0x5ff6) as usize, val)
+            }
+            0x5ff8..=0x5fff if self.player.nsf.initial_banks.is_some() => {
+                self.switch_bank((addr - 0x5ff6) as usize, val)
+            }
+            0x6000..=0x7fff => self.memory[(addr - 0x6000) as usize] = val,
+            // fds tunes run from ram
+            0x8000..=0xdfff if self.fds.is_some() => self.memory[(addr - 0x6000) as usize] = val,
+            _ => (),
+        }
+
+        self.catch_up(cpu);
+        self.write_expansion(addr, val);
+    }
+
+    fn base(&mut self) -> (&mut CpuAddressBusBase<'a>, &mut dyn PpuAddressBus) {
+        (&mut self.base, &mut self.ppu_bus)
+    }
+
+    fn catch_up(&mut self, cpu: &mut cpu::Cpu) {
+        macro_rules! clock_chip {
+            ($chip:ident) => {
+                if let Some(ref mut chip) = self.$chip {
+                    chip.clock();
+                }
+            };
+        }
+
+        while self.cycle_count < cpu.cycle_count {
+            self.player.clock();
+
+            clock_chip!(vrc6);
+            clock_chip!(vrc7);
+            clock_chip!(fds);
+            clock_chip!(mmc5);
+            clock_chip!(n163);
+            clock_chip!(sunsoft5b);
+
+            self.cycle_count += 1;
+        }
+    }
+
+    fn sub_cycle_count(&mut self, sub: i32) {
+        self.cycle_count -= sub;
+        self.player.add_elapsed_cycles(sub as u32);
+    }
+
+    fn expansion_audio_output(&self) -> f32 {
+        let chips: [Option<&dyn ExpansionAudio>; 6] = [
+            self.vrc6.as_ref().map(|chip| chip as _),
+            self.vrc7.as_ref().map(|chip| chip as _),
+            self.fds.as_ref().map(|chip| chip as _),
+            self.mmc5.as_ref().map(|chip| chip as _),
+            self.n163.as_ref().map(|chip| chip as _),
+            self.sunsoft5b.as_ref().map(|chip| chip as _),
+        ];
+
+        let output: f32 = chips.iter().flatten().map(|chip| chip.output()).sum();
+        output * self.player.fade_gain()
+    }
+
+    fn nsf_player(&mut self) -> Option<&mut NsfPlayer> {
+        Some(&mut self.player)
+    }
+}
+
+impl PpuAddressBus for NsfPpuAddressBus {
+    fn read(&mut self, addr: u16, _: i32, _: &mut cpu::Cpu) -> u8 {
+        if addr >= 0x3f00 {
+            return self.read_palette_memory(addr as u8);
+        }
+
+        0
+    }
+
+    fn write(&mut self, addr: u16, val: u8, _: i32, _: &mut cpu::Cpu) {
+        if addr >= 0x3f00 {
+            self.palettes[super::calc_ppu_palette_addr(addr) as usize] = val;
+        }
+    }
+
+    fn set_address(&mut self, _: u16, _: i32, _: &mut cpu::Cpu) {}
+
+    fn read_palette_memory(&self, color_idx: u8) -> u8 {
+        self.palettes[super::calc_ppu_palette_addr(color_idx as u16) as usize]
+    }
+}
+
+impl serialize::Serialize for NsfPpuAddressBus {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        self.palettes.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        self.palettes.deserialize(file)
+    }
+}
+
+// NOTE: the tune's metadata isn't serialized
+impl serialize::Serialize for NsfPlayer {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        self.track.serialize(file)?;
+        self.play_timer.serialize(file)?;
+        self.elapsed_ms.serialize(file)?;
+        self.elapsed_remainder.serialize(file)?;
+        self.bits.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        self.track.deserialize(file)?;
+        self.play_timer.deserialize(file)?;
+        self.elapsed_ms.deserialize(file)?;
+        self.elapsed_remainder.deserialize(file)?;
+        self.bits.deserialize(file)
+    }
+}
+
+// NOTE: 'Serialize' is implemented manually to avoid serializing 'prg' and the driver.
+// the expansion chips are fixed by the tune, so only the ones present are serialized
+impl<'a> serialize::Serialize for NsfCpuAddressBus<'a> {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        self.base.serialize(file)?;
+        self.ppu_bus.serialize(file)?;
+        self.internal_ram.serialize(file)?;
+        self.memory.serialize(file)?;
+        self.player.serialize(file)?;
+
+        macro_rules! serialize_chip {
+            ($chip:ident) => {
+                if let Some(ref chip) = self.$chip {
+                    chip.serialize(file)?;
+                }
+            };
+        }
+        serialize_chip!(vrc6);
+        serialize_chip!(vrc7);
+        serialize_chip!(fds);
+        serialize_chip!(mmc5);
+        serialize_chip!(n163);
+        serialize_chip!(sunsoft5b);
+
+        self.multiplicand.serialize(file)?;
+        self.multiplier.serialize(file)?;
+        self.exram.serialize(file)?;
+        self.cycle_count.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        self.base.deserialize(file)?;
+        self.ppu_bus.deserialize(file)?;
+        self.internal_ram.deserialize(file)?;
+        self.memory.deserialize(file)?;
+        self.player.deserialize(file)?;
+
+        macro_rules! deserialize_chip {
+            ($chip:ident) => {
+                if let Some(ref mut chip) = self.$chip {
+                    chip.deserialize(file)?;
+                }
+            };
+        }
+        deserialize_chip!(vrc6);
+        deserialize_chip!(vrc7);
+        deserialize_chip!(fds);
+        deserialize_chip!(mmc5);
+        deserialize_chip!(n163);
+        deserialize_chip!(sunsoft5b);
+
+        self.multiplicand.deserialize(file)?;
+        self.multiplier.deserialize(file)?;
+        self.exram.deserialize(file)?;
+        self.cycle_count.deserialize(file)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_test_bus<'a>(
+        nsf: nsf::Nsf,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> NsfCpuAddressBus<'a> {
+        NsfCpuAddressBus::new(
+            nsf,
+            ppu::Ppu::new(),
+            apu::Apu::new(),
+            ctrl::Controller::default(),
+            framebuffer,
+        )
+    }
+
+    // runs the cpu for (at least) 'cycles' cycles, resetting the cycle
+    // counts every now and then (like at the end of each frame)
+    fn run(bus: &mut NsfCpuAddressBus, cpu: &mut cpu::Cpu, cycles: u32) {
+        let mut elapsed = 0;
+        while elapsed < cycles {
+            cpu.exec_instruction(bus);
+            bus.catch_up(cpu);
+
+            if cpu.cycle_count >= 0x4000 {
+                elapsed += cpu.cycle_count as u32;
+                bus.sub_cycle_count(cpu.cycle_count);
+                cpu.cycle_count = 0;
+            }
+        }
+    }
+
+    #[test]
+    fn test_driver() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        // init: sta $00, rts. play: inc $01, rts
+        let mut data = vec![0x85, 0x00, 0x60];
+        data.resize(0x10, 0);
+        data.extend_from_slice(&[0xe6, 0x01, 0x60]);
+        let nsf = nsf::parse(&nsf::test::new_nsf(3, 0, &data)).unwrap();
+        let mut bus = new_test_bus(nsf, unsafe { &*(&framebuffer as *const _ as *const _) });
+
+        let mut cpu = cpu::Cpu::default();
+        cpu.pc = u16::from_le_bytes([bus.read(0xfffc, &mut cpu), bus.read(0xfffd, &mut cpu)]);
+        assert_eq!(cpu.pc, DRIVER_ADDR);
+
+        // the play routine is called every 29780 cycles (~60 hz)
+        run(&mut bus, &mut cpu, 100_000);
+        assert_eq!(bus.internal_ram[..2], [1, 3]);
+
+        // changing the track restarts the tune
+        bus.player.next_track();
+        run(&mut bus, &mut cpu, 40_000);
+        assert_eq!(bus.internal_ram[..2], [2, 1]);
+        bus.player.next_track();
+        run(&mut bus, &mut cpu, 1);
+        assert_eq!(bus.player.track(), 0);
+        assert_eq!(bus.internal_ram[..2], [0, 0]);
+    }
+
+    #[test]
+    fn test_bankswitching() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let framebuffer = unsafe { &*(&framebuffer as *const _ as *const _) };
+        let data = (0..3)
+            .flat_map(|bank| vec![bank; 0x1000])
+            .collect::<Vec<_>>();
+        let mut rom = nsf::test::new_nsf(1, 0, &data);
+        rom[0x70..0x78].copy_from_slice(&[0, 1, 2, 3, 0, 0, 0, 0]);
+        let mut bus = new_test_bus(nsf::parse(&rom).unwrap(), framebuffer);
+        let mut cpu = cpu::Cpu::default();
+
+        bus.read(TRACK_REGISTER, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 0);
+        assert_eq!(bus.read(0x9fff, &mut cpu), 1);
+        assert_eq!(bus.read(0xa000, &mut cpu), 2);
+        // out of range banks wrap around
+        assert_eq!(bus.read(0xb000, &mut cpu), 0);
+
+        bus.write(0x5ff8, 2, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 2);
+        // 0x8000- is read-only, and 0x5ff6-0x5ff7 are fds-only
+        bus.write(0x8000, 0xff, &mut cpu);
+        bus.write(0x5ff6, 1, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 2);
+        assert_eq!(bus.read(0x6000, &mut cpu), 0);
+
+        // fds tunes can write to all of their memory
+        rom[0x7b] = nsf::EXPANSION_FDS;
+        let mut bus = new_test_bus(nsf::parse(&rom).unwrap(), framebuffer);
+        bus.read(TRACK_REGISTER, &mut cpu);
+        bus.write(0x8000, 0xff, &mut cpu);
+        bus.write(0x5ff6, 1, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 0xff);
+        assert_eq!(bus.read(0x6000, &mut cpu), 1);
+    }
+
+    #[test]
+    fn test_track_time() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut nsf = nsf::parse(&nsf::test::new_nsf(2, 0, &[0x60])).unwrap();
+        nsf.tracks[1].time = Some(100);
+        nsf.tracks[1].fade = Some(100);
+        let mut bus = new_test_bus(nsf, unsafe { &*(&framebuffer as *const _ as *const _) });
+
+        // advances 'elapsed_ms' by 'ms' milliseconds (in 'frames' of 10 ms)
+        let advance = |bus: &mut NsfCpuAddressBus, ms: u32| {
+            for _ in 0..ms / 10 {
+                bus.cycle_count += 17898;
+                bus.sub_cycle_count(17898);
+            }
+        };
+
+        assert_eq!(bus.player.track(), 1);
+        advance(&mut bus, 100);
+        assert_eq!(bus.player.elapsed_ms(), 100);
+        assert_eq!(bus.player.fade_gain(), 1.0);
+        advance(&mut bus, 50);
+        assert!((bus.player.fade_gain() - 0.5).abs() < 0.02);
+
+        // moves on to the next track (wrapping around) after the fade out
+        advance(&mut bus, 60);
+        assert_eq!(bus.player.track(), 0);
+        assert!(bus.player.bits.restart_requested.is_true());
+    }
+
+    #[test]
+    fn test_fade_audio() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut nsf = nsf::parse(&nsf::test::new_nsf(2, nsf::EXPANSION_VRC6, &[0x60])).unwrap();
+        nsf.tracks[1].time = Some(100);
+        nsf.tracks[1].fade = Some(100);
+        let mut bus = new_test_bus(nsf, unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        // vrc6 pulse 1: 50% duty at full volume
+        bus.write(0x9000, 0x7f, &mut cpu);
+        bus.write(0x9001, 0xff, &mut cpu);
+        bus.write(0x9002, 0x80, &mut cpu);
+
+        // the output is mixed into the apu's samples (as in the main loop). returns the
+        // peak-to-peak amplitude of each 10 ms frame
+        bus.base.apu.set_recording(true);
+        let mut amplitudes = Vec::new();
+        for _ in 0..15 {
+            while cpu.cycle_count < 17898 {
+                cpu.cycle_count += 4;
+                bus.catch_up(&mut cpu);
+                let output = bus.expansion_audio_output();
+                bus.base.apu.catch_up(cpu.cycle_count, output);
+            }
+            bus.sub_cycle_count(cpu.cycle_count);
+            bus.base.apu.sub_cycle_count(cpu.cycle_count);
+            cpu.cycle_count = 0;
+
+            let samples = bus.base.apu.take_samples();
+            let max = samples.iter().copied().max().unwrap() as i32;
+            let min = samples.iter().copied().min().unwrap() as i32;
+            amplitudes.push(max - min);
+        }
+
+        // 40 ms into the 100 ms fade out, the track plays at 60% volume
+        assert!(amplitudes[5] > 8000);
+        let ratio = amplitudes[14] as f32 / amplitudes[5] as f32;
+        assert!((ratio - 0.6).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_region() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let framebuffer = unsafe { &*(&framebuffer as *const _ as *const _) };
+        let mut rom = nsf::test::new_nsf(1, 0, &[0x60]);
+
+        // dual-region tunes play as ntsc, unless another region is passed
+        rom[0x7a] = 0b10;
+        let nsf = nsf::parse(&rom).unwrap();
+        assert_eq!(nsf.region(), ppu::Region::Ntsc);
+        let bus = new_test_bus(nsf, framebuffer);
+        assert_eq!(bus.player.play_period, 29780);
+        assert!(!bus.player.bits.is_pal.is_true());
+
+        let nsf = nsf::parse(&rom).unwrap();
+        let mut ppu = ppu::Ppu::new();
+        ppu.set_region(ppu::Region::Pal);
+        let mut bus = NsfCpuAddressBus::new(
+            nsf,
+            ppu,
+            apu::Apu::new(),
+            ctrl::Controller::default(),
+            framebuffer,
+        );
+        assert_eq!(bus.player.play_period, 33247);
+        assert!(bus.player.bits.is_pal.is_true());
+
+        // a second of pal cpu cycles
+        bus.cycle_count += 1_662_607;
+        bus.sub_cycle_count(1_662_607);
+        assert_eq!(bus.player.elapsed_ms(), 1000);
+
+        rom[0x7a] = 0b01;
+        assert_eq!(nsf::parse(&rom).unwrap().region(), ppu::Region::Pal);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::{parse, serialize};
+
+use std::{fs, io};
+
+// the prg ram on a cartridge, sized from the header. ram that's smaller than the window
+// it's mapped to is mirrored, and without any ram, reads return 0 (like the rest of the
+// unmapped address space) and writes are ignored
+pub struct PrgRam {
+    ram: Box<[u8]>,
+    // the number of bytes at the start of 'ram' that are kept alive by a battery
+    battery_backed_size: usize,
+}
+
+impl PrgRam {
+    pub fn new(size: usize, battery_backed_size: usize) -> Self {
+        Self {
+            ram: vec![0; size].into_boxed_slice(),
+            battery_backed_size: battery_backed_size.min(size),
+        }
+    }
+
+    // 'max_size' is the amount of ram the mapper can address (see 'RomHeader::prg_ram_sizes()')
+    pub fn from_header(header: &parse::RomHeader, max_size: usize) -> Self {
+        let (size, battery_backed_size) = header.prg_ram_sizes();
+        Self::new(size.min(max_size), battery_backed_size)
+    }
+
+    pub fn read(&self, offset: usize) -> u8 {
+        if self.ram.is_empty() {
+            0
+        } else {
+            self.ram[offset % self.ram.len()]
+        }
+    }
+
+    pub fn write(&mut self, offset: usize, val: u8) {
+        if !self.ram.is_empty() {
+            let len = self.ram.len();
+            self.ram[offset % len] = val;
+        }
+    }
+
+    #[cfg(test)]
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    // the part of the ram to persist (see 'CpuAddressBus::battery_backed_ram()')
+    pub fn battery_backed(&mut self) -> &mut [u8] {
+        &mut self.ram[..self.battery_backed_size]
+    }
+
+    // the (up to) 8 KB mapped to 0x6000-0x7fff on power-on (see 'CpuAddressBus::prg_ram()')
+    pub fn window(&mut self) -> Option<&mut [u8]> {
+        if self.ram.is_empty() {
+            None
+        } else {
+            let len = self.ram.len().min(0x2000);
+            Some(&mut self.ram[..len])
+        }
+    }
+}
+
+// NOTE: the battery-backed size comes from the header, so only the contents are stored
+impl serialize::Serialize for PrgRam {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        self.ram.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        self.ram.deserialize(file)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mirroring() {
+        let mut prg_ram = PrgRam::new(0x800, 0x800);
+        prg_ram.write(0x1801, 0xaa);
+        assert_eq!(prg_ram.read(0x0001), 0xaa);
+        assert_eq!(prg_ram.read(0x0801), 0xaa);
+        assert_eq!(prg_ram.window().unwrap().len(), 0x800);
+        assert_eq!(prg_ram.battery_backed().len(), 0x800);
+
+        let mut prg_ram = PrgRam::new(0, 0);
+        prg_ram.write(0, 0xaa);
+        assert_eq!(prg_ram.read(0), 0);
+        assert!(prg_ram.window().is_none());
+        assert!(prg_ram.battery_backed().is_empty());
+    }
+
+    #[test]
+    fn test_from_header() {
+        let mut header = parse::RomHeader::parse(&[
+            b'N', b'E', b'S', 0x1a, 1, 1, 0b10, 0x08, 0, 0, 0x07, 0, 0, 0, 0, 0,
+        ])
+        .unwrap();
+        // 8 KB of ram and nothing battery-backed, even though the board has a battery
+        let mut prg_ram = PrgRam::from_header(&header, 0x2000);
+        assert_eq!(prg_ram.as_mut_slice().len(), 0x2000);
+        assert!(prg_ram.battery_backed().is_empty());
+
+        // capped to what the mapper can address
+        header.prg_nvram_size = 0x8000;
+        let mut prg_ram = PrgRam::from_header(&header, 0x8000);
+        assert_eq!(prg_ram.as_mut_slice().len(), 0x8000);
+        assert_eq!(prg_ram.battery_backed().len(), 0x8000);
+    }
+}
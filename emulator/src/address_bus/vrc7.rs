@@ -0,0 +1,587 @@
+use super::nametable_layout::NametableLayout;
+use super::prg_ram::PrgRam;
+use super::{CpuAddressBus, CpuAddressBusBase, FromRom, PpuAddressBus};
+use crate::apu::ExpansionAudio;
+use crate::{apu, controller as ctrl, cpu, parse, ppu, serialize};
+
+use std::cell::Cell;
+use std::{fs, io};
+
+// konami VRC7. its two board variants differ in which address line selects the
+// second register in each range (a4 on VRC7a and a3 on VRC7b), so both are decoded
+
+pub struct Vrc7CpuAddressBus<'a> {
+    base: CpuAddressBusBase<'a>,
+    ppu_bus: Vrc7PpuAddressBus,
+    internal_ram: [u8; 0x800],
+    prg_rom: Box<[u8]>,
+    prg_ram: PrgRam,
+    // 0x8000, 0x8010 and 0x9000 (8 KB banks at 0x8000, 0xa000 and 0xc000)
+    prg_banks: [u8; 3],
+    // 0xe010
+    irq_latch: u8,
+    irq_counter: u8,
+    // counts down by 3 every cpu cycle and clocks the irq counter when it
+    // reaches 0, which approximates one clock per scanline (341 ppu cycles)
+    irq_prescaler: i16,
+    audio: apu::Opll,
+    // the number of cpu cycles the irq counter and 'audio'
+    // have been clocked for (see 'CpuAddressBus::catch_up()')
+    cycle_count: i32,
+    bits: Vrc7CpuBits::BitField,
+}
+
+bitfield!(Vrc7CpuBits<u8>(
+    // 0xe000 (bit 7)
+    prg_ram_enable: 0..0,
+    // 0xf000 (bits 0-2)
+    irq_enable_after_ack: 1..1,
+    irq_enable: 2..2,
+    irq_cycle_mode: 3..3,
+    irq_asserted: 4..4,
+));
+
+pub struct Vrc7PpuAddressBus {
+    chr: Box<[u8]>,
+    // some VRC7 boards have chr ram instead of chr rom
+    chr_is_ram: bool,
+    nametables: [u8; 0x800],
+    palettes: [u8; 32],
+    // 0xa000-0xd010 (1 KB banks)
+    chr_banks: [u8; 8],
+    // bits 0-1 of 0xe000
+    layout: NametableLayout,
+}
+
+impl<'a> Vrc7CpuAddressBus<'a> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        prg_rom: &[u8],
+        chr_rom: &[u8],
+        chr_ram_size: usize,
+        prg_ram: PrgRam,
+        mirroring: parse::MirroringType,
+        ppu: ppu::Ppu,
+        apu: apu::Apu,
+        controller: ctrl::Controller,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> Self {
+        if prg_rom.is_empty() || prg_rom.len() > 0x80000 || !prg_rom.len().is_multiple_of(0x2000) {
+            error_exit!(
+                "Failed to load rom file: prg rom must be a multiple of \
+                 8 KB and at most 512 KB for VRC7 (mapper 85)"
+            );
+        }
+
+        if chr_rom.len() > 0x40000 {
+            error_exit!(
+                "Failed to load rom file: chr rom must be at most 256 KB for VRC7 (mapper 85)"
+            );
+        }
+
+        let (chr, chr_is_ram) = super::new_chr(chr_rom, chr_ram_size);
+
+        let ppu_bus = Vrc7PpuAddressBus {
+            chr,
+            chr_is_ram,
+            nametables: [0; 0x800],
+            palettes: [0; 32],
+            chr_banks: [0; 8],
+            layout: match mirroring {
+                parse::MirroringType::Hor => NametableLayout::HORIZONTAL,
+                _ => NametableLayout::VERTICAL,
+            },
+        };
+
+        Self {
+            base: CpuAddressBusBase::new(ppu, apu, controller, framebuffer),
+            ppu_bus,
+            internal_ram: [0; 0x800],
+            prg_rom: prg_rom.to_vec().into_boxed_slice(),
+            prg_ram,
+            prg_banks: [0; 3],
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_prescaler: 341,
+            audio: apu::Opll::default(),
+            cycle_count: 0,
+            bits: Vrc7CpuBits::BitField::zeroed(),
+        }
+    }
+
+    fn calc_prg_rom_addr(&self, bank: usize, addr: u16) -> usize {
+        (bank * 0x2000) % self.prg_rom.len() + (addr & 0x1fff) as usize
+    }
+
+    // asserts or acknowledges the irq on the cpu
+    fn set_irq(&mut self, assert: bool, cpu: &mut cpu::Cpu) {
+        if assert != self.bits.irq_asserted.is_true() {
+            self.bits.irq_asserted.set(assert as u8);
+            if assert {
+                cpu.irq += 1;
+            } else {
+                cpu.irq = cpu.irq.saturating_sub(1);
+            }
+        }
+    }
+
+    fn clock_irq_counter(&mut self, cpu: &mut cpu::Cpu) {
+        if self.irq_counter == 0xff {
+            self.irq_counter = self.irq_latch;
+            self.set_irq(true, cpu);
+        } else {
+            self.irq_counter += 1;
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8, cpu: &mut cpu::Cpu) {
+        // the audio ports also decode a5, and are the same on both variants
+        match addr & 0xf030 {
+            0x9010 => return self.audio.select_register(val),
+            0x9030 => {
+                self.catch_up(cpu);
+                return self.audio.write_register(val);
+            }
+            _ => (),
+        }
+
+        let is_second_register = addr & 0x18 != 0;
+        match (addr & 0xf000, is_second_register) {
+            (0x8000, false) => self.prg_banks[0] = val & 0x3f,
+            (0x8000, true) => self.prg_banks[1] = val & 0x3f,
+            (0x9000, false) => self.prg_banks[2] = val & 0x3f,
+            (0xa000..=0xd000, _) => {
+                let idx = (((addr - 0xa000) >> 11) | is_second_register as u16) as usize;
+                self.ppu_bus.chr_banks[idx] = val;
+            }
+            (0xe000, false) => {
+                self.ppu_bus.layout = NametableLayout::from_mirroring_bits(val);
+                self.bits.prg_ram_enable.set(val >> 7);
+
+                self.catch_up(cpu);
+                self.audio.set_reset(val & 0x40 != 0);
+            }
+            (0xe000, true) => self.irq_latch = val,
+            (0xf000, false) => {
+                self.catch_up(cpu);
+                self.bits.irq_enable_after_ack.set(val & 1);
+                self.bits.irq_enable.set((val >> 1) & 1);
+                self.bits.irq_cycle_mode.set((val >> 2) & 1);
+
+                if self.bits.irq_enable.is_true() {
+                    self.irq_counter = self.irq_latch;
+                    self.irq_prescaler = 341;
+                }
+
+                self.set_irq(false, cpu);
+            }
+            (0xf000, true) => {
+                self.catch_up(cpu);
+                self.set_irq(false, cpu);
+                let enable_after_ack = self.bits.irq_enable_after_ack.get();
+                self.bits.irq_enable.set(enable_after_ack);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl<'a> FromRom<'a> for Vrc7CpuAddressBus<'a> {
+    fn from_rom(
+        rom: &parse::RomDescription,
+        ppu: ppu::Ppu,
+        apu: apu::Apu,
+        controller: ctrl::Controller,
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+    ) -> Self {
+        Self::new(
+            &rom.prg_rom,
+            &rom.chr_rom,
+            rom.header.total_chr_ram_size(),
+            PrgRam::from_header(&rom.header, 0x2000),
+            rom.header.mirroring,
+            ppu,
+            apu,
+            controller,
+            framebuffer,
+        )
+    }
+}
+
+impl<'a> CpuAddressBus<'a> for Vrc7CpuAddressBus<'a> {
+    fn read(&mut self, mut addr: u16, cpu: &mut cpu::Cpu) -> u8 {
+        // internal ram
+        if super::is_0_to_1fff(addr) {
+            addr &= !0b1_1000_0000_0000;
+            return unsafe { *self.internal_ram.get_unchecked(addr as usize) };
+        }
+
+        // ppu registers
+        if super::is_2000_to_3fff(addr) {
+            self.base
+                .ppu
+                .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+
+            addr &= 0b111;
+            return self
+                .base
+                .ppu
+                .read_register_by_index(addr as u8, &mut self.ppu_bus, cpu);
+        }
+
+        // prg ram
+        if super::is_6000_to_7fff(addr) {
+            return if self.bits.prg_ram_enable.is_true() {
+                self.prg_ram.read((addr & 0x1fff) as usize)
+            } else {
+                0
+            };
+        }
+
+        // prg rom
+        if addr & 0x8000 != 0 {
+            let bank = match (addr >> 13) & 0b11 {
+                3 => self.prg_rom.len() / 0x2000 - 1,
+                window => self.prg_banks[window as usize] as usize,
+            };
+
+            return self.prg_rom[self.calc_prg_rom_addr(bank, addr)];
+        }
+
+        if addr == 0x4016 {
+            return self.base.controller.read();
+        }
+
+        0
+    }
+
+    fn write(&mut self, mut addr: u16, val: u8, cpu: &mut cpu::Cpu) {
+        if super::is_0_to_1fff(addr) {
+            addr &= !0b1_1000_0000_0000;
+            unsafe { *self.internal_ram.get_unchecked_mut(addr as usize) = val };
+            return;
+        }
+
+        if super::is_2000_to_3fff(addr) {
+            self.base
+                .ppu
+                .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+
+            self.base
+                .ppu
+                .write_register_by_index(addr as u8 & 0b111, val, cpu, &mut self.ppu_bus);
+
+            return;
+        }
+
+        if super::is_6000_to_7fff(addr) {
+            if self.bits.prg_ram_enable.is_true() {
+                self.prg_ram.write((addr & 0x1fff) as usize, val);
+            }
+
+            return;
+        }
+
+        match addr {
+            // oamdma
+            0x4014 => {
+                self.base
+                    .ppu
+                    .catch_up(cpu, &mut self.ppu_bus, self.base.framebuffer);
+                super::write_oamdma(self, val, cpu);
+            }
+            // standard controller 1
+            0x4016 => self.base.controller.write(val),
+            0x8000..=0xffff => self.write_register(addr, val, cpu),
+            _ => (),
+        }
+    }
+
+    fn base(&mut self) -> (&mut CpuAddressBusBase<'a>, &mut dyn PpuAddressBus) {
+        (&mut self.base, &mut self.ppu_bus)
+    }
+
+    fn catch_up(&mut self, cpu: &mut cpu::Cpu) {
+        while self.cycle_count < cpu.cycle_count {
+            self.audio.clock();
+
+            if self.bits.irq_enable.is_true() {
+                if self.bits.irq_cycle_mode.is_true() {
+                    self.clock_irq_counter(cpu);
+                } else {
+                    self.irq_prescaler -= 3;
+                    if self.irq_prescaler <= 0 {
+                        self.irq_prescaler += 341;
+                        self.clock_irq_counter(cpu);
+                    }
+                }
+            }
+
+            self.cycle_count += 1;
+        }
+    }
+
+    fn sub_cycle_count(&mut self, sub: i32) {
+        self.cycle_count -= sub;
+    }
+
+    fn expansion_audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn battery_backed_ram(&mut self) -> Vec<&mut [u8]> {
+        vec![self.prg_ram.battery_backed()]
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        self.prg_ram.window()
+    }
+}
+
+impl Vrc7PpuAddressBus {
+    fn calc_chr_addr(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr >> 10) as usize] as usize;
+        (bank * 0x400) % self.chr.len() + (addr & 0x3ff) as usize
+    }
+}
+
+impl PpuAddressBus for Vrc7PpuAddressBus {
+    fn read(&mut self, addr: u16, _: i32, _: &mut cpu::Cpu) -> u8 {
+        if addr >= 0x3f00 {
+            let addr = super::calc_ppu_palette_addr(addr);
+            return unsafe { *self.palettes.get_unchecked(addr as usize) };
+        }
+
+        if addr >= 0x2000 {
+            return self.nametables[self.layout.calc_vram_addr(addr)];
+        }
+
+        self.chr[self.calc_chr_addr(addr)]
+    }
+
+    fn write(&mut self, addr: u16, val: u8, _: i32, _: &mut cpu::Cpu) {
+        if addr >= 0x3f00 {
+            let addr = super::calc_ppu_palette_addr(addr);
+            unsafe { *self.palettes.get_unchecked_mut(addr as usize) = val };
+            return;
+        }
+
+        if addr >= 0x2000 {
+            let addr = self.layout.calc_vram_addr(addr);
+            self.nametables[addr] = val;
+        } else if self.chr_is_ram {
+            let addr = self.calc_chr_addr(addr);
+            self.chr[addr] = val;
+        }
+    }
+
+    fn set_address(&mut self, _: u16, _: i32, _: &mut cpu::Cpu) {}
+
+    fn read_palette_memory(&self, color_idx: u8) -> u8 {
+        self.palettes[super::calc_ppu_palette_addr(color_idx as u16) as usize]
+    }
+}
+
+// NOTE: 'Serialize' is implemented manually to avoid serializing rom
+impl serialize::Serialize for Vrc7PpuAddressBus {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        if self.chr_is_ram {
+            self.chr.serialize(file)?;
+        }
+        self.nametables.serialize(file)?;
+        self.palettes.serialize(file)?;
+        self.chr_banks.serialize(file)?;
+        self.layout.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        if self.chr_is_ram {
+            self.chr.deserialize(file)?;
+        }
+        self.nametables.deserialize(file)?;
+        self.palettes.deserialize(file)?;
+        self.chr_banks.deserialize(file)?;
+        self.layout.deserialize(file)
+    }
+}
+
+impl<'a> serialize::Serialize for Vrc7CpuAddressBus<'a> {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        self.base.serialize(file)?;
+        self.ppu_bus.serialize(file)?;
+        self.internal_ram.serialize(file)?;
+        self.prg_ram.serialize(file)?;
+        self.prg_banks.serialize(file)?;
+        self.irq_latch.serialize(file)?;
+        self.irq_counter.serialize(file)?;
+        self.irq_prescaler.serialize(file)?;
+        self.audio.serialize(file)?;
+        self.cycle_count.serialize(file)?;
+        self.bits.serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        self.base.deserialize(file)?;
+        self.ppu_bus.deserialize(file)?;
+        self.internal_ram.deserialize(file)?;
+        self.prg_ram.deserialize(file)?;
+        self.prg_banks.deserialize(file)?;
+        self.irq_latch.deserialize(file)?;
+        self.irq_counter.deserialize(file)?;
+        self.irq_prescaler.deserialize(file)?;
+        self.audio.deserialize(file)?;
+        self.cycle_count.deserialize(file)?;
+        self.bits.deserialize(file)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_test_bus<'a>(framebuffer: &'a [Cell<u32>; 256 * 240]) -> Vrc7CpuAddressBus<'a> {
+        let prg_rom = (0..0x20000).map(|i| (i / 0x2000) as u8).collect::<Vec<_>>();
+        let chr_rom = (0..0x20000).map(|i| (i / 0x400) as u8).collect::<Vec<_>>();
+
+        Vrc7CpuAddressBus::new(
+            &prg_rom,
+            &chr_rom,
+            0,
+            PrgRam::new(0x2000, 0x2000),
+            parse::MirroringType::Vert,
+            ppu::Ppu::new(),
+            apu::Apu::new(),
+            ctrl::Controller::default(),
+            framebuffer,
+        )
+    }
+
+    #[test]
+    fn test_prg_banking() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        bus.write(0x8000, 3, &mut cpu);
+        bus.write(0x8010, 5, &mut cpu);
+        bus.write(0x9000, 7, &mut cpu);
+        assert_eq!(bus.read(0x8000, &mut cpu), 3);
+        assert_eq!(bus.read(0xa000, &mut cpu), 5);
+        assert_eq!(bus.read(0xc000, &mut cpu), 7);
+        assert_eq!(bus.read(0xe000, &mut cpu), 15);
+
+        // VRC7b
+        bus.write(0x8008, 9, &mut cpu);
+        assert_eq!(bus.read(0xa000, &mut cpu), 9);
+
+        // prg ram, disabled and then enabled
+        bus.write(0x6000, 0xaa, &mut cpu);
+        assert_eq!(bus.read(0x6000, &mut cpu), 0);
+
+        bus.write(0xe000, 0x80, &mut cpu);
+        bus.write(0x6000, 0xaa, &mut cpu);
+        assert_eq!(bus.read(0x6000, &mut cpu), 0xaa);
+    }
+
+    #[test]
+    fn test_chr_banking_and_mirroring() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        bus.write(0xa000, 0x11, &mut cpu);
+        bus.write(0xa010, 0x22, &mut cpu);
+        bus.write(0xb008, 0x33, &mut cpu);
+        bus.write(0xd010, 0x44, &mut cpu);
+        assert_eq!(bus.ppu_bus.read(0x0000, 0, &mut cpu), 0x11);
+        assert_eq!(bus.ppu_bus.read(0x0400, 0, &mut cpu), 0x22);
+        assert_eq!(bus.ppu_bus.read(0x0c00, 0, &mut cpu), 0x33);
+        assert_eq!(bus.ppu_bus.read(0x1fff, 0, &mut cpu), 0x44);
+
+        // single-screen using the second nametable
+        bus.write(0xe000, 3, &mut cpu);
+        bus.ppu_bus.write(0x2005, 0x55, 0, &mut cpu);
+        assert_eq!(bus.ppu_bus.read(0x2c05, 0, &mut cpu), 0x55);
+        assert_eq!(bus.ppu_bus.nametables[0x405], 0x55);
+
+        // horizontal
+        bus.write(0xe000, 1, &mut cpu);
+        assert_eq!(bus.ppu_bus.read(0x2805, 0, &mut cpu), 0x55);
+        assert_eq!(bus.ppu_bus.read(0x2005, 0, &mut cpu), 0);
+    }
+
+    #[test]
+    fn test_irq_counter() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        // cycle mode, reloading from 0xfd
+        bus.write(0xe010, 0xfd, &mut cpu);
+        bus.write(0xf000, 0b111, &mut cpu);
+
+        cpu.cycle_count += 2;
+        bus.catch_up(&mut cpu);
+        assert_eq!(cpu.irq, 0);
+
+        cpu.cycle_count += 1;
+        bus.catch_up(&mut cpu);
+        assert_eq!(cpu.irq, 1);
+        assert_eq!(bus.irq_counter, 0xfd);
+
+        // acknowledging restores the enable bit from 'irq_enable_after_ack'
+        bus.write(0xf010, 0, &mut cpu);
+        assert_eq!(cpu.irq, 0);
+        assert!(bus.bits.irq_enable.is_true());
+
+        // scanline mode clocks the counter every 341 / 3 cpu cycles
+        bus.write(0xf000, 0b010, &mut cpu);
+        cpu.cycle_count += 2 * 114;
+        bus.catch_up(&mut cpu);
+        assert_eq!(bus.irq_counter, 0xff);
+        assert_eq!(cpu.irq, 0);
+
+        cpu.cycle_count += 114;
+        bus.catch_up(&mut cpu);
+        assert_eq!(cpu.irq, 1);
+    }
+
+    #[test]
+    fn test_audio_registers() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut bus = new_test_bus(unsafe { &*(&framebuffer as *const _ as *const _) });
+        let mut cpu = cpu::Cpu::default();
+
+        // channel 0: patch 1 at full volume, key on
+        bus.write(0x9010, 0x30, &mut cpu);
+        bus.write(0x9030, 0x10, &mut cpu);
+        bus.write(0x9010, 0x10, &mut cpu);
+        bus.write(0x9030, 0x80, &mut cpu);
+        bus.write(0x9010, 0x20, &mut cpu);
+        bus.write(0x9030, 0x18, &mut cpu);
+
+        // the output is mixed into the apu's samples (as in the main loop)
+        bus.base.apu.set_recording(true);
+        let mut is_audible = false;
+        for _ in 0..100 {
+            cpu.cycle_count += 36;
+            bus.catch_up(&mut cpu);
+            let output = bus.expansion_audio_output();
+            bus.base.apu.catch_up(cpu.cycle_count, output);
+            is_audible |= output != 0.0;
+        }
+        assert!(is_audible);
+        assert!(bus
+            .base
+            .apu
+            .take_samples()
+            .iter()
+            .any(|&sample| sample != 0));
+
+        // bit 6 of 0xe000 silences and resets the sound chip
+        bus.write(0xe000, 0x40, &mut cpu);
+        cpu.cycle_count += 36;
+        bus.catch_up(&mut cpu);
+        assert_eq!(bus.expansion_audio_output(), 0.0);
+    }
+}
@@ -1,7 +0,0 @@
-#[macro_use]
-use derive_serialize::Serialize;
-
-#[derive(Serialize)]
-pub struct Apu {
-    // TODO: implement
-}
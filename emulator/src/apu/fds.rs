@@ -0,0 +1,361 @@
+use super::ExpansionAudio;
+
+#[macro_use]
+use derive_serialize::Serialize;
+
+// how much each modulation table entry adds to the modulation counter. entry
+// 4 resets the counter to 0 instead
+static MOD_ADJUSTMENTS: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+
+// the master volume (bits 0-1 of 0x4089) scales the output by 2/2, 2/3, 2/4 or 2/5
+static MASTER_VOLUMES: [u32; 4] = [36, 24, 17, 14];
+
+// the sound of the famicom disk system: a single channel playing back a 64-step
+// wavetable of 6-bit samples. its volume is controlled by an envelope, and its pitch
+// by a modulator that steps through a table of pitch adjustments (scaled by an
+// envelope of its own)
+#[derive(Serialize)]
+pub struct FdsAudio {
+    wavetable: [u8; 64],
+    // bit 7 of 0x4089. the wavetable can only be written to (and is held) while set
+    wavetable_writable: bool,
+    // bits 0-1 of 0x4089
+    master_volume: u8,
+    // 0x408a. scales the period of both envelopes
+    envelope_speed: u8,
+    // bit 7 of 0x4083. resets the wave to its first step
+    wave_halted: bool,
+    // bit 6 of 0x4083
+    envelopes_halted: bool,
+    volume_envelope: FdsEnvelope,
+    wave_frequency: u16,
+    // the wave advances a step each time this 16-bit accumulator overflows
+    wave_accumulator: u16,
+    wave_position: u8,
+    mod_envelope: FdsEnvelope,
+    mod_frequency: u16,
+    mod_accumulator: u16,
+    // bit 7 of 0x4087. the modulation table can only be written to while set
+    mod_halted: bool,
+    // 32 entries, each of which is stored (and stepped through) twice
+    mod_table: [u8; 64],
+    mod_position: u8,
+    // a signed 7-bit counter, adjusted by each modulation table entry
+    mod_counter: i8,
+    // the amount added to 'wave_frequency', calculated from 'mod_counter' and the
+    // gain of the modulation envelope
+    mod_pitch: i32,
+}
+
+// the envelopes of the volume (0x4080) and the modulator (0x4084)
+#[derive(Serialize, Default, Clone, Copy)]
+struct FdsEnvelope {
+    speed: u8,
+    increasing: bool,
+    // when disabled, the gain is set directly from 'speed'
+    disabled: bool,
+    gain: u8,
+    // counts down cpu cycles until the gain is stepped
+    timer: u32,
+}
+
+impl FdsEnvelope {
+    fn write(&mut self, val: u8, envelope_speed: u8) {
+        self.speed = val & 0x3f;
+        self.increasing = val & 0x40 != 0;
+        self.disabled = val & 0x80 != 0;
+        self.reset_timer(envelope_speed);
+
+        if self.disabled {
+            self.gain = self.speed;
+        }
+    }
+
+    fn reset_timer(&mut self, envelope_speed: u8) {
+        self.timer = 8 * (self.speed as u32 + 1) * envelope_speed as u32;
+    }
+
+    // returns whether the gain changed
+    fn clock(&mut self, envelope_speed: u8) -> bool {
+        if self.disabled || envelope_speed == 0 {
+            return false;
+        }
+
+        if self.timer > 1 {
+            self.timer -= 1;
+            return false;
+        }
+        self.reset_timer(envelope_speed);
+
+        // the gain goes up to 32, but may be set higher through 'speed'
+        if self.increasing && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increasing && self.gain > 0 {
+            self.gain -= 1;
+        }
+
+        true
+    }
+}
+
+impl Default for FdsAudio {
+    fn default() -> Self {
+        Self {
+            wavetable: [0; 64],
+            wavetable_writable: false,
+            master_volume: 0,
+            envelope_speed: 0xe8,
+            wave_halted: false,
+            envelopes_halted: false,
+            volume_envelope: FdsEnvelope::default(),
+            wave_frequency: 0,
+            wave_accumulator: 0,
+            wave_position: 0,
+            mod_envelope: FdsEnvelope::default(),
+            mod_frequency: 0,
+            mod_accumulator: 0,
+            mod_halted: false,
+            mod_table: [0; 64],
+            mod_position: 0,
+            mod_counter: 0,
+            mod_pitch: 0,
+        }
+    }
+}
+
+impl FdsAudio {
+    // handles writes to 0x4040-0x408a
+    pub fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4040..=0x407f => {
+                if self.wavetable_writable {
+                    self.wavetable[(addr & 0x3f) as usize] = val & 0x3f;
+                }
+            }
+            0x4080 => self.volume_envelope.write(val, self.envelope_speed),
+            0x4082 => self.wave_frequency = (self.wave_frequency & 0xf00) | val as u16,
+            0x4083 => {
+                self.wave_frequency = (self.wave_frequency & 0xff) | ((val & 0xf) as u16) << 8;
+                self.wave_halted = val & 0x80 != 0;
+                self.envelopes_halted = val & 0x40 != 0;
+
+                if self.wave_halted {
+                    self.wave_position = 0;
+                    self.wave_accumulator = 0;
+                }
+            }
+            0x4084 => self.mod_envelope.write(val, self.envelope_speed),
+            // sign-extend the 7-bit value
+            0x4085 => self.mod_counter = ((val << 1) as i8) >> 1,
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0xf00) | val as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0xff) | ((val & 0xf) as u16) << 8;
+                self.mod_halted = val & 0x80 != 0;
+
+                if self.mod_halted {
+                    self.mod_accumulator = 0;
+                }
+            }
+            0x4088 => {
+                if self.mod_halted {
+                    let position = self.mod_position as usize;
+                    self.mod_table[position] = val & 0b111;
+                    self.mod_table[(position + 1) & 0x3f] = val & 0b111;
+                    self.mod_position = (self.mod_position + 2) & 0x3f;
+                }
+            }
+            0x4089 => {
+                self.master_volume = val & 0b11;
+                self.wavetable_writable = val & 0x80 != 0;
+            }
+            0x408a => self.envelope_speed = val,
+            _ => return,
+        }
+
+        self.update_mod_pitch();
+    }
+
+    // handles reads from 0x4040-0x4092
+    pub fn read_register(&self, addr: u16) -> u8 {
+        match addr {
+            0x4040..=0x407f => self.wavetable[(addr & 0x3f) as usize],
+            0x4090 => self.volume_envelope.gain,
+            0x4092 => self.mod_envelope.gain,
+            _ => 0,
+        }
+    }
+
+    // steps through the modulation table. returns whether the counter was adjusted
+    fn clock_modulator(&mut self) -> bool {
+        if self.mod_halted || self.mod_frequency == 0 {
+            return false;
+        }
+
+        let (accumulator, overflowed) = self.mod_accumulator.overflowing_add(self.mod_frequency);
+        self.mod_accumulator = accumulator;
+        if !overflowed {
+            return false;
+        }
+
+        let entry = self.mod_table[self.mod_position as usize];
+        self.mod_counter = if entry == 4 {
+            0
+        } else {
+            // the counter wraps around within 7 bits
+            ((self.mod_counter + MOD_ADJUSTMENTS[entry as usize]) << 1) >> 1
+        };
+        self.mod_position = (self.mod_position + 1) & 0x3f;
+
+        true
+    }
+
+    // the pitch calculation of the hardware (including its odd rounding), as
+    // described on the nesdev.com 'FDS audio' page
+    fn update_mod_pitch(&mut self) {
+        let mut temp = self.mod_counter as i32 * self.mod_envelope.gain as i32;
+        let remainder = temp & 0xf;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if self.mod_counter < 0 { -1 } else { 2 };
+        }
+
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+
+        temp *= self.wave_frequency as i32;
+        let remainder = temp & 0x3f;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+
+        self.mod_pitch = temp;
+    }
+}
+
+impl ExpansionAudio for FdsAudio {
+    fn clock(&mut self) {
+        if !self.wave_halted && !self.envelopes_halted {
+            self.volume_envelope.clock(self.envelope_speed);
+            if self.mod_envelope.clock(self.envelope_speed) {
+                self.update_mod_pitch();
+            }
+        }
+
+        if self.clock_modulator() {
+            self.update_mod_pitch();
+        }
+
+        if self.wave_halted || self.wavetable_writable {
+            return;
+        }
+
+        let pitch = self.wave_frequency as i32 + self.mod_pitch;
+        if pitch > 0 {
+            let (accumulator, overflowed) = self.wave_accumulator.overflowing_add(pitch as u16);
+            self.wave_accumulator = accumulator;
+            if overflowed {
+                self.wave_position = (self.wave_position + 1) & 0x3f;
+            }
+        }
+    }
+
+    fn output(&self) -> f32 {
+        // gains above 32 are clamped
+        let level = self.wavetable[self.wave_position as usize] as u32
+            * self.volume_envelope.gain.min(32) as u32
+            * MASTER_VOLUMES[self.master_volume as usize]
+            / 1152;
+
+        // at full volume, the wave is roughly 2.4 times as loud as an apu pulse channel
+        level as f32 / 63.0 * 2.4
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wavetable() {
+        let mut audio = FdsAudio::default();
+
+        // the wavetable is only writable through 0x4089
+        audio.write_register(0x4040, 0x3f);
+        assert_eq!(audio.read_register(0x4040), 0);
+
+        audio.write_register(0x4089, 0x80);
+        for i in 0..64 {
+            audio.write_register(0x4040 + i, i as u8);
+        }
+        audio.write_register(0x4089, 0);
+
+        // direct gain of 32, and a frequency of 0x800 (a step every 32 cycles)
+        audio.write_register(0x4080, 0x80 | 32);
+        audio.write_register(0x4082, 0x00);
+        audio.write_register(0x4083, 0x08);
+        assert_eq!(audio.read_register(0x4090), 32);
+
+        for _ in 0..32 * 10 {
+            audio.clock();
+        }
+        assert_eq!(audio.wave_position, 10);
+        assert_eq!(audio.output(), 10.0 / 63.0 * 2.4);
+
+        // halting the wave resets it
+        audio.write_register(0x4083, 0x80);
+        audio.clock();
+        assert_eq!(audio.wave_position, 0);
+    }
+
+    #[test]
+    fn test_modulator() {
+        let mut audio = FdsAudio::default();
+
+        audio.write_register(0x4087, 0x80);
+        for &entry in [1, 1, 4, 7].iter() {
+            audio.write_register(0x4088, entry);
+        }
+        assert_eq!(audio.mod_position, 8);
+        assert_eq!(audio.mod_table[..8], [1, 1, 1, 1, 4, 4, 7, 7]);
+
+        // counter values are 7-bit signed
+        audio.write_register(0x4085, 0x7f);
+        assert_eq!(audio.mod_counter, -1);
+        audio.write_register(0x4085, 62);
+
+        // the highest frequency (0xfff) steps through the table every ~16 cycles
+        audio.mod_position = 0;
+        audio.write_register(0x4086, 0xff);
+        audio.write_register(0x4087, 0x0f);
+        let mut counters = Vec::new();
+        for _ in 0..16 * 8 + 1 {
+            if audio.clock_modulator() {
+                counters.push(audio.mod_counter);
+            }
+        }
+        // the counter wraps from 63 to -64
+        assert_eq!(counters, [63, -64, -63, -62, 0, 0, -1, -2]);
+    }
+
+    #[test]
+    fn test_envelope() {
+        let mut envelope = FdsEnvelope::default();
+
+        // increasing, speed 0 (stepped every 8 * 1 * 'envelope_speed' cycles)
+        envelope.write(0x40, 2);
+        for _ in 0..16 * 3 {
+            envelope.clock(2);
+        }
+        assert_eq!(envelope.gain, 3);
+
+        // the envelope stops at 32
+        for _ in 0..16 * 40 {
+            envelope.clock(2);
+        }
+        assert_eq!(envelope.gain, 32);
+    }
+}
@@ -0,0 +1,277 @@
+use super::ExpansionAudio;
+
+#[macro_use]
+use derive_serialize::Serialize;
+
+// number of cpu cycles between each clock of the envelopes and length
+// counters. unlike the apu, MMC5 clocks these at a fixed rate of ~240 hz
+const QUARTER_FRAME_CYCLES: u16 = 7457;
+
+// the 4 pulse waveforms (12.5%, 25%, 50% and 25% negated duty cycle)
+static DUTY_SEQUENCES: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+// the two pulse channels and the pcm channel of MMC5. the pulse channels work
+// like the ones in the apu, except that they lack a sweep unit and aren't
+// silenced by low timer periods
+#[derive(Serialize, Default)]
+pub struct Mmc5Audio {
+    pulse1: Mmc5Pulse,
+    pulse2: Mmc5Pulse,
+    pcm_level: u8,
+    // true means the pcm level is set by cpu reads from 0x8000-0xbfff,
+    // instead of by writes to 0x5011
+    pcm_read_mode: bool,
+    pcm_irq_enable: bool,
+    pcm_irq_pending: bool,
+    quarter_frame_counter: u16,
+    // the pulse timers are only clocked on every other cpu cycle
+    odd_cycle: bool,
+}
+
+#[derive(Serialize, Default)]
+struct Mmc5Pulse {
+    // the value last written to 0x5000/0x5004 (duty, envelope loop/length
+    // counter halt, constant volume flag and volume/envelope period)
+    control: u8,
+    timer_period: u16,
+    timer: u16,
+    sequence_step: u8,
+    length_counter: u8,
+    envelope_start: bool,
+    envelope_divider: u8,
+    envelope_decay: u8,
+    enabled: bool,
+}
+
+impl Mmc5Pulse {
+    fn write_register(&mut self, index: u16, val: u8) {
+        match index {
+            0 => self.control = val,
+            // NOTE: 0x5001 and 0x5005 (sweep) do nothing on MMC5
+            1 => (),
+            2 => self.timer_period = (self.timer_period & 0x700) | val as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0xff) | (((val & 0b111) as u16) << 8);
+                if self.enabled {
+                    self.length_counter = super::LENGTH_TABLE[(val >> 3) as usize];
+                }
+
+                self.sequence_step = 0;
+                self.envelope_start = true;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence_step = (self.sequence_step + 1) & 0b111;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        let halt_or_loop = self.control & 0b10_0000 != 0;
+
+        // envelope
+        if self.envelope_start {
+            self.envelope_start = false;
+            self.envelope_decay = 15;
+            self.envelope_divider = self.control & 0xf;
+        } else if self.envelope_divider == 0 {
+            self.envelope_divider = self.control & 0xf;
+            if self.envelope_decay > 0 {
+                self.envelope_decay -= 1;
+            } else if halt_or_loop {
+                self.envelope_decay = 15;
+            }
+        } else {
+            self.envelope_divider -= 1;
+        }
+
+        // length counter
+        if !halt_or_loop && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        let duty = (self.control >> 6) as usize;
+        if self.length_counter == 0 || DUTY_SEQUENCES[duty][self.sequence_step as usize] == 0 {
+            return 0;
+        }
+
+        if self.control & 0b1_0000 != 0 {
+            // constant volume
+            self.control & 0xf
+        } else {
+            self.envelope_decay
+        }
+    }
+}
+
+impl Mmc5Audio {
+    // handles writes to 0x5000-0x5015. returns whether the write
+    // changed the state of the pcm irq
+    pub fn write_register(&mut self, addr: u16, val: u8) -> bool {
+        match addr {
+            0x5000..=0x5003 => self.pulse1.write_register(addr & 0b11, val),
+            0x5004..=0x5007 => self.pulse2.write_register(addr & 0b11, val),
+            0x5010 => {
+                self.pcm_read_mode = val & 1 != 0;
+                self.pcm_irq_enable = val & 0x80 != 0;
+                return true;
+            }
+            // writing zero is ignored (zero triggers the irq in read mode instead)
+            0x5011 if !self.pcm_read_mode && val != 0 => self.pcm_level = val,
+            0x5015 => {
+                self.pulse1.set_enabled(val & 1 != 0);
+                self.pulse2.set_enabled(val & 0b10 != 0);
+            }
+            _ => (),
+        }
+
+        false
+    }
+
+    // handles reads from 0x5010 and 0x5015. reading 0x5010 acknowledges the pcm irq
+    pub fn read_register(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x5010 => {
+                let val = (self.pcm_irq_pending as u8) << 7;
+                self.pcm_irq_pending = false;
+                val
+            }
+            0x5015 => {
+                (self.pulse1.length_counter != 0) as u8
+                    | ((self.pulse2.length_counter != 0) as u8) << 1
+            }
+            _ => 0,
+        }
+    }
+
+    // called on cpu reads from 0x8000-0xbfff. in read mode, the byte read
+    // becomes the new pcm level, with zero raising an irq instead
+    pub fn snoop_prg_read(&mut self, val: u8) {
+        if !self.pcm_read_mode {
+            return;
+        }
+
+        if val == 0 {
+            self.pcm_irq_pending = true;
+        } else {
+            self.pcm_level = val;
+        }
+    }
+
+    // whether the pcm channel is currently asserting its irq
+    pub fn is_irq_asserted(&self) -> bool {
+        self.pcm_irq_pending && self.pcm_irq_enable
+    }
+}
+
+impl ExpansionAudio for Mmc5Audio {
+    fn clock(&mut self) {
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+
+        self.quarter_frame_counter += 1;
+        if self.quarter_frame_counter == QUARTER_FRAME_CYCLES {
+            self.quarter_frame_counter = 0;
+            self.pulse1.clock_quarter_frame();
+            self.pulse2.clock_quarter_frame();
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let pulses = super::mix_pulses(self.pulse1.output(), self.pulse2.output());
+
+        // the pcm channel is about as loud as the apu dmc channel, which has half the resolution
+        let pcm = if self.pcm_level == 0 {
+            0.0
+        } else {
+            let full_volume_pulse = 95.88 / (8128.0 / 15.0 + 100.0);
+            let dmc = (self.pcm_level >> 1) as f32;
+            (159.79 / (1.0 / (dmc / 22638.0) + 100.0)) / full_volume_pulse
+        };
+
+        pulses + pcm
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pulse() {
+        let mut audio = Mmc5Audio::default();
+
+        // enable pulse 1, constant volume 10, 50% duty
+        audio.write_register(0x5015, 1);
+        audio.write_register(0x5000, 0b1011_1010);
+        audio.write_register(0x5002, 0x10);
+        audio.write_register(0x5003, 0b0000_1000);
+
+        assert_eq!(audio.pulse1.timer_period, 0x10);
+        assert_eq!(audio.pulse1.length_counter, 254);
+        assert_eq!(audio.read_register(0x5015), 1);
+
+        // step through one period of the waveform
+        let mut levels = Vec::new();
+        for _ in 0..8 {
+            levels.push(audio.pulse1.output());
+            for _ in 0..(0x11 * 2) {
+                audio.clock();
+            }
+        }
+        assert_eq!(levels, [0, 10, 10, 10, 10, 0, 0, 0]);
+
+        // disabling the channel should silence it
+        audio.write_register(0x5015, 0);
+        assert_eq!(audio.read_register(0x5015), 0);
+        assert_eq!(audio.pulse1.output(), 0);
+    }
+
+    #[test]
+    fn test_pcm_read_mode() {
+        let mut audio = Mmc5Audio::default();
+
+        // write mode
+        audio.write_register(0x5011, 0x40);
+        assert_eq!(audio.pcm_level, 0x40);
+        audio.snoop_prg_read(0x20);
+        assert_eq!(audio.pcm_level, 0x40);
+
+        // read mode with irq enabled
+        audio.write_register(0x5010, 0x81);
+        audio.snoop_prg_read(0x20);
+        assert_eq!(audio.pcm_level, 0x20);
+        assert!(!audio.is_irq_asserted());
+
+        audio.snoop_prg_read(0);
+        assert_eq!(audio.pcm_level, 0x20);
+        assert!(audio.is_irq_asserted());
+
+        // reading 0x5010 acknowledges the irq
+        assert_eq!(audio.read_register(0x5010), 0x80);
+        assert!(!audio.is_irq_asserted());
+    }
+}
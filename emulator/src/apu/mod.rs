@@ -0,0 +1,195 @@
+use crate::{ppu, serialize};
+
+use std::{fs, io};
+
+mod fds;
+mod mmc5;
+mod namco163;
+mod opll;
+mod sunsoft5b;
+mod vrc6;
+
+pub use fds::FdsAudio;
+pub use mmc5::Mmc5Audio;
+pub use namco163::Namco163Audio;
+pub use opll::Opll;
+pub use sunsoft5b::Sunsoft5bAudio;
+pub use vrc6::Vrc6Audio;
+
+// the rate that the mixed output is downsampled to
+pub const SAMPLE_RATE: u32 = 44_100;
+
+// the sample value that an output level of 1.0 (see 'ExpansionAudio::output()') maps
+// to. leaves headroom for several expansion chips playing at once
+const FULL_SCALE: f32 = 8192.0;
+
+// mixes the audio of the console and downsamples it to 'SAMPLE_RATE'. the apu's own
+// channels aren't implemented yet, so for now this is only the cartridge's expansion
+// audio (see 'CpuAddressBus::expansion_audio_output()')
+pub struct Apu {
+    cpu_clock_rate: u32,
+    // the cpu cycle the mixer has been caught up to
+    cycle_count: i32,
+    // whether samples are collected (they otherwise pile up if nothing drains them)
+    recording: bool,
+    // the sum of the output levels since the last sample, which are averaged into the
+    // next sample. 'sample_phase' counts up by 'SAMPLE_RATE' every cpu cycle
+    level_sum: f32,
+    level_cycles: u32,
+    sample_phase: u32,
+    // state of the high-pass filter that removes the dc offset of the output
+    prev_level: f32,
+    prev_filtered: f32,
+    samples: Vec<i16>,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Self {
+            cpu_clock_rate: ppu::Region::Ntsc.cpu_clock_rate(),
+            cycle_count: 0,
+            recording: false,
+            level_sum: 0.0,
+            level_cycles: 0,
+            sample_phase: 0,
+            prev_level: 0.0,
+            prev_filtered: 0.0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn set_region(&mut self, region: ppu::Region) {
+        self.cpu_clock_rate = region.cpu_clock_rate();
+    }
+
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+    }
+
+    // mixes 'expansion_output' into the output for every cpu cycle up to 'cpu_cycle_count'.
+    // called after every instruction (once the cartridge has been caught up as well)
+    pub fn catch_up(&mut self, cpu_cycle_count: i32, expansion_output: f32) {
+        if !self.recording {
+            self.cycle_count = cpu_cycle_count;
+            return;
+        }
+
+        while self.cycle_count < cpu_cycle_count {
+            self.level_sum += expansion_output;
+            self.level_cycles += 1;
+
+            self.sample_phase += SAMPLE_RATE;
+            if self.sample_phase >= self.cpu_clock_rate {
+                self.sample_phase -= self.cpu_clock_rate;
+                self.push_sample();
+            }
+
+            self.cycle_count += 1;
+        }
+    }
+
+    // called at the end of each frame, right before 'Cpu::cycle_count' is reset
+    pub fn sub_cycle_count(&mut self, sub: i32) {
+        self.cycle_count -= sub;
+    }
+
+    // the samples mixed since the last call
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.samples)
+    }
+
+    fn push_sample(&mut self) {
+        let level = self.level_sum / self.level_cycles as f32;
+        self.level_sum = 0.0;
+        self.level_cycles = 0;
+
+        // the expansion chips only output positive levels, which the console's own
+        // high-pass filter (at around 37hz) centers around zero
+        let filtered = level - self.prev_level + 0.995 * self.prev_filtered;
+        self.prev_level = level;
+        self.prev_filtered = filtered;
+
+        let sample = (filtered * FULL_SCALE).clamp(i16::MIN as f32, i16::MAX as f32);
+        self.samples.push(sample as i16);
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// NOTE: the mixer only holds output that has yet to be written out, so there's nothing
+// to save (this keeps save states compatible with the emulated apu that will follow)
+impl serialize::Serialize for Apu {
+    fn serialize(&self, _file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn deserialize(&mut self, _file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// trait for the sound chips found on some cartridges. implementors are owned
+// by the mapper containing them, which forwards register writes and clocks
+// them once per cpu cycle (see 'CpuAddressBus::catch_up()')
+pub trait ExpansionAudio: serialize::Serialize {
+    fn clock(&mut self);
+    // the current output level. 1.0 is the loudness of an apu pulse channel at
+    // full volume, and chips are expected to stay within their hardware level
+    // relative to that
+    fn output(&self) -> f32;
+}
+
+// length counter load values, indexed by bits 3-7 of the value written to a
+// channel's length counter register. shared by the apu and MMC5 pulse channels
+pub static LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// the nonlinear pulse channel mixing formula from the nesdev.com 'APU Mixer'
+// page, normalized so that a single channel at volume 15 gives 1.0
+pub fn mix_pulses(pulse1: u8, pulse2: u8) -> f32 {
+    let sum = (pulse1 + pulse2) as f32;
+    if sum == 0.0 {
+        return 0.0;
+    }
+
+    let full_volume = 95.88 / (8128.0 / 15.0 + 100.0);
+    (95.88 / (8128.0 / sum + 100.0)) / full_volume
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mixer() {
+        let mut apu = Apu::new();
+        let cpu_clock_rate = ppu::Region::Ntsc.cpu_clock_rate() as i32;
+
+        // nothing is mixed unless recording
+        apu.catch_up(1000, 1.0);
+        assert!(apu.take_samples().is_empty());
+
+        // a second of output gives a second of samples
+        apu.set_recording(true);
+        apu.sub_cycle_count(1000);
+        apu.catch_up(cpu_clock_rate, 0.5);
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), SAMPLE_RATE as usize);
+
+        // a step in the level shows up as a spike that the high-pass filter decays
+        assert_eq!(samples[0], (0.5 * FULL_SCALE) as i16);
+        assert!(samples[SAMPLE_RATE as usize - 1].abs() < 10);
+
+        // pal runs the cpu slower, so each sample covers fewer cpu cycles
+        apu.set_region(ppu::Region::Pal);
+        apu.sub_cycle_count(cpu_clock_rate);
+        apu.catch_up(ppu::Region::Pal.cpu_clock_rate() as i32, 0.0);
+        assert_eq!(apu.take_samples().len(), SAMPLE_RATE as usize);
+    }
+}
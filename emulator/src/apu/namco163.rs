@@ -0,0 +1,203 @@
+use super::ExpansionAudio;
+
+#[macro_use]
+use derive_serialize::Serialize;
+
+// the number of cpu cycles it takes namco 163 to update a single channel
+const CHANNEL_UPDATE_CYCLES: u8 = 15;
+
+// the wavetable synth of namco 163. the 8 channels (of which 1-8 can be enabled)
+// are configured through the upper part of a 128 byte internal ram, with the rest
+// of the ram holding the 4-bit waveform samples. channels are updated one at a time
+// (every 15 cpu cycles), and the chip only ever outputs the most recently updated
+// channel. this time-multiplexing is inaudible on hardware but aliases badly when
+// emulated naively, so 'output()' averages the active channels instead
+#[derive(Serialize)]
+pub struct Namco163Audio {
+    ram: [u8; 0x80],
+    // the value last written to 0xf800 (ram address in bits 0-6 and auto-increment in bit 7)
+    ram_addr: u8,
+    // the channel to update next (counts down from 7)
+    current_channel: u8,
+    cycle_counter: u8,
+    // the output level of each channel as of its last update
+    channel_outputs: [i16; 8],
+    // set through bit 6 of 0xe000
+    disabled: bool,
+}
+
+impl Default for Namco163Audio {
+    fn default() -> Self {
+        Self {
+            ram: [0; 0x80],
+            ram_addr: 0,
+            current_channel: 7,
+            cycle_counter: 0,
+            channel_outputs: [0; 8],
+            disabled: false,
+        }
+    }
+}
+
+impl Namco163Audio {
+    // handles writes to 0xf800-0xffff
+    pub fn write_addr(&mut self, val: u8) {
+        self.ram_addr = val;
+    }
+
+    // handles writes to 0x4800-0x4fff
+    pub fn write_data(&mut self, val: u8) {
+        self.ram[(self.ram_addr & 0x7f) as usize] = val;
+        self.increment_addr();
+    }
+
+    // handles reads from 0x4800-0x4fff
+    pub fn read_data(&mut self) -> u8 {
+        let val = self.ram[(self.ram_addr & 0x7f) as usize];
+        self.increment_addr();
+        val
+    }
+
+    pub fn set_disabled(&mut self, disabled: bool) {
+        self.disabled = disabled;
+    }
+
+    // the internal ram is battery-backed on some boards
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn increment_addr(&mut self) {
+        if self.ram_addr & 0x80 != 0 {
+            self.ram_addr = (self.ram_addr.wrapping_add(1) & 0x7f) | 0x80;
+        }
+    }
+
+    // the channels from 'lowest_active_channel()' up to 7 are enabled
+    fn lowest_active_channel(&self) -> u8 {
+        7 - ((self.ram[0x7f] >> 4) & 0b111)
+    }
+
+    fn update_channel(&mut self, channel: u8) {
+        // each channel has 8 bytes of registers, starting at 0x40
+        let regs = 0x40 + channel as usize * 8;
+
+        let freq = self.ram[regs] as u32
+            | (self.ram[regs + 2] as u32) << 8
+            | ((self.ram[regs + 4] & 0b11) as u32) << 16;
+        let phase = self.ram[regs + 1] as u32
+            | (self.ram[regs + 3] as u32) << 8
+            | (self.ram[regs + 5] as u32) << 16;
+        let length = 256 - (self.ram[regs + 4] & 0xfc) as u32;
+
+        let phase = (phase + freq) % (length << 16);
+        self.ram[regs + 1] = phase as u8;
+        self.ram[regs + 3] = (phase >> 8) as u8;
+        self.ram[regs + 5] = (phase >> 16) as u8;
+
+        // samples are stored as nibbles, with the low nibble first
+        let sample_idx = (((phase >> 16) + self.ram[regs + 6] as u32) & 0xff) as usize;
+        let sample = (self.ram[sample_idx >> 1] >> ((sample_idx & 1) * 4)) & 0xf;
+        let volume = self.ram[regs + 7] & 0xf;
+
+        self.channel_outputs[channel as usize] = (sample as i16 - 8) * volume as i16;
+    }
+}
+
+impl ExpansionAudio for Namco163Audio {
+    fn clock(&mut self) {
+        if self.disabled {
+            return;
+        }
+
+        self.cycle_counter += 1;
+        if self.cycle_counter < CHANNEL_UPDATE_CYCLES {
+            return;
+        }
+        self.cycle_counter = 0;
+
+        let lowest_active_channel = self.lowest_active_channel();
+        if self.current_channel < lowest_active_channel {
+            // the number of active channels was reduced
+            self.current_channel = 7;
+        }
+
+        self.update_channel(self.current_channel);
+
+        self.current_channel = if self.current_channel == lowest_active_channel {
+            7
+        } else {
+            self.current_channel - 1
+        };
+    }
+
+    fn output(&self) -> f32 {
+        if self.disabled {
+            return 0.0;
+        }
+
+        let lowest_active_channel = self.lowest_active_channel() as usize;
+        let sum = self.channel_outputs[lowest_active_channel..]
+            .iter()
+            .map(|&output| output as f32)
+            .sum::<f32>();
+
+        // a single channel playing a full-range waveform at volume 15 is roughly
+        // twice as loud as an apu pulse channel
+        sum / (8 - lowest_active_channel) as f32 / 60.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ram_auto_increment() {
+        let mut audio = Namco163Audio::default();
+
+        audio.write_addr(0x80 | 0x7e);
+        audio.write_data(0x11);
+        audio.write_data(0x22);
+        audio.write_data(0x33);
+        assert_eq!(audio.ram[0x7e], 0x11);
+        assert_eq!(audio.ram[0x7f], 0x22);
+        assert_eq!(audio.ram[0x00], 0x33);
+
+        // without auto-increment
+        audio.write_addr(0x7e);
+        assert_eq!(audio.read_data(), 0x11);
+        assert_eq!(audio.read_data(), 0x11);
+    }
+
+    #[test]
+    fn test_channel_update() {
+        let mut audio = Namco163Audio::default();
+
+        // a square wave with 4 samples (0xf, 0xf, 0, 0) at address 0
+        audio.ram[0] = 0xff;
+        audio.ram[1] = 0x00;
+
+        // channel 7 (the only active channel): frequency 0x10000 (one sample
+        // per update), length 4, volume 15
+        audio.ram[0x78] = 0x00;
+        audio.ram[0x7a] = 0x00;
+        audio.ram[0x7c] = 0x01 | (256 - 4) as u8;
+        audio.ram[0x7e] = 0;
+        audio.ram[0x7f] = 15;
+
+        let mut outputs = Vec::new();
+        for _ in 0..4 {
+            for _ in 0..CHANNEL_UPDATE_CYCLES {
+                audio.clock();
+            }
+            outputs.push(audio.channel_outputs[7]);
+        }
+
+        assert_eq!(outputs, [7 * 15, -8 * 15, -8 * 15, 7 * 15]);
+
+        // disabling the sound silences it
+        audio.set_disabled(true);
+        assert_eq!(audio.output(), 0.0);
+    }
+}
@@ -0,0 +1,535 @@
+use super::ExpansionAudio;
+
+#[macro_use]
+use derive_serialize::Serialize;
+
+// the opll runs at twice the cpu clock on VRC7 and produces one sample every
+// 72 of its own clocks, so a sample is generated every 36 cpu cycles (~49.7 khz)
+const SAMPLE_CYCLES: u8 = 36;
+
+const N_CHANNELS: usize = 6;
+
+// the built-in instrument patches (1-15) of the VRC7 variant of the opll. the
+// layout of each patch is the same as that of the custom patch in registers 0-7
+static VRC7_PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xe8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0d, 0xd8, 0xf6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xfa, 0xb2, 0x20, 0x12],
+    [0x31, 0x61, 0x0c, 0x07, 0xa8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1e, 0x06, 0xe1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xa3, 0xe2, 0xf4, 0xf4],
+    [0x21, 0x61, 0x1d, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xa2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xb5, 0x01, 0x0f, 0x0f, 0xa8, 0xa5, 0x51, 0x02],
+    [0x17, 0xc1, 0x24, 0x07, 0xf8, 0xf8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xd3, 0x05, 0xc9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0c, 0x00, 0x94, 0xc0, 0x33, 0xf6],
+    [0x21, 0x72, 0x0d, 0x00, 0xc1, 0xd5, 0x56, 0x06],
+];
+
+// frequency multipliers (times two, since the first one is 0.5)
+static MULTIPLIERS: [u32; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+
+// key scale level attenuation (in 0.375 db units) for block 7,
+// indexed by the upper 4 bits of the f-number
+static KSL_TABLE: [u8; 16] = [
+    0, 24, 32, 37, 40, 43, 45, 47, 48, 50, 51, 52, 53, 54, 55, 56,
+];
+
+// envelope increments for the 4 fractional rates, spread out over 8 steps
+static ENVELOPE_INCREMENTS: [[u8; 8]; 4] = [
+    [0, 1, 0, 1, 0, 1, 0, 1],
+    [0, 1, 0, 1, 1, 1, 0, 1],
+    [0, 1, 1, 1, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 1],
+];
+
+// frequency offsets (in 1/256ths of the f-number) applied by vibrato
+static VIBRATO_TABLE: [i32; 8] = [0, 1, 2, 1, 0, -1, -2, -1];
+
+// the maximum envelope attenuation (~48 db), which silences an operator
+const MAX_ATTENUATION: u16 = 127;
+
+// envelope states
+const ATTACK: u8 = 0;
+const DECAY: u8 = 1;
+const SUSTAIN: u8 = 2;
+const RELEASE: u8 = 3;
+
+// the yamaha ym2413 (opll) fm synthesizer, as found in VRC7. the VRC7 variant only
+// has 6 channels, lacks the rhythm mode and has its own set of built-in patches.
+// each channel consists of a modulator operator feeding into a carrier operator,
+// where each operator is a sine wave with its own envelope generator
+//
+// attenuation is handled in the log domain like on the real chip (in units of
+// 0.375 db for envelopes and levels), but the log-sin and exponent lookups are
+// computed instead of read from the original rom tables. the short damping phase
+// that precedes each key-on on hardware is also skipped
+#[derive(Serialize)]
+pub struct Opll {
+    // the register selected through 0x9010
+    selected_register: u8,
+    // registers 0-7
+    custom_patch: [u8; 8],
+    channels: [OpllChannel; N_CHANNELS],
+    // counts samples. drives the envelope generators and the am/vibrato lfos
+    sample_counter: u32,
+    divider: u8,
+    // the sum of all channel outputs from the last generated sample
+    output: i32,
+    // set while the chip is held in reset (through bit 6 of 0xe000 on VRC7)
+    reset: bool,
+}
+
+#[derive(Serialize, Default, Clone, Copy)]
+struct OpllChannel {
+    // registers 0x10-0x15 and bit 0 of 0x20-0x25
+    fnum: u16,
+    // bits 1-3 of 0x20-0x25
+    block: u8,
+    key_on: bool,
+    // bit 5 of 0x20-0x25. makes released notes fade out slowly
+    sustain: bool,
+    // bits 4-7 of 0x30-0x35 (0 is the custom patch)
+    instrument: u8,
+    // bits 0-3 of 0x30-0x35 (attenuation in 3 db steps)
+    volume: u8,
+    // the modulator and the carrier
+    operators: [OpllOperator; 2],
+}
+
+#[derive(Serialize, Clone, Copy)]
+struct OpllOperator {
+    // 19-bit phase accumulator. the upper 10 bits index one period of the sine wave
+    phase: u32,
+    envelope: u16,
+    envelope_state: u8,
+    // the last two outputs (used for the feedback of the modulator)
+    output: i16,
+    prev_output: i16,
+}
+
+impl Default for OpllOperator {
+    fn default() -> Self {
+        Self {
+            phase: 0,
+            envelope: MAX_ATTENUATION,
+            envelope_state: RELEASE,
+            output: 0,
+            prev_output: 0,
+        }
+    }
+}
+
+impl Default for Opll {
+    fn default() -> Self {
+        Self {
+            selected_register: 0,
+            custom_patch: [0; 8],
+            channels: [OpllChannel::default(); N_CHANNELS],
+            sample_counter: 0,
+            divider: 0,
+            output: 0,
+            reset: false,
+        }
+    }
+}
+
+impl Opll {
+    // handles writes to 0x9010
+    pub fn select_register(&mut self, val: u8) {
+        self.selected_register = val;
+    }
+
+    // handles writes to 0x9030
+    pub fn write_register(&mut self, val: u8) {
+        if self.reset {
+            return;
+        }
+
+        let reg = self.selected_register;
+        let channel_idx = (reg & 0xf) as usize;
+
+        match reg {
+            0x00..=0x07 => self.custom_patch[reg as usize] = val,
+            0x10..=0x15 => {
+                let channel = &mut self.channels[channel_idx];
+                channel.fnum = (channel.fnum & 0x100) | val as u16;
+            }
+            0x20..=0x25 => {
+                let channel = &mut self.channels[channel_idx];
+                channel.fnum = (channel.fnum & 0xff) | ((val & 1) as u16) << 8;
+                channel.block = (val >> 1) & 0b111;
+                channel.sustain = val & 0x20 != 0;
+
+                let key_on = val & 0x10 != 0;
+                if key_on && !channel.key_on {
+                    for op in channel.operators.iter_mut() {
+                        op.envelope_state = ATTACK;
+                        op.phase = 0;
+                    }
+                } else if !key_on && channel.key_on {
+                    for op in channel.operators.iter_mut() {
+                        op.envelope_state = RELEASE;
+                    }
+                }
+                channel.key_on = key_on;
+            }
+            0x30..=0x35 => {
+                let channel = &mut self.channels[channel_idx];
+                channel.instrument = val >> 4;
+                channel.volume = val & 0xf;
+            }
+            // NOTE: registers 0x0e (rhythm mode) and 0x0f (test) are not present on VRC7
+            _ => (),
+        }
+    }
+
+    // silences the chip and resets it to its power-on state for as long as 'reset' is true
+    pub fn set_reset(&mut self, reset: bool) {
+        if reset {
+            *self = Self::default();
+        }
+        self.reset = reset;
+    }
+
+    fn get_patch(&self, instrument: u8) -> [u8; 8] {
+        match instrument {
+            0 => self.custom_patch,
+            n => VRC7_PATCHES[n as usize - 1],
+        }
+    }
+
+    fn generate_sample(&mut self) {
+        // amplitude modulation: a triangle wave between 0 and 13 (~4.8 db) at ~3.7 hz
+        let am_step = (self.sample_counter >> 6) % 210;
+        let am_level = if am_step < 105 {
+            am_step
+        } else {
+            209 - am_step
+        } as u16
+            * 13
+            / 104;
+        // vibrato: ~6.1 hz
+        let vibrato = VIBRATO_TABLE[((self.sample_counter >> 10) & 0b111) as usize];
+
+        let mut output = 0;
+        for channel_idx in 0..N_CHANNELS {
+            let patch = self.get_patch(self.channels[channel_idx].instrument);
+            let sample_counter = self.sample_counter;
+            let channel = &mut self.channels[channel_idx];
+
+            // modulator
+            let feedback = patch[3] & 0b111;
+            let modulation = if feedback == 0 {
+                0
+            } else {
+                let mod_op = &channel.operators[0];
+                (mod_op.output as i32 + mod_op.prev_output as i32) >> (9 - feedback)
+            };
+            let base_attenuation = (patch[2] & 0x3f) as u16 * 2;
+            let modulator_output = channel.clock_operator(
+                0,
+                &patch,
+                modulation,
+                base_attenuation,
+                am_level,
+                vibrato,
+                sample_counter,
+            );
+
+            // carrier
+            let base_attenuation = channel.volume as u16 * 8;
+            output += channel.clock_operator(
+                1,
+                &patch,
+                modulator_output as i32 >> 1,
+                base_attenuation,
+                am_level,
+                vibrato,
+                sample_counter,
+            ) as i32;
+        }
+
+        self.output = output;
+        self.sample_counter = self.sample_counter.wrapping_add(1);
+    }
+}
+
+impl OpllChannel {
+    // the key scale rate offset added to envelope rates
+    fn calc_key_scale_rate(&self, ksr: bool) -> u8 {
+        if ksr {
+            (self.block << 1) | (self.fnum >> 8) as u8
+        } else {
+            self.block >> 1
+        }
+    }
+
+    // the key scale level attenuation (in 0.375 db units) for the given ksl setting (0-3)
+    fn calc_key_scale_level(&self, ksl: u8) -> u16 {
+        if ksl == 0 {
+            return 0;
+        }
+
+        let level = KSL_TABLE[(self.fnum >> 5) as usize] as i16 - 8 * (7 - self.block as i16);
+        (level.max(0) as u16) >> (3 - ksl)
+    }
+
+    // advances operator 'op_idx' (0 = modulator, 1 = carrier) by one sample and
+    // returns its new output. 'modulation' is added to the phase (in units of 1/1024
+    // of a period), and 'base_attenuation' is the total level or volume
+    #[allow(clippy::too_many_arguments)]
+    fn clock_operator(
+        &mut self,
+        op_idx: usize,
+        patch: &[u8; 8],
+        modulation: i32,
+        base_attenuation: u16,
+        am_level: u16,
+        vibrato: i32,
+        sample_counter: u32,
+    ) -> i16 {
+        let flags = patch[op_idx];
+        let is_am = flags & 0x80 != 0;
+        let is_vibrato = flags & 0x40 != 0;
+        let is_sustained = flags & 0x20 != 0;
+        let is_ksr = flags & 0x10 != 0;
+        let multiplier = MULTIPLIERS[(flags & 0xf) as usize];
+
+        let ksl = patch[2 + op_idx] >> 6;
+        let attack_rate = patch[4 + op_idx] >> 4;
+        let decay_rate = patch[4 + op_idx] & 0xf;
+        let sustain_level = (patch[6 + op_idx] >> 4) as u16;
+        let release_rate = patch[6 + op_idx] & 0xf;
+        // bit 3 (modulator) and 4 (carrier) of register 3 select a half-wave rectified sine
+        let is_half_sine = patch[3] & (0b1000 << op_idx) != 0;
+
+        let key_scale_rate = self.calc_key_scale_rate(is_ksr);
+        let key_scale_level = self.calc_key_scale_level(ksl);
+        let sustain = self.sustain;
+        let fnum = self.fnum as i32;
+        let block = self.block;
+
+        let op = &mut self.operators[op_idx];
+
+        // phase generator
+        let fnum = if is_vibrato {
+            fnum + ((fnum * vibrato) >> 8)
+        } else {
+            fnum
+        };
+        let phase_increment = ((fnum as u32 * multiplier) << block) >> 1;
+        op.phase = (op.phase + phase_increment) & 0x7ffff;
+
+        // envelope generator
+        let rate = match op.envelope_state {
+            ATTACK => attack_rate,
+            DECAY => decay_rate,
+            SUSTAIN if is_sustained => 0,
+            SUSTAIN => release_rate,
+            _ if sustain => 5,
+            _ if is_sustained => release_rate,
+            _ => 7,
+        };
+        let effective_rate = if rate == 0 {
+            0
+        } else {
+            (rate * 4 + key_scale_rate).min(63)
+        };
+        let step = calc_envelope_step(effective_rate, sample_counter) as u16;
+
+        match op.envelope_state {
+            ATTACK => {
+                if effective_rate >= 60 {
+                    op.envelope = 0;
+                } else if step > 0 {
+                    // the attack is exponential
+                    let decrement = (((op.envelope + 1) * step) >> 3).max(1);
+                    op.envelope = op.envelope.saturating_sub(decrement);
+                }
+
+                if op.envelope == 0 {
+                    op.envelope_state = DECAY;
+                }
+            }
+            DECAY => {
+                op.envelope = (op.envelope + step).min(MAX_ATTENUATION);
+                if op.envelope >= sustain_level * 8 {
+                    op.envelope_state = SUSTAIN;
+                }
+            }
+            _ => op.envelope = (op.envelope + step).min(MAX_ATTENUATION),
+        }
+
+        // operator output
+        let mut attenuation = op.envelope + base_attenuation + key_scale_level;
+        if is_am {
+            attenuation += am_level;
+        }
+
+        let phase = (((op.phase >> 9) as i32 + modulation) & 0x3ff) as u16;
+        let output = calc_operator_output(phase, attenuation.min(MAX_ATTENUATION), is_half_sine);
+
+        op.prev_output = op.output;
+        op.output = output;
+        output
+    }
+}
+
+// the amount to increase the envelope attenuation by (or to base the
+// exponential attack decrement on) at 'rate' (0-63) for the current sample
+fn calc_envelope_step(rate: u8, sample_counter: u32) -> u8 {
+    if rate == 0 {
+        return 0;
+    }
+
+    let increments = &ENVELOPE_INCREMENTS[(rate & 0b11) as usize];
+    let rate_high = rate >> 2;
+
+    if rate_high < 13 {
+        // lower rates only step on every 2^n samples
+        let shift = 13 - rate_high;
+        if sample_counter & ((1 << shift) - 1) != 0 {
+            return 0;
+        }
+
+        increments[((sample_counter >> shift) & 0b111) as usize]
+    } else {
+        increments[(sample_counter & 0b111) as usize] << (rate_high - 12)
+    }
+}
+
+// calculates the output of an operator at 'phase' (0-0x3ff) with 'attenuation'
+// (in 0.375 db units). the result is a 13-bit signed value
+fn calc_operator_output(phase: u16, attenuation: u16, is_half_sine: bool) -> i16 {
+    let is_negative = phase & 0x200 != 0;
+    if (is_negative && is_half_sine) || attenuation >= MAX_ATTENUATION {
+        return 0;
+    }
+
+    // both the sine and the attenuation are combined in the log domain (in
+    // units of 1/256 of a halving), and then converted back through 2^-x
+    let quarter_phase = if phase & 0x100 != 0 {
+        !phase & 0xff
+    } else {
+        phase & 0xff
+    };
+    let log_sin = -((quarter_phase as f64 + 0.5) * std::f64::consts::PI / 512.0)
+        .sin()
+        .log2()
+        * 256.0;
+    let total = log_sin + (attenuation as f64) * 16.0;
+
+    let magnitude = (2f64.powf(-total / 256.0) * 4095.0) as i16;
+    if is_negative {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+impl ExpansionAudio for Opll {
+    fn clock(&mut self) {
+        if self.reset {
+            return;
+        }
+
+        self.divider += 1;
+        if self.divider == SAMPLE_CYCLES {
+            self.divider = 0;
+            self.generate_sample();
+        }
+    }
+
+    fn output(&self) -> f32 {
+        // a single channel at full volume peaks at about the level of an apu pulse channel
+        self.output as f32 / 4095.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(opll: &mut Opll, reg: u8, val: u8) {
+        opll.select_register(reg);
+        opll.write_register(val);
+    }
+
+    fn run_samples(opll: &mut Opll, n_samples: usize) -> Vec<i32> {
+        let mut samples = Vec::new();
+        for _ in 0..n_samples {
+            for _ in 0..SAMPLE_CYCLES {
+                opll.clock();
+            }
+            samples.push(opll.output);
+        }
+        samples
+    }
+
+    #[test]
+    fn test_operator_output() {
+        assert_eq!(calc_operator_output(0x100, 0, false), 4094);
+        assert_eq!(calc_operator_output(0x300, 0, false), -4094);
+        assert_eq!(calc_operator_output(0x300, 0, true), 0);
+        // 16 steps of 0.375 db halve the output
+        assert_eq!(calc_operator_output(0x100, 16, false), 2047);
+        assert_eq!(calc_operator_output(0x100, MAX_ATTENUATION, false), 0);
+    }
+
+    #[test]
+    fn test_key_on_off() {
+        let mut opll = Opll::default();
+
+        // custom patch: pure sine carrier with instant attack, no decay and
+        // moderate release (the modulator never leaves its attack phase)
+        write(&mut opll, 0x00, 0x21);
+        write(&mut opll, 0x01, 0x21);
+        write(&mut opll, 0x02, 0x3f);
+        write(&mut opll, 0x03, 0x00);
+        write(&mut opll, 0x04, 0x00);
+        write(&mut opll, 0x05, 0xf0);
+        write(&mut opll, 0x06, 0x08);
+        write(&mut opll, 0x07, 0x08);
+
+        // channel 0: f-number 0x100, block 4, custom patch at full volume
+        write(&mut opll, 0x10, 0x00);
+        write(&mut opll, 0x30, 0x00);
+        assert!(run_samples(&mut opll, 16).iter().all(|&s| s == 0));
+
+        write(&mut opll, 0x20, 0x10 | (4 << 1) | 1);
+        let samples = run_samples(&mut opll, 256);
+        assert!(samples.iter().any(|&s| s > 4000));
+        assert!(samples.iter().any(|&s| s < -4000));
+
+        // one period of the wave takes 2^19 / ((0x100 * 2 << 4) >> 1) = 128 samples,
+        // with the phase having advanced by one step by the first sample
+        let first_half = &samples[127..191];
+        let second_half = &samples[191..255];
+        assert!(first_half.iter().all(|&s| s >= 0));
+        assert!(second_half.iter().all(|&s| s <= 0));
+
+        // release fades the channel out
+        write(&mut opll, 0x20, (4 << 1) | 1);
+        run_samples(&mut opll, 0x8000);
+        assert_eq!(opll.channels[0].operators[1].envelope, MAX_ATTENUATION);
+        assert!(run_samples(&mut opll, 256).iter().all(|&s| s == 0));
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut opll = Opll::default();
+
+        write(&mut opll, 0x30, 0x30);
+        write(&mut opll, 0x20, 0x18);
+        opll.set_reset(true);
+        write(&mut opll, 0x10, 0x55);
+
+        assert_eq!(opll.channels[0].fnum, 0);
+        assert!(!opll.channels[0].key_on);
+        assert_eq!(opll.output(), 0.0);
+    }
+}
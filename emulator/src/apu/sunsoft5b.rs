@@ -0,0 +1,281 @@
+use super::ExpansionAudio;
+
+#[macro_use]
+use derive_serialize::Serialize;
+
+// the sound chip of the sunsoft 5b (a licensed variant of the yamaha ym2149f,
+// which is in turn a clone of the general instrument ay-3-8910). it has three
+// square wave channels that can each be mixed with a shared noise generator and
+// have their volume controlled by a shared envelope generator
+#[derive(Serialize, Default)]
+pub struct Sunsoft5bAudio {
+    // the register selected through 0xc000
+    selected_register: u8,
+    tones: [Sunsoft5bTone; 3],
+    noise_period: u8,
+    noise_counter: u8,
+    // 17-bit linear feedback shift register
+    noise_shift: u32,
+    // bits 0-2 disable the tone and bits 3-5 the noise of each channel
+    mixer: u8,
+    // bits 0-3 are the volume of each channel, and bit 4 selects the envelope instead
+    volumes: [u8; 3],
+    envelope_period: u16,
+    envelope_counter: u16,
+    // the envelope shape (bit 0 = hold, bit 1 = alternate, bit 2 = attack, bit 3 = continue)
+    envelope_shape: u8,
+    // the current envelope level (0-31)
+    envelope_level: u8,
+    envelope_rising: bool,
+    envelope_holding: bool,
+    // counts cpu cycles. tones and noise are clocked every 16 cpu
+    // cycles, and the envelope every 8 cpu cycles
+    divider: u8,
+    // the noise generator only steps on every other tone clock
+    noise_half_clock: bool,
+}
+
+#[derive(Serialize, Default, Clone, Copy)]
+struct Sunsoft5bTone {
+    period: u16,
+    counter: u16,
+    // the current output of the square wave (0 or 1)
+    output: u8,
+}
+
+impl Sunsoft5bAudio {
+    // handles writes to 0xc000-0xdfff
+    pub fn select_register(&mut self, val: u8) {
+        self.selected_register = val;
+    }
+
+    // handles writes to 0xe000-0xffff
+    pub fn write_register(&mut self, val: u8) {
+        // NOTE: writes are ignored unless the upper 4 bits of the selected register are 0
+        match self.selected_register {
+            0x0 | 0x2 | 0x4 => {
+                let tone = &mut self.tones[(self.selected_register >> 1) as usize];
+                tone.period = (tone.period & 0xf00) | val as u16;
+            }
+            0x1 | 0x3 | 0x5 => {
+                let tone = &mut self.tones[(self.selected_register >> 1) as usize];
+                tone.period = (tone.period & 0xff) | ((val & 0xf) as u16) << 8;
+            }
+            0x6 => self.noise_period = val & 0x1f,
+            0x7 => self.mixer = val,
+            0x8..=0xa => self.volumes[(self.selected_register - 8) as usize] = val & 0x1f,
+            0xb => self.envelope_period = (self.envelope_period & 0xff00) | val as u16,
+            0xc => self.envelope_period = (self.envelope_period & 0xff) | (val as u16) << 8,
+            0xd => {
+                // writing the shape restarts the envelope
+                self.envelope_shape = val & 0xf;
+                self.envelope_rising = val & 0b100 != 0;
+                self.envelope_level = if self.envelope_rising { 0 } else { 31 };
+                self.envelope_holding = false;
+                self.envelope_counter = 0;
+            }
+            _ => (),
+        }
+    }
+
+    fn clock_noise(&mut self) {
+        self.noise_counter += 1;
+        if self.noise_counter >= self.noise_period.max(1) {
+            self.noise_counter = 0;
+
+            if self.noise_shift == 0 {
+                self.noise_shift = 1;
+            }
+
+            // taps at bits 0 and 3
+            let feedback = (self.noise_shift ^ (self.noise_shift >> 3)) & 1;
+            self.noise_shift = (self.noise_shift >> 1) | (feedback << 16);
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        self.envelope_counter += 1;
+        if self.envelope_counter < self.envelope_period.max(1) {
+            return;
+        }
+        self.envelope_counter = 0;
+
+        if self.envelope_holding {
+            return;
+        }
+
+        let is_end_of_ramp = if self.envelope_rising {
+            self.envelope_level == 31
+        } else {
+            self.envelope_level == 0
+        };
+
+        if !is_end_of_ramp {
+            if self.envelope_rising {
+                self.envelope_level += 1;
+            } else {
+                self.envelope_level -= 1;
+            }
+
+            return;
+        }
+
+        let shape = self.envelope_shape;
+        if shape & 0b1000 == 0 {
+            // shapes without 'continue' drop to 0 and stay there after a single ramp
+            self.envelope_level = 0;
+            self.envelope_holding = true;
+        } else if shape & 0b1 != 0 {
+            // hold at the end of the ramp (or at the start, when alternating)
+            if shape & 0b10 != 0 {
+                self.envelope_level ^= 31;
+            }
+            self.envelope_holding = true;
+        } else if shape & 0b10 != 0 {
+            // alternate between ramping up and down
+            self.envelope_rising = !self.envelope_rising;
+        } else {
+            // repeat the ramp
+            self.envelope_level ^= 31;
+        }
+    }
+
+    // the 5-bit level of 'channel' (0 is silent, and each step is 1.5 db)
+    fn calc_channel_level(&self, channel: usize) -> u8 {
+        let tone_disabled = self.mixer & (1 << channel) != 0;
+        let noise_disabled = self.mixer & (0b1000 << channel) != 0;
+
+        let tone_on = tone_disabled || self.tones[channel].output != 0;
+        let noise_on = noise_disabled || self.noise_shift & 1 != 0;
+        if !tone_on || !noise_on {
+            return 0;
+        }
+
+        let volume = self.volumes[channel];
+        if volume & 0x10 != 0 {
+            self.envelope_level
+        } else if volume & 0xf == 0 {
+            0
+        } else {
+            // the 4-bit volumes map to every other envelope level (3 db steps)
+            ((volume & 0xf) << 1) | 1
+        }
+    }
+}
+
+impl ExpansionAudio for Sunsoft5bAudio {
+    fn clock(&mut self) {
+        self.divider = (self.divider + 1) & 0xf;
+
+        if self.divider & 0b111 == 0 {
+            self.clock_envelope();
+        }
+
+        if self.divider != 0 {
+            return;
+        }
+
+        for tone in self.tones.iter_mut() {
+            tone.counter += 1;
+            if tone.counter >= tone.period.max(1) {
+                tone.counter = 0;
+                tone.output ^= 1;
+            }
+        }
+
+        self.noise_half_clock = !self.noise_half_clock;
+        if self.noise_half_clock {
+            self.clock_noise();
+        }
+    }
+
+    fn output(&self) -> f32 {
+        // the volume curve is logarithmic, with level 31 being about as
+        // loud as an apu pulse channel at full volume
+        (0..3)
+            .map(|channel| match self.calc_channel_level(channel) {
+                0 => 0.0,
+                level => 10f32.powf((level as f32 - 31.0) * 1.5 / 20.0),
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(audio: &mut Sunsoft5bAudio, register: u8, val: u8) {
+        audio.select_register(register);
+        audio.write_register(val);
+    }
+
+    #[test]
+    fn test_tone() {
+        let mut audio = Sunsoft5bAudio::default();
+
+        // channel a: period 2, volume 15, tone only
+        write(&mut audio, 0x0, 2);
+        write(&mut audio, 0x1, 0);
+        write(&mut audio, 0x7, 0b111_110);
+        write(&mut audio, 0x8, 15);
+
+        // the square wave toggles every 2 * 16 cpu cycles
+        let mut levels = Vec::new();
+        for _ in 0..4 {
+            for _ in 0..32 {
+                audio.clock();
+            }
+            levels.push(audio.calc_channel_level(0));
+        }
+        assert_eq!(levels, [31, 0, 31, 0]);
+        assert_eq!(audio.calc_channel_level(1), 0);
+
+        // each volume step is 3 db
+        write(&mut audio, 0x8, 14);
+        for _ in 0..32 {
+            audio.clock();
+        }
+        let db = 20.0 * audio.output().log10();
+        assert!((db + 3.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_envelope() {
+        let mut audio = Sunsoft5bAudio::default();
+
+        // ramp up once, then hold at the top ('/¯')
+        write(&mut audio, 0xb, 1);
+        write(&mut audio, 0xc, 0);
+        write(&mut audio, 0xd, 0b1101);
+        assert_eq!(audio.envelope_level, 0);
+
+        for _ in 0..(31 * 8) {
+            audio.clock();
+        }
+        assert_eq!(audio.envelope_level, 31);
+
+        for _ in 0..(8 * 8) {
+            audio.clock();
+        }
+        assert_eq!(audio.envelope_level, 31);
+        assert!(audio.envelope_holding);
+
+        // ramp down, then drop to 0 ('\_')
+        write(&mut audio, 0xd, 0b0000);
+        for _ in 0..(40 * 8) {
+            audio.clock();
+        }
+        assert_eq!(audio.envelope_level, 0);
+
+        // triangle ('/\/\')
+        write(&mut audio, 0xd, 0b1110);
+        for _ in 0..(32 * 8) {
+            audio.clock();
+        }
+        assert!(!audio.envelope_rising);
+        assert_eq!(audio.envelope_level, 31);
+        audio.clock_envelope();
+        assert_eq!(audio.envelope_level, 30);
+    }
+}
@@ -0,0 +1,238 @@
+use super::ExpansionAudio;
+
+#[macro_use]
+use derive_serialize::Serialize;
+
+// the sound of konami's vrc6: two pulse channels with 16-step duty cycles and
+// 4-bit volume, and a sawtooth channel built from a 6-bit accumulator
+#[derive(Serialize, Default)]
+pub struct Vrc6Audio {
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    saw: Vrc6Saw,
+    // 0x9003 (bit 0 = halt all channels, bits 1-2 = divide the periods by 16 or 256)
+    frequency_control: u8,
+}
+
+#[derive(Serialize, Default)]
+struct Vrc6Pulse {
+    // 0x9000/0xa000 (bit 7 = ignore duty, bits 4-6 = duty, bits 0-3 = volume)
+    control: u8,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    // counts down from 15, with the channel outputting while it's <= the duty
+    duty_step: u8,
+}
+
+#[derive(Serialize, Default)]
+struct Vrc6Saw {
+    // 0xb000 (bits 0-5)
+    rate: u8,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    // the accumulator is added to on every other step, and reset on the 14th
+    step: u8,
+    accumulator: u8,
+}
+
+// the period registers of both channel types: the low 8 bits, followed by the
+// enable flag (bit 7) and the high 4 bits
+fn write_period(period: &mut u16, enabled: &mut bool, index: u16, val: u8) {
+    if index == 1 {
+        *period = (*period & 0xf00) | val as u16;
+    } else {
+        *period = (*period & 0xff) | ((val & 0xf) as u16) << 8;
+        *enabled = val & 0x80 != 0;
+    }
+}
+
+impl Vrc6Pulse {
+    fn write_register(&mut self, index: u16, val: u8) {
+        match index {
+            0 => self.control = val,
+            _ => {
+                write_period(&mut self.period, &mut self.enabled, index, val);
+                if !self.enabled {
+                    self.duty_step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.duty_step = self.duty_step.wrapping_sub(1) & 0xf;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        let ignore_duty = self.control & 0x80 != 0;
+        let duty = (self.control >> 4) & 0b111;
+        if self.enabled && (ignore_duty || self.duty_step <= duty) {
+            self.control & 0xf
+        } else {
+            0
+        }
+    }
+}
+
+impl Vrc6Saw {
+    fn write_register(&mut self, index: u16, val: u8) {
+        match index {
+            0 => self.rate = val & 0x3f,
+            _ => {
+                write_period(&mut self.period, &mut self.enabled, index, val);
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step & 1 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        // the high 5 bits of the accumulator
+        self.accumulator >> 3
+    }
+}
+
+impl Vrc6Audio {
+    // handles writes to 0x9000-0x9003, 0xa000-0xa002 and 0xb000-0xb002. 'addr'
+    // is expected to have the address lines swapped by the board undone
+    pub fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x9000..=0x9002 => self.pulse1.write_register(addr & 0b11, val),
+            0x9003 => self.frequency_control = val,
+            0xa000..=0xa002 => self.pulse2.write_register(addr & 0b11, val),
+            0xb000..=0xb002 => self.saw.write_register(addr & 0b11, val),
+            _ => (),
+        }
+    }
+}
+
+impl ExpansionAudio for Vrc6Audio {
+    fn clock(&mut self) {
+        if self.frequency_control & 1 != 0 {
+            return;
+        }
+
+        let shift = if self.frequency_control & 0b100 != 0 {
+            8
+        } else if self.frequency_control & 0b10 != 0 {
+            4
+        } else {
+            0
+        };
+
+        self.pulse1.clock(shift);
+        self.pulse2.clock(shift);
+        self.saw.clock(shift);
+    }
+
+    fn output(&self) -> f32 {
+        // the pulse channels are about as loud as those of the apu (and mixed
+        // linearly), with the sawtooth reaching roughly twice that
+        (self.pulse1.output() + self.pulse2.output() + self.saw.output()) as f32 / 15.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pulse() {
+        let mut audio = Vrc6Audio::default();
+
+        // volume 10, duty 3 (4/16), period 1 (a step every 2 cycles)
+        audio.write_register(0x9000, 0x3a);
+        audio.write_register(0x9001, 1);
+        audio.write_register(0x9002, 0x80);
+
+        let mut outputs = Vec::new();
+        for _ in 0..32 {
+            audio.clock();
+            outputs.push(audio.pulse1.output());
+        }
+        assert_eq!(outputs.iter().filter(|&&output| output == 10).count(), 8);
+        assert_eq!(outputs.iter().filter(|&&output| output == 0).count(), 24);
+
+        // the duty is ignored in mode 1
+        audio.write_register(0x9000, 0x8a);
+        assert_eq!(audio.output(), 10.0 / 15.0);
+
+        audio.write_register(0x9002, 0);
+        assert_eq!(audio.output(), 0.0);
+    }
+
+    #[test]
+    fn test_saw() {
+        let mut audio = Vrc6Audio::default();
+
+        audio.write_register(0xb000, 0x2a);
+        audio.write_register(0xb001, 0);
+        audio.write_register(0xb002, 0x80);
+
+        let mut accumulators = Vec::new();
+        for _ in 0..14 {
+            audio.clock();
+            accumulators.push(audio.saw.accumulator);
+        }
+        assert_eq!(
+            accumulators,
+            [0, 42, 42, 84, 84, 126, 126, 168, 168, 210, 210, 252, 252, 0]
+        );
+        assert_eq!(audio.saw.output(), 0);
+    }
+
+    #[test]
+    fn test_frequency_control() {
+        let mut audio = Vrc6Audio::default();
+
+        audio.write_register(0xb000, 0x3f);
+        audio.write_register(0xb002, 0x81);
+
+        // periods are divided by 256 (0x100 -> 1, so the saw steps every 2 cycles)
+        audio.write_register(0x9003, 0b100);
+        for _ in 0..4 {
+            audio.clock();
+        }
+        assert_eq!(audio.saw.step, 2);
+
+        // halted
+        audio.write_register(0x9003, 0b101);
+        for _ in 0..4 {
+            audio.clock();
+        }
+        assert_eq!(audio.saw.step, 2);
+    }
+}
@@ -0,0 +1,280 @@
+use crate::{hash, inflate};
+
+// the extensions of the files that are extracted from zip archives (if no entry is given)
+static ROM_EXTENSIONS: &[&str] = &["nes", "unf", "fds", "nsf", "nsfe"];
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, String> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| "unexpected end of archive".to_string())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| "unexpected end of archive".to_string())
+}
+
+fn is_zip(data: &[u8]) -> bool {
+    data.starts_with(b"PK\x03\x04")
+}
+
+fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&[0x1f, 0x8b])
+}
+
+// returns the rom stored in a zip or gzip archive. 'entry_name' selects the file to
+// extract from zip archives, otherwise the first file with a rom extension is used.
+// anything that isn't an archive is returned as-is
+pub fn extract_rom(data: Vec<u8>, entry_name: Option<&str>) -> Result<Vec<u8>, String> {
+    if is_zip(&data) {
+        extract_zip_entry(&data, entry_name)
+    } else if is_gzip(&data) {
+        extract_gzip(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+fn has_rom_extension(name: &str) -> bool {
+    match name.rsplit_once('.') {
+        Some((_, extension)) => ROM_EXTENSIONS
+            .iter()
+            .any(|rom_extension| extension.eq_ignore_ascii_case(rom_extension)),
+        None => false,
+    }
+}
+
+// a file in the central directory of a zip archive
+struct ZipEntry<'d> {
+    name: String,
+    method: u16,
+    crc32: u32,
+    compressed_size: usize,
+    uncompressed_size: usize,
+    local_header: &'d [u8],
+}
+
+// zip archives end with the 'end of central directory' record, which points to the central
+// directory (a list of all files). each entry in the central directory points to a local
+// header, which is followed by the (possibly compressed) data
+fn read_zip_entries(data: &[u8]) -> Result<Vec<ZipEntry<'_>>, String> {
+    // the record is at least 22 bytes, followed by a comment of up to 64 KB
+    let end_of_central_dir = (0..=data.len().saturating_sub(22))
+        .rev()
+        .take(0x10000)
+        .find(|&offset| data[offset..].starts_with(b"PK\x05\x06"))
+        .ok_or("zip archive is missing its central directory")?;
+
+    let n_entries = read_u16(data, end_of_central_dir + 10)? as usize;
+    let mut offset = read_u32(data, end_of_central_dir + 16)? as usize;
+    let mut entries = Vec::with_capacity(n_entries);
+
+    for _ in 0..n_entries {
+        if !data[offset.min(data.len())..].starts_with(b"PK\x01\x02") {
+            return Err("invalid zip central directory entry".to_string());
+        }
+
+        let name_len = read_u16(data, offset + 28)? as usize;
+        let extra_len = read_u16(data, offset + 30)? as usize;
+        let comment_len = read_u16(data, offset + 32)? as usize;
+        let local_header_offset = read_u32(data, offset + 42)? as usize;
+        let name = data
+            .get(offset + 46..offset + 46 + name_len)
+            .ok_or("unexpected end of archive")?;
+
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            method: read_u16(data, offset + 10)?,
+            crc32: read_u32(data, offset + 16)?,
+            compressed_size: read_u32(data, offset + 20)? as usize,
+            uncompressed_size: read_u32(data, offset + 24)? as usize,
+            local_header: data
+                .get(local_header_offset..)
+                .ok_or("unexpected end of archive")?,
+        });
+
+        offset += 46 + name_len + extra_len + comment_len;
+    }
+
+    Ok(entries)
+}
+
+fn extract_zip_entry(data: &[u8], entry_name: Option<&str>) -> Result<Vec<u8>, String> {
+    let entries = read_zip_entries(data)?;
+    let entry = match entry_name {
+        Some(entry_name) => entries
+            .iter()
+            .find(|entry| entry.name == entry_name)
+            .ok_or_else(|| format!("zip archive doesn't contain '{}'", entry_name))?,
+        None => entries
+            .iter()
+            .find(|entry| has_rom_extension(&entry.name))
+            .ok_or("zip archive doesn't contain a rom (.nes, .unf, .fds, .nsf or .nsfe file)")?,
+    };
+    logln!("extracting '{}' from zip archive", entry.name);
+
+    if entry.compressed_size == 0xffffffff || entry.uncompressed_size == 0xffffffff {
+        return Err("zip64 archives are not supported".to_string());
+    }
+
+    let local_header = entry.local_header;
+    if !local_header.starts_with(b"PK\x03\x04") {
+        return Err("invalid zip local header".to_string());
+    }
+
+    // the local header has its own copies of the name and extra field lengths
+    let data_start =
+        30 + read_u16(local_header, 26)? as usize + read_u16(local_header, 28)? as usize;
+    let compressed = local_header
+        .get(data_start..data_start + entry.compressed_size)
+        .ok_or("unexpected end of archive")?;
+
+    let uncompressed = match entry.method {
+        0 => compressed.to_vec(),
+        8 => inflate::inflate(compressed)?,
+        method => return Err(format!("unsupported zip compression method {}", method)),
+    };
+
+    if uncompressed.len() != entry.uncompressed_size || hash::crc32(&uncompressed) != entry.crc32 {
+        return Err(format!("'{}' is corrupted (checksum mismatch)", entry.name));
+    }
+
+    Ok(uncompressed)
+}
+
+// gzip files hold a single file: a 10 byte header (plus optional fields), the deflate
+// stream, and the crc32 and size of the uncompressed data
+fn extract_gzip(data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < 18 || data[2] != 8 {
+        return Err("invalid gzip header".to_string());
+    }
+
+    let flags = data[3];
+    let mut offset = 10;
+    // extra field
+    if flags & 0b100 != 0 {
+        offset += 2 + read_u16(data, offset)? as usize;
+    }
+    // file name and comment (null-terminated)
+    for &flag in [0b1000u8, 0b1_0000].iter() {
+        if flags & flag != 0 {
+            let len = data
+                .get(offset..)
+                .and_then(|rest| rest.iter().position(|&byte| byte == 0))
+                .ok_or("unexpected end of archive")?;
+            offset += len + 1;
+        }
+    }
+    // header crc
+    if flags & 0b10 != 0 {
+        offset += 2;
+    }
+
+    let trailer = data.len() - 8;
+    let compressed = data
+        .get(offset..trailer)
+        .ok_or("unexpected end of archive")?;
+    let uncompressed = inflate::inflate(compressed)?;
+
+    if hash::crc32(&uncompressed) != read_u32(data, trailer)?
+        || uncompressed.len() as u32 != read_u32(data, trailer + 4)?
+    {
+        return Err("gzip archive is corrupted (checksum mismatch)".to_string());
+    }
+
+    Ok(uncompressed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // builds a zip archive from (name, method, data, uncompressed data) entries
+    fn new_zip(entries: &[(&str, u16, &[u8], &[u8])]) -> Vec<u8> {
+        let mut zip = Vec::new();
+        let mut central_dir = Vec::new();
+
+        for &(name, method, data, uncompressed) in entries {
+            let mut header = Vec::new();
+            header.extend_from_slice(&[20, 0, 0, 0]);
+            header.extend_from_slice(&method.to_le_bytes());
+            header.extend_from_slice(&[0; 4]);
+            header.extend_from_slice(&hash::crc32(uncompressed).to_le_bytes());
+            header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(uncompressed.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            header.extend_from_slice(&[0, 0]);
+
+            central_dir.extend_from_slice(b"PK\x01\x02\x14\x00");
+            central_dir.extend_from_slice(&header);
+            central_dir.extend_from_slice(&[0; 10]);
+            central_dir.extend_from_slice(&(zip.len() as u32).to_le_bytes());
+            central_dir.extend_from_slice(name.as_bytes());
+
+            zip.extend_from_slice(b"PK\x03\x04");
+            zip.extend_from_slice(&header);
+            zip.extend_from_slice(name.as_bytes());
+            zip.extend_from_slice(data);
+        }
+
+        let central_dir_offset = zip.len() as u32;
+        zip.extend_from_slice(&central_dir);
+        zip.extend_from_slice(b"PK\x05\x06\x00\x00\x00\x00");
+        zip.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&(central_dir.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&central_dir_offset.to_le_bytes());
+        zip.extend_from_slice(&[0, 0]);
+
+        zip
+    }
+
+    #[test]
+    fn test_zip() {
+        // "hello, hello, hello nes", deflated
+        let deflated = [
+            0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0xd7, 0x51, 0xc8, 0x40, 0xa2, 0x14, 0xf2, 0x52, 0x8b,
+            0x01,
+        ];
+        let zip = new_zip(&[
+            ("readme.txt", 0, b"readme", b"readme"),
+            ("game.NES", 8, &deflated, b"hello, hello, hello nes"),
+            ("game2.nes", 0, b"second", b"second"),
+        ]);
+
+        assert_eq!(
+            extract_rom(zip.clone(), None).unwrap(),
+            b"hello, hello, hello nes"
+        );
+        assert_eq!(
+            extract_rom(zip.clone(), Some("game2.nes")).unwrap(),
+            b"second"
+        );
+        assert!(extract_rom(zip, Some("game3.nes")).is_err());
+
+        // checksum mismatch
+        let zip = new_zip(&[("game.nes", 0, b"data", b"date")]);
+        assert!(extract_rom(zip, None).is_err());
+
+        let zip = new_zip(&[("readme.txt", 0, b"readme", b"readme")]);
+        assert!(extract_rom(zip, None).is_err());
+    }
+
+    #[test]
+    fn test_gzip() {
+        let gzip = vec![
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xf3, 0x73, 0x0d, 0x96,
+            0x52, 0x48, 0xaf, 0x52, 0x28, 0x49, 0x2d, 0x2e, 0x01, 0x00, 0x06, 0x52, 0x71, 0x4b,
+            0x0c, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(extract_rom(gzip.clone(), None).unwrap(), b"NES\x1a gz test");
+
+        let mut corrupted = gzip;
+        corrupted[24] ^= 1;
+        assert!(extract_rom(corrupted, None).is_err());
+
+        // anything else is passed through
+        assert_eq!(extract_rom(b"NES\x1a".to_vec(), None).unwrap(), b"NES\x1a");
+    }
+}
@@ -0,0 +1,193 @@
+use crate::address_bus::CpuAddressBus;
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+// how often the battery-backed ram is written out while the game is running
+// (if it changed), so that a crash loses at most a few seconds of progress
+pub const FLUSH_INTERVAL_FRAMES: u32 = 60 * 5;
+
+// persists the cartridge's battery-backed ram (see 'CpuAddressBus::battery_backed_ram()')
+// to a '.sav' file, which is stored next to the rom unless a directory is given
+pub struct BatterySave {
+    path: PathBuf,
+    // the ram contents as of the last load or flush, used to skip redundant writes
+    last_flushed: Vec<u8>,
+    frames_since_flush: u32,
+}
+
+impl BatterySave {
+    pub fn new(rom_path: &Path, save_dir: Option<&Path>) -> Self {
+        let path = match (save_dir, rom_path.file_name()) {
+            (Some(dir), Some(file_name)) => dir.join(file_name).with_extension("sav"),
+            _ => rom_path.with_extension("sav"),
+        };
+
+        Self {
+            path,
+            last_flushed: Vec::new(),
+            frames_since_flush: 0,
+        }
+    }
+
+    // loads the contents of the battery-backed ram from the '.sav' file (if it exists)
+    pub fn load(&mut self, bus: &mut dyn CpuAddressBus) -> Result<(), String> {
+        let mut ram = bus.battery_backed_ram();
+        if ram.is_empty() {
+            return Ok(());
+        }
+
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.to_string()),
+        };
+
+        let len = ram.iter().map(|ram| ram.len()).sum::<usize>();
+        if data.len() != len {
+            return Err(format!(
+                "expected {} bytes, but the file was {} bytes",
+                len,
+                data.len()
+            ));
+        }
+
+        let mut remaining = &data[..];
+        for ram in ram.iter_mut() {
+            let (chunk, rest) = remaining.split_at(ram.len());
+            ram.copy_from_slice(chunk);
+            remaining = rest;
+        }
+
+        self.last_flushed = data;
+
+        Ok(())
+    }
+
+    // writes the battery-backed ram to the '.sav' file if it changed since the last flush
+    pub fn flush(&mut self, bus: &mut dyn CpuAddressBus) -> Result<(), String> {
+        self.frames_since_flush = 0;
+
+        let data = bus.battery_backed_ram().concat();
+        if data.is_empty() || data == self.last_flushed {
+            return Ok(());
+        }
+
+        write_atomically(&self.path, &data).map_err(|e| e.to_string())?;
+        self.last_flushed = data;
+
+        Ok(())
+    }
+
+    // called once per frame. flushes every 'FLUSH_INTERVAL_FRAMES' frames
+    pub fn end_frame(&mut self, bus: &mut dyn CpuAddressBus) -> Result<(), String> {
+        self.frames_since_flush += 1;
+        if self.frames_since_flush >= FLUSH_INTERVAL_FRAMES {
+            self.flush(bus)?;
+        }
+
+        Ok(())
+    }
+}
+
+// writes 'data' to a temporary file, and then renames it over 'path'. this way, 'path'
+// always contains either the old or the new save, even if the emulator crashes mid-write
+pub fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::address_bus::{NromCpuAddressBus, PrgRam};
+    use crate::{apu, controller as ctrl, parse, ppu};
+    use std::cell::Cell;
+
+    fn new_bus(framebuffer: &Cell<[u32; 256 * 240]>) -> NromCpuAddressBus<'_> {
+        NromCpuAddressBus::new(
+            &[0; 0x8000],
+            &[0; 0x2000],
+            0,
+            PrgRam::new(0x2000, 0x2000),
+            parse::MirroringType::Vert,
+            ppu::Ppu::new(),
+            apu::Apu::new(),
+            ctrl::Controller::default(),
+            unsafe { &*(framebuffer as *const _ as *const _) },
+        )
+    }
+
+    #[test]
+    fn test_save_path() {
+        let rom_path = Path::new("roms/zelda.nes");
+        assert_eq!(
+            BatterySave::new(rom_path, None).path,
+            Path::new("roms/zelda.sav")
+        );
+        assert_eq!(
+            BatterySave::new(rom_path, Some(Path::new("saves"))).path,
+            Path::new("saves/zelda.sav")
+        );
+    }
+
+    #[test]
+    fn test_flush_and_load() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut cpu = crate::cpu::Cpu::default();
+        let dir = std::env::temp_dir();
+        let rom_path = dir.join("nees_battery_save_test.nes");
+        let save_path = dir.join("nees_battery_save_test.sav");
+        let _ = fs::remove_file(&save_path);
+
+        let mut bus = new_bus(&framebuffer);
+        let mut save = BatterySave::new(&rom_path, None);
+        // nothing to load yet
+        save.load(&mut bus).unwrap();
+
+        bus.write(0x6000, 0x12, &mut cpu);
+        bus.write(0x7fff, 0x34, &mut cpu);
+
+        // only flushed once the interval has passed
+        for _ in 0..FLUSH_INTERVAL_FRAMES - 1 {
+            save.end_frame(&mut bus).unwrap();
+        }
+        assert!(!save_path.exists());
+        save.end_frame(&mut bus).unwrap();
+        assert_eq!(fs::metadata(&save_path).unwrap().len(), 0x2000);
+
+        let mut bus = new_bus(&framebuffer);
+        let mut save = BatterySave::new(&rom_path, None);
+        save.load(&mut bus).unwrap();
+        assert_eq!(bus.read(0x6000, &mut cpu), 0x12);
+        assert_eq!(bus.read(0x7fff, &mut cpu), 0x34);
+
+        fs::remove_file(&save_path).unwrap();
+        // unchanged ram isn't written again
+        save.flush(&mut bus).unwrap();
+        assert!(!save_path.exists());
+    }
+
+    #[test]
+    fn test_size_mismatch() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let dir = std::env::temp_dir();
+        let save_path = dir.join("nees_battery_save_size_test.sav");
+        fs::write(&save_path, [0; 0x400]).unwrap();
+
+        let mut bus = new_bus(&framebuffer);
+        let mut save = BatterySave::new(&save_path, None);
+        assert!(save.load(&mut bus).is_err());
+
+        fs::remove_file(&save_path).unwrap();
+    }
+}
@@ -2,10 +2,11 @@ macro_rules! bitfield {
     ( $name:ident<$field_type:ty> ($( $field:ident: $lower:literal..$upper:literal ),*)) => {
         #[allow(non_snake_case)]
         pub mod $name {
+            #[repr(C)]
             #[derive(Default, PartialEq, Debug, Eq)]
             pub struct Fields {
-                bits: $field_type,
                 $(pub $field: $field,)*
+                bits: $field_type,
             }
 
             const HIGHEST_BIT: $field_type = (::std::mem::size_of::<$field_type>() * 8 - 1) as $field_type;
@@ -62,7 +63,7 @@ macro_rules! bitfield {
                 }
 
                 #[inline]
-                #[allow(dead_code)]
+                #[allow(dead_code, clippy::too_many_arguments)]
                 pub fn new($($field: $field_type,)*) -> Self {
                     let mut new = Self::zeroed();
 
@@ -129,3 +130,29 @@ fn test() {
     bits.bool1.set(0);
     assert!(!bits.bool1.is_true());
 }
+
+// the field accessors cast a pointer to their (zero-sized) field to a pointer to
+// 'Fields', so every field has to sit at the very start of the struct, which only
+// '#[repr(C)]' with the zero-sized fields declared first guarantees
+#[test]
+fn test_layout() {
+    bitfield!(WideBits<u32>(
+        low: 0..7,
+        flag: 8..8,
+        high: 24..31,
+    ));
+
+    let mut bits = WideBits::BitField::zeroed();
+    let fields_addr = &*bits as *const _ as usize;
+    assert_eq!(&bits.low as *const _ as usize, fields_addr);
+    assert_eq!(&bits.flag as *const _ as usize, fields_addr);
+    assert_eq!(&bits.high as *const _ as usize, fields_addr);
+
+    bits.low.set(0x12);
+    bits.flag.set(1);
+    bits.high.set(0xab);
+    assert_eq!(bits.low.get(), 0x12);
+    assert!(bits.flag.is_true());
+    assert_eq!(bits.high.get(), 0xab);
+    assert_eq!(bits, WideBits::BitField::new(0x12, 1, 0xab));
+}
@@ -15,7 +15,7 @@ pub struct Cpu {
     pub p: u8,
     pub sp: u8,
     pub pc: u16,
-    pub cycle_count: i16,
+    pub cycle_count: i32,
     pub irq: u8,
     pub bits: CpuBits::BitField,
 }
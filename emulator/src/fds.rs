@@ -0,0 +1,321 @@
+use crate::address_bus::CpuAddressBus;
+use crate::{battery_save, patch};
+
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+// the size of a disk side in .fds images, which store the blocks of each side back to back
+pub const SIDE_SIZE: usize = 65500;
+
+// on the disk itself, blocks are separated by gaps (zero bits), start with a 0x80 start
+// mark and end with a crc. these are the gap sizes used by the bios when writing
+const LEAD_IN_GAP_BYTES: usize = 28300 / 8;
+const BLOCK_GAP_BYTES: usize = 976 / 8;
+
+// the length of a side once gaps and crcs are added, which is roughly the capacity of
+// a real disk. the drive reaches the end of the disk (and stops the motor) after this
+pub const RAW_SIDE_SIZE: usize = 80000;
+
+pub fn is_fds(rom: &[u8]) -> bool {
+    rom.starts_with(b"FDS\x1a") || rom.starts_with(b"\x01*NINTENDO-HVC*")
+}
+
+// returns the disk sides of an .fds image, which may start with a 16 byte fwNES header
+// ('FDS', 0x1a, the number of sides and padding). the side count in the header is
+// frequently wrong, so the number of sides is derived from the size of the image
+pub fn parse_sides(rom: &[u8]) -> Result<Vec<&[u8]>, String> {
+    let image = if rom.starts_with(b"FDS\x1a") {
+        rom.get(0x10..).unwrap_or_default()
+    } else {
+        rom
+    };
+
+    if image.len() < SIDE_SIZE {
+        return Err(format!(
+            "fds image is {} bytes, but a disk side is {} bytes",
+            image.len(),
+            SIDE_SIZE
+        ));
+    }
+    if image.len() % SIDE_SIZE != 0 {
+        logln!(
+            "ignoring {} bytes at the end of the fds image",
+            image.len() % SIDE_SIZE
+        );
+    }
+
+    Ok(image.chunks_exact(SIDE_SIZE).collect())
+}
+
+// the crc-16 (polynomial 0x8005, reflected) that the ram adapter calculates over each
+// block, including its start mark
+pub fn update_crc(mut crc: u16, byte: u8) -> u16 {
+    for bit in 0..8 {
+        let carry = crc & 1 != 0;
+        crc >>= 1;
+        if carry {
+            crc ^= 0x8408;
+        }
+        if byte & (1 << bit) != 0 {
+            crc ^= 0x8000;
+        }
+    }
+
+    crc
+}
+
+// returns the length of the block at the start of 'data' (which includes the block
+// type), or 'None' if it doesn't start with a valid block. file data blocks (4) take
+// their size from the preceding file header block (3)
+fn block_len(data: &[u8], file_size: usize) -> Option<usize> {
+    let len = match data.first()? {
+        1 => 56,
+        2 => 2,
+        3 => 16,
+        4 => 1 + file_size,
+        _ => return None,
+    };
+
+    if data.len() >= len {
+        Some(len)
+    } else {
+        None
+    }
+}
+
+// converts a side from an .fds image to the layout on an actual disk, as read by the drive
+pub fn add_gaps(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0; LEAD_IN_GAP_BYTES];
+    let mut pos = 0;
+    let mut file_size = 0;
+
+    while let Some(len) = block_len(&side[pos..], file_size) {
+        let block = &side[pos..pos + len];
+        if block[0] == 3 {
+            file_size = u16::from_le_bytes([block[13], block[14]]) as usize;
+        }
+
+        let crc = block
+            .iter()
+            .fold(update_crc(0, 0x80), |crc, &byte| update_crc(crc, byte));
+        // the crc is shifted out after two more (zero) bytes are fed into it
+        let crc = update_crc(update_crc(crc, 0), 0);
+
+        raw.push(0x80);
+        raw.extend_from_slice(block);
+        raw.extend_from_slice(&crc.to_le_bytes());
+        raw.resize(raw.len() + BLOCK_GAP_BYTES, 0);
+
+        pos += len;
+    }
+
+    raw.resize(raw.len().max(RAW_SIDE_SIZE), 0);
+    raw
+}
+
+// converts a side back from the layout on the disk (see 'add_gaps()') to the layout
+// used by .fds images, dropping the gaps, start marks and crcs
+pub fn remove_gaps(raw: &[u8]) -> Vec<u8> {
+    let mut side = Vec::with_capacity(SIDE_SIZE);
+    let mut pos = 0;
+    let mut file_size = 0;
+
+    // each block is preceded by a gap, which ends with the 0x80 start mark
+    while let Some(gap_len) = raw[pos..].iter().position(|&byte| byte != 0) {
+        pos += gap_len;
+        if raw[pos] != 0x80 {
+            break;
+        }
+        pos += 1;
+
+        let len = match block_len(&raw[pos..], file_size) {
+            Some(len) => len,
+            None => break,
+        };
+        let block = &raw[pos..pos + len];
+        if block[0] == 3 {
+            file_size = u16::from_le_bytes([block[13], block[14]]) as usize;
+        }
+
+        side.extend_from_slice(block);
+        pos = (pos + len + 2).min(raw.len());
+    }
+
+    side.resize(SIDE_SIZE, 0);
+    side
+}
+
+// stores the changes the game makes to the disk as an ips patch against the original
+// image, which is kept next to the rom ('[rom].disk.ips') unless a directory is given.
+// this leaves the image itself untouched, like a soft patch (see 'patch')
+pub struct DiskSave {
+    path: PathBuf,
+    // the disk sides of the rom file, without the fwNES header
+    original: Vec<u8>,
+    // the disk contents as of the last load or flush, used to skip redundant writes
+    last_flushed: Vec<u8>,
+    frames_since_flush: u32,
+}
+
+impl DiskSave {
+    pub fn new(rom_path: &Path, save_dir: Option<&Path>, original: Vec<u8>) -> Self {
+        let path = match (save_dir, rom_path.file_name()) {
+            (Some(dir), Some(file_name)) => dir.join(file_name).with_extension("disk.ips"),
+            _ => rom_path.with_extension("disk.ips"),
+        };
+
+        Self {
+            path,
+            last_flushed: original.clone(),
+            original,
+            frames_since_flush: 0,
+        }
+    }
+
+    // returns the disk image with the saved changes (if any) applied
+    pub fn load(&mut self) -> Result<Vec<u8>, String> {
+        let diff = match fs::read(&self.path) {
+            Ok(diff) => diff,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(self.original.clone()),
+            Err(e) => return Err(e.to_string()),
+        };
+
+        let image = patch::apply(&self.original, &diff)?;
+        if image.len() != self.original.len() {
+            return Err(format!(
+                "expected the disk image to stay {} bytes, but it was {} bytes after patching",
+                self.original.len(),
+                image.len()
+            ));
+        }
+
+        self.last_flushed = image.clone();
+
+        Ok(image)
+    }
+
+    // writes the changes to the disk if it was written to since the last flush
+    pub fn flush(&mut self, bus: &mut dyn CpuAddressBus) -> Result<(), String> {
+        self.frames_since_flush = 0;
+
+        let image = match bus.disk_drive() {
+            Some(drive) => drive.image(),
+            None => return Ok(()),
+        };
+        if image == self.last_flushed {
+            return Ok(());
+        }
+
+        let diff = patch::create_ips(&self.original, &image);
+        battery_save::write_atomically(&self.path, &diff).map_err(|e| e.to_string())?;
+        self.last_flushed = image;
+
+        Ok(())
+    }
+
+    // called once per frame. flushes as often as battery saves are
+    pub fn end_frame(&mut self, bus: &mut dyn CpuAddressBus) -> Result<(), String> {
+        self.frames_since_flush += 1;
+        if self.frames_since_flush >= battery_save::FLUSH_INTERVAL_FRAMES {
+            self.flush(bus)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // builds a side holding a single file with the given contents
+    pub fn new_side(file: &[u8]) -> Vec<u8> {
+        let mut side = vec![1];
+        side.extend_from_slice(b"*NINTENDO-HVC*");
+        side.resize(56, 0);
+        side.extend_from_slice(&[2, 1]);
+
+        let mut file_header = vec![3, 0, 0];
+        file_header.extend_from_slice(b"TESTFILE");
+        file_header.extend_from_slice(&[0x00, 0x60]);
+        file_header.extend_from_slice(&(file.len() as u16).to_le_bytes());
+        file_header.push(0);
+        side.extend_from_slice(&file_header);
+        side.push(4);
+        side.extend_from_slice(file);
+
+        side.resize(SIDE_SIZE, 0);
+        side
+    }
+
+    #[test]
+    fn test_parse_sides() {
+        let side = new_side(&[1, 2, 3]);
+        let mut image = b"FDS\x1a\x02".to_vec();
+        image.resize(0x10, 0);
+        image.extend_from_slice(&side);
+        image.extend_from_slice(&side);
+
+        assert!(is_fds(&image));
+        assert_eq!(parse_sides(&image).unwrap().len(), 2);
+        assert!(is_fds(&side));
+        assert_eq!(parse_sides(&side).unwrap(), [&side[..]]);
+        assert!(parse_sides(&side[..0x100]).is_err());
+    }
+
+    #[test]
+    fn test_disk_save() {
+        use crate::address_bus::FdsCpuAddressBus;
+        use crate::{apu, controller as ctrl, ppu};
+        use std::cell::Cell;
+
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let dir = std::env::temp_dir();
+        let rom_path = dir.join("nees_disk_save_test.fds");
+        let save_path = dir.join("nees_disk_save_test.disk.ips");
+
+        let original = new_side(&[1, 2, 3]);
+        let modified = new_side(&[1, 5, 3]);
+        fs::write(&save_path, patch::create_ips(&original, &modified)).unwrap();
+
+        let mut save = DiskSave::new(&rom_path, None, original.clone());
+        assert_eq!(save.load().unwrap(), modified);
+
+        // nothing is written while the disk matches the saved changes
+        fs::remove_file(&save_path).unwrap();
+        let mut bus = FdsCpuAddressBus::new(
+            &[0; 0x2000],
+            &[&modified],
+            ppu::Ppu::new(),
+            apu::Apu::new(),
+            ctrl::Controller::default(),
+            unsafe { &*(&framebuffer as *const _ as *const _) },
+        );
+        save.flush(&mut bus).unwrap();
+        assert!(!save_path.exists());
+
+        // but the changes are written again for a fresh save
+        let mut save = DiskSave::new(&rom_path, None, original);
+        save.flush(&mut bus).unwrap();
+        let mut save = DiskSave::new(&rom_path, None, new_side(&[1, 2, 3]));
+        assert_eq!(save.load().unwrap(), modified);
+
+        fs::remove_file(&save_path).unwrap();
+    }
+
+    #[test]
+    fn test_gaps() {
+        let side = new_side(&[0x11; 0x20]);
+        let raw = add_gaps(&side);
+        assert_eq!(raw.len(), RAW_SIDE_SIZE);
+        assert_eq!(
+            raw[LEAD_IN_GAP_BYTES - 1..LEAD_IN_GAP_BYTES + 2],
+            [0, 0x80, 1]
+        );
+        assert_eq!(remove_gaps(&raw), side);
+
+        // the crc of a block (including the start mark and the crc) is 0
+        let block = &raw[LEAD_IN_GAP_BYTES..LEAD_IN_GAP_BYTES + 1 + 56 + 2];
+        assert_eq!(block.iter().fold(0, |crc, &byte| update_crc(crc, byte)), 0);
+    }
+}
@@ -0,0 +1,257 @@
+use crate::{hash, parse};
+
+// corrections for roms with bad headers, looked up by the hash of their prg and chr rom
+// (see 'game_db.txt' for the format)
+static BUILT_IN_DB: &str = include_str!("game_db.txt");
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GameHash {
+    Crc32(u32),
+    Sha1([u8; 20]),
+}
+
+// the header fields to override for a single game. 'None' keeps the value from the header
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GameDbEntry {
+    pub mapper: Option<u16>,
+    pub submapper: Option<u8>,
+    pub mirroring: Option<parse::MirroringType>,
+    pub prg_ram_size: Option<usize>,
+    pub prg_nvram_size: Option<usize>,
+    pub has_battery: Option<bool>,
+    pub timing: Option<parse::Timing>,
+    // whether mapper 4 uses the revision a irq (submapper 4) or not (submapper 0)
+    pub mmc3_revision_a: Option<bool>,
+}
+
+pub struct GameDb {
+    entries: Vec<(GameHash, GameDbEntry)>,
+}
+
+impl GameDb {
+    pub fn built_in() -> Self {
+        Self::parse(BUILT_IN_DB).unwrap_or_else(|e| panic!("invalid built-in game db: {}", e))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut entries = Vec::new();
+
+        for (line_idx, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let entry = parse_line(line).map_err(|e| format!("line {}: {}", line_idx + 1, e))?;
+            entries.push(entry);
+        }
+
+        Ok(Self { entries })
+    }
+
+    // looks up the entry for a rom. sha-1 matches take priority over crc32 matches
+    pub fn find(&self, prg_and_chr_rom: &[u8]) -> Option<&GameDbEntry> {
+        let crc32 = hash::crc32(prg_and_chr_rom);
+        let sha1 = hash::sha1(prg_and_chr_rom);
+
+        self.entries
+            .iter()
+            .find(|(hash, _)| *hash == GameHash::Sha1(sha1))
+            .or_else(|| {
+                self.entries
+                    .iter()
+                    .find(|(hash, _)| *hash == GameHash::Crc32(crc32))
+            })
+            .map(|(_, entry)| entry)
+    }
+}
+
+fn parse_line(line: &str) -> Result<(GameHash, GameDbEntry), String> {
+    let mut words = line.split_whitespace();
+    let hash_str = words.next().unwrap();
+    let hash = match hash_str.len() {
+        8 => GameHash::Crc32(
+            u32::from_str_radix(hash_str, 16)
+                .map_err(|_| format!("invalid crc32 '{}'", hash_str))?,
+        ),
+        40 => {
+            let mut sha1 = [0; 20];
+            for (i, byte) in sha1.iter_mut().enumerate() {
+                *byte = hash_str
+                    .get(i * 2..i * 2 + 2)
+                    .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                    .ok_or_else(|| format!("invalid sha-1 '{}'", hash_str))?;
+            }
+
+            GameHash::Sha1(sha1)
+        }
+        _ => return Err(format!("expected a crc32 or sha-1, got '{}'", hash_str)),
+    };
+
+    let mut entry = GameDbEntry::default();
+    for word in words {
+        let mut split = word.splitn(2, '=');
+        let (key, val) = match (split.next(), split.next()) {
+            (Some(key), Some(val)) => (key, val),
+            _ => return Err(format!("expected 'field=value', got '{}'", word)),
+        };
+
+        let invalid_value = || format!("invalid value '{}' for '{}'", val, key);
+        match key {
+            "mapper" => entry.mapper = Some(val.parse().map_err(|_| invalid_value())?),
+            "submapper" => entry.submapper = Some(val.parse().map_err(|_| invalid_value())?),
+            "mirroring" => {
+                entry.mirroring = Some(match val {
+                    "h" => parse::MirroringType::Hor,
+                    "v" => parse::MirroringType::Vert,
+                    "4" => parse::MirroringType::FourScreen,
+                    _ => return Err(invalid_value()),
+                })
+            }
+            "prg_ram" => entry.prg_ram_size = Some(val.parse().map_err(|_| invalid_value())?),
+            "prg_nvram" => entry.prg_nvram_size = Some(val.parse().map_err(|_| invalid_value())?),
+            "battery" => {
+                entry.has_battery = Some(match val {
+                    "0" => false,
+                    "1" => true,
+                    _ => return Err(invalid_value()),
+                })
+            }
+            "timing" => {
+                entry.timing = Some(match val {
+                    "ntsc" => parse::Timing::Ntsc,
+                    "pal" => parse::Timing::Pal,
+                    "multi" => parse::Timing::MultiRegion,
+                    "dendy" => parse::Timing::Dendy,
+                    _ => return Err(invalid_value()),
+                })
+            }
+            "mmc3_revision" => {
+                entry.mmc3_revision_a = Some(match val {
+                    "a" => true,
+                    "b" => false,
+                    _ => return Err(invalid_value()),
+                })
+            }
+            _ => return Err(format!("unknown field '{}'", key)),
+        }
+    }
+
+    Ok((hash, entry))
+}
+
+impl GameDbEntry {
+    // overrides the fields of 'header' that the entry specifies, logging every change
+    pub fn apply(&self, header: &mut parse::RomHeader) {
+        macro_rules! override_field {
+            ($field:ident, $val:expr) => {
+                if let Some(val) = $val {
+                    if header.$field != val {
+                        logln!(
+                            "game db: overriding {} ({:?} -> {:?})",
+                            stringify!($field),
+                            header.$field,
+                            val
+                        );
+                        header.$field = val;
+                    }
+                }
+            };
+        }
+
+        override_field!(mapper, self.mapper);
+        override_field!(submapper, self.submapper);
+        override_field!(mirroring, self.mirroring);
+        override_field!(prg_ram_size, self.prg_ram_size);
+        override_field!(prg_nvram_size, self.prg_nvram_size);
+        override_field!(has_battery, self.prg_nvram_size.map(|size| size != 0));
+        override_field!(has_battery, self.has_battery);
+        override_field!(timing, self.timing);
+
+        if header.mapper == 4 {
+            override_field!(
+                submapper,
+                self.mmc3_revision_a
+                    .map(|revision_a| if revision_a { 4 } else { 0 })
+            );
+        }
+    }
+}
+
+// applies the entry for a rom from the first database that has one (the user-supplied
+// database comes first, so that it takes priority over the built-in one)
+pub fn apply_overrides(rom: &mut parse::RomDescription, dbs: &[&GameDb]) {
+    let mut prg_and_chr_rom = rom.prg_rom.to_vec();
+    prg_and_chr_rom.extend_from_slice(&rom.chr_rom);
+
+    if let Some(entry) = dbs.iter().find_map(|db| db.find(&prg_and_chr_rom)) {
+        entry.apply(&mut rom.header);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let db = GameDb::parse(
+            "# comment\n\
+             \n\
+             0123abcd mapper=4 mirroring=v timing=pal # trailing comment\n\
+             a9993e364706816aba3e25717850c26c9cd0d89d prg_nvram=8192 mmc3_revision=a\n",
+        )
+        .unwrap();
+
+        assert_eq!(db.entries.len(), 2);
+        assert_eq!(db.entries[0].0, GameHash::Crc32(0x0123abcd));
+        assert_eq!(db.entries[0].1.mapper, Some(4));
+        assert_eq!(db.entries[0].1.mirroring, Some(parse::MirroringType::Vert));
+        assert_eq!(db.entries[0].1.timing, Some(parse::Timing::Pal));
+        assert_eq!(db.entries[1].0, GameHash::Sha1(hash::sha1(b"abc")));
+        assert_eq!(db.entries[1].1.prg_nvram_size, Some(0x2000));
+        assert_eq!(db.entries[1].1.mmc3_revision_a, Some(true));
+
+        assert!(GameDb::parse("0123abcd mapper=x").is_err());
+        assert!(GameDb::parse("0123abcd foo=1").is_err());
+        assert!(GameDb::parse("0123abc mapper=1").is_err());
+        assert!(!GameDb::built_in().entries.is_empty());
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        let mut rom = vec![b'N', b'E', b'S', 0x1a, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom.extend_from_slice(&[0x12; 0x4000]);
+        rom.extend_from_slice(&[0x34; 0x2000]);
+
+        let mut prg_and_chr_rom = vec![0x12; 0x4000];
+        prg_and_chr_rom.extend_from_slice(&[0x34; 0x2000]);
+        let crc32 = hash::crc32(&prg_and_chr_rom);
+
+        let built_in = GameDb::parse(&format!("{:08x} mapper=4 mmc3_revision=a", crc32)).unwrap();
+        let user = GameDb::parse(&format!("{:08x} mapper=4 prg_nvram=8192", crc32)).unwrap();
+
+        let mut rom_description = parse::RomDescription::new(&rom).unwrap();
+        apply_overrides(&mut rom_description, &[&built_in]);
+        assert_eq!(rom_description.header.mapper, 4);
+        assert_eq!(rom_description.header.submapper, 4);
+        assert!(!rom_description.header.has_battery);
+
+        // only the first matching entry is used
+        let mut rom_description = parse::RomDescription::new(&rom).unwrap();
+        apply_overrides(&mut rom_description, &[&user, &built_in]);
+        assert_eq!(rom_description.header.submapper, 0);
+        assert_eq!(rom_description.header.prg_nvram_size, 0x2000);
+        assert!(rom_description.header.has_battery);
+    }
+
+    #[test]
+    fn test_built_in_db() {
+        let rom = std::fs::read("src/test/mmc3_test_2/rom_singles/6-MMC3_alt.nes").unwrap();
+        let mut rom_description = parse::RomDescription::new(&rom).unwrap();
+        assert_eq!(rom_description.header.submapper, 0);
+
+        apply_overrides(&mut rom_description, &[&GameDb::built_in()]);
+        assert_eq!(rom_description.header.submapper, 4);
+    }
+}
@@ -0,0 +1,289 @@
+// decompresses raw deflate streams (rfc 1951), as stored in zip and gzip archives
+
+// the base lengths and distances of length/distance codes, and how many extra bits follow
+static LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+static LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+static DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+static DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// the order in which the code lengths of the code length alphabet are stored
+static CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+struct BitReader<'d> {
+    data: &'d [u8],
+    // position in bits
+    pos: usize,
+}
+
+impl<'d> BitReader<'d> {
+    fn read_bit(&mut self) -> Result<u16, String> {
+        let byte = *self
+            .data
+            .get(self.pos / 8)
+            .ok_or("unexpected end of deflate stream")?;
+        let bit = (byte >> (self.pos % 8)) & 1;
+        self.pos += 1;
+
+        Ok(bit as u16)
+    }
+
+    // reads 'n' bits, least significant bit first
+    fn read_bits(&mut self, n: u8) -> Result<u16, String> {
+        let mut val = 0;
+        for i in 0..n {
+            val |= self.read_bit()? << i;
+        }
+
+        Ok(val)
+    }
+
+    fn align_to_byte(&mut self) {
+        self.pos = (self.pos + 7) & !7;
+    }
+
+    fn read_aligned_bytes(&mut self, len: usize) -> Result<&'d [u8], String> {
+        let start = self.pos / 8;
+        let bytes = self
+            .data
+            .get(start..start + len)
+            .ok_or("unexpected end of deflate stream")?;
+        self.pos += len * 8;
+
+        Ok(bytes)
+    }
+}
+
+// a canonical huffman code, stored as the number of codes of each length and
+// the symbols ordered by their codes
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        // unused symbols don't get a code
+        counts[0] = 0;
+
+        let mut symbols = Vec::with_capacity(lengths.len());
+        for len in 1..16 {
+            for (symbol, _) in lengths.iter().enumerate().filter(|(_, &l)| l == len) {
+                symbols.push(symbol as u16);
+            }
+        }
+
+        Self { counts, symbols }
+    }
+
+    // huffman codes are stored most significant bit first. the codes of each length are
+    // consecutive, so reading one bit at a time, the code can be checked against the range
+    // of codes of the current length
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, String> {
+        let mut code = 0;
+        // the first code of the current length, and the index of its symbol
+        let mut first = 0;
+        let mut idx = 0;
+
+        for len in 1..16 {
+            code |= reader.read_bit()? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(idx + code - first) as usize]);
+            }
+
+            idx += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        Err("invalid huffman code in deflate stream".to_string())
+    }
+}
+
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut reader = BitReader { data, pos: 0 };
+    let mut output = Vec::new();
+
+    loop {
+        let is_final_block = reader.read_bit()? == 1;
+
+        match reader.read_bits(2)? {
+            0 => inflate_stored_block(&mut reader, &mut output)?,
+            1 => {
+                let (literals, distances) = fixed_codes();
+                inflate_block(&mut reader, &mut output, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = read_dynamic_codes(&mut reader)?;
+                inflate_block(&mut reader, &mut output, &literals, &distances)?;
+            }
+            _ => return Err("invalid deflate block type".to_string()),
+        }
+
+        if is_final_block {
+            return Ok(output);
+        }
+    }
+}
+
+fn inflate_stored_block(reader: &mut BitReader, output: &mut Vec<u8>) -> Result<(), String> {
+    reader.align_to_byte();
+    let header = reader.read_aligned_bytes(4)?;
+    let len = u16::from_le_bytes([header[0], header[1]]);
+    let inverted_len = u16::from_le_bytes([header[2], header[3]]);
+    if len != !inverted_len {
+        return Err("invalid stored block length in deflate stream".to_string());
+    }
+
+    output.extend_from_slice(reader.read_aligned_bytes(len as usize)?);
+
+    Ok(())
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0; 288];
+    lengths[0..144].iter_mut().for_each(|len| *len = 8);
+    lengths[144..256].iter_mut().for_each(|len| *len = 9);
+    lengths[256..280].iter_mut().for_each(|len| *len = 7);
+    lengths[280..288].iter_mut().for_each(|len| *len = 8);
+
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+// dynamic blocks start with the code lengths of the literal/length and distance codes,
+// which are themselves huffman coded (with run-length encoding for repeated lengths)
+fn read_dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    let n_literal_codes = reader.read_bits(5)? as usize + 257;
+    let n_distance_codes = reader.read_bits(5)? as usize + 1;
+    let n_code_length_codes = reader.read_bits(4)? as usize + 4;
+
+    let mut code_length_lengths = [0; 19];
+    for &idx in CODE_LENGTH_ORDER[..n_code_length_codes].iter() {
+        code_length_lengths[idx] = reader.read_bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_length_lengths);
+
+    let mut lengths = Vec::with_capacity(n_literal_codes + n_distance_codes);
+    while lengths.len() < n_literal_codes + n_distance_codes {
+        let (len, repeat) = match code_length_code.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let prev = *lengths
+                    .last()
+                    .ok_or("repeated code length without a previous length")?;
+                (prev, 3 + reader.read_bits(2)?)
+            }
+            17 => (0, 3 + reader.read_bits(3)?),
+            _ => (0, 11 + reader.read_bits(7)?),
+        };
+
+        for _ in 0..repeat {
+            lengths.push(len);
+        }
+    }
+
+    if lengths.len() > n_literal_codes + n_distance_codes {
+        return Err("too many code lengths in deflate stream".to_string());
+    }
+
+    Ok((
+        Huffman::new(&lengths[..n_literal_codes]),
+        Huffman::new(&lengths[n_literal_codes..]),
+    ))
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    output: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), String> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        match symbol {
+            0..=255 => output.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let idx = symbol - 257;
+                if idx >= LENGTH_BASE.len() {
+                    return Err("invalid length code in deflate stream".to_string());
+                }
+                let len = LENGTH_BASE[idx] + reader.read_bits(LENGTH_EXTRA_BITS[idx])?;
+
+                let idx = distances.decode(reader)? as usize;
+                if idx >= DISTANCE_BASE.len() {
+                    return Err("invalid distance code in deflate stream".to_string());
+                }
+                let distance =
+                    (DISTANCE_BASE[idx] + reader.read_bits(DISTANCE_EXTRA_BITS[idx])?) as usize;
+
+                if distance > output.len() {
+                    return Err("distance too far back in deflate stream".to_string());
+                }
+
+                // the copied range may overlap the bytes being written
+                let start = output.len() - distance;
+                for i in 0..len as usize {
+                    output.push(output[start + i]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stored_and_fixed_blocks() {
+        assert_eq!(
+            inflate(&[0x01, 0x06, 0x00, 0xf9, 0xff, b's', b't', b'o', b'r', b'e', b'd']).unwrap(),
+            b"stored"
+        );
+        assert!(inflate(&[0x01, 0x06, 0x00, 0xf9, 0xfe, b's']).is_err());
+
+        assert_eq!(
+            inflate(&[
+                0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0xd7, 0x51, 0xc8, 0x40, 0xa2, 0x14, 0xf2, 0x52, 0x8b,
+                0x01
+            ])
+            .unwrap(),
+            b"hello, hello, hello nes"
+        );
+    }
+
+    #[test]
+    fn test_dynamic_block() {
+        let compressed = [
+            0x35, 0x8c, 0xc1, 0x11, 0x00, 0x30, 0x08, 0xc2, 0x66, 0x25, 0xec, 0xbf, 0x43, 0x05,
+            0xad, 0x0f, 0x05, 0xcc, 0x21, 0x90, 0xd0, 0x6c, 0xe4, 0xa8, 0x18, 0xe7, 0xd4, 0x99,
+            0x7a, 0x45, 0xcc, 0xdf, 0x09, 0x37, 0x28, 0x74, 0x13, 0xae, 0xd1, 0x6f, 0x6a, 0x19,
+            0x4b, 0xc2, 0x03,
+        ];
+
+        assert_eq!(
+            &inflate(&compressed).unwrap()[..],
+            &b"abbaababbabacaabaababcbaabcaabacbababcaacbaacaccaabbabcaabcbaaaaaaaaab\
+               acbcaabcababbabaabacabbbabcabb"[..]
+        );
+        assert!(inflate(&compressed[..20]).is_err());
+    }
+}
@@ -8,12 +8,24 @@ mod serialize;
 mod util;
 mod address_bus;
 mod apu;
+mod archive;
+mod battery_save;
 mod controller;
 mod cpu;
+mod fds;
+mod game_db;
+mod hash;
+mod inflate;
+mod nsf;
 mod parse;
+mod patch;
+mod power_on;
 mod ppu;
 #[cfg(test)]
 mod test;
+mod text;
+mod unif;
+mod wav;
 mod win;
 
 use address_bus::CpuAddressBus;
@@ -26,99 +38,186 @@ use pixel_renderer::PixelRenderer;
 use xcb_util::keysyms;
 
 use std::cell::Cell;
-use std::io::{Read, Seek, Write};
+use std::io::{Seek, Write};
 
 struct Nes<'a> {
     cpu: cpu::Cpu,
     bus: &'a mut dyn CpuAddressBus<'a>,
+    // whether the cartridge has battery-backed ram (see 'CpuAddressBus::battery_backed_ram()')
+    has_battery: bool,
+    // copied to prg ram after the battery save is loaded, so that it isn't overwritten
+    // by it (see 'load_trainer()')
+    trainer: Option<Vec<u8>>,
 }
 
 impl<'a> Nes<'a> {
-    fn new(framebuffer: &'a [Cell<u32>; 256 * 240], rom_file: &mut std::fs::File) -> Self {
-        let rom_len = rom_file
-            .metadata()
-            .unwrap_or_else(|e| error_exit!("Failed to query rom file metadata: {}", e))
-            .len() as usize;
-        let mut rom = vec![0; rom_len];
-        rom_file
-            .read(&mut rom)
-            .unwrap_or_else(|e| error_exit!("Failed to read rom file: {}", e));
-
-        let prg_size = 0x4000 * (parse::get_prg_size(&rom) as usize);
-        let chr_size = 0x2000 * (parse::get_chr_size(&rom) as usize);
-        let mirroring = parse::get_mirroring_type(&rom);
-
-        if !parse::is_valid(&rom) {
-            error_exit!("Failed to load rom file: invalid ines header information");
+    fn new(
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+        rom: &[u8],
+        user_game_db: Option<&game_db::GameDb>,
+        fds_bios: Option<&[u8]>,
+        region: Option<ppu::Region>,
+    ) -> Self {
+        if fds::is_fds(rom) {
+            return Self::new_fds(framebuffer, rom, fds_bios, region);
         }
 
+        if nsf::is_nsf(rom) {
+            return Self::new_nsf(framebuffer, rom, region);
+        }
+
+        let rom_description = if unif::is_unif(rom) {
+            unif::parse(rom)
+        } else {
+            parse::RomDescription::new(rom)
+        };
+        let mut rom_description =
+            rom_description.unwrap_or_else(|e| error_exit!("Failed to load rom file: {}", e));
+
+        // fix up bad headers using the game database (see 'game_db.txt')
+        let built_in_game_db = game_db::GameDb::built_in();
+        match user_game_db {
+            Some(user_game_db) => {
+                game_db::apply_overrides(&mut rom_description, &[user_game_db, &built_in_game_db])
+            }
+            None => game_db::apply_overrides(&mut rom_description, &[&built_in_game_db]),
+        }
+
+        let header = &rom_description.header;
+
         logln!("{}", std::str::from_utf8(&rom[0..=3]).unwrap());
-        logln!("is nes 2.0: {}", parse::is_nes_2_format(&rom));
-        logln!("has trainer: {}", parse::has_trainer(&rom));
-        logln!("mirroring type: {:?}", parse::get_mirroring_type(&rom));
-        logln!("mapper number: {}", parse::get_mapper_num(&rom));
-        logln!("prg rom size: {}KB", parse::get_prg_size(&rom) as u32 * 16);
-        logln!("chr rom size: {}KB", parse::get_chr_size(&rom) as u32 * 8);
-        logln!("has battery-backed RAM: {}", parse::has_prg_ram(&rom));
+        logln!("is nes 2.0: {}", header.is_nes_2_format);
+        logln!("has trainer: {}", header.has_trainer);
+        logln!("mirroring type: {:?}", header.mirroring);
+        logln!("mapper number: {}", header.mapper);
+        logln!("submapper number: {}", header.submapper);
+        logln!("prg rom size: {}KB", header.prg_rom_size / 1024);
+        logln!("chr rom size: {}KB", header.chr_rom_size / 1024);
+        logln!("prg ram size: {}KB", header.prg_ram_size / 1024);
+        logln!("prg nvram size: {}KB", header.prg_nvram_size / 1024);
+        logln!("chr ram size: {}KB", header.chr_ram_size / 1024);
+        logln!("chr nvram size: {}KB", header.chr_nvram_size / 1024);
+        logln!("has battery-backed RAM: {}", header.has_battery);
+        logln!("timing: {:?}", header.timing);
+        logln!("console type: {:?}", header.console_type);
+        logln!("misc rom count: {}", header.misc_rom_count);
+        logln!(
+            "default expansion device: {}",
+            header.default_expansion_device
+        );
+
+        let has_battery = header.has_battery;
+
+        let mapper = bus::find_mapper(header.mapper, header.submapper).unwrap_or_else(|| {
+            error_exit!(
+                "Failed to load rom file: ines mapper {} is not supported",
+                header.mapper
+            )
+        });
+        logln!("mapper: {}", mapper.name);
 
-        let ppu = ppu::Ppu::new();
-        let apu = apu::Apu {};
+        // a region passed on the commandline takes priority over the header (and game db)
+        let region = region.unwrap_or_else(|| ppu::Region::from_timing(header.timing));
+        logln!("region: {:?}", region);
+
+        let ppu = Self::new_ppu(region);
+        let apu = Self::new_apu(region);
         let controller = ctrl::Controller::default();
 
         let cpu = cpu::Cpu::default();
-        let bus: &mut dyn CpuAddressBus = match parse::get_mapper_num(&rom) {
-            // use custom address bus structs for tests
-            #[cfg(test)]
-            0 => Box::leak(Box::new(
-                test::TestCpuAddressBus::<bus::NromCpuAddressBus>::new(
-                    &rom[0x10..=prg_size + 0xf],
-                    &rom[0x10 + prg_size..=prg_size + chr_size + 0xf],
-                    mirroring,
-                    ppu,
-                    apu,
-                    controller,
-                    framebuffer,
-                ),
-            )),
-            // mapper 0 => nrom
-            0 => Box::leak(Box::new(bus::NromCpuAddressBus::new(
-                &rom[0x10..=prg_size + 0xf],
-                &rom[0x10 + prg_size..=prg_size + chr_size + 0xf],
-                mirroring,
-                ppu,
-                apu,
-                controller,
-                framebuffer,
-            ))),
-            #[cfg(test)]
-            4 => Box::leak(Box::new(
-                test::TestCpuAddressBus::<bus::Mmc3CpuAddressBus>::new(
-                    &rom[0x10..=prg_size + 0xf],
-                    &rom[0x10 + prg_size..=prg_size + chr_size + 0xf],
-                    mirroring,
-                    ppu,
-                    apu,
-                    controller,
-                    framebuffer,
-                ),
-            )),
-            // mapper 4 => mmc3
-            4 => Box::leak(Box::new(bus::Mmc3CpuAddressBus::new(
-                &rom[0x10..=prg_size + 0xf],
-                &rom[0x10 + prg_size..=prg_size + chr_size + 0xf],
-                mirroring,
-                ppu,
-                apu,
-                controller,
-                framebuffer,
-            ))),
-            n => error_exit!(
-                "Failed to load rom file: ines mapper {} is not supported",
-                n
-            ),
-        };
+        let bus = (mapper.new)(&rom_description, ppu, apu, controller, framebuffer);
+
+        // tests wrap the bus to capture the results that test roms write to 0x6000-
+        #[cfg(test)]
+        let bus = Box::new(test::TestCpuAddressBus::new(bus));
+
+        let bus = Box::leak(bus);
+
+        Self {
+            cpu,
+            bus,
+            has_battery,
+            trainer: rom_description.trainer.map(<[u8]>::to_vec),
+        }
+    }
+
+    // disk images run on the ram adapter of the famicom disk system, which holds the bios
+    // rather than the rom, and isn't registered in 'MAPPERS' (since it has no ines header)
+    fn new_fds(
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+        image: &[u8],
+        fds_bios: Option<&[u8]>,
+        region: Option<ppu::Region>,
+    ) -> Self {
+        let bios = fds_bios.unwrap_or_else(|| {
+            error_exit!("Failed to load rom file: disk images require the fds bios ('--fds-bios')")
+        });
+        let sides = fds::parse_sides(image)
+            .unwrap_or_else(|e| error_exit!("Failed to load rom file: {}", e));
+        logln!("fds disk sides: {}", sides.len());
+
+        // the famicom disk system was only sold in japan, so images run as ntsc by default
+        let region = region.unwrap_or(ppu::Region::Ntsc);
+        logln!("region: {:?}", region);
+
+        let bus = bus::FdsCpuAddressBus::new(
+            bios,
+            &sides,
+            Self::new_ppu(region),
+            Self::new_apu(region),
+            ctrl::Controller::default(),
+            framebuffer,
+        );
 
-        Self { cpu, bus }
+        Self {
+            cpu: cpu::Cpu::default(),
+            bus: Box::leak(Box::new(bus)),
+            has_battery: false,
+            trainer: None,
+        }
+    }
+
+    // nsf tunes are played through a synthetic mapper, which calls the tune's init and
+    // play routines (see 'NsfCpuAddressBus')
+    fn new_nsf(
+        framebuffer: &'a [Cell<u32>; 256 * 240],
+        rom: &[u8],
+        region: Option<ppu::Region>,
+    ) -> Self {
+        let nsf = nsf::parse(rom).unwrap_or_else(|e| error_exit!("Failed to load rom file: {}", e));
+        logln!("nsf title: {}", nsf.title);
+        logln!("nsf tracks: {}", nsf.tracks.len());
+        logln!("nsf expansion chips: {:#04x}", nsf.expansion);
+
+        let region = region.unwrap_or_else(|| nsf.region());
+        logln!("region: {:?}", region);
+
+        let bus = bus::NsfCpuAddressBus::new(
+            nsf,
+            Self::new_ppu(region),
+            Self::new_apu(region),
+            ctrl::Controller::default(),
+            framebuffer,
+        );
+
+        Self {
+            cpu: cpu::Cpu::default(),
+            bus: Box::leak(Box::new(bus)),
+            has_battery: false,
+            trainer: None,
+        }
+    }
+
+    fn new_ppu(region: ppu::Region) -> ppu::Ppu {
+        let mut ppu = ppu::Ppu::new();
+        ppu.set_region(region);
+        ppu
+    }
+
+    fn new_apu(region: ppu::Region) -> apu::Apu {
+        let mut apu = apu::Apu::new();
+        apu.set_region(region);
+        apu
     }
 
     #[cfg(test)]
@@ -127,14 +226,14 @@ impl<'a> Nes<'a> {
         let base = self.bus.base().0;
         base.ppu.reset_state();
         base.controller = ctrl::Controller::default();
-        base.apu = apu::Apu {};
+        base.apu = apu::Apu::new();
         // TODO: rest of state
     }
 
     #[cfg(test)]
     fn new_test(framebuffer: &'a [Cell<u32>; 256 * 240]) -> Self {
         let ppu = ppu::Ppu::new();
-        let apu = apu::Apu {};
+        let apu = apu::Apu::new();
         let cpu = cpu::Cpu::default();
         let controller = ctrl::Controller::default();
         let bus = Box::leak(Box::new(bus::NromCpuAddressBus::new_empty(
@@ -145,7 +244,24 @@ impl<'a> Nes<'a> {
             framebuffer,
         )));
 
-        Self { cpu, bus }
+        Self {
+            cpu,
+            bus,
+            has_battery: false,
+            trainer: None,
+        }
+    }
+}
+
+// trainers are loaded to 0x7000-0x71ff. roms whose mapper has no prg ram there still run,
+// just without the trainer
+fn load_trainer<'a>(bus: &mut dyn CpuAddressBus<'a>, trainer: &[u8]) {
+    match bus
+        .prg_ram()
+        .and_then(|prg_ram| prg_ram.get_mut(0x1000..0x1200))
+    {
+        Some(prg_ram) => prg_ram.copy_from_slice(trainer),
+        None => eprintln!("Ignoring the trainer: the mapper has no prg ram at 0x7000-0x71ff"),
     }
 }
 
@@ -155,28 +271,151 @@ fn main() {
         error_exit!("Failed to parse commandline arguments: too few arguments provided");
     }
     let rom_path = args.nth(1).unwrap();
-    let mut rom_file = std::fs::File::open(rom_path)
-        .unwrap_or_else(|e| error_exit!("Failed to open rom file: {}", e));
-
-    let mut save_file: Option<std::fs::File> = match args.next() {
-        Some(string) if string == "--save" => match args.next() {
-            Some(save_file_path) => std::fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .open(save_file_path)
-                .unwrap_or_else(|e| error_exit!("Failed to open save file: {}", e))
-                .into(),
+
+    let mut save_file: Option<std::fs::File> = None;
+    let mut battery_save_dir: Option<std::path::PathBuf> = None;
+    let mut user_game_db: Option<game_db::GameDb> = None;
+    let mut patch_paths: Vec<std::path::PathBuf> = Vec::new();
+    let mut archive_entry: Option<String> = None;
+    let mut fds_bios_path: Option<std::path::PathBuf> = None;
+    let mut region: Option<ppu::Region> = None;
+    let mut dot_accurate_ppu = false;
+    let mut power_on_state: Option<power_on::PowerOnState> = None;
+    let mut oam_decay = false;
+    let mut audio_recording_path: Option<std::path::PathBuf> = None;
+
+    while let Some(string) = args.next() {
+        match string.as_str() {
+            "--save" => match args.next() {
+                Some(save_file_path) => {
+                    save_file = std::fs::OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create(true)
+                        .open(save_file_path)
+                        .unwrap_or_else(|e| error_exit!("Failed to open save file: {}", e))
+                        .into()
+                }
+                _ => error_exit!(
+                    "Failed to parse commandline arguments: expected path to save file after '--save'"
+                ),
+            },
+            "--battery-save-dir" => match args.next() {
+                Some(dir) => battery_save_dir = Some(dir.into()),
+                _ => error_exit!(
+                    "Failed to parse commandline arguments: expected directory after '--battery-save-dir'"
+                ),
+            },
+            "--game-db" => match args.next() {
+                Some(path) => {
+                    let text = std::fs::read_to_string(path)
+                        .unwrap_or_else(|e| error_exit!("Failed to read game db file: {}", e));
+                    user_game_db = Some(game_db::GameDb::parse(&text).unwrap_or_else(|e| {
+                        error_exit!("Failed to parse game db file: {}", e)
+                    }));
+                }
+                _ => error_exit!(
+                    "Failed to parse commandline arguments: expected path to game db file after '--game-db'"
+                ),
+            },
+            "--patch" => match args.next() {
+                Some(path) => patch_paths.push(path.into()),
+                _ => error_exit!(
+                    "Failed to parse commandline arguments: expected path to patch file after '--patch'"
+                ),
+            },
+            "--fds-bios" => match args.next() {
+                Some(path) => fds_bios_path = Some(path.into()),
+                _ => error_exit!(
+                    "Failed to parse commandline arguments: expected path to fds bios after '--fds-bios'"
+                ),
+            },
+            "--archive-entry" => match args.next() {
+                Some(name) => archive_entry = Some(name),
+                _ => error_exit!(
+                    "Failed to parse commandline arguments: expected file name after '--archive-entry'"
+                ),
+            },
+            "--region" => match args.next() {
+                Some(name) => {
+                    region = Some(name.parse().unwrap_or_else(|e| {
+                        error_exit!("Failed to parse commandline arguments: {}", e)
+                    }))
+                }
+                _ => error_exit!(
+                    "Failed to parse commandline arguments: expected ntsc, pal or dendy after '--region'"
+                ),
+            },
+            "--dot-accurate-ppu" => dot_accurate_ppu = true,
+            "--power-on-state" => match args.next() {
+                Some(name) => {
+                    power_on_state = Some(name.parse().unwrap_or_else(|e| {
+                        error_exit!("Failed to parse commandline arguments: {}", e)
+                    }))
+                }
+                _ => error_exit!(
+                    "Failed to parse commandline arguments: expected zeros, ones, random or hardware after '--power-on-state'"
+                ),
+            },
+            "--oam-decay" => oam_decay = true,
+            "--record-audio" => match args.next() {
+                Some(path) => audio_recording_path = Some(path.into()),
+                _ => error_exit!(
+                    "Failed to parse commandline arguments: expected path to .wav file after '--record-audio'"
+                ),
+            },
             _ => error_exit!(
-                "Failed to parse commandline arguments: expected path to save file after '--save'"
+                "Failed to parse commandline arguments: invalid argument '{}'",
+                string
             ),
-        },
-        Some(string) => error_exit!(
-            "Failed to parse commandline arguments: invalid argument '{}'",
-            string
-        ),
-        _ => None,
-    };
+        }
+    }
+
+    let rom =
+        std::fs::read(&rom_path).unwrap_or_else(|e| error_exit!("Failed to read rom file: {}", e));
+    // zip and gzip archives are extracted in memory
+    let mut rom = archive::extract_rom(rom, archive_entry.as_deref())
+        .unwrap_or_else(|e| error_exit!("Failed to extract rom from archive: {}", e));
+
+    // patches are applied in memory (leaving the rom file untouched), in the order they
+    // were passed. if none were passed, patches next to the rom are applied instead
+    if patch_paths.is_empty() {
+        patch_paths = patch::find_patches(std::path::Path::new(&rom_path));
+    }
+
+    for patch_path in patch_paths.iter() {
+        let patch = std::fs::read(patch_path)
+            .unwrap_or_else(|e| error_exit!("Failed to read patch file: {}", e));
+        rom = patch::apply(&rom, &patch).unwrap_or_else(|e| {
+            error_exit!("Failed to apply patch '{}': {}", patch_path.display(), e)
+        });
+        logln!("applied patch: {}", patch_path.display());
+    }
+
+    // disk images need the fds bios, which is read from 'disksys.rom' next to the rom
+    // unless a path is given. writes to the disk are kept in a separate patch
+    let mut disk_save = None;
+    let mut fds_bios = None;
+    if fds::is_fds(&rom) {
+        let image = fds::parse_sides(&rom)
+            .unwrap_or_else(|e| error_exit!("Failed to load rom file: {}", e))
+            .concat();
+        let mut save = fds::DiskSave::new(
+            std::path::Path::new(&rom_path),
+            battery_save_dir.as_deref(),
+            image,
+        );
+        rom = save
+            .load()
+            .unwrap_or_else(|e| error_exit!("Failed to read disk save file: {}", e));
+        disk_save = Some(save);
+
+        let bios_path = fds_bios_path
+            .unwrap_or_else(|| std::path::Path::new(&rom_path).with_file_name("disksys.rom"));
+        fds_bios = Some(std::fs::read(&bios_path).unwrap_or_else(|e| {
+            error_exit!("Failed to read fds bios '{}': {}", bios_path.display(), e)
+        }));
+    }
 
     let win = win::XcbWindowWrapper::new("nees", 1200, 600)
         .unwrap_or_else(|e| error_exit!("Failed to create XCB window: {}", e));
@@ -184,7 +423,53 @@ fn main() {
         .unwrap_or_else(|e| error_exit!("Failed to initialize renderer: {}", e));
     let key_syms = keysyms::KeySymbols::new(&win.connection);
 
-    let Nes { mut cpu, bus } = Nes::new(util::pixels_to_u32(&renderer), &mut rom_file);
+    let Nes {
+        mut cpu,
+        bus,
+        has_battery,
+        trainer,
+    } = Nes::new(
+        util::pixels_to_u32(&renderer),
+        &rom,
+        user_game_db.as_ref(),
+        fds_bios.as_deref(),
+        region,
+    );
+    bus.base().0.ppu.set_dot_accurate(dot_accurate_ppu);
+    bus.base().0.ppu.set_oam_decay(oam_decay);
+
+    // memory is left zeroed (as most emulators do) unless a power-on state is given
+    if let Some(state) = power_on_state {
+        logln!("power-on state: {:?}", state);
+        power_on::apply(state, bus, &mut cpu);
+    }
+
+    // battery-backed ram is stored next to the rom (with a '.sav' extension),
+    // or in the directory passed with '--battery-save-dir'
+    let mut battery_save = if has_battery {
+        let mut battery_save = battery_save::BatterySave::new(
+            std::path::Path::new(&rom_path),
+            battery_save_dir.as_deref(),
+        );
+        battery_save
+            .load(bus)
+            .unwrap_or_else(|e| error_exit!("Failed to read battery save file: {}", e));
+
+        Some(battery_save)
+    } else {
+        None
+    };
+
+    if let Some(trainer) = trainer {
+        load_trainer(bus, &trainer);
+    }
+
+    // there's no audio output yet, but the mixed audio can be recorded to a .wav file
+    let mut wav_recorder = audio_recording_path.map(|path| {
+        bus.base().0.apu.set_recording(true);
+        wav::WavRecorder::new(&path)
+            .unwrap_or_else(|e| error_exit!("Failed to create audio recording: {}", e))
+    });
 
     match save_file {
         Some(ref mut save)
@@ -228,11 +513,14 @@ fn main() {
     let (base_raw, ppu_bus_raw): (*mut bus::CpuAddressBusBase, *mut dyn bus::PpuAddressBus) =
         (bus.base().0, bus.base().1);
 
+    // frames are paced to the refresh rate of the region's tv system
+    let frame_duration = unsafe { (*base_raw).ppu.region().frame_duration() };
+
     win.map_and_flush();
 
     let mut is_paused = false;
 
-    loop {
+    'main: loop {
         let start_of_frame = std::time::Instant::now();
 
         // loop through all pending events
@@ -266,7 +554,7 @@ fn main() {
                                             (win::Keys::ESC, _) => is_paused = false,
                                             // quit on ctrl+q
                                             (win::Keys::Q, modifier) if (modifier & 4) != 0 => {
-                                                return;
+                                                break 'main;
                                             }
                                             _ => (),
                                         }
@@ -281,7 +569,7 @@ fn main() {
                                         let msg: &xcb::ClientMessageEvent =
                                             unsafe { xcb::cast_event(&e) };
                                         if msg.data().data32()[0] == win.delete_reply.atom() {
-                                            return;
+                                            break 'main;
                                         }
                                     }
                                     _ => (),
@@ -317,8 +605,28 @@ fn main() {
                                 });
                             }
                         }
+                        // eject or re-insert the disk (fds only)
+                        (win::Keys::E, _) if bus.disk_drive().is_some() => {
+                            let drive = bus.disk_drive().unwrap();
+                            match drive.inserted_side() {
+                                Some(_) => drive.eject(),
+                                None => drive.insert(),
+                            }
+                        }
+                        // switch to the next disk side (fds only)
+                        (win::Keys::X, _) if bus.disk_drive().is_some() => {
+                            let drive = bus.disk_drive().unwrap();
+                            drive.switch_side();
+                        }
+                        // change tracks (nsf only)
+                        (win::Keys::LEFT, _) if bus.nsf_player().is_some() => {
+                            bus.nsf_player().unwrap().previous_track();
+                        }
+                        (win::Keys::RIGHT, _) if bus.nsf_player().is_some() => {
+                            bus.nsf_player().unwrap().next_track();
+                        }
                         // quit on ctrl+q
-                        (win::Keys::Q, modifier) if (modifier & 4) != 0 => return,
+                        (win::Keys::Q, modifier) if (modifier & 4) != 0 => break 'main,
                         // pass input to emulator
                         (sym, _) => unsafe { (*base_raw).controller.set_key(sym) },
                     }
@@ -354,7 +662,7 @@ fn main() {
                     let msg: &xcb::ClientMessageEvent = unsafe { xcb::cast_event(&e) };
                     if msg.data().data32()[0] == win.delete_reply.atom() {
                         // 'WM_DELETE_WINDOW' message was sent
-                        return;
+                        break 'main;
                     }
                 }
                 _ => (),
@@ -368,6 +676,11 @@ fn main() {
         unsafe {
             while !(*base_raw).ppu.is_frame_done() {
                 cpu.exec_instruction(bus);
+                // catch up cartridge hardware (e.g. expansion audio)
+                bus.catch_up(&mut cpu);
+                (*base_raw)
+                    .apu
+                    .catch_up(cpu.cycle_count, bus.expansion_audio_output());
                 (*base_raw).ppu.catch_up(
                     &mut cpu,
                     &mut *ppu_bus_raw,
@@ -378,19 +691,63 @@ fn main() {
 
         // reset counters
         unsafe {
-            (*base_raw).ppu.sub_cycle_count(cpu.cycle_count as i32 * 3);
+            (*base_raw).ppu.sub_cpu_cycles(cpu.cycle_count);
             (*base_raw).ppu.set_frame_done(false);
+            (*base_raw).apu.sub_cycle_count(cpu.cycle_count);
         }
+        bus.sub_cycle_count(cpu.cycle_count);
         cpu.cycle_count = 0;
 
+        if let Some(ref mut battery_save) = battery_save {
+            // a failed periodic flush isn't fatal, as the save is retried on exit
+            if let Err(e) = battery_save.end_frame(bus) {
+                eprintln!("Failed to write battery save file: {}", e);
+            }
+        }
+
+        if let Some(ref mut disk_save) = disk_save {
+            if let Err(e) = disk_save.end_frame(bus) {
+                eprintln!("Failed to write disk save file: {}", e);
+            }
+        }
+
+        if let Some(player) = bus.nsf_player() {
+            nsf::draw_track_info(player, util::pixels_to_u32(&renderer));
+        }
+
+        if let Some(ref mut wav_recorder) = wav_recorder {
+            let samples = unsafe { (*base_raw).apu.take_samples() };
+            wav_recorder
+                .write_samples(&samples)
+                .unwrap_or_else(|e| error_exit!("Failed to write audio recording: {}", e));
+        }
+
         let idx = renderer.render_frame();
         let elapsed = start_of_frame.elapsed();
-        let frame_time_left = std::time::Duration::from_nanos(16_666_677 - 200_000)
+        let frame_time_left = (frame_duration - std::time::Duration::from_nanos(200_000))
             .checked_sub(elapsed)
             .unwrap_or_default();
 
-        // sleep until slightly less than 16.67 ms have passed
+        // sleep until slightly less than a frame's worth of time has passed
         std::thread::sleep(frame_time_left);
         renderer.present(idx);
     }
+
+    if let Some(ref mut battery_save) = battery_save {
+        battery_save
+            .flush(bus)
+            .unwrap_or_else(|e| error_exit!("Failed to write battery save file: {}", e));
+    }
+
+    if let Some(ref mut disk_save) = disk_save {
+        disk_save
+            .flush(bus)
+            .unwrap_or_else(|e| error_exit!("Failed to write disk save file: {}", e));
+    }
+
+    if let Some(ref mut wav_recorder) = wav_recorder {
+        wav_recorder
+            .finish()
+            .unwrap_or_else(|e| error_exit!("Failed to write audio recording: {}", e));
+    }
 }
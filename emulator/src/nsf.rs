@@ -0,0 +1,414 @@
+use crate::address_bus::NsfPlayer;
+use crate::ppu::{self, palette};
+use crate::text;
+
+use std::cell::Cell;
+
+// bits of the expansion chip byte (header offset 0x7b, or the 'INFO' chunk of nsfe files)
+pub const EXPANSION_VRC6: u8 = 0x01;
+pub const EXPANSION_VRC7: u8 = 0x02;
+pub const EXPANSION_FDS: u8 = 0x04;
+pub const EXPANSION_MMC5: u8 = 0x08;
+pub const EXPANSION_N163: u8 = 0x10;
+pub const EXPANSION_5B: u8 = 0x20;
+
+// the names of the expansion chips, in the order of their bits
+static EXPANSION_NAMES: [&str; 6] = ["VRC6", "VRC7", "FDS", "MMC5", "N163", "5B"];
+
+// the metadata of a track (from the nsfe chunks, if any)
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct Track {
+    pub name: Option<String>,
+    // the length of the track in milliseconds, after which it fades out
+    pub time: Option<u32>,
+    // the length of the fade out in milliseconds ('None' uses a default)
+    pub fade: Option<u32>,
+}
+
+// a parsed .nsf or .nsfe file
+#[derive(Debug)]
+pub struct Nsf {
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    // the initial banks of 0x8000-0xffff (in 4 KB units), if the tune is bankswitched
+    pub initial_banks: Option<[u8; 8]>,
+    // the play rate in microseconds
+    pub ntsc_play_speed: u16,
+    pub pal_play_speed: u16,
+    // bit 0 = pal, bit 1 = dual pal/ntsc
+    pub region: u8,
+    pub expansion: u8,
+    // zero-based
+    pub starting_track: u8,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub tracks: Vec<Track>,
+    pub data: Vec<u8>,
+}
+
+impl Nsf {
+    // the region tunes are played in, unless one is passed on the commandline. tunes
+    // that support both regions are played as ntsc
+    pub fn region(&self) -> ppu::Region {
+        if self.region & 0b11 == 1 {
+            ppu::Region::Pal
+        } else {
+            ppu::Region::Ntsc
+        }
+    }
+}
+
+pub fn is_nsf(rom: &[u8]) -> bool {
+    rom.starts_with(b"NESM\x1a") || rom.starts_with(b"NSFE")
+}
+
+pub fn parse(rom: &[u8]) -> Result<Nsf, String> {
+    if rom.starts_with(b"NSFE") {
+        parse_nsfe(rom)
+    } else {
+        parse_nsf(rom)
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+// reads a null-terminated string (which may also be cut off by the end of 'bytes')
+fn read_string(bytes: &[u8]) -> String {
+    let len = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+fn new_tracks(track_count: u8) -> Vec<Track> {
+    vec![Track::default(); track_count as usize]
+}
+
+// nsf files start with a 0x80 byte header, followed by the program data. from version 2,
+// the header may give the length of the data, with nsfe metadata chunks following it
+fn parse_nsf(rom: &[u8]) -> Result<Nsf, String> {
+    if rom.len() <= 0x80 {
+        return Err("nsf file is too short".to_string());
+    }
+
+    let header = &rom[..0x80];
+    let track_count = header[6];
+    if track_count == 0 {
+        return Err("nsf file has no tracks".to_string());
+    }
+
+    let mut initial_banks = [0; 8];
+    initial_banks.copy_from_slice(&header[0x70..0x78]);
+    let data_len = u32::from_le_bytes([header[0x7d], header[0x7e], header[0x7f], 0]) as usize;
+    let (data, metadata) = if header[5] >= 2 && data_len != 0 {
+        rom[0x80..].split_at(data_len.min(rom.len() - 0x80))
+    } else {
+        (&rom[0x80..], &[][..])
+    };
+
+    let mut nsf = Nsf {
+        load_addr: read_u16(header, 0x8),
+        init_addr: read_u16(header, 0xa),
+        play_addr: read_u16(header, 0xc),
+        initial_banks: if initial_banks.iter().any(|&bank| bank != 0) {
+            Some(initial_banks)
+        } else {
+            None
+        },
+        ntsc_play_speed: read_u16(header, 0x6e),
+        pal_play_speed: read_u16(header, 0x78),
+        region: header[0x7a] & 0b11,
+        expansion: header[0x7b],
+        starting_track: header[7].saturating_sub(1).min(track_count - 1),
+        title: read_string(&header[0xe..0x2e]),
+        artist: read_string(&header[0x2e..0x4e]),
+        copyright: read_string(&header[0x4e..0x6e]),
+        tracks: new_tracks(track_count),
+        data: data.to_vec(),
+    };
+
+    if !metadata.is_empty() {
+        parse_chunks(&mut nsf, metadata)?;
+    }
+
+    Ok(nsf)
+}
+
+// nsfe files hold the same information as nsf files, but split into chunks (a 4 byte
+// length, a 4 byte id and the chunk data) after the 'NSFE' magic. the 'INFO' and 'DATA'
+// chunks are required, and the file ends with an 'NEND' chunk
+fn parse_nsfe(rom: &[u8]) -> Result<Nsf, String> {
+    let mut nsf = Nsf {
+        load_addr: 0,
+        init_addr: 0,
+        play_addr: 0,
+        initial_banks: None,
+        // nsfe files without a 'RATE' chunk use the standard rates
+        ntsc_play_speed: 16639,
+        pal_play_speed: 19997,
+        region: 0,
+        expansion: 0,
+        starting_track: 0,
+        title: String::new(),
+        artist: String::new(),
+        copyright: String::new(),
+        tracks: new_tracks(1),
+        data: Vec::new(),
+    };
+
+    parse_chunks(&mut nsf, &rom[4..])?;
+
+    if nsf.data.is_empty() || nsf.init_addr == 0 {
+        return Err("nsfe file is missing its 'INFO' or 'DATA' chunk".to_string());
+    }
+
+    Ok(nsf)
+}
+
+fn parse_chunks(nsf: &mut Nsf, mut chunks: &[u8]) -> Result<(), String> {
+    while chunks.len() >= 8 {
+        let len = u32::from_le_bytes([chunks[0], chunks[1], chunks[2], chunks[3]]) as usize;
+        let id = &chunks[4..8];
+        let data = chunks
+            .get(8..8 + len)
+            .ok_or_else(|| format!("nsfe chunk '{}' is cut off", read_string(id)))?;
+        chunks = &chunks[8 + len..];
+
+        match id {
+            b"INFO" => {
+                if data.len() < 8 {
+                    return Err("nsfe 'INFO' chunk is too short".to_string());
+                }
+
+                nsf.load_addr = read_u16(data, 0);
+                nsf.init_addr = read_u16(data, 2);
+                nsf.play_addr = read_u16(data, 4);
+                nsf.region = data[6] & 0b11;
+                nsf.expansion = data[7];
+
+                let track_count = data.get(8).copied().unwrap_or(1).max(1);
+                nsf.tracks.resize(track_count as usize, Track::default());
+                nsf.starting_track = data.get(9).copied().unwrap_or(0).min(track_count - 1);
+            }
+            b"DATA" => nsf.data = data.to_vec(),
+            b"BANK" => {
+                let mut banks = [0; 8];
+                let len = data.len().min(8);
+                banks[..len].copy_from_slice(&data[..len]);
+                nsf.initial_banks = Some(banks);
+            }
+            b"RATE" => {
+                if data.len() >= 2 {
+                    nsf.ntsc_play_speed = read_u16(data, 0);
+                }
+                if data.len() >= 4 {
+                    nsf.pal_play_speed = read_u16(data, 2);
+                }
+            }
+            b"NEND" => break,
+            // the game title, artist, copyright and ripper
+            b"auth" => {
+                let mut strings = data.split(|&byte| byte == 0).map(read_string);
+                nsf.title = strings.next().unwrap_or_default();
+                nsf.artist = strings.next().unwrap_or_default();
+                nsf.copyright = strings.next().unwrap_or_default();
+            }
+            b"tlbl" => {
+                for (track, name) in nsf.tracks.iter_mut().zip(data.split(|&byte| byte == 0)) {
+                    track.name = Some(read_string(name));
+                }
+            }
+            // signed 32-bit times, where negative values mean 'not specified'
+            b"time" | b"fade" => {
+                for (track, time) in nsf.tracks.iter_mut().zip(data.chunks_exact(4)) {
+                    let time = i32::from_le_bytes([time[0], time[1], time[2], time[3]]);
+                    let time = if time >= 0 { Some(time as u32) } else { None };
+
+                    if id == b"time" {
+                        track.time = time;
+                    } else {
+                        track.fade = time;
+                    }
+                }
+            }
+            // chunks starting with an uppercase letter can't be skipped
+            _ if id[0].is_ascii_uppercase() => {
+                return Err(format!(
+                    "unsupported nsfe chunk '{}'",
+                    String::from_utf8_lossy(id)
+                ))
+            }
+            _ => logln!("skipping nsfe chunk '{}'", String::from_utf8_lossy(id)),
+        }
+    }
+
+    Ok(())
+}
+
+// formats milliseconds as minutes and seconds
+fn format_time(ms: u32) -> String {
+    format!("{}:{:02}", ms / 60000, ms / 1000 % 60)
+}
+
+// draws the tune's metadata and the current track into the framebuffer. as nsf players
+// don't use the ppu, this is drawn over the (blank) frame after it has been rendered
+pub fn draw_track_info(player: &NsfPlayer, framebuffer: &[Cell<u32>; 256 * 240]) {
+    let nsf = player.nsf();
+    let white = palette::COLOR_LUT.get(0x30, false, 0);
+    let grey = palette::COLOR_LUT.get(0x10, false, 0);
+
+    // lines are drawn at double size, unless that doesn't fit
+    let mut y = 24;
+    let mut draw_line = |text: &str, color: u32| {
+        let max_len = (256 - 16) / text::GLYPH_ADVANCE;
+        let scale = if text.chars().count() * 2 <= max_len {
+            2
+        } else {
+            1
+        };
+        let text = text.chars().take(max_len).collect::<String>();
+
+        text::draw_text(framebuffer, 8, y, scale, color, &text);
+        y += (text::GLYPH_HEIGHT + 3) * scale;
+    };
+
+    draw_line(&nsf.title, white);
+    draw_line(&nsf.artist, grey);
+    draw_line(&nsf.copyright, grey);
+
+    let chips = EXPANSION_NAMES
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| nsf.expansion & (1 << i) != 0)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>();
+    if !chips.is_empty() {
+        draw_line(&chips.join(" "), grey);
+    }
+    draw_line("", white);
+
+    let track = &nsf.tracks[player.track() as usize];
+    draw_line(
+        &format!("track {}/{}", player.track() + 1, nsf.tracks.len()),
+        white,
+    );
+    if let Some(ref name) = track.name {
+        draw_line(name, white);
+    }
+    let elapsed = format_time(player.elapsed_ms());
+    match track.time {
+        Some(time) => draw_line(&format!("{} / {}", elapsed, format_time(time)), grey),
+        None => draw_line(&elapsed, grey),
+    }
+
+    draw_line("", white);
+    draw_line("left/right: change track", grey);
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // builds an nsf file with the given program, loaded at 0x8000 (with init at
+    // 0x8000 and play at 0x8010)
+    pub fn new_nsf(track_count: u8, expansion: u8, data: &[u8]) -> Vec<u8> {
+        let mut nsf = b"NESM\x1a\x01".to_vec();
+        nsf.extend_from_slice(&[track_count, 2, 0x00, 0x80, 0x00, 0x80, 0x10, 0x80]);
+        for string in [&b"Title"[..], b"Artist", b"2021 Copyright"].iter() {
+            let mut field = string.to_vec();
+            field.resize(32, 0);
+            nsf.extend_from_slice(&field);
+        }
+        nsf.extend_from_slice(&16639u16.to_le_bytes());
+        nsf.extend_from_slice(&[0; 8]);
+        nsf.extend_from_slice(&19997u16.to_le_bytes());
+        nsf.extend_from_slice(&[0, expansion, 0, 0, 0, 0]);
+        nsf.extend_from_slice(data);
+        nsf
+    }
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_le_bytes().to_vec();
+        chunk.extend_from_slice(id);
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    #[test]
+    fn test_parse_nsf() {
+        let nsf = parse(&new_nsf(3, EXPANSION_VRC6 | EXPANSION_N163, &[0x60])).unwrap();
+        assert_eq!(nsf.load_addr, 0x8000);
+        assert_eq!(nsf.play_addr, 0x8010);
+        assert_eq!(nsf.initial_banks, None);
+        assert_eq!(nsf.starting_track, 1);
+        assert_eq!(nsf.title, "Title");
+        assert_eq!(nsf.copyright, "2021 Copyright");
+        assert_eq!(nsf.expansion, 0x11);
+        assert_eq!(nsf.tracks, vec![Track::default(); 3]);
+        assert_eq!(nsf.data, [0x60]);
+
+        // bankswitched
+        let mut rom = new_nsf(1, 0, &[0x60]);
+        rom[0x77] = 5;
+        assert_eq!(
+            parse(&rom).unwrap().initial_banks,
+            Some([0, 0, 0, 0, 0, 0, 0, 5])
+        );
+
+        assert!(parse(&new_nsf(0, 0, &[0x60])).is_err());
+    }
+
+    #[test]
+    fn test_parse_nsfe() {
+        let mut rom = b"NSFE".to_vec();
+        rom.extend(chunk(
+            b"INFO",
+            &[0x00, 0x80, 0x00, 0x80, 0x10, 0x80, 0, EXPANSION_FDS, 2, 1],
+        ));
+        rom.extend(chunk(b"DATA", &[0x60]));
+        rom.extend(chunk(b"auth", b"Title\0Artist\0Copyright\0Ripper\0"));
+        rom.extend(chunk(b"tlbl", b"First\0Second\0"));
+        rom.extend(chunk(b"time", &[0x10, 0x27, 0, 0, 0xff, 0xff, 0xff, 0xff]));
+        rom.extend(chunk(b"fade", &[0xe8, 0x03, 0, 0]));
+        rom.extend(chunk(b"plst", &[1, 0]));
+        rom.extend(chunk(b"NEND", &[]));
+
+        let nsf = parse(&rom).unwrap();
+        assert_eq!(nsf.init_addr, 0x8000);
+        assert_eq!(nsf.expansion, EXPANSION_FDS);
+        assert_eq!(nsf.starting_track, 1);
+        assert_eq!(nsf.artist, "Artist");
+        assert_eq!(
+            nsf.tracks,
+            [
+                Track {
+                    name: Some("First".to_string()),
+                    time: Some(10000),
+                    fade: Some(1000),
+                },
+                Track {
+                    name: Some("Second".to_string()),
+                    time: None,
+                    fade: None,
+                },
+            ]
+        );
+
+        // unknown required chunks are an error
+        let mut rom = rom[..rom.len() - 8].to_vec();
+        rom.extend(chunk(b"XTRA", &[]));
+        assert!(parse(&rom).is_err());
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "0:00");
+        assert_eq!(format_time(150_000), "2:30");
+        assert_eq!(format_time(61_999), "1:01");
+    }
+}
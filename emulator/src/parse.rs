@@ -1,50 +1,357 @@
-#[derive(Debug)]
+use std::borrow::Cow;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MirroringType {
     Hor = 0,
     Vert = 1,
     FourScreen = 0xf,
+    // only set by unif roms (ines headers can't specify single-screen mirroring)
+    SingleScreenA,
+    SingleScreenB,
+}
+
+// the cpu/ppu timing the rom was made for (byte 12 of a nes 2.0 header)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    // works on both ntsc and pal consoles
+    MultiRegion,
+    Dendy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsoleType {
+    Nes,
+    // the ppu and hardware type are stored as the raw nibbles of byte 13
+    VsSystem { ppu_type: u8, hardware_type: u8 },
+    Playchoice10,
+    // the extended console type (byte 13) for famiclones, vt0x and the like
+    Extended(u8),
 }
 
-pub fn is_valid(rom_header: &[u8]) -> bool {
-    if rom_header[0..=3] != [b'N', b'E', b'S', 0x1a] {
-        // magic number is not present
-        return false;
+// the 16 byte header at the start of an ines (1.0 or 2.0) rom. all sizes are in bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomHeader {
+    pub is_nes_2_format: bool,
+    pub mapper: u16,
+    // always 0 for ines 1.0 headers
+    pub submapper: u8,
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    // NOTE: ines 1.0 headers only specify the total amount of prg ram (which is
+    // stored as 'prg_ram_size'), and never specify an amount of chr ram
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub mirroring: MirroringType,
+    // whether the cartridge contains battery-backed (or otherwise non-volatile) memory
+    pub has_battery: bool,
+    // whether there's a 512 byte trainer between the header and prg rom
+    pub has_trainer: bool,
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    pub misc_rom_count: u8,
+    // the raw id of the default expansion device (e.g. 1 = standard controllers)
+    pub default_expansion_device: u8,
+}
+
+impl RomHeader {
+    pub fn parse(rom: &[u8]) -> Result<Self, String> {
+        if rom.len() < 0x10 {
+            return Err(format!(
+                "expected a 16 byte header, but the file only had {} bytes",
+                rom.len()
+            ));
+        }
+
+        if rom[0..=3] != [b'N', b'E', b'S', 0x1a] {
+            return Err("invalid ines header information (magic number is missing)".to_string());
+        }
+
+        if (rom[7] & 0b1100) == 8 {
+            Self::parse_nes_2(rom)
+        } else {
+            Ok(Self::parse_ines(rom))
+        }
+    }
+
+    fn parse_ines(rom: &[u8]) -> Self {
+        // old rippers wrote garbage (like 'DiskDude!') to bytes 7-15. if the last 4 bytes
+        // aren't zero, byte 7 can't be trusted either, so the upper mapper nibble is ignored
+        let mapper_hi = if rom[12..=15] == [0; 4] {
+            rom[7] & 0xf0
+        } else {
+            0
+        };
+
+        Self {
+            is_nes_2_format: false,
+            mapper: (mapper_hi | (rom[6] >> 4)) as u16,
+            submapper: 0,
+            prg_rom_size: 0x4000 * rom[4] as usize,
+            chr_rom_size: 0x2000 * rom[5] as usize,
+            // a value of 0 means 8 KB for compatibility
+            prg_ram_size: 0x2000 * (rom[8] as usize).max(1),
+            prg_nvram_size: 0,
+            chr_ram_size: 0,
+            chr_nvram_size: 0,
+            mirroring: parse_mirroring(rom[6]),
+            has_battery: (rom[6] & 0b10) != 0,
+            has_trainer: (rom[6] & 0b100) != 0,
+            timing: if rom[9] & 1 != 0 {
+                Timing::Pal
+            } else {
+                Timing::Ntsc
+            },
+            console_type: ConsoleType::Nes,
+            misc_rom_count: 0,
+            default_expansion_device: 0,
+        }
     }
 
-    if rom_header[12..=15] != [0; 4] && (rom_header[7] & 0b1100) == 8 {
-        // last 4 bytes are not zero and header isn't in nes 2.0 format
-        return false;
+    fn parse_nes_2(rom: &[u8]) -> Result<Self, String> {
+        let console_type = match rom[7] & 0b11 {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem {
+                ppu_type: rom[13] & 0xf,
+                hardware_type: rom[13] >> 4,
+            },
+            2 => ConsoleType::Playchoice10,
+            _ => ConsoleType::Extended(rom[13] & 0xf),
+        };
+
+        let timing = match rom[12] & 0b11 {
+            0 => Timing::Ntsc,
+            1 => Timing::Pal,
+            2 => Timing::MultiRegion,
+            _ => Timing::Dendy,
+        };
+
+        Ok(Self {
+            is_nes_2_format: true,
+            mapper: ((rom[8] & 0xf) as u16) << 8 | (rom[7] & 0xf0) as u16 | (rom[6] >> 4) as u16,
+            submapper: rom[8] >> 4,
+            prg_rom_size: parse_rom_size(rom[4], rom[9] & 0xf, 0x4000)?,
+            chr_rom_size: parse_rom_size(rom[5], rom[9] >> 4, 0x2000)?,
+            prg_ram_size: parse_ram_size(rom[10] & 0xf),
+            prg_nvram_size: parse_ram_size(rom[10] >> 4),
+            chr_ram_size: parse_ram_size(rom[11] & 0xf),
+            chr_nvram_size: parse_ram_size(rom[11] >> 4),
+            mirroring: parse_mirroring(rom[6]),
+            has_battery: (rom[6] & 0b10) != 0,
+            has_trainer: (rom[6] & 0b100) != 0,
+            timing,
+            console_type,
+            misc_rom_count: rom[14] & 0b11,
+            default_expansion_device: rom[15] & 0x3f,
+        })
     }
 
-    true
-}
+    // the chr ram that mappers should allocate, or 0 if the header doesn't specify it
+    pub fn total_chr_ram_size(&self) -> usize {
+        self.chr_ram_size + self.chr_nvram_size
+    }
 
-pub fn get_prg_size(rom_header: &[u8]) -> u8 {
-    rom_header[4]
+    // the prg ram that mappers should allocate, and how much of it is battery-backed.
+    // ines 1.0 headers don't tell the two apart, so boards with a battery back all of it
+    pub fn prg_ram_sizes(&self) -> (usize, usize) {
+        let size = self.prg_ram_size + self.prg_nvram_size;
+        if self.has_battery && self.prg_nvram_size == 0 && !self.is_nes_2_format {
+            (size, size)
+        } else {
+            (size, self.prg_nvram_size)
+        }
+    }
 }
 
-// NOTE: if zero, game uses chr /ram/, not chr rom
-pub fn get_chr_size(rom_header: &[u8]) -> u8 {
-    rom_header[5]
+fn parse_mirroring(flags_6: u8) -> MirroringType {
+    if (flags_6 & 0b1000) != 0 {
+        MirroringType::FourScreen
+    } else if (flags_6 & 1) != 0 {
+        MirroringType::Vert
+    } else {
+        MirroringType::Hor
+    }
 }
 
-pub fn get_mapper_num(rom_header: &[u8]) -> u8 {
-    (rom_header[7] & 0xf0) + ((rom_header[6] & 0xf0) >> 4)
+// nes 2.0 rom sizes are either a 12 bit count of 'unit' sized banks, or (if the high
+// nibble is 0xf) stored in exponent-multiplier notation as 2^e * (mm * 2 + 1) bytes
+fn parse_rom_size(lo: u8, hi_nibble: u8, unit: usize) -> Result<usize, String> {
+    if hi_nibble == 0xf {
+        let exponent = lo >> 2;
+        let multiplier = (lo & 0b11) as usize * 2 + 1;
+        1usize
+            .checked_shl(exponent as u32)
+            .and_then(|size| size.checked_mul(multiplier))
+            .ok_or_else(|| {
+                format!(
+                    "rom size of 2^{} * {} bytes is too large",
+                    exponent, multiplier
+                )
+            })
+    } else {
+        Ok((((hi_nibble as usize) << 8) | lo as usize) * unit)
+    }
 }
 
-pub fn get_mirroring_type(rom_header: &[u8]) -> MirroringType {
-    unsafe { std::mem::transmute((rom_header[6] & 1) | ((rom_header[6] & 0b1000) >> 3) * 0xf) }
+// nes 2.0 ram sizes are stored as a shift count (64 << n), with 0 meaning no ram
+fn parse_ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
 }
 
-pub fn has_prg_ram(rom_header: &[u8]) -> bool {
-    (rom_header[6] & 0b10) != 0
+// the parts of a rom file that mappers are created from (see 'address_bus::MAPPERS')
+pub struct RomDescription<'r> {
+    pub header: RomHeader,
+    // 512 bytes that are loaded to 0x7000-0x71ff before reset. used by hacked roms
+    // that were made to run on copier devices (see 'CpuAddressBus::prg_ram()')
+    pub trainer: Option<&'r [u8]>,
+    // borrowed from the rom file, except for formats that store rom in pieces (unif)
+    pub prg_rom: Cow<'r, [u8]>,
+    pub chr_rom: Cow<'r, [u8]>,
 }
 
-// NOTE: we ignore trainers anyway
-pub fn has_trainer(rom_header: &[u8]) -> bool {
-    (rom_header[6] & 0b100) != 0
+impl<'r> RomDescription<'r> {
+    pub fn new(rom: &'r [u8]) -> Result<Self, String> {
+        let header = RomHeader::parse(rom)?;
+        let trainer_size: usize = if header.has_trainer { 0x200 } else { 0 };
+        let prg_start = 0x10 + trainer_size;
+        let chr_end = prg_start
+            .checked_add(header.prg_rom_size)
+            .and_then(|chr_start| chr_start.checked_add(header.chr_rom_size))
+            .ok_or_else(|| "the rom sizes in the header are too large".to_string())?;
+        let chr_start = prg_start + header.prg_rom_size;
+
+        if rom.len() < chr_end {
+            return Err(format!(
+                "expected {} bytes after the header, but the file only had {}",
+                chr_end - 0x10,
+                rom.len() - 0x10
+            ));
+        }
+
+        Ok(Self {
+            trainer: if header.has_trainer {
+                Some(&rom[0x10..prg_start])
+            } else {
+                None
+            },
+            header,
+            prg_rom: Cow::Borrowed(&rom[prg_start..chr_start]),
+            chr_rom: Cow::Borrowed(&rom[chr_start..chr_end]),
+        })
+    }
 }
 
-pub fn is_nes_2_format(rom_header: &[u8]) -> bool {
-    (rom_header[7] & 0b1100) == 8
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(bytes: [u8; 12]) -> [u8; 16] {
+        let mut header = [0; 16];
+        header[0..4].copy_from_slice(&[b'N', b'E', b'S', 0x1a]);
+        header[4..].copy_from_slice(&bytes);
+        header
+    }
+
+    #[test]
+    fn test_ines() {
+        let rom = header([2, 1, 0x43, 0x10, 0, 1, 0, 0, 0, 0, 0, 0]);
+        let header = RomHeader::parse(&rom).unwrap();
+        assert!(!header.is_nes_2_format);
+        assert_eq!(header.mapper, 0x14);
+        assert_eq!(header.submapper, 0);
+        assert_eq!(header.prg_rom_size, 0x8000);
+        assert_eq!(header.chr_rom_size, 0x2000);
+        assert_eq!(header.prg_ram_size, 0x2000);
+        assert_eq!(header.prg_ram_sizes(), (0x2000, 0x2000));
+        assert_eq!(header.mirroring, MirroringType::Vert);
+        assert!(header.has_battery);
+        assert!(!header.has_trainer);
+        assert_eq!(header.timing, Timing::Pal);
+
+        // garbage in the last bytes of the header invalidates byte 7
+        let mut rom = rom;
+        rom[12..].copy_from_slice(b"ude!");
+        assert_eq!(RomHeader::parse(&rom).unwrap().mapper, 4);
+
+        rom[0] = b'M';
+        assert!(RomHeader::parse(&rom).is_err());
+        assert!(RomHeader::parse(&[0; 8]).is_err());
+    }
+
+    #[test]
+    fn test_nes_2() {
+        let rom = header([
+            0x10, 0, 0x4c, 0x19, 0x12, 0x01, 0x70, 0x07, 0x03, 0x21, 0x02, 0x01,
+        ]);
+        let header = RomHeader::parse(&rom).unwrap();
+        assert!(header.is_nes_2_format);
+        assert_eq!(header.mapper, 0x214);
+        assert_eq!(header.submapper, 1);
+        assert_eq!(header.prg_rom_size, 0x110 * 0x4000);
+        assert_eq!(header.chr_rom_size, 0);
+        assert_eq!(header.prg_ram_size, 0);
+        assert_eq!(header.prg_nvram_size, 0x2000);
+        assert_eq!(header.prg_ram_sizes(), (0x2000, 0x2000));
+        assert_eq!(header.chr_ram_size, 0x2000);
+        assert_eq!(header.total_chr_ram_size(), 0x2000);
+        assert_eq!(header.mirroring, MirroringType::FourScreen);
+        assert!(header.has_trainer);
+        assert_eq!(header.timing, Timing::Dendy);
+        assert_eq!(
+            header.console_type,
+            ConsoleType::VsSystem {
+                ppu_type: 1,
+                hardware_type: 2
+            }
+        );
+        assert_eq!(header.misc_rom_count, 2);
+        assert_eq!(header.default_expansion_device, 1);
+    }
+
+    #[test]
+    fn test_exponent_multiplier_sizes() {
+        // 2^7 * 3 bytes of prg rom, 2^10 * 7 bytes of chr rom
+        let rom = header([0b0001_1101, 0b0010_1011, 0, 0x08, 0, 0xff, 0, 0, 0, 0, 0, 0]);
+        let header = RomHeader::parse(&rom).unwrap();
+        assert_eq!(header.prg_rom_size, 128 * 3);
+        assert_eq!(header.chr_rom_size, 1024 * 7);
+    }
+
+    #[test]
+    fn test_oversized_rom_sizes() {
+        // sizes that don't fit in a usize are rejected instead of overflowing
+        let rom = header([0xff, 0, 0, 0x08, 0, 0x0f, 0, 0, 0, 0, 0, 0]);
+        assert!(RomHeader::parse(&rom).is_err());
+        let rom = header([0xfc, 0xfc, 0, 0x08, 0, 0xff, 0, 0, 0, 0, 0, 0]);
+        assert!(RomDescription::new(&rom).is_err());
+    }
+
+    #[test]
+    fn test_trainer() {
+        let mut rom = header([1, 1, 0b100, 0, 0, 0, 0, 0, 0, 0, 0, 0]).to_vec();
+        rom.extend_from_slice(&[0xaa; 0x200]);
+        rom.extend_from_slice(&[0xbb; 0x4000]);
+        rom.extend_from_slice(&[0xcc; 0x2000]);
+
+        let rom_description = RomDescription::new(&rom).unwrap();
+        assert_eq!(rom_description.trainer, Some(&[0xaa; 0x200][..]));
+        assert_eq!(rom_description.prg_rom, &[0xbb; 0x4000][..]);
+        assert_eq!(rom_description.chr_rom, &[0xcc; 0x2000][..]);
+
+        // the trainer counts towards the expected file size
+        rom.pop();
+        assert!(RomDescription::new(&rom).is_err());
+
+        rom[6] = 0;
+        let rom_description = RomDescription::new(&rom).unwrap();
+        assert_eq!(rom_description.trainer, None);
+        assert_eq!(rom_description.prg_rom[0], 0xaa);
+    }
 }
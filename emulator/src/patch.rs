@@ -0,0 +1,513 @@
+use crate::hash;
+
+use std::path::{Path, PathBuf};
+
+// the extensions of patches that are applied automatically if they're found next to the
+// rom (with the same file name), unless patches are passed with '--patch'
+static PATCH_EXTENSIONS: &[&str] = &["ips", "ups", "bps"];
+
+pub fn find_patches(rom_path: &Path) -> Vec<PathBuf> {
+    PATCH_EXTENSIONS
+        .iter()
+        .map(|extension| rom_path.with_extension(extension))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+// applies an ips, ups or bps patch (detected from the patch's header) to 'rom'
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(b"UPS1") {
+        apply_ups(rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(rom, patch)
+    } else {
+        Err("unknown patch format (expected an ips, ups or bps patch)".to_string())
+    }
+}
+
+// reads bytes from a patch, failing once the end (or the footer) is reached
+struct PatchReader<'p> {
+    data: &'p [u8],
+    pos: usize,
+}
+
+impl<'p> PatchReader<'p> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'p [u8], String> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or("unexpected end of patch")?;
+        self.pos += len;
+
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_be(&mut self, len: usize) -> Result<usize, String> {
+        Ok(self
+            .read_bytes(len)?
+            .iter()
+            .fold(0, |val, &byte| (val << 8) | byte as usize))
+    }
+
+    // the variable-length integers used by ups and bps. each byte holds 7 bits, with
+    // the top bit marking the last byte. non-final bytes have an implicit +1 (so that
+    // every number has exactly one encoding)
+    fn read_varint(&mut self) -> Result<usize, String> {
+        let mut val = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let byte = self.read_u8()?;
+            val = ((byte & 0x7f) as usize)
+                .checked_mul(shift)
+                .and_then(|digit| val.checked_add(digit))
+                .ok_or("invalid number in patch")?;
+            if byte & 0x80 != 0 {
+                return Ok(val);
+            }
+
+            shift = shift.checked_mul(0x80).ok_or("invalid number in patch")?;
+            val = val.checked_add(shift).ok_or("invalid number in patch")?;
+        }
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+
+// ips patches are a list of records, each holding a 24 bit offset and a 16 bit length
+// followed by the data, or a 16 bit repeat count and a value (rle records, length 0).
+// the list ends with 'EOF', optionally followed by a 24 bit length to truncate the rom to
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut reader = PatchReader {
+        data: patch,
+        pos: 5,
+    };
+    let mut target = rom.to_vec();
+
+    loop {
+        let offset_bytes = reader.read_bytes(3)?;
+        if offset_bytes == b"EOF" {
+            break;
+        }
+
+        let offset = offset_bytes
+            .iter()
+            .fold(0, |val, &byte| (val << 8) | byte as usize);
+        let len = reader.read_be(2)?;
+
+        if len == 0 {
+            let count = reader.read_be(2)?;
+            let val = reader.read_u8()?;
+            if target.len() < offset + count {
+                target.resize(offset + count, 0);
+            }
+            target[offset..offset + count]
+                .iter_mut()
+                .for_each(|byte| *byte = val);
+        } else {
+            let data = reader.read_bytes(len)?;
+            if target.len() < offset + len {
+                target.resize(offset + len, 0);
+            }
+            target[offset..offset + len].copy_from_slice(data);
+        }
+    }
+
+    // truncate extension
+    if !reader.is_at_end() {
+        let len = reader.read_be(3)?;
+        target.truncate(len);
+    }
+
+    Ok(target)
+}
+
+// creates an ips patch that turns 'source' into 'target' (the reverse of 'apply_ips()').
+// used to store changes to disk images without touching the original file (see 'fds')
+pub fn create_ips(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut patch = b"PATCH".to_vec();
+    let mut pos = 0;
+
+    while pos < target.len() {
+        if source.get(pos) == Some(&target[pos]) {
+            pos += 1;
+            continue;
+        }
+
+        // a record at offset 0x454f46 would be read as 'EOF', so it's started a byte earlier
+        let start = if pos == 0x454f46 { pos - 1 } else { pos };
+        let mut end = pos;
+        while end < target.len() && end - start < 0xffff && source.get(end) != Some(&target[end]) {
+            end += 1;
+        }
+
+        patch.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        patch.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        patch.extend_from_slice(&target[start..end]);
+        pos = end;
+    }
+
+    patch.extend_from_slice(b"EOF");
+    if target.len() < source.len() {
+        patch.extend_from_slice(&(target.len() as u32).to_be_bytes()[1..]);
+    }
+
+    patch
+}
+
+// ups and bps patches end with the crc32 of the source, the target and the patch itself
+fn verify_footer(source: &[u8], patch: &[u8]) -> Result<(u32, usize), String> {
+    if patch.len() < 4 + 12 {
+        return Err("unexpected end of patch".to_string());
+    }
+
+    let footer = &patch[patch.len() - 12..];
+    let read_crc = |idx: usize| {
+        u32::from_le_bytes([
+            footer[idx * 4],
+            footer[idx * 4 + 1],
+            footer[idx * 4 + 2],
+            footer[idx * 4 + 3],
+        ])
+    };
+
+    if hash::crc32(&patch[..patch.len() - 4]) != read_crc(2) {
+        return Err("patch checksum mismatch (the patch is corrupted)".to_string());
+    }
+
+    if hash::crc32(source) != read_crc(0) {
+        return Err("rom checksum mismatch (the patch is meant for a different rom)".to_string());
+    }
+
+    // returns the expected target crc and where the footer starts
+    Ok((read_crc(1), patch.len() - 12))
+}
+
+fn verify_target(target: &[u8], target_crc: u32) -> Result<(), String> {
+    if hash::crc32(target) != target_crc {
+        return Err("patched rom checksum mismatch".to_string());
+    }
+
+    Ok(())
+}
+
+// ups patches hold the source and target sizes, followed by hunks made up of an offset
+// (relative to the end of the previous hunk) and bytes to xor with, ending in a zero
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let (target_crc, footer_start) = verify_footer(rom, patch)?;
+    let mut reader = PatchReader {
+        data: &patch[..footer_start],
+        pos: 4,
+    };
+
+    let source_size = reader.read_varint()?;
+    let target_size = reader.read_varint()?;
+    if source_size != rom.len() {
+        return Err(format!(
+            "expected a {} byte rom, but the rom is {} bytes",
+            source_size,
+            rom.len()
+        ));
+    }
+
+    let mut target = rom.to_vec();
+    target.resize(target_size, 0);
+
+    let invalid_offset = || "invalid hunk offset in patch".to_string();
+    let mut pos = 0usize;
+    while !reader.is_at_end() {
+        pos = pos
+            .checked_add(reader.read_varint()?)
+            .ok_or_else(invalid_offset)?;
+        loop {
+            let xor = reader.read_u8()?;
+            if let Some(byte) = target.get_mut(pos) {
+                *byte ^= xor;
+            }
+
+            pos = pos.checked_add(1).ok_or_else(invalid_offset)?;
+            if xor == 0 {
+                break;
+            }
+        }
+    }
+
+    verify_target(&target, target_crc)?;
+
+    Ok(target)
+}
+
+// bps patches hold the source, target and metadata sizes (and the metadata), followed by
+// actions that build the target from the source, the patch, or earlier parts of the target
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let (target_crc, footer_start) = verify_footer(rom, patch)?;
+    let mut reader = PatchReader {
+        data: &patch[..footer_start],
+        pos: 4,
+    };
+
+    let source_size = reader.read_varint()?;
+    let target_size = reader.read_varint()?;
+    let metadata_size = reader.read_varint()?;
+    reader.read_bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(format!(
+            "expected a {} byte rom, but the rom is {} bytes",
+            source_size,
+            rom.len()
+        ));
+    }
+
+    let mut target = Vec::new();
+    let mut source_offset = 0usize;
+    let mut target_offset = 0usize;
+
+    // the offsets of the copy actions are stored as a sign bit and a magnitude
+    let apply_relative_offset = |offset: usize, data: usize| {
+        if data & 1 != 0 {
+            offset.checked_sub(data >> 1)
+        } else {
+            offset.checked_add(data >> 1)
+        }
+        .ok_or_else(|| "invalid copy offset in patch".to_string())
+    };
+
+    while !reader.is_at_end() {
+        let data = reader.read_varint()?;
+        let len = (data >> 2) + 1;
+        let out_of_bounds = || "patch action is out of bounds".to_string();
+        if len > target_size - target.len() {
+            return Err(out_of_bounds());
+        }
+
+        match data & 0b11 {
+            // source read
+            0 => {
+                let pos = target.len();
+                let bytes = rom.get(pos..pos + len).ok_or_else(out_of_bounds)?;
+                target.extend_from_slice(bytes);
+            }
+            // target read
+            1 => target.extend_from_slice(reader.read_bytes(len)?),
+            // source copy
+            2 => {
+                source_offset = apply_relative_offset(source_offset, reader.read_varint()?)?;
+                let end = source_offset.checked_add(len).ok_or_else(out_of_bounds)?;
+                let bytes = rom.get(source_offset..end).ok_or_else(out_of_bounds)?;
+                target.extend_from_slice(bytes);
+                source_offset = end;
+            }
+            // target copy. the copied range can overlap the bytes being written
+            _ => {
+                target_offset = apply_relative_offset(target_offset, reader.read_varint()?)?;
+                if target_offset >= target.len() {
+                    return Err(out_of_bounds());
+                }
+
+                for _ in 0..len {
+                    target.push(target[target_offset]);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size {
+        return Err(format!(
+            "expected a {} byte patched rom, but the patch produced {} bytes",
+            target_size,
+            target.len()
+        ));
+    }
+
+    verify_target(&target, target_crc)?;
+
+    Ok(target)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn push_varint(patch: &mut Vec<u8>, mut val: usize) {
+        loop {
+            let byte = (val & 0x7f) as u8;
+            val >>= 7;
+            if val == 0 {
+                patch.push(byte | 0x80);
+                return;
+            }
+
+            patch.push(byte);
+            val -= 1;
+        }
+    }
+
+    fn push_footer(patch: &mut Vec<u8>, source: &[u8], target: &[u8]) {
+        patch.extend_from_slice(&hash::crc32(source).to_le_bytes());
+        patch.extend_from_slice(&hash::crc32(target).to_le_bytes());
+        let patch_crc = hash::crc32(patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+    }
+
+    #[test]
+    fn test_varint() {
+        for &val in [0, 1, 0x7f, 0x80, 0x4000, 0x123456].iter() {
+            let mut data = Vec::new();
+            push_varint(&mut data, val);
+            let mut reader = PatchReader {
+                data: &data,
+                pos: 0,
+            };
+            assert_eq!(reader.read_varint().unwrap(), val);
+            assert!(reader.is_at_end());
+        }
+
+        let mut reader = PatchReader {
+            data: &[0x7f; 12],
+            pos: 0,
+        };
+        assert!(reader.read_varint().is_err());
+    }
+
+    #[test]
+    fn test_ips() {
+        let rom = [0u8; 8];
+        let mut patch = b"PATCH".to_vec();
+        // regular record
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, 0xaa, 0xbb]);
+        // rle record, extending the rom
+        patch.extend_from_slice(&[0, 0, 6, 0, 0, 0, 4, 0xcc]);
+        patch.extend_from_slice(b"EOF");
+
+        assert_eq!(
+            apply(&rom, &patch).unwrap(),
+            [0, 0xaa, 0xbb, 0, 0, 0, 0xcc, 0xcc, 0xcc, 0xcc]
+        );
+
+        // truncate extension
+        patch.extend_from_slice(&[0, 0, 3]);
+        assert_eq!(apply(&rom, &patch).unwrap(), [0, 0xaa, 0xbb]);
+
+        patch.truncate(10);
+        assert!(apply(&rom, &patch).is_err());
+    }
+
+    #[test]
+    fn test_create_ips() {
+        let source = (0..0x100).map(|i| i as u8).collect::<Vec<_>>();
+        let mut target = source.clone();
+        target[3] = 0xff;
+        target[10..20].iter_mut().for_each(|byte| *byte = 0);
+        target.extend_from_slice(&[1, 2, 3]);
+
+        let patch = create_ips(&source, &target);
+        assert_eq!(apply(&source, &patch).unwrap(), target);
+        assert_eq!(
+            apply(&source, &create_ips(&source, &source)).unwrap(),
+            source
+        );
+        assert_eq!(
+            apply(&source, &create_ips(&source, &source[..5])).unwrap(),
+            &source[..5]
+        );
+    }
+
+    #[test]
+    fn test_ups() {
+        let rom = [1u8, 2, 3, 4];
+        let target = [1u8, 0x12, 3, 4, 0, 5];
+
+        let mut patch = b"UPS1".to_vec();
+        push_varint(&mut patch, rom.len());
+        push_varint(&mut patch, target.len());
+        // xor 0x10 at offset 1
+        push_varint(&mut patch, 1);
+        patch.extend_from_slice(&[0x10, 0]);
+        // xor 5 at offset 5 (after the previous hunk, which ended at offset 3)
+        push_varint(&mut patch, 2);
+        patch.extend_from_slice(&[5, 0]);
+        push_footer(&mut patch, &rom, &target);
+
+        assert_eq!(apply(&rom, &patch).unwrap(), target);
+        // wrong source rom
+        assert!(apply(&[1, 2, 3, 5], &patch).is_err());
+
+        // corrupted patch
+        patch[7] ^= 1;
+        assert!(apply(&rom, &patch).is_err());
+
+        // hunks past the end of the address space
+        let mut patch = b"UPS1".to_vec();
+        push_varint(&mut patch, rom.len());
+        push_varint(&mut patch, rom.len());
+        push_varint(&mut patch, usize::MAX - 1);
+        patch.extend_from_slice(&[1, 0]);
+        push_footer(&mut patch, &rom, &rom);
+        assert!(apply(&rom, &patch).is_err());
+    }
+
+    #[test]
+    fn test_bps() {
+        let rom = b"abcdefgh";
+        let target = b"abcXYcdcdcdcdfgh";
+
+        let mut patch = b"BPS1".to_vec();
+        push_varint(&mut patch, rom.len());
+        push_varint(&mut patch, target.len());
+        push_varint(&mut patch, 3);
+        patch.extend_from_slice(b"abc");
+        // source read "abc"
+        push_varint(&mut patch, (3 - 1) << 2);
+        // target read "XY"
+        push_varint(&mut patch, ((2 - 1) << 2) | 1);
+        patch.extend_from_slice(b"XY");
+        // source copy "cd" (from offset 2)
+        push_varint(&mut patch, ((2 - 1) << 2) | 2);
+        push_varint(&mut patch, 2 << 1);
+        // target copy "cdcdcd" (from offset 5, overlapping the bytes being written)
+        push_varint(&mut patch, ((6 - 1) << 2) | 3);
+        push_varint(&mut patch, 5 << 1);
+        // source copy "fgh" (from offset 5, relative to the end of the last copy)
+        push_varint(&mut patch, ((3 - 1) << 2) | 2);
+        push_varint(&mut patch, 1 << 1);
+        push_footer(&mut patch, rom, target);
+
+        assert_eq!(apply(rom, &patch).unwrap(), &target[..]);
+        assert!(apply(b"abcdefgx", &patch).is_err());
+
+        // target copy past the end of the target
+        let mut patch = b"BPS1".to_vec();
+        push_varint(&mut patch, rom.len());
+        push_varint(&mut patch, 4);
+        push_varint(&mut patch, 0);
+        push_varint(&mut patch, 1);
+        patch.push(b'a');
+        push_varint(&mut patch, ((usize::MAX >> 2) << 2) | 3);
+        push_varint(&mut patch, 0);
+        push_footer(&mut patch, rom, b"aaaa");
+        assert!(apply(rom, &patch).is_err());
+    }
+
+    #[test]
+    fn test_find_patches() {
+        let dir = std::env::temp_dir().join("nees_patch_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("game.ips"), b"PATCHEOF").unwrap();
+        std::fs::write(dir.join("game.bps"), b"BPS1").unwrap();
+
+        assert_eq!(
+            find_patches(&dir.join("game.nes")),
+            [dir.join("game.ips"), dir.join("game.bps")]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,161 @@
+use crate::address_bus::CpuAddressBus;
+use crate::cpu;
+
+// the contents of cpu ram, oam, the nametables and palette ram when the console is
+// powered on. on real hardware, they start out in a (mostly) unpredictable state, which
+// some games end up depending on by accident
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PowerOnState {
+    Zeros,
+    Ones,
+    Random,
+    // the cpu ram pattern and palettes of a typical console (see 'PowerOnState::fill()')
+    Hardware,
+}
+
+impl std::str::FromStr for PowerOnState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zeros" => Ok(PowerOnState::Zeros),
+            "ones" => Ok(PowerOnState::Ones),
+            "random" => Ok(PowerOnState::Random),
+            "hardware" => Ok(PowerOnState::Hardware),
+            _ => Err(format!(
+                "unknown power-on state '{}' (expected zeros, ones, random or hardware)",
+                s
+            )),
+        }
+    }
+}
+
+// the palette ram contents most consoles power on with (from the nesdev.com 'PPU power
+// up state' page). the entries at 0x3f10, 0x3f14, 0x3f18 and 0x3f1c mirror those at
+// 0x3f00, 0x3f04, 0x3f08 and 0x3f0c, and so have the same values
+const HARDWARE_PALETTES: [u8; 32] = [
+    0x09, 0x01, 0x00, 0x01, 0x00, 0x02, 0x02, 0x0d, 0x08, 0x10, 0x08, 0x24, 0x00, 0x00, 0x04, 0x2c,
+    0x09, 0x01, 0x34, 0x03, 0x00, 0x04, 0x00, 0x14, 0x08, 0x3a, 0x00, 0x02, 0x00, 0x20, 0x2c, 0x08,
+];
+
+// xorshift generator for the 'random' power-on state
+struct Rng(u32);
+
+impl Rng {
+    fn from_time() -> Self {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.subsec_nanos());
+        // xorshift gets stuck on a state of zero
+        Self(nanos | 1)
+    }
+
+    fn next(&mut self) -> u8 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 >> 24) as u8
+    }
+}
+
+impl PowerOnState {
+    // fills 'mem' with the power-on state. apart from palette ram (see 'HARDWARE_PALETTES'),
+    // the 'hardware' state alternates between 4 bytes of 0x00 and 4 bytes of 0xff, which
+    // is the most common pattern for cpu ram. the contents of oam and the nametables vary
+    // too much between consoles to have a typical pattern, so they reuse the cpu ram
+    // pattern as an approximation
+    fn fill(self, mem: &mut [u8], rng: &mut Rng) {
+        for (i, byte) in mem.iter_mut().enumerate() {
+            *byte = match self {
+                PowerOnState::Zeros => 0,
+                PowerOnState::Ones => 0xff,
+                PowerOnState::Random => rng.next(),
+                PowerOnState::Hardware if i & 4 == 0 => 0,
+                PowerOnState::Hardware => 0xff,
+            };
+        }
+    }
+
+    fn fill_palettes(self, palettes: &mut [u8; 32], rng: &mut Rng) {
+        match self {
+            PowerOnState::Hardware => *palettes = HARDWARE_PALETTES,
+            _ => self.fill(palettes, rng),
+        }
+
+        // palette ram is only 6 bits wide
+        for entry in palettes.iter_mut() {
+            *entry &= 0b111111;
+        }
+    }
+}
+
+// puts cpu ram, oam, the nametables and palette ram in the given power-on state. the
+// memory is written through the buses, so that it works the same way for every mapper
+// (including those that map nametables to cartridge memory)
+pub fn apply(state: PowerOnState, bus: &mut dyn CpuAddressBus, cpu: &mut cpu::Cpu) {
+    let mut rng = Rng::from_time();
+
+    let mut ram = [0u8; 0x800];
+    state.fill(&mut ram, &mut rng);
+    for (addr, &val) in ram.iter().enumerate() {
+        bus.write(addr as u16, val, cpu);
+    }
+
+    let mut nametables = [0u8; 0x1000];
+    state.fill(&mut nametables, &mut rng);
+    let mut palettes = [0u8; 32];
+    state.fill_palettes(&mut palettes, &mut rng);
+
+    let (base, ppu_bus) = bus.base();
+    state.fill(base.ppu.primary_oam_mut().as_bytes_mut(), &mut rng);
+    for (i, &val) in nametables.iter().enumerate() {
+        ppu_bus.write(0x2000 + i as u16, val, 0, cpu);
+    }
+    for (i, &val) in palettes.iter().enumerate() {
+        ppu_bus.write(0x3f00 + i as u16, val, 0, cpu);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_fill() {
+        let mut rng = Rng(1);
+        let mut mem = [0x55u8; 16];
+
+        PowerOnState::Ones.fill(&mut mem, &mut rng);
+        assert_eq!(mem, [0xff; 16]);
+
+        PowerOnState::Hardware.fill(&mut mem, &mut rng);
+        assert_eq!(mem[..8], [0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(mem[..8], mem[8..]);
+
+        // random palette entries are masked to 6 bits
+        let mut palettes = [0u8; 32];
+        PowerOnState::Random.fill_palettes(&mut palettes, &mut rng);
+        assert!(palettes.iter().all(|&entry| entry < 0x40));
+        assert!(palettes.iter().any(|&entry| entry != palettes[0]));
+    }
+
+    #[test]
+    fn test_apply() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let mut nes = crate::Nes::new_test(unsafe { &*(&framebuffer as *const _ as *const _) });
+
+        apply(PowerOnState::Hardware, nes.bus, &mut nes.cpu);
+
+        assert_eq!(nes.bus.read(0x0003, &mut nes.cpu), 0);
+        assert_eq!(nes.bus.read(0x07fc, &mut nes.cpu), 0xff);
+        // 0x0800-0x1fff mirrors the 2 KB of cpu ram
+        assert_eq!(nes.bus.read(0x0804, &mut nes.cpu), 0xff);
+
+        let (base, ppu_bus) = nes.bus.base();
+        assert_eq!(base.ppu.primary_oam_mut().get_byte(5), 0xff);
+        assert_eq!(ppu_bus.read(0x2004, 0, &mut nes.cpu), 0xff);
+        assert_eq!(ppu_bus.read(0x3f0b, 0, &mut nes.cpu), 0x24);
+        assert_eq!(ppu_bus.read(0x3f19, 0, &mut nes.cpu), 0x3a);
+    }
+}
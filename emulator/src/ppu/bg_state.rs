@@ -27,6 +27,11 @@ pub(super) struct BgDrawState {
     pub tile_bitplanes_lo: TileBitPlanes,
     // holds the palette indices of the two current tiles (in the first 4 bits)
     pub tile_palette_indices: u8,
+    // the data fetched for the next tile, before it's loaded into the shift registers
+    next_tile_index: u8,
+    next_palette_idx: u8,
+    next_bitplane_lo: u8,
+    next_bitplane_hi: u8,
 }
 
 impl BgDrawState {
@@ -46,52 +51,100 @@ impl BgDrawState {
         cpu: &mut cpu::Cpu,
     ) {
         // get the high and low bitplanes for the current row of the current tile
-        let (bg_bitplane_lo, bg_bitplane_hi) = {
-            // get tile index from nametable using lower 12 bits of 'current_vram_addr' + 0x2000
-            let addr = (current_vram_addr.get_addr() & 0xfff) | 0x2000;
-            let tile_index = bus.read(addr, cycle_count, cpu);
-
-            let tile_addr = background_pattern_table_addr | ((tile_index as u16) << 4);
-            let fine_y = current_vram_addr.get_fine_y();
-
-            (
-                bus.read(tile_addr + fine_y as u16, cycle_count + 2, cpu),
-                bus.read(tile_addr + 8 + fine_y as u16, cycle_count + 4, cpu),
-            )
+        self.next_tile_index = bus.read(nametable_addr(current_vram_addr), cycle_count, cpu);
+        let tile_addr = self.pattern_addr(background_pattern_table_addr, current_vram_addr);
+        self.next_bitplane_lo = bus.read(tile_addr, cycle_count + 2, cpu);
+        self.next_bitplane_hi = bus.read(tile_addr + 8, cycle_count + 4, cpu);
+
+        // get the 'attribute' byte from the attribute table
+        let attribute = bus.read(attribute_addr(current_vram_addr), cycle_count + 6, cpu);
+        self.set_next_attribute(attribute, current_vram_addr);
+
+        self.load_next_tile();
+    }
+
+    // makes the part of a tile fetch that happens on the given dot of the tile (0-7, with
+    // 0 being dots 1, 9, 17, etc). the address of each fetch is put on the bus on even
+    // dots, and read on odd dots. only used when stepping dot by dot, with the data being
+    // loaded into the shift registers by 'load_next_tile()' once the fetch is done
+    pub(super) fn fetch_tile_data_at_dot(
+        &mut self,
+        tile_dot: u16,
+        cycle_count: i32,
+        background_pattern_table_addr: u16,
+        current_vram_addr: super::VramAddrRegister,
+        bus: &mut dyn PpuAddressBus,
+        cpu: &mut cpu::Cpu,
+    ) {
+        let addr = match tile_dot >> 1 {
+            0 => nametable_addr(current_vram_addr),
+            1 => attribute_addr(current_vram_addr),
+            2 => self.pattern_addr(background_pattern_table_addr, current_vram_addr),
+            _ => self.pattern_addr(background_pattern_table_addr, current_vram_addr) + 8,
         };
 
-        let bg_palette_idx = {
-            let coarse_y = current_vram_addr.get_coarse_y();
-            let coarse_x = current_vram_addr.get_coarse_x();
+        if tile_dot & 1 == 0 {
+            bus.set_address(addr, cycle_count, cpu);
+            return;
+        }
+
+        let val = bus.read(addr, cycle_count, cpu);
+        match tile_dot {
+            1 => self.next_tile_index = val,
+            3 => self.set_next_attribute(val, current_vram_addr),
+            5 => self.next_bitplane_lo = val,
+            _ => self.next_bitplane_hi = val,
+        }
+    }
 
-            // calculate the address of the current tile's 'attribute' in the attribute table
-            let attribute_addr = 0x23c0
-                | (current_vram_addr.get_nametable_select() as u16) << 10
-                | (coarse_y << 1) as u16 & 0b111000
-                | (coarse_x >> 2) as u16;
+    // the address of the low bitplane for the current row of the next tile
+    fn pattern_addr(
+        &self,
+        background_pattern_table_addr: u16,
+        current_vram_addr: super::VramAddrRegister,
+    ) -> u16 {
+        let tile_addr = background_pattern_table_addr | ((self.next_tile_index as u16) << 4);
+        tile_addr + current_vram_addr.get_fine_y() as u16
+    }
 
-            // get the 'attribute' byte from the attribute table
-            let attribute = bus.read(attribute_addr, cycle_count + 6, cpu);
-            // calculate how much to shift 'attribute' by to get the current tile's palette index
-            let shift_amt = ((coarse_y << 1) & 0b100) | (coarse_x & 0b10);
+    fn set_next_attribute(&mut self, attribute: u8, current_vram_addr: super::VramAddrRegister) {
+        let coarse_y = current_vram_addr.get_coarse_y();
+        let coarse_x = current_vram_addr.get_coarse_x();
 
-            (attribute >> shift_amt) & 0b11
-        };
+        // calculate how much to shift 'attribute' by to get the current tile's palette index
+        let shift_amt = ((coarse_y << 1) & 0b100) | (coarse_x & 0b10);
+        self.next_palette_idx = (attribute >> shift_amt) & 0b11;
+    }
 
-        // store the data
-        {
-            // the tile bitplane byte we want to store our high bg bitplane
-            // in should be zero (its contents should have been shifted
-            // leftwards into 'self.tile_bitplanes_hi.0[1]' by a call to
-            // 'shift_tile_data_by_8()' previously)
-            debug_assert_eq!(self.tile_bitplanes_hi.0[0], 0);
-            self.tile_bitplanes_hi.0[0] = bg_bitplane_hi;
+    // stores the data fetched for the next tile in the rightmost 8 bits of the shift registers
+    pub(super) fn load_next_tile(&mut self) {
+        // the tile bitplane byte we want to store our high bg bitplane
+        // in should be zero (its contents should have been shifted
+        // leftwards into 'self.tile_bitplanes_hi.0[1]' by a call to
+        // 'shift_tile_data_by_8()' previously)
+        debug_assert_eq!(self.tile_bitplanes_hi.0[0], 0);
+        self.tile_bitplanes_hi.0[0] = self.next_bitplane_hi;
 
-            debug_assert_eq!(self.tile_bitplanes_lo.0[0], 0);
-            self.tile_bitplanes_lo.0[0] = bg_bitplane_lo;
+        debug_assert_eq!(self.tile_bitplanes_lo.0[0], 0);
+        self.tile_bitplanes_lo.0[0] = self.next_bitplane_lo;
 
-            debug_assert_eq!(self.tile_palette_indices & 0b11, 0);
-            self.tile_palette_indices |= bg_palette_idx;
-        }
+        debug_assert_eq!(self.tile_palette_indices & 0b11, 0);
+        self.tile_palette_indices |= self.next_palette_idx;
     }
 }
+
+// get tile index from nametable using lower 12 bits of 'current_vram_addr' + 0x2000
+pub(super) fn nametable_addr(current_vram_addr: super::VramAddrRegister) -> u16 {
+    (current_vram_addr.get_addr() & 0xfff) | 0x2000
+}
+
+// calculate the address of the current tile's 'attribute' in the attribute table
+pub(super) fn attribute_addr(current_vram_addr: super::VramAddrRegister) -> u16 {
+    let coarse_y = current_vram_addr.get_coarse_y();
+    let coarse_x = current_vram_addr.get_coarse_x();
+
+    0x23c0
+        | (current_vram_addr.get_nametable_select() as u16) << 10
+        | (coarse_y << 1) as u16 & 0b111000
+        | (coarse_x >> 2) as u16
+}
@@ -1,13 +1,14 @@
-use crate::address_bus::PpuAddressBus;
-use crate::cpu;
+use crate::address_bus::{PpuAddressBus, PpuFetchKind};
+use crate::{cpu, parse, serialize};
 
 #[macro_use]
 use derive_serialize::Serialize;
 
 use std::cell::Cell;
+use std::{fs, io};
 
 mod bg_state;
-mod palette;
+pub mod palette;
 mod sprite_state;
 #[cfg(test)]
 mod test;
@@ -30,6 +31,12 @@ pub struct Ppu {
     // emulated, but it may be worth keeping in mind
     oamaddr: u8,
     ppudata_read_buffer: u8,
+    // the latch on the ppu's data bus (its 'open bus'). filled by writes to any register,
+    // and returned by reads of the write-only ones. each bit decays to 0 if it isn't
+    // refreshed for about 600ms
+    open_bus: u8,
+    // the number of frames left until each bit of 'open_bus' decays
+    open_bus_decay_frames: [u8; 8],
     // bitfield w/ misc ppu flags
     bits: PpuBits::BitField,
     // address of the current tile to be fetched and drawn. points
@@ -42,16 +49,144 @@ pub struct Ppu {
     // registers go into this). referred to as 't' in the
     // nesdev.com 'ppu scrolling' article
     temp_vram_addr: VramAddrRegister,
+    region: Region,
+    // the fifths of a dot that pal's 3.2 dots per cpu cycle left over when the cpu
+    // cycle count was last reset (see 'Ppu::sub_cpu_cycles()')
+    dot_fraction: u8,
+    // whether oam decays when it isn't refreshed (see 'Ppu::set_oam_decay()')
+    oam_decay: bool,
+    // the total number of cycles subtracted from 'cycle_count' (which makes
+    // 'elapsed_cycles' + 'cycle_count' the number of cycles since power-on)
+    elapsed_cycles: u64,
+    // the cycle each of the 32 8-byte rows of oam was last refreshed on
+    oam_row_refresh_cycles: [u64; 32],
 }
 
+// the number of cpu cycles an oam row keeps its contents for without being refreshed.
+// this is longer than ntsc vblank, but shorter than pal vblank (which is why pal consoles
+// refresh oam partway through vblank)
+const OAM_DECAY_CPU_CYCLES: i32 = 3000;
+
 bitfield!(PpuBits<u8>(
     frame_done: 0..0,
     even_frame: 1..1,
     low_bits_toggle: 2..2,
     suppress_vblank_flag: 3..3,
     fine_x_scroll: 4..6,
+    // whether the ppu is stepped dot by dot (see 'Ppu::set_dot_accurate()')
+    dot_accurate: 7..7,
 ));
 
+// the tv system the console is timed for. pal consoles have 50 more vblank scanlines
+// and 3.2 ppu dots per cpu cycle, while dendy famiclones combine the 312 scanlines of
+// pal with the 3:1 dot ratio of ntsc (and put 50 of the extra scanlines before vblank)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    // multi-region roms are run as ntsc
+    pub fn from_timing(timing: parse::Timing) -> Self {
+        match timing {
+            parse::Timing::Ntsc | parse::Timing::MultiRegion => Region::Ntsc,
+            parse::Timing::Pal => Region::Pal,
+            parse::Timing::Dendy => Region::Dendy,
+        }
+    }
+
+    // the scanline the vblank flag is set (and nmi is asserted) on
+    fn vblank_scanline(self) -> i16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    // the last vblank scanline before the pre-render line
+    fn last_scanline(self) -> i16 {
+        match self {
+            Region::Ntsc => 260,
+            Region::Pal | Region::Dendy => 310,
+        }
+    }
+
+    pub fn cpu_to_ppu_cycles(self, cpu_cycles: i32) -> i32 {
+        self.cpu_to_ppu_cycles_with_fraction(cpu_cycles, 0).0
+    }
+
+    // pal has 3.2 dots per cpu cycle, so the conversion leaves a fraction of a dot (in
+    // fifths), which is returned along with the dots. 'fraction' is added on beforehand
+    fn cpu_to_ppu_cycles_with_fraction(self, cpu_cycles: i32, fraction: u8) -> (i32, u8) {
+        match self {
+            Region::Ntsc | Region::Dendy => (cpu_cycles * 3, 0),
+            Region::Pal => {
+                let fifths = cpu_cycles * 16 + fraction as i32;
+                (fifths / 5, (fifths % 5) as u8)
+            }
+        }
+    }
+
+    // the number of frames it takes for a bit of the ppu open bus to decay (about 600ms)
+    fn open_bus_decay_frames(self) -> u8 {
+        (600_000_000 / self.frame_duration().as_nanos()) as u8
+    }
+
+    // the length of one frame (ntsc runs at 60.10 hz, and pal and dendy at 50.01 hz)
+    pub fn frame_duration(self) -> std::time::Duration {
+        match self {
+            Region::Ntsc => std::time::Duration::from_nanos(16_639_261),
+            Region::Pal | Region::Dendy => std::time::Duration::from_nanos(19_997_209),
+        }
+    }
+
+    // the number of cpu cycles per second
+    pub fn cpu_clock_rate(self) -> u32 {
+        match self {
+            Region::Ntsc => 1_789_773,
+            Region::Pal => 1_662_607,
+            Region::Dendy => 1_773_448,
+        }
+    }
+}
+
+impl std::str::FromStr for Region {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ntsc" => Ok(Region::Ntsc),
+            "pal" => Ok(Region::Pal),
+            "dendy" => Ok(Region::Dendy),
+            _ => Err(format!(
+                "unknown region '{}' (expected ntsc, pal or dendy)",
+                s
+            )),
+        }
+    }
+}
+
+impl serialize::Serialize for Region {
+    fn serialize(&self, file: &mut io::BufWriter<fs::File>) -> Result<(), String> {
+        (*self as u8).serialize(file)
+    }
+
+    fn deserialize(&mut self, file: &mut io::BufReader<fs::File>) -> Result<(), String> {
+        let mut val = 0u8;
+        val.deserialize(file)?;
+        *self = match val {
+            0 => Region::Ntsc,
+            1 => Region::Pal,
+            2 => Region::Dendy,
+            _ => return Err(format!("invalid ppu region {}", val)),
+        };
+
+        Ok(())
+    }
+}
+
 #[derive(Copy, Clone)]
 enum SpriteSize {
     S8x8 = 8,
@@ -183,15 +318,39 @@ impl Ppu {
             ppustatus: 0,
             oamaddr: 0,
             ppudata_read_buffer: 0,
+            open_bus: 0,
+            open_bus_decay_frames: [0; 8],
             current_vram_addr: VramAddrRegister { inner: 0 },
             temp_vram_addr: VramAddrRegister { inner: 0 },
             current_scanline: 240,
             current_scanline_dot: 0,
-            bits: PpuBits::BitField::new(0, 1, 0, 0, 0),
+            bits: PpuBits::BitField::new(0, 1, 0, 0, 0, 0),
             cycle_count: 0,
+            region: Region::Ntsc,
+            dot_fraction: 0,
+            oam_decay: false,
+            elapsed_cycles: 0,
+            oam_row_refresh_cycles: [0; 32],
         }
     }
 
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.dot_fraction = 0;
+    }
+
+    // by default, the ppu is stepped a tile (8 dots) at a time, with the memory accesses
+    // for each tile made together. in dot-accurate mode, it's stepped one dot at a time
+    // instead, with every fetch made on the exact dot it happens on (which is slower, but
+    // needed by mappers that watch the fetches closely, and some timing tests)
+    pub fn set_dot_accurate(&mut self, dot_accurate: bool) {
+        self.bits.dot_accurate.set(dot_accurate as u8);
+    }
+
     #[cfg(test)]
     pub fn reset_state(&mut self) {
         self.secondary_oam = SecondaryOam::default();
@@ -201,22 +360,85 @@ impl Ppu {
         self.ppustatus = 0;
         self.oamaddr = 0;
         self.ppudata_read_buffer = 0;
+        self.open_bus = 0;
+        self.open_bus_decay_frames = [0; 8];
         self.current_vram_addr = VramAddrRegister { inner: 0 };
         self.temp_vram_addr = VramAddrRegister { inner: 0 };
         self.current_scanline = 240;
         self.current_scanline_dot = 0;
-        self.bits = PpuBits::BitField::new(0, 1, 0, 0, 0);
+        self.bits = PpuBits::BitField::new(0, 1, 0, 0, 0, self.bits.dot_accurate.get());
         self.cycle_count = 0;
     }
 
-    pub fn sub_cycle_count(&mut self, sub: i32) {
+    // called right before 'Cpu::cycle_count' is reset. the fraction of a dot that the
+    // cpu cycles don't add up to is carried over to the next frame
+    pub fn sub_cpu_cycles(&mut self, cpu_cycles: i32) {
+        let (sub, dot_fraction) = self
+            .region
+            .cpu_to_ppu_cycles_with_fraction(cpu_cycles, self.dot_fraction);
+        self.dot_fraction = dot_fraction;
         self.cycle_count -= sub;
+        self.elapsed_cycles += sub as u64;
     }
 
-    // sets the low 5 bits of 'ppustatus' equal to the low 5 bits of 'val'
-    pub fn set_ppustatus_low_bits(&mut self, val: u8) {
-        self.ppustatus &= !0b11111;
-        self.ppustatus |= val & 0b11111;
+    pub fn primary_oam_mut(&mut self) -> &mut PrimaryOam {
+        &mut self.primary_oam
+    }
+
+    // oam is dynamic ram, and a row of it loses its contents if it isn't accessed for a
+    // while (by sprite evaluation while rendering, or reads and writes from the cpu). this
+    // is off by default, as most emulators don't model it (and games don't depend on it)
+    pub fn set_oam_decay(&mut self, oam_decay: bool) {
+        self.oam_decay = oam_decay;
+    }
+
+    // refreshes the row of oam containing 'addr'. if it hasn't been refreshed for too long,
+    // it decays first (to 0xff, which also moves its sprites off the screen)
+    fn refresh_oam_row(&mut self, addr: u8) {
+        if !self.oam_decay {
+            return;
+        }
+
+        let now = (self.elapsed_cycles as i64 + self.cycle_count as i64) as u64;
+        let row = (addr >> 3) as usize;
+        let decay_cycles = self.region.cpu_to_ppu_cycles(OAM_DECAY_CPU_CYCLES) as u64;
+        if now.saturating_sub(self.oam_row_refresh_cycles[row]) > decay_cycles {
+            self.primary_oam.as_bytes_mut()[row * 8..row * 8 + 8].fill(0xff);
+        }
+
+        self.oam_row_refresh_cycles[row] = now;
+    }
+
+    // fills the open bus latch with 'val' (as done by writes to any ppu register)
+    // NOTE: this is also used by 'write_oamdma()' in 'address_bus'
+    pub fn set_open_bus(&mut self, val: u8) {
+        self.refresh_open_bus(val, 0xff);
+    }
+
+    // sets the bits of the open bus latch selected by 'mask' equal to those of 'val', and
+    // restarts their decay
+    fn refresh_open_bus(&mut self, val: u8, mask: u8) {
+        self.open_bus = (self.open_bus & !mask) | (val & mask);
+
+        let decay_frames = self.region.open_bus_decay_frames();
+        for (i, frames) in self.open_bus_decay_frames.iter_mut().enumerate() {
+            if mask & (1 << i) != 0 {
+                *frames = decay_frames;
+            }
+        }
+    }
+
+    // called once per frame, decays the bits of the open bus latch that haven't been
+    // refreshed in a while
+    fn decay_open_bus(&mut self) {
+        for (i, frames) in self.open_bus_decay_frames.iter_mut().enumerate() {
+            if *frames > 0 {
+                *frames -= 1;
+                if *frames == 0 {
+                    self.open_bus &= !(1 << i);
+                }
+            }
+        }
     }
 
     pub fn is_frame_done(&self) -> bool {
@@ -229,10 +451,36 @@ impl Ppu {
 
     // NOTE: this is also used by 'write_oamdma()' in 'address_bus'
     pub fn write_to_oam_and_increment_addr(&mut self, val: u8) {
+        if self.is_currently_rendering() {
+            // writes are ignored while rendering, but oamaddr is still incremented, and
+            // by 4 instead of 1 (incrementing 'n' instead of 'm')
+            self.oamaddr = self.oamaddr.wrapping_add(4);
+            return;
+        }
+
+        self.refresh_oam_row(self.oamaddr);
         self.primary_oam.set_byte(self.oamaddr, val);
         self.oamaddr = self.oamaddr.wrapping_add(1);
     }
 
+    // sprite evaluation reads (and so refreshes) every row of oam
+    fn refresh_oam(&mut self) {
+        for row in 0..32 {
+            self.refresh_oam_row(row << 3);
+        }
+    }
+
+    // if oamaddr is 8 or more when rendering starts (on the pre-render line), the 8 bytes
+    // of oam starting at (oamaddr & 0xf8) are copied over the first 8 bytes of oam
+    fn copy_oam_row_on_render_start(&mut self) {
+        if self.oamaddr < 8 {
+            return;
+        }
+
+        let row = (self.oamaddr & 0xf8) as usize;
+        self.primary_oam.as_bytes_mut().copy_within(row..row + 8, 0);
+    }
+
     // used for reading the registers located in the cpu memory map at 0x2000-0x2007
     pub fn read_register_by_index(
         &mut self,
@@ -247,36 +495,41 @@ impl Ppu {
 
         {
             return match index {
-                // ppuctrl
-                0 => 0,
-                // ppumask
-                1 => self.ppumask,
+                // ppuctrl | ppumask | oamaddr | ppuscroll | ppuaddr (write-only, so the
+                // open bus is returned)
+                // FIXME: should ppuscroll and ppuaddr reset the low bits toggle as well??
+                0 | 1 | 3 | 5 | 6 => self.open_bus,
                 // ppustatus
                 2 => read_ppustatus(self),
-                // oamaddr
-                3 => 0,
                 // oamdata
                 4 => read_oamdata(self),
-                // ppuscroll | ppuaddr
-                5 | 6 => 0, // FIXME: should these reset the low bits toggle as well??
                 // ppudata
                 7 => read_ppudata(self, bus, cpu),
-                _ => 0,
+                _ => self.open_bus,
             };
         }
 
         fn read_ppustatus(ppu: &mut Ppu) -> u8 {
             // clear low bits toggle
             ppu.bits.low_bits_toggle.set(0);
-            let status = ppu.ppustatus;
+            let mut status = ppu.ppustatus;
+            if ppu.current_scanline == -1 && ppu.current_scanline_dot == 1 {
+                // the sprite zero hit and overflow flags are cleared early enough on dot 1
+                // of the pre-render line for a read on the same dot to see them cleared
+                status &= !0b1100000;
+            }
+            // the low 5 bits come from the open bus
+            status = (status & 0b11100000) | (ppu.open_bus & 0b11111);
+            ppu.refresh_open_bus(status, 0b11100000);
             // clear vblank flag
             ppu.set_vblank(false);
 
-            if ppu.current_scanline == 241 && ppu.current_scanline_dot == 1 {
+            if ppu.current_scanline == ppu.region.vblank_scanline() && ppu.current_scanline_dot == 1
+            {
                 // vblank flag should not have been set yet
                 debug_assert!(status & 0b10000000 == 0);
                 // if there is one cycle left before the vblank flag will be set
-                // (when scanline = 241 and dot = 1, the flag will be set on the
+                // (when on the vblank scanline and dot = 1, the flag will be set on the
                 // next call to 'step()'), prevent the vblank flag from being set
                 ppu.bits.suppress_vblank_flag.set(1);
             }
@@ -285,21 +538,28 @@ impl Ppu {
         }
 
         fn read_oamdata(ppu: &mut Ppu) -> u8 {
-            if let (0..=239, 1..=64) = (ppu.current_scanline, ppu.current_scanline_dot) {
-                // if on dots/cycles 1-64 of a visible scanline, return 0xff
-                return 0xff;
-            }
-
-            let mut byte = ppu.primary_oam.get_byte(ppu.oamaddr);
-            if ppu.oamaddr % 4 == 2 {
-                // if 'byte' is a sprite attribute byte, clear bits 2-4
-                byte &= 0b11100011;
-            }
+            let byte = if ppu.current_scanline >= 0 && ppu.is_currently_rendering() {
+                // if rendering on a visible scanline, return whatever the ppu is
+                // currently reading from oam (0xff while clearing secondary oam)
+                ppu.sprite_state
+                    .oam_bus(&ppu.secondary_oam, ppu.current_scanline_dot)
+            } else {
+                ppu.refresh_oam_row(ppu.oamaddr);
+                if ppu.oamaddr % 4 == 2 {
+                    // if the byte is a sprite attribute byte, clear bits 2-4
+                    ppu.primary_oam.get_byte(ppu.oamaddr) & 0b11100011
+                } else {
+                    ppu.primary_oam.get_byte(ppu.oamaddr)
+                }
+            };
 
+            ppu.refresh_open_bus(byte, 0xff);
             byte
         }
 
         fn read_ppudata(ppu: &mut Ppu, bus: &mut dyn PpuAddressBus, cpu: &mut cpu::Cpu) -> u8 {
+            bus.set_fetch_kind(PpuFetchKind::Ppudata);
+
             let val = if (ppu.current_vram_addr.inner >> 8) == 0b111111 {
                 // read directly from vram if address is in range
                 // 0x3f00-0x3fff (palette ram)
@@ -311,12 +571,16 @@ impl Ppu {
                     ppu.cycle_count,
                     cpu,
                 );
-                val
+                // palette entries are 6 bits wide, with the high 2 bits coming
+                // from the open bus
+                ppu.refresh_open_bus(val, 0b111111);
+                (val & 0b111111) | (ppu.open_bus & 0b11000000)
             } else {
                 // read from read buffer if address is in range 0-0x3eff
                 let val = ppu.ppudata_read_buffer;
                 ppu.ppudata_read_buffer =
                     bus.read(ppu.current_vram_addr.get_addr(), ppu.cycle_count, cpu);
+                ppu.refresh_open_bus(val, 0xff);
                 val
             };
 
@@ -333,6 +597,8 @@ impl Ppu {
                 ppu.increment_vram_addr_y();
             }
 
+            bus.set_fetch_kind(ppu.get_rendering_fetch_kind());
+
             val
         }
     }
@@ -345,6 +611,9 @@ impl Ppu {
         cpu: &mut cpu::Cpu,
         bus: &mut dyn PpuAddressBus,
     ) {
+        // writes to any register (including ppustatus) fill the open bus
+        self.set_open_bus(val);
+
         {
             match index {
                 // ppuctrl
@@ -375,7 +644,6 @@ impl Ppu {
             let nmi_toggled = ((ppu.ppuctrl ^ val) >> 7) != 0;
 
             ppu.ppuctrl = val;
-            ppu.set_ppustatus_low_bits(val);
 
             if nmi_toggled && ppu.is_vblank_nmi_enabled() && ppu.is_vblank() {
                 cpu.bits.nmi.set(1);
@@ -390,16 +658,15 @@ impl Ppu {
             if !ppu.is_currently_rendering() {
                 bus.set_address(ppu.current_vram_addr.inner, ppu.cycle_count, cpu);
             }
+
+            bus.set_rendering(
+                ppu.current_scanline < 240
+                    && (ppu.is_background_enable() || ppu.is_sprites_enable()),
+            );
         }
 
         fn write_oamdata(ppu: &mut Ppu, val: u8) {
-            if ppu.is_currently_rendering() {
-                // ignore attemps to write when rendering
-                return;
-            }
-
             ppu.write_to_oam_and_increment_addr(val);
-            ppu.set_ppustatus_low_bits(val);
         }
 
         fn write_ppuscroll(ppu: &mut Ppu, val: u8) {
@@ -421,7 +688,6 @@ impl Ppu {
                 ppu.temp_vram_addr.set_fine_y(val & 0b111);
             }
 
-            ppu.set_ppustatus_low_bits(val);
             ppu.toggle_low_bits_toggle();
         }
 
@@ -449,13 +715,12 @@ impl Ppu {
                 }
             }
 
-            ppu.set_ppustatus_low_bits(val);
             ppu.toggle_low_bits_toggle();
         }
 
         fn write_ppudata(ppu: &mut Ppu, val: u8, bus: &mut dyn PpuAddressBus, cpu: &mut cpu::Cpu) {
+            bus.set_fetch_kind(PpuFetchKind::Ppudata);
             bus.write(ppu.current_vram_addr.get_addr(), val, ppu.cycle_count, cpu);
-            ppu.set_ppustatus_low_bits(val);
 
             // increment 'current_vram_addr' (same as when reading ppudata)
             if !ppu.is_currently_rendering() {
@@ -465,6 +730,8 @@ impl Ppu {
                 ppu.increment_vram_addr_coarse_x();
                 ppu.increment_vram_addr_y();
             }
+
+            bus.set_fetch_kind(ppu.get_rendering_fetch_kind());
         }
     }
 
@@ -475,9 +742,17 @@ impl Ppu {
         bus: &mut dyn PpuAddressBus,
         framebuffer: &[Cell<u32>; 256 * 240],
     ) {
-        let target_cycles = cpu.cycle_count as i32 * 3;
-        while self.cycle_count < target_cycles {
-            self.step(cpu, bus, framebuffer);
+        let (target_cycles, _) = self
+            .region
+            .cpu_to_ppu_cycles_with_fraction(cpu.cycle_count, self.dot_fraction);
+        if self.bits.dot_accurate.is_true() {
+            while self.cycle_count < target_cycles {
+                self.step_dot(cpu, bus, framebuffer);
+            }
+        } else {
+            while self.cycle_count < target_cycles {
+                self.step(cpu, bus, framebuffer);
+            }
         }
     }
 
@@ -494,11 +769,10 @@ impl Ppu {
             match self.current_scanline {
                 // pre-render and visible scanlines
                 -1..=239 => step_pre_render_or_visible_line(self, bus, framebuffer, cpu),
-                // idle scanline
-                240 => step_idle_line(self),
+                // idle scanline(s)
+                sl if sl < self.region.vblank_scanline() => step_idle_line(self),
                 // vblank 'scanlines'
-                241..=260 => step_vblank_line(self, cpu),
-                _ => (),
+                _ => step_vblank_line(self, cpu),
             };
         }
 
@@ -514,8 +788,11 @@ impl Ppu {
 
                     // if rendering is enabled and we're on the first visible scanline,
                     // only increment cycle count if current frame is even-numbered (idle
-                    // cycle is skipped on odd frames)
-                    if sl == 0 && (ppu.is_background_enable() || ppu.is_sprites_enable()) {
+                    // cycle is skipped on odd frames). pal and dendy never skip it
+                    if sl == 0
+                        && ppu.region == Region::Ntsc
+                        && (ppu.is_background_enable() || ppu.is_sprites_enable())
+                    {
                         ppu.cycle_count += ppu.bits.even_frame.get() as i32;
                     } else {
                         ppu.cycle_count += 1;
@@ -525,14 +802,12 @@ impl Ppu {
                     // use (in dots 65-256)
                     ppu.sprite_state.sprites_found = 0;
                     ppu.sprite_state.eval_done = false;
-                    // TODO: obscure behavior where 'current_sprite_idx' is set equal to
-                    // the current value of oamaddr (which may not be zero) at the start
-                    // of sprite evaluation, meaning a litle later than this
                     ppu.sprite_state.current_sprite_idx = 0;
                 }
                 // NOTE: though we often match on contiguous ranges of dots (x..=y) while
                 // stepping, the ppu is usually only advanced 8 cycles/dots at a time. this
-                // means that most of the dots in the range are never actually hit.
+                // means that most of the dots in the range are never actually hit (see
+                // 'step_dot()' for the dot-by-dot version).
                 (1..=256, sl) => {
                     match sl {
                         // pre-render line
@@ -545,23 +820,34 @@ impl Ppu {
                                 ppu.set_vblank(false);
                                 ppu.set_sprite_zero_hit(false);
                                 ppu.set_sprite_overflow(false);
+
+                                bus.set_rendering(
+                                    ppu.is_background_enable() || ppu.is_sprites_enable(),
+                                );
+
+                                if ppu.is_background_enable() || ppu.is_sprites_enable() {
+                                    ppu.copy_oam_row_on_render_start();
+                                }
                             }
                         }
                         // visible lines
                         _ => {
-                            if ppu.current_scanline_dot >= 65
-                                && (ppu.is_sprites_enable() || ppu.is_background_enable())
-                            {
-                                // evaluate sprite on next scanline
-                                for _ in 0..4 {
+                            if ppu.is_sprites_enable() || ppu.is_background_enable() {
+                                // evaluate sprites on next scanline (clearing secondary oam
+                                // on dots 1-64)
+                                for dot in ppu.current_scanline_dot..ppu.current_scanline_dot + 8 {
+                                    if dot == 65 {
+                                        ppu.refresh_oam();
+                                    }
+
                                     let sprite_overflow =
-                                        ppu.sprite_state.eval_next_scanline_sprite(
-                                            ppu.is_sprite_overflow(),
+                                        ppu.sprite_state.eval_next_scanline_sprites_at_dot(
+                                            dot,
+                                            ppu.current_scanline,
                                             ppu.get_sprite_size(),
                                             &ppu.primary_oam,
                                             &mut ppu.secondary_oam,
-                                            ppu.current_scanline,
-                                            ppu.current_scanline_dot,
+                                            &mut ppu.oamaddr,
                                         );
 
                                     if sprite_overflow {
@@ -607,7 +893,6 @@ impl Ppu {
                     ppu.current_scanline_dot += 8;
                 }
                 (257, _) => {
-                    ppu.oamaddr = 0;
                     // set current sprite to zero so it can be re-used
                     // in 'fetch_next_scanline_sprite_data()'
                     ppu.sprite_state.current_sprite_idx = 0;
@@ -615,6 +900,9 @@ impl Ppu {
 
                     // fetch sprite data for the sprites found previously (during dots 65-256)
                     if ppu.is_sprites_enable() || ppu.is_background_enable() {
+                        // oamaddr is cleared on each of dots 257-320 while rendering
+                        ppu.oamaddr = 0;
+                        bus.set_fetch_kind(PpuFetchKind::Sprite);
                         ppu.sprite_state.fetch_next_scanline_sprite_data(
                             &ppu.secondary_oam,
                             ppu.get_sprite_size(),
@@ -635,10 +923,9 @@ impl Ppu {
                     ppu.current_scanline_dot += 8;
                 }
                 (258..=320, sl) => {
-                    ppu.oamaddr = 0;
-
                     // continue fetching sprite data
                     if ppu.is_sprites_enable() || ppu.is_background_enable() {
+                        ppu.oamaddr = 0;
                         ppu.sprite_state.fetch_next_scanline_sprite_data(
                             &ppu.secondary_oam,
                             ppu.get_sprite_size(),
@@ -663,6 +950,7 @@ impl Ppu {
                     ppu.current_scanline_dot += 8;
                 }
                 (321..=327, _) => {
+                    bus.set_fetch_kind(PpuFetchKind::Background);
                     ppu.cycle_count += 7;
                     ppu.current_scanline_dot += 7;
                 }
@@ -685,12 +973,21 @@ impl Ppu {
                             ppu.current_scanline_dot += 8;
                         }
                         336 => {
+                            if ppu.is_background_enable() || ppu.is_sprites_enable() {
+                                // make the two dummy nametable fetches at dots 337 and 339 (MMC5
+                                // relies on these to detect the start of each scanline)
+                                let addr = (ppu.current_vram_addr.get_addr() & 0xfff) | 0x2000;
+                                let _ = bus.read(addr, ppu.cycle_count + 1, cpu);
+                                let _ = bus.read(addr, ppu.cycle_count + 3, cpu);
+                            }
+
                             ppu.cycle_count += 5;
                             ppu.current_scanline_dot = 0;
                             ppu.current_scanline += 1;
 
                             if ppu.current_scanline == 240 {
                                 ppu.bits.frame_done.set(1);
+                                bus.set_rendering(false);
                             }
                         }
                         _ => (),
@@ -705,7 +1002,7 @@ impl Ppu {
                 336 => {
                     ppu.cycle_count += 5;
                     ppu.current_scanline_dot = 0;
-                    ppu.current_scanline = 241;
+                    ppu.current_scanline += 1;
                 }
                 0 | 168 => {
                     ppu.cycle_count += 168;
@@ -723,7 +1020,9 @@ impl Ppu {
                 }
                 1 => {
                     // NOTE: setting of vblank flag may be suppressed by reads to ppustatus
-                    if (ppu.current_scanline == 241) && !ppu.bits.suppress_vblank_flag.is_true() {
+                    if (ppu.current_scanline == ppu.region.vblank_scanline())
+                        && !ppu.bits.suppress_vblank_flag.is_true()
+                    {
                         ppu.set_vblank(true);
                     }
 
@@ -739,7 +1038,7 @@ impl Ppu {
                     // is set on dot 1), in order to more accurately emulate nmi/vblank flag
                     // suppression. this way, any reads to ppustatus during dot 2 or 3 (before
                     // this chunk of code is executed) will prevent nmi from being asserted
-                    if ppu.current_scanline == 241 {
+                    if ppu.current_scanline == ppu.region.vblank_scanline() {
                         if ppu.is_vblank_nmi_enabled() && ppu.is_vblank() {
                             cpu.bits.nmi.set(1);
                         }
@@ -752,8 +1051,9 @@ impl Ppu {
                     ppu.cycle_count += 5;
                     ppu.current_scanline_dot = 0;
 
-                    if ppu.current_scanline == 260 {
+                    if ppu.current_scanline == ppu.region.last_scanline() {
                         ppu.toggle_even_frame();
+                        ppu.decay_open_bus();
                         // reset scanline count
                         ppu.current_scanline = -1;
                         // ensure next frame's vblank flag will not be suppressed
@@ -770,6 +1070,217 @@ impl Ppu {
         }
     }
 
+    // steps the ppu by a single dot. only used internally by the ppu,
+    // in 'Ppu::catch_up()' (when in dot-accurate mode)
+    fn step_dot(
+        &mut self,
+        cpu: &mut cpu::Cpu,
+        bus: &mut dyn PpuAddressBus,
+        framebuffer: &[Cell<u32>; 256 * 240],
+    ) {
+        // NOTE: like 'step()', this function is split into multiple subfunctions
+        {
+            match self.current_scanline {
+                // pre-render and visible scanlines
+                -1..=239 => step_pre_render_or_visible_dot(self, bus, framebuffer, cpu),
+                // idle scanline(s)
+                sl if sl < self.region.vblank_scanline() => (),
+                // vblank 'scanlines'
+                _ => step_vblank_dot(self, cpu),
+            };
+
+            self.cycle_count += 1;
+            match self.current_scanline_dot {
+                340 => {
+                    self.current_scanline_dot = 0;
+                    go_to_next_scanline(self, bus);
+                }
+                // one dot at the end of the pre-render line is skipped on odd frames if
+                // rendering is enabled. pal and dendy never skip it
+                338 if self.current_scanline == -1
+                    && self.region == Region::Ntsc
+                    && !self.bits.even_frame.is_true()
+                    && (self.is_background_enable() || self.is_sprites_enable()) =>
+                {
+                    self.current_scanline_dot = 340;
+                }
+                _ => self.current_scanline_dot += 1,
+            }
+        }
+
+        fn step_pre_render_or_visible_dot(
+            ppu: &mut Ppu,
+            bus: &mut dyn PpuAddressBus,
+            framebuffer: &[Cell<u32>; 256 * 240],
+            cpu: &mut cpu::Cpu,
+        ) {
+            let dot = ppu.current_scanline_dot;
+            let sl = ppu.current_scanline;
+            let rendering = ppu.is_background_enable() || ppu.is_sprites_enable();
+
+            match dot {
+                0 => {
+                    // reset sprite evaluation state before use (in dots 65-256)
+                    ppu.sprite_state.sprites_found = 0;
+                    ppu.sprite_state.eval_done = false;
+                    ppu.sprite_state.current_sprite_idx = 0;
+                    return;
+                }
+                1 if sl == -1 => {
+                    // clear vblank, sprite zero hit and sprite overflow flags
+                    ppu.set_vblank(false);
+                    ppu.set_sprite_zero_hit(false);
+                    ppu.set_sprite_overflow(false);
+                    bus.set_rendering(rendering);
+
+                    if rendering {
+                        ppu.copy_oam_row_on_render_start();
+                    }
+                }
+                257 => {
+                    // set current sprite to zero so it can be re-used
+                    // in 'fetch_sprite_data_at_dot()'
+                    ppu.sprite_state.current_sprite_idx = 0;
+                    if rendering {
+                        bus.set_fetch_kind(PpuFetchKind::Sprite);
+                    }
+                }
+                321 => bus.set_fetch_kind(PpuFetchKind::Background),
+                _ => (),
+            }
+
+            if rendering && matches!(dot, 257..=320) {
+                // oamaddr is cleared on each of dots 257-320 while rendering
+                ppu.oamaddr = 0;
+            }
+
+            // pixels are still drawn 8 at a time, at the start of each tile
+            if sl >= 0 && matches!(dot, 1..=256) && dot & 7 == 1 {
+                let sprite_zero_hit = ppu.draw_8_pixels(framebuffer, bus);
+                if sprite_zero_hit {
+                    ppu.set_sprite_zero_hit(true);
+                }
+            }
+
+            if !rendering {
+                return;
+            }
+
+            // evaluate the sprites on the next scanline (clearing secondary oam on dots 1-64)
+            if sl >= 0 && matches!(dot, 1..=256) {
+                if dot == 65 {
+                    ppu.refresh_oam();
+                }
+
+                let sprite_overflow = ppu.sprite_state.eval_next_scanline_sprites_at_dot(
+                    dot,
+                    sl,
+                    ppu.get_sprite_size(),
+                    &ppu.primary_oam,
+                    &mut ppu.secondary_oam,
+                    &mut ppu.oamaddr,
+                );
+
+                if sprite_overflow {
+                    ppu.set_sprite_overflow(true);
+                }
+            }
+
+            match dot {
+                1..=256 | 321..=336 => {
+                    // shift previously drawn tile data leftwards in 'bg_state' shift
+                    // registers (the tile fetched on dots 249-256 is never used)
+                    if dot & 7 == 1 && dot != 249 {
+                        ppu.bg_state.shift_tile_data_by_8();
+                    }
+
+                    ppu.bg_state.fetch_tile_data_at_dot(
+                        (dot - 1) & 7,
+                        ppu.cycle_count,
+                        ppu.get_background_pattern_table_addr(),
+                        ppu.current_vram_addr,
+                        bus,
+                        cpu,
+                    );
+
+                    if dot & 7 == 0 {
+                        if dot != 256 {
+                            ppu.bg_state.load_next_tile();
+                        }
+
+                        ppu.increment_vram_addr_coarse_x();
+                        if dot == 256 {
+                            ppu.increment_vram_addr_y();
+                        }
+                    }
+                }
+                257..=320 => {
+                    if dot == 257 {
+                        ppu.transfer_temp_horizontal_bits();
+                    }
+
+                    ppu.sprite_state.fetch_sprite_data_at_dot(
+                        (dot - 257) & 7,
+                        &ppu.secondary_oam,
+                        ppu.get_sprite_size(),
+                        ppu.current_scanline,
+                        ppu.get_8x8_sprite_pattern_table_addr(),
+                        ppu.current_vram_addr,
+                        ppu.cycle_count,
+                        bus,
+                        cpu,
+                    );
+
+                    if sl == -1 && matches!(dot, 280..=304) {
+                        ppu.transfer_temp_vert_bits();
+                    }
+                }
+                // the two dummy nametable fetches (MMC5 relies on these to detect the
+                // start of each scanline)
+                _ => {
+                    let addr = bg_state::nametable_addr(ppu.current_vram_addr);
+                    if dot & 1 != 0 {
+                        bus.set_address(addr, ppu.cycle_count, cpu);
+                    } else {
+                        let _ = bus.read(addr, ppu.cycle_count, cpu);
+                    }
+                }
+            }
+        }
+
+        fn step_vblank_dot(ppu: &mut Ppu, cpu: &mut cpu::Cpu) {
+            if ppu.current_scanline != ppu.region.vblank_scanline() {
+                return;
+            }
+
+            match ppu.current_scanline_dot {
+                // NOTE: setting of vblank flag may be suppressed by reads to ppustatus
+                1 if !ppu.bits.suppress_vblank_flag.is_true() => ppu.set_vblank(true),
+                // NOTE: nmi is asserted on dot 3 for the same reason as in 'step()'
+                3 if ppu.is_vblank_nmi_enabled() && ppu.is_vblank() => cpu.bits.nmi.set(1),
+                _ => (),
+            }
+        }
+
+        fn go_to_next_scanline(ppu: &mut Ppu, bus: &mut dyn PpuAddressBus) {
+            if ppu.current_scanline == ppu.region.last_scanline() {
+                ppu.toggle_even_frame();
+                ppu.decay_open_bus();
+                // reset scanline count
+                ppu.current_scanline = -1;
+                // ensure next frame's vblank flag will not be suppressed
+                ppu.bits.suppress_vblank_flag.set(0);
+                return;
+            }
+
+            ppu.current_scanline += 1;
+            if ppu.current_scanline == 240 {
+                ppu.bits.frame_done.set(1);
+                bus.set_rendering(false);
+            }
+        }
+    }
+
     // draws 8 pixels, without making any state changes to 'self'.
     // returns whether sprite zero was hit
     fn draw_8_pixels(
@@ -891,7 +1402,7 @@ impl Ppu {
                     palette::COLOR_LUT.get(
                         bg_color_byte,
                         ppu.is_greyscale_enabled(),
-                        ppu.ppumask >> 5,
+                        ppu.get_emphasis_bits(),
                     )
                 };
 
@@ -914,7 +1425,11 @@ impl Ppu {
             };
 
             let color_byte = bus.read_palette_memory(final_idx);
-            palette::COLOR_LUT.get(color_byte, ppu.is_greyscale_enabled(), ppu.ppumask >> 5)
+            palette::COLOR_LUT.get(
+                color_byte,
+                ppu.is_greyscale_enabled(),
+                ppu.get_emphasis_bits(),
+            )
         }
     }
 
@@ -1040,6 +1555,18 @@ impl Ppu {
         (self.ppuctrl >> 7) != 0
     }
 
+    // the emphasis bits of ppumask, in the (red, green, blue) order of 'COLOR_LUT'. the
+    // pal and dendy ppus swap the red and green bits
+    fn get_emphasis_bits(&self) -> u8 {
+        let emphasis = self.ppumask >> 5;
+        match self.region {
+            Region::Ntsc => emphasis,
+            Region::Pal | Region::Dendy => {
+                (emphasis & 0b100) | ((emphasis & 1) << 1) | ((emphasis >> 1) & 1)
+            }
+        }
+    }
+
     fn is_greyscale_enabled(&self) -> bool {
         (self.ppumask & 1) != 0
     }
@@ -1064,6 +1591,7 @@ impl Ppu {
         (self.ppumask & 0b10000) != 0
     }
 
+    #[cfg(test)]
     fn is_sprite_overflow(&self) -> bool {
         (self.ppustatus & 0b100000) != 0
     }
@@ -1085,6 +1613,15 @@ impl Ppu {
     }
 
     fn is_currently_rendering(&self) -> bool {
-        self.current_scanline < 241 && (self.is_sprites_enable() || self.is_background_enable())
+        self.current_scanline < 240 && (self.is_sprites_enable() || self.is_background_enable())
+    }
+
+    // the kind of fetch the ppu makes at the current dot when rendering
+    fn get_rendering_fetch_kind(&self) -> PpuFetchKind {
+        if matches!(self.current_scanline_dot, 257..=320) {
+            PpuFetchKind::Sprite
+        } else {
+            PpuFetchKind::Background
+        }
     }
 }
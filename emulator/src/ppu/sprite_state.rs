@@ -11,15 +11,26 @@ pub(super) struct SpriteDrawState {
     // next scanline (should be filled with sprite data for the next
     // scanline on cycles 257-320)
     current_sprites_data: [SpriteRenderData; 8],
-    // holds the bits corresponding to the 'n' and 'm' OAM indices
-    // (from nesdev.com 'PPU Sprite Evaluation' page). is used
-    // both during sprite evaluation (dots 65-256 of each visible
-    // scanline) and sprite fetching (dots 257-320)
+    // index of the sprite in secondary oam whose data is being fetched
+    // (dots 257-320). sprite evaluation (dots 65-256 of each visible
+    // scanline) uses oamaddr instead
     pub current_sprite_idx: u8,
     // number of sprites found on the next scanline
     pub sprites_found: u8,
     // OPTIMIZE: pack 'eval_done' into same byte as 'sprites_found'
     pub eval_done: bool,
+    // the index of the next byte to write to secondary oam during sprite evaluation
+    secondary_oam_idx: u8,
+    // whether the sprite currently being copied to secondary oam is in range
+    sprite_in_range: bool,
+    // whether the first sprite evaluated (sprite zero) is on the next scanline
+    sprite_zero_found: bool,
+    // the number of bytes left to read of a sprite found after secondary oam is full
+    overflow_bytes_left: u8,
+    // the byte last read from oam during sprite evaluation
+    oam_bus: u8,
+    // the low bitplane fetched for the current sprite (only used when stepping dot by dot)
+    next_bitplane_lo: u8,
 }
 
 // struct used internally in 'SpriteDrawState' to store sprite data between scanlines
@@ -42,107 +53,153 @@ pub(super) struct SpritePixelInfo {
 }
 
 impl SpriteDrawState {
-    pub(super) fn eval_next_scanline_sprite(
+    // runs the part of sprite evaluation for the next scanline that happens on the given
+    // dot (1-256). secondary oam is cleared on dots 1-64, after which oam is searched for
+    // sprites on dots 65-256, with a byte being read from oam on odd dots and written to
+    // secondary oam on even dots. as on the real ppu, oamaddr is used as the index into oam
+    // (so evaluation starts from its value at dot 65). returns whether the sprite overflow
+    // flag should be set
+    pub(super) fn eval_next_scanline_sprites_at_dot(
         &mut self,
-        mut sprite_overflow: bool,
+        current_scanline_dot: u16,
+        current_scanline: i16,
         sprite_height: SpriteSize,
         primary_oam: &PrimaryOam,
         secondary_oam: &mut SecondaryOam,
-        current_scanline: i16,
-        current_scanline_dot: u16,
+        oamaddr: &mut u8,
     ) -> bool {
         debug_assert!(matches!(current_scanline, 0..=239));
-        debug_assert!(matches!(current_scanline_dot, 65..=256));
+        debug_assert!(matches!(current_scanline_dot, 1..=256));
+
+        if current_scanline_dot <= 64 {
+            if current_scanline_dot & 1 == 0 {
+                secondary_oam.set_byte(((current_scanline_dot - 1) >> 1) as u8, 0xff);
+            }
 
-        if self.eval_done {
             return false;
         }
 
-        // TODO: refactor this
+        if current_scanline_dot == 65 {
+            self.sprites_found = 0;
+            self.secondary_oam_idx = 0;
+            self.eval_done = false;
+            self.sprite_in_range = false;
+            self.sprite_zero_found = false;
+            self.overflow_bytes_left = 0;
+        }
+
+        if current_scanline_dot & 1 != 0 {
+            // read a byte from oam (the attribute bytes have no bits 2-4)
+            let byte = primary_oam.get_byte(*oamaddr);
+            self.oam_bus = if *oamaddr & 0b11 == 2 {
+                byte & 0b11100011
+            } else {
+                byte
+            };
 
-        if self.sprites_found < 8 {
-            // copy the byte pointed to by 'current_sprite_idx' from primary to secondary oam
-            let byte = primary_oam.get_byte(self.current_sprite_idx);
-            secondary_oam.set_byte(
-                (self.sprites_found << 2) | self.current_sprite_idx & 0b11,
-                byte,
-            );
+            return false;
+        }
 
-            if self.current_sprite_idx & 0b11 == 0 {
-                // if 'current_sprite_idx' is a multiple of 4, treat 'byte' as a sprite
-                // y-coordinate and use it to test whether the current sprite is in range
+        // 'n' and 'm' (from nesdev.com 'PPU Sprite Evaluation' page)
+        let (mut n, mut m) = (*oamaddr >> 2, *oamaddr & 0b11);
+        let mut sprite_overflow = false;
 
-                // NOTE: sprite y-coordinate is 1 less than the actual screen y-coordinate
-                if (current_scanline as u16).wrapping_sub(byte as u16) < sprite_height as u16 {
-                    // set index to point to next byte (on the same sprite)
-                    self.current_sprite_idx += 1;
-                } else {
-                    // set index to point to next sprite
-                    self.current_sprite_idx = self.current_sprite_idx.wrapping_add(4);
+        // NOTE: sprite y-coordinate is 1 less than the actual screen y-coordinate
+        let is_in_range =
+            (current_scanline as u16).wrapping_sub(self.oam_bus as u16) < sprite_height as u16;
 
-                    if self.current_sprite_idx == 0 {
-                        // index has overflown back to zero
-                        self.eval_done = true;
-                    }
+        if self.eval_done {
+            // keep failing to copy the y-coordinates of the remaining sprites
+            n = (n + 1) & 0x3f;
+            if self.secondary_oam_idx >= 32 {
+                self.oam_bus = secondary_oam.as_bytes()[0];
+            }
+        } else if self.secondary_oam_idx < 32 {
+            if !self.sprite_in_range && is_in_range {
+                self.sprite_in_range = true;
+                // the first sprite evaluated is treated as sprite zero, even if
+                // evaluation started in the middle of oam
+                if current_scanline_dot == 66 {
+                    self.sprite_zero_found = true;
                 }
-            } else {
-                if self.current_sprite_idx & 0b11 == 2 {
-                    // the byte just copied into secondary oam is an 'attributes' byte
+            }
 
-                    debug_assert_eq!(
-                        byte,
-                        secondary_oam.entries[self.sprites_found as usize].attributes
-                    );
+            // copy the byte to secondary oam (this happens even for y-coordinates
+            // that aren't in range, which are overwritten by the next sprite)
+            secondary_oam.set_byte(self.secondary_oam_idx, self.oam_bus);
 
-                    // clear unused bits
-                    secondary_oam.entries[self.sprites_found as usize].attributes &= 0b11100011;
+            if self.sprite_in_range {
+                m += 1;
+                self.secondary_oam_idx += 1;
 
-                    if self.current_sprite_idx & !0b11 == 0 {
-                        // if the byte belongs to sprite zero, set bit 3
-                        secondary_oam.entries[self.sprites_found as usize].attributes |= 0b1000;
-                    }
-                } else if self.current_sprite_idx & 0b11 == 0b11 {
-                    // the byte just copied is the last byte on the current sprite
+                if self.secondary_oam_idx & 0b11 == 0 {
+                    // done copying all 4 bytes of the sprite
+                    self.sprite_in_range = false;
                     self.sprites_found += 1;
-
-                    debug_assert!({
-                        if self.sprites_found == 8 {
-                            (self.current_sprite_idx.wrapping_add(1)) % 4 == 0
-                        } else {
-                            true
-                        }
-                    });
+                    m = 0;
+                    n = (n + 1) & 0x3f;
+                    self.eval_done = n == 0;
                 }
-
-                // set index to point to next byte (increment 'm')
-                self.current_sprite_idx = self.current_sprite_idx.wrapping_add(1);
+            } else {
+                // go to the next sprite
+                m = 0;
+                n = (n + 1) & 0x3f;
+                self.eval_done = n == 0;
             }
-        } else if !sprite_overflow {
-            let sprite_y = primary_oam.get_byte(self.current_sprite_idx);
+        } else {
+            // once 8 sprites have been found, writes to secondary oam turn into reads
+            self.oam_bus = secondary_oam.as_bytes()[0];
 
-            if (current_scanline as u16).wrapping_sub(sprite_y as u16) < sprite_height as u16 {
+            if is_in_range || self.overflow_bytes_left > 0 {
+                // a sprite is found, and its remaining 3 bytes are read as well
                 sprite_overflow = true;
-            } else {
-                // increment 'n' (from nesdev.com 'PPU Sprite Evaluation' page)
-                self.current_sprite_idx = self.current_sprite_idx.wrapping_add(4);
-
-                if self.current_sprite_idx & !0b11 == 0 {
-                    // 'n' has overflown back to zero
-                    self.eval_done = true;
+                m += 1;
+                if m == 4 {
+                    m = 0;
+                    n = (n + 1) & 0x3f;
                 }
 
-                // increment 'm' without carry (from nesdev.com 'PPU Sprite Evaluation' page)
-                self.current_sprite_idx = if self.current_sprite_idx & 0b11 == 0b11 {
-                    self.current_sprite_idx & !0b11
+                if self.overflow_bytes_left == 0 {
+                    self.overflow_bytes_left = 3;
                 } else {
-                    self.current_sprite_idx + 1
-                };
+                    self.overflow_bytes_left -= 1;
+                    if self.overflow_bytes_left == 0 {
+                        self.eval_done = true;
+                        m = 0;
+                    }
+                }
+            } else {
+                // the sprite isn't in range, and a hardware bug increments both 'n' and
+                // 'm' (without carry), making the search go diagonally through oam
+                n = (n + 1) & 0x3f;
+                m = (m + 1) & 0b11;
+                self.eval_done = n == 0;
             }
         }
 
+        *oamaddr = (n << 2) | m;
         sprite_overflow
     }
 
+    // the byte 'oamdata' (0x2004) reads return while rendering, which is the byte last
+    // read from (primary or secondary) oam by the ppu
+    pub(super) fn oam_bus(&self, secondary_oam: &SecondaryOam, current_scanline_dot: u16) -> u8 {
+        match current_scanline_dot {
+            // secondary oam is being cleared
+            1..=64 => 0xff,
+            65..=256 => self.oam_bus,
+            // sprite fetches read the 4 bytes of each sprite, followed by the x-coordinate
+            // 4 more times
+            257..=320 => {
+                let sprite_dot = (current_scanline_dot - 257) & 7;
+                let sprite = (current_scanline_dot - 257) >> 3;
+                secondary_oam.as_bytes()[(sprite * 4 + sprite_dot.min(3)) as usize]
+            }
+            // the first byte of secondary oam is read while the first two tiles are fetched
+            _ => secondary_oam.as_bytes()[0],
+        }
+    }
+
     pub(super) fn fetch_next_scanline_sprite_data(
         &mut self,
         secondary_oam: &SecondaryOam,
@@ -161,81 +218,157 @@ impl SpriteDrawState {
         let _ = bus.read(0x2000, cycle_count, cpu);
         let _ = bus.read(0x2000, cycle_count + 2, cpu);
 
-        assert!(self.sprites_found <= 8);
+        let (lo_addr, hi_addr) = self.calc_sprite_pattern_addrs(
+            secondary_oam,
+            sprite_height,
+            current_scanline,
+            sprite_pattern_table_addr,
+        );
+        let tile_bitplane_lo = bus.read(lo_addr, cycle_count + 4, cpu);
+        let tile_bitplane_hi = bus.read(hi_addr, cycle_count + 6, cpu);
 
-        if (self.current_sprite_idx >> 2) < self.sprites_found {
-            // fill a slot in 'current_sprites_data' with data for the current sprite
+        self.load_sprite_data(secondary_oam, tile_bitplane_lo, tile_bitplane_hi);
+    }
 
-            assert!((self.current_sprite_idx >> 2) < 8);
+    // makes the part of a sprite fetch that happens on the given dot of the sprite (0-7,
+    // with 0 being dots 257, 265, 273, etc). like background tiles, the address of each
+    // fetch is put on the bus on even dots, and read on odd dots. only used when stepping
+    // dot by dot
+    #[allow(clippy::too_many_arguments)]
+    pub(super) fn fetch_sprite_data_at_dot(
+        &mut self,
+        sprite_dot: u16,
+        secondary_oam: &SecondaryOam,
+        sprite_height: SpriteSize,
+        current_scanline: i16,
+        sprite_pattern_table_addr: u16,
+        current_vram_addr: super::VramAddrRegister,
+        cycle_count: i32,
+        bus: &mut dyn PpuAddressBus,
+        cpu: &mut cpu::Cpu,
+    ) {
+        let addr = match sprite_dot >> 1 {
+            // the garbage fetches use the same addresses as background tile fetches
+            0 => super::bg_state::nametable_addr(current_vram_addr),
+            1 => super::bg_state::attribute_addr(current_vram_addr),
+            n => {
+                let (lo_addr, hi_addr) = self.calc_sprite_pattern_addrs(
+                    secondary_oam,
+                    sprite_height,
+                    current_scanline,
+                    sprite_pattern_table_addr,
+                );
+                if n == 2 {
+                    lo_addr
+                } else {
+                    hi_addr
+                }
+            }
+        };
+
+        if sprite_dot & 1 == 0 {
+            bus.set_address(addr, cycle_count, cpu);
+            return;
+        }
+
+        let val = bus.read(addr, cycle_count, cpu);
+        match sprite_dot {
+            5 => self.next_bitplane_lo = val,
+            7 => self.load_sprite_data(secondary_oam, self.next_bitplane_lo, val),
+            _ => (),
+        }
+    }
+
+    // calculates the addresses of the low and high bitplanes for the row of the current
+    // sprite on the current scanline (or those of tile 0xff, if the current slot is empty)
+    fn calc_sprite_pattern_addrs(
+        &self,
+        secondary_oam: &SecondaryOam,
+        sprite_height: SpriteSize,
+        current_scanline: i16,
+        sprite_pattern_table_addr: u16,
+    ) -> (u16, u16) {
+        assert!(self.sprites_found <= 8);
 
-            // SAFETY: if ('current_sprite_idx' >> 2) is less than 8, and'ing away the lower two
-            // bits gives a maximum index of 0x1c, which aligns with the byte index of the last
-            // sprite in secondary oam
-            let sprite =
-                unsafe { secondary_oam.get_sprite_unchecked(self.current_sprite_idx & !0b11) };
+        if (self.current_sprite_idx >> 2) >= self.sprites_found {
+            // make dummy pattern table fetches (as if sprite was all 0xff-bytes)
+            let tile_addr = match sprite_height {
+                SpriteSize::S8x8 => sprite_pattern_table_addr | (0xff << 4),
+                SpriteSize::S8x16 => 0x1000 | (0xff << 4),
+            };
+            // TODO: emulate the exact dummy bitplane that would be fetched?
+            return (tile_addr, tile_addr + 8);
+        }
 
-            let x = sprite.x;
-            let y = sprite.y;
-            let attributes = sprite.attributes;
-            let is_vert_flipped = attributes & 0b10000000 != 0;
-            let tile_index = sprite.tile_index;
+        assert!((self.current_sprite_idx >> 2) < 8);
 
-            let (tile_bitplane_lo, tile_bitplane_hi) = {
-                let tile_addr = match sprite_height {
-                    SpriteSize::S8x8 => sprite_pattern_table_addr | ((tile_index as u16) << 4),
-                    SpriteSize::S8x16 => {
-                        // address of pattern table is stored in the lowest tile index bit
-                        let sprite_pattern_table_addr_8x16 = (tile_index as u16 & 1) << 12;
-                        let mut tile_index_8x16 = tile_index & !1;
+        // SAFETY: if ('current_sprite_idx' >> 2) is less than 8, and'ing away the lower two
+        // bits gives a maximum index of 0x1c, which aligns with the byte index of the last
+        // sprite in secondary oam
+        let sprite = unsafe { secondary_oam.get_sprite_unchecked(self.current_sprite_idx & !0b11) };
 
-                        debug_assert!((current_scanline as u8).wrapping_sub(sprite.y) < 16);
+        let y = sprite.y;
+        let is_vert_flipped = sprite.attributes & 0b10000000 != 0;
+        let tile_index = sprite.tile_index;
 
-                        if (current_scanline as u8).wrapping_sub(sprite.y) >= 8 {
-                            tile_index_8x16 |= 1;
-                        }
+        let tile_addr = match sprite_height {
+            SpriteSize::S8x8 => sprite_pattern_table_addr | ((tile_index as u16) << 4),
+            SpriteSize::S8x16 => {
+                // address of pattern table is stored in the lowest tile index bit
+                let sprite_pattern_table_addr_8x16 = (tile_index as u16 & 1) << 12;
+                let mut tile_index_8x16 = tile_index & !1;
 
-                        if is_vert_flipped {
-                            tile_index_8x16 ^= 1;
-                        }
+                debug_assert!((current_scanline as u8).wrapping_sub(sprite.y) < 16);
 
-                        sprite_pattern_table_addr_8x16 | ((tile_index_8x16 as u16) << 4)
-                    }
-                };
+                if (current_scanline as u8).wrapping_sub(sprite.y) >= 8 {
+                    tile_index_8x16 |= 1;
+                }
 
-                // NOTE: 'sprite.y' is 1 less than the screen y coordinate
-                let y_offset = (current_scanline as u16 - y as u16) % 8;
                 if is_vert_flipped {
-                    // use flipped tile bitplanes if sprite is vertically flipped
-                    (
-                        bus.read(tile_addr + 7 - y_offset, cycle_count + 4, cpu),
-                        bus.read(tile_addr + 15 - y_offset, cycle_count + 6, cpu),
-                    )
-                } else {
-                    (
-                        bus.read(tile_addr + y_offset, cycle_count + 4, cpu),
-                        bus.read(tile_addr + 8 + y_offset, cycle_count + 6, cpu),
-                    )
+                    tile_index_8x16 ^= 1;
                 }
-            };
+
+                sprite_pattern_table_addr_8x16 | ((tile_index_8x16 as u16) << 4)
+            }
+        };
+
+        // NOTE: 'sprite.y' is 1 less than the screen y coordinate
+        let y_offset = (current_scanline as u16 - y as u16) % 8;
+        if is_vert_flipped {
+            // use flipped tile bitplanes if sprite is vertically flipped
+            (tile_addr + 7 - y_offset, tile_addr + 15 - y_offset)
+        } else {
+            (tile_addr + y_offset, tile_addr + 8 + y_offset)
+        }
+    }
+
+    // stores the bitplanes fetched for the current sprite, and moves on to the next one
+    fn load_sprite_data(
+        &mut self,
+        secondary_oam: &SecondaryOam,
+        tile_bitplane_lo: u8,
+        tile_bitplane_hi: u8,
+    ) {
+        if (self.current_sprite_idx >> 2) < self.sprites_found {
+            // fill a slot in 'current_sprites_data' with data for the current sprite
+            let sprite = secondary_oam.entries[(self.current_sprite_idx >> 2) as usize];
+
+            // bits 2-4 of the attributes are always clear in secondary oam, so bit 3 is used
+            // to mark sprite zero (which is always in the first slot)
+            let mut attributes = sprite.attributes & 0b11100011;
+            if self.current_sprite_idx == 0 && self.sprite_zero_found {
+                attributes |= 0b1000;
+            }
 
             self.current_sprites_data[(self.current_sprite_idx >> 2) as usize] = SpriteRenderData {
                 tile_bitplane_lo,
                 tile_bitplane_hi,
                 attributes,
-                x,
+                x: sprite.x,
             };
 
             self.current_sprite_idx = self.current_sprite_idx.wrapping_add(4);
         } else {
-            // make dummy pattern table fetches (as if sprite was all 0xff-bytes)
-            let tile_addr = match sprite_height {
-                SpriteSize::S8x8 => sprite_pattern_table_addr | (0xff << 4),
-                SpriteSize::S8x16 => 0x1000 | (0xff << 4),
-            };
-            // TODO: emulate the exact dummy bitplane that would be fetched?
-            let _ = bus.read(tile_addr, cycle_count + 4, cpu);
-            let _ = bus.read(tile_addr, cycle_count + 6, cpu);
-
             // fill a slot in 'current_sprites_data' with sentinel value
             // (bit 2 of 'attributes' being set indicates end of array)
             self.current_sprites_data[(self.current_sprite_idx >> 2) as usize] = SpriteRenderData {
@@ -368,3 +368,276 @@ fn test_all() {
 
     test_temp_to_current_vram_transfer(&mut nes.bus.base().0.ppu);
 }
+
+#[test]
+fn test_region_timing() {
+    let framebuffer = Cell::new([0u32; 256 * 240]);
+    let framebuffer: &[Cell<u32>; 256 * 240] = unsafe { &*(&framebuffer as *const _ as *const _) };
+    let mut nes = Nes::new_test(framebuffer);
+
+    for &(region, vblank_scanline, frame_lengths) in [
+        // with rendering enabled, odd ntsc frames are one dot shorter
+        (super::Region::Ntsc, 241, [341 * 262 - 1, 341 * 262]),
+        (super::Region::Pal, 241, [341 * 312, 341 * 312]),
+        (super::Region::Dendy, 291, [341 * 312, 341 * 312]),
+    ]
+    .iter()
+    {
+        nes.reset_state();
+        let cpu = &mut nes.cpu;
+        let (bus::CpuAddressBusBase { ppu, .. }, ppu_bus) = nes.bus.base();
+        ppu.set_region(region);
+        ppu.ppumask = 0b00011000;
+        ppu.ppuctrl = 0b10000000;
+
+        let mut run_frame = |ppu: &mut super::Ppu| {
+            let start = ppu.cycle_count;
+            while !ppu.is_frame_done() {
+                ppu.step(cpu, ppu_bus, framebuffer);
+            }
+            ppu.set_frame_done(false);
+            ppu.cycle_count - start
+        };
+
+        // finish the first (partial) frame
+        run_frame(ppu);
+        let lengths = [run_frame(ppu), run_frame(ppu)];
+        assert!(lengths == frame_lengths || lengths == [frame_lengths[1], frame_lengths[0]]);
+
+        while !ppu.is_vblank() {
+            ppu.step(cpu, ppu_bus, framebuffer);
+        }
+        assert_eq!(ppu.current_scanline, vblank_scanline);
+    }
+
+    let ppu = &mut nes.bus.base().0.ppu;
+    assert_eq!(super::Region::Pal.cpu_to_ppu_cycles(5), 16);
+    assert_eq!(super::Region::Dendy.cpu_to_ppu_cycles(5), 15);
+
+    // the fraction of a dot left over each frame on pal is carried over to the next one
+    ppu.set_region(super::Region::Pal);
+    let elapsed_cycles = ppu.elapsed_cycles;
+    for _ in 0..5 {
+        ppu.sub_cpu_cycles(1);
+    }
+    assert_eq!(ppu.elapsed_cycles - elapsed_cycles, 16);
+
+    // emphasize red
+    ppu.ppumask = 0b00100000;
+    ppu.set_region(super::Region::Ntsc);
+    assert_eq!(ppu.get_emphasis_bits(), 0b001);
+    ppu.set_region(super::Region::Pal);
+    assert_eq!(ppu.get_emphasis_bits(), 0b010);
+}
+
+// records the accesses the ppu makes, for checking the dots they're made on
+#[cfg(test)]
+#[derive(Default)]
+struct RecordingPpuAddressBus {
+    // (address, ppu cycle count, whether the access was a read)
+    accesses: Vec<(u16, i32, bool)>,
+}
+
+#[cfg(test)]
+impl PpuAddressBus for RecordingPpuAddressBus {
+    fn read(&mut self, addr: u16, ppu_cycle_count: i32, _: &mut cpu::Cpu) -> u8 {
+        self.accesses.push((addr, ppu_cycle_count, true));
+        0
+    }
+
+    fn write(&mut self, _: u16, _: u8, _: i32, _: &mut cpu::Cpu) {}
+
+    fn set_address(&mut self, addr: u16, ppu_cycle_count: i32, _: &mut cpu::Cpu) {
+        self.accesses.push((addr, ppu_cycle_count, false));
+    }
+
+    fn read_palette_memory(&self, _: u8) -> u8 {
+        0
+    }
+}
+
+#[test]
+fn test_dot_accurate_fetches() {
+    let framebuffer = Cell::new([0u32; 256 * 240]);
+    let framebuffer: &[Cell<u32>; 256 * 240] = unsafe { &*(&framebuffer as *const _ as *const _) };
+    let mut cpu = cpu::Cpu::default();
+    let mut bus = RecordingPpuAddressBus::default();
+
+    let mut ppu = super::Ppu::new();
+    ppu.set_dot_accurate(true);
+    // background at 0, sprites at 0x1000
+    ppu.ppuctrl = 0b00001000;
+    ppu.ppumask = 0b00011000;
+    ppu.current_scanline = 0;
+
+    // step through scanline 0 (where the cycle count of each dot is equal to the dot)
+    for _ in 0..341 {
+        ppu.step_dot(&mut cpu, &mut bus, framebuffer);
+    }
+    assert_eq!((ppu.current_scanline, ppu.current_scanline_dot), (1, 0));
+
+    let reads = |dots: std::ops::RangeInclusive<i32>| {
+        bus.accesses
+            .iter()
+            .filter(|&&(_, cycle, is_read)| is_read && dots.contains(&cycle))
+            .map(|&(addr, cycle, _)| (cycle, addr))
+            .collect::<Vec<_>>()
+    };
+
+    // the first tile: nametable, attribute, then both bitplanes
+    assert_eq!(
+        reads(1..=8),
+        [(2, 0x2000), (4, 0x23c0), (6, 0x0000), (8, 0x0008)]
+    );
+    // the address of each read is put on the bus a dot earlier
+    assert!(bus.accesses.contains(&(0x2000, 1, false)));
+    // the first sprite: the garbage nametable and attribute fetches, then the tile (oam is
+    // all zeroes, so every sprite is in range, with tile 0)
+    assert_eq!(
+        reads(257..=264),
+        [(258, 0x2000), (260, 0x23c0), (262, 0x1000), (264, 0x1008)]
+    );
+    // the two dummy nametable fetches at the end of the scanline (after the two tiles of
+    // the next scanline have been fetched)
+    assert_eq!(reads(337..=340), [(338, 0x2002), (340, 0x2002)]);
+    assert_eq!(reads(0..=340).len(), 34 * 4 + 8 * 4 + 2);
+}
+
+#[test]
+fn test_sprite_evaluation() {
+    let framebuffer = Cell::new([0u32; 256 * 240]);
+    let framebuffer: &[Cell<u32>; 256 * 240] = unsafe { &*(&framebuffer as *const _ as *const _) };
+    let mut cpu = cpu::Cpu::default();
+    let mut bus = RecordingPpuAddressBus::default();
+
+    let mut ppu = super::Ppu::new();
+    ppu.set_dot_accurate(true);
+    ppu.ppumask = 0b00011000;
+
+    let mut run_scanline_0 = |ppu: &mut super::Ppu, oamaddr: u8| {
+        ppu.current_scanline = 0;
+        ppu.current_scanline_dot = 0;
+        ppu.oamaddr = oamaddr;
+        while ppu.current_scanline_dot <= 256 {
+            ppu.step_dot(&mut cpu, &mut bus, framebuffer);
+        }
+    };
+
+    // 8 sprites on scanline 0, followed by one that isn't, and one whose tile index is 0
+    ppu.primary_oam.as_bytes_mut().fill(0xf0);
+    for sprite in 0..8 {
+        ppu.primary_oam.set_byte(sprite * 4, 0);
+    }
+    ppu.primary_oam.set_byte(9 * 4 + 1, 0);
+
+    // the search for a 9th sprite goes diagonally through oam after sprite 8, and treats
+    // the tile index of sprite 9 as a y-coordinate
+    run_scanline_0(&mut ppu, 0);
+    assert_eq!(ppu.sprite_state.sprites_found, 8);
+    assert!(ppu.is_sprite_overflow());
+
+    // evaluation starts from oamaddr, and stops at the end of oam (so sprites 0 and 1 are
+    // never found)
+    ppu.set_sprite_overflow(false);
+    run_scanline_0(&mut ppu, 2 * 4);
+    assert_eq!(ppu.sprite_state.sprites_found, 6);
+    assert_eq!(ppu.secondary_oam.as_bytes()[6 * 4], 0xf0);
+    assert!(!ppu.is_sprite_overflow());
+
+    // oamdata reads return the byte the ppu is reading from (secondary) oam
+    ppu.secondary_oam.as_bytes_mut()[1] = 0x12;
+    ppu.current_scanline_dot = 32;
+    assert_eq!(ppu.read_register_by_index(4, &mut bus, &mut cpu), 0xff);
+    ppu.current_scanline_dot = 258;
+    assert_eq!(ppu.read_register_by_index(4, &mut bus, &mut cpu), 0x12);
+
+    // oamdata writes while rendering don't write to oam, and increment oamaddr by 4
+    ppu.oamaddr = 1;
+    ppu.write_register_by_index(4, 0xaa, &mut cpu, &mut bus);
+    assert_eq!(ppu.oamaddr, 5);
+    assert_eq!(ppu.primary_oam.get_byte(1), 0xf0);
+
+    // if rendering starts with oamaddr >= 8, the row of oam it points to is copied to the
+    // first row
+    ppu.current_scanline = -1;
+    ppu.current_scanline_dot = 1;
+    ppu.oamaddr = 9 * 4 + 3;
+    ppu.step_dot(&mut cpu, &mut bus, framebuffer);
+    assert_eq!(
+        ppu.primary_oam.as_bytes()[..8],
+        ppu.primary_oam.as_bytes()[32..40]
+    );
+    assert_eq!(ppu.primary_oam.get_byte(5), 0);
+}
+
+#[test]
+fn test_open_bus() {
+    let mut cpu = cpu::Cpu::default();
+    let mut bus = RecordingPpuAddressBus::default();
+    let mut ppu = super::Ppu::new();
+
+    // writes to any register fill the open bus, which write-only registers return
+    ppu.write_register_by_index(2, 0xff, &mut cpu, &mut bus);
+    for &index in [0, 1, 3, 5, 6].iter() {
+        assert_eq!(ppu.read_register_by_index(index, &mut bus, &mut cpu), 0xff);
+    }
+
+    // the low 5 bits of ppustatus come from the open bus
+    ppu.ppustatus = 0b11100000;
+    assert_eq!(ppu.read_register_by_index(2, &mut bus, &mut cpu), 0xff);
+
+    // the high 2 bits of palette reads come from the open bus (the recording bus
+    // returns 0 for every read)
+    ppu.current_vram_addr.inner = 0x3f00;
+    assert_eq!(
+        ppu.read_register_by_index(7, &mut bus, &mut cpu),
+        0b11000000
+    );
+    assert_eq!(ppu.open_bus, 0b11000000);
+
+    // bits decay after about 600ms (36 ntsc frames) without being refreshed
+    ppu.write_register_by_index(2, 0xff, &mut cpu, &mut bus);
+    for _ in 0..35 {
+        ppu.decay_open_bus();
+    }
+    ppu.ppustatus = 0b10100000;
+    let _ = ppu.read_register_by_index(2, &mut bus, &mut cpu);
+    ppu.decay_open_bus();
+    assert_eq!(ppu.open_bus, 0b10100000);
+    for _ in 0..36 {
+        ppu.decay_open_bus();
+    }
+    assert_eq!(ppu.open_bus, 0);
+}
+
+#[test]
+fn test_oam_decay() {
+    let mut cpu = cpu::Cpu::default();
+    let mut bus = RecordingPpuAddressBus::default();
+    let mut ppu = super::Ppu::new();
+    ppu.set_oam_decay(true);
+
+    ppu.write_register_by_index(3, 0, &mut cpu, &mut bus);
+    for val in 0..16 {
+        ppu.write_register_by_index(4, val, &mut cpu, &mut bus);
+    }
+
+    // reading the first row keeps it refreshed, while the second one decays
+    for _ in 0..4 {
+        ppu.cycle_count += super::OAM_DECAY_CPU_CYCLES * 3 / 2;
+        ppu.write_register_by_index(3, 0, &mut cpu, &mut bus);
+        assert_eq!(ppu.read_register_by_index(4, &mut bus, &mut cpu), 0);
+    }
+    ppu.write_register_by_index(3, 8, &mut cpu, &mut bus);
+    assert_eq!(ppu.read_register_by_index(4, &mut bus, &mut cpu), 0xff);
+    assert_eq!(
+        ppu.primary_oam.as_bytes()[..16],
+        [0, 1, 2, 3, 4, 5, 6, 7, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]
+    );
+
+    // oam doesn't decay unless enabled
+    ppu.set_oam_decay(false);
+    ppu.primary_oam.set_byte(8, 0);
+    ppu.cycle_count += super::OAM_DECAY_CPU_CYCLES * 6;
+    assert_eq!(ppu.read_register_by_index(4, &mut bus, &mut cpu), 0);
+}
@@ -36,6 +36,8 @@ impl_serialize_for_num!(i8);
 impl_serialize_for_num!(u16);
 impl_serialize_for_num!(i16);
 impl_serialize_for_num!(i32);
+impl_serialize_for_num!(u32);
+impl_serialize_for_num!(u64);
 impl_serialize_for_num!(usize);
 
 macro_rules! impl_serialize_for_byte_array {
@@ -0,0 +1,144 @@
+use std::cell::Cell;
+
+// the width and height of each glyph in 'FONT', and the horizontal distance between
+// the start of two glyphs (both at a scale of 1)
+pub const GLYPH_WIDTH: usize = 3;
+pub const GLYPH_HEIGHT: usize = 5;
+pub const GLYPH_ADVANCE: usize = GLYPH_WIDTH + 1;
+
+// a tiny 3x5 pixel font covering ascii 0x20-0x5f (lowercase letters are drawn as
+// uppercase). each glyph is 5 rows of 3 pixels, with bit 2 being the leftmost pixel
+#[rustfmt::skip]
+static FONT: [[u8; 5]; 64] = [
+    [0b000, 0b000, 0b000, 0b000, 0b000], // ' '
+    [0b010, 0b010, 0b010, 0b000, 0b010], // '!'
+    [0b101, 0b101, 0b000, 0b000, 0b000], // '"'
+    [0b101, 0b111, 0b101, 0b111, 0b101], // '#'
+    [0b011, 0b110, 0b010, 0b011, 0b110], // '$'
+    [0b101, 0b001, 0b010, 0b100, 0b101], // '%'
+    [0b010, 0b101, 0b010, 0b101, 0b011], // '&'
+    [0b010, 0b010, 0b000, 0b000, 0b000], // '''
+    [0b001, 0b010, 0b010, 0b010, 0b001], // '('
+    [0b100, 0b010, 0b010, 0b010, 0b100], // ')'
+    [0b000, 0b101, 0b010, 0b101, 0b000], // '*'
+    [0b000, 0b010, 0b111, 0b010, 0b000], // '+'
+    [0b000, 0b000, 0b000, 0b010, 0b100], // ','
+    [0b000, 0b000, 0b111, 0b000, 0b000], // '-'
+    [0b000, 0b000, 0b000, 0b000, 0b010], // '.'
+    [0b001, 0b001, 0b010, 0b100, 0b100], // '/'
+    [0b111, 0b101, 0b101, 0b101, 0b111], // '0'
+    [0b010, 0b110, 0b010, 0b010, 0b111], // '1'
+    [0b111, 0b001, 0b111, 0b100, 0b111], // '2'
+    [0b111, 0b001, 0b111, 0b001, 0b111], // '3'
+    [0b101, 0b101, 0b111, 0b001, 0b001], // '4'
+    [0b111, 0b100, 0b111, 0b001, 0b111], // '5'
+    [0b111, 0b100, 0b111, 0b101, 0b111], // '6'
+    [0b111, 0b001, 0b001, 0b001, 0b001], // '7'
+    [0b111, 0b101, 0b111, 0b101, 0b111], // '8'
+    [0b111, 0b101, 0b111, 0b001, 0b111], // '9'
+    [0b000, 0b010, 0b000, 0b010, 0b000], // ':'
+    [0b000, 0b010, 0b000, 0b010, 0b100], // ';'
+    [0b001, 0b010, 0b100, 0b010, 0b001], // '<'
+    [0b000, 0b111, 0b000, 0b111, 0b000], // '='
+    [0b100, 0b010, 0b001, 0b010, 0b100], // '>'
+    [0b111, 0b001, 0b011, 0b000, 0b010], // '?'
+    [0b111, 0b101, 0b111, 0b100, 0b111], // '@'
+    [0b010, 0b101, 0b111, 0b101, 0b101], // 'A'
+    [0b110, 0b101, 0b110, 0b101, 0b110], // 'B'
+    [0b011, 0b100, 0b100, 0b100, 0b011], // 'C'
+    [0b110, 0b101, 0b101, 0b101, 0b110], // 'D'
+    [0b111, 0b100, 0b110, 0b100, 0b111], // 'E'
+    [0b111, 0b100, 0b110, 0b100, 0b100], // 'F'
+    [0b011, 0b100, 0b101, 0b101, 0b011], // 'G'
+    [0b101, 0b101, 0b111, 0b101, 0b101], // 'H'
+    [0b111, 0b010, 0b010, 0b010, 0b111], // 'I'
+    [0b001, 0b001, 0b001, 0b101, 0b010], // 'J'
+    [0b101, 0b101, 0b110, 0b101, 0b101], // 'K'
+    [0b100, 0b100, 0b100, 0b100, 0b111], // 'L'
+    [0b101, 0b111, 0b111, 0b101, 0b101], // 'M'
+    [0b110, 0b101, 0b101, 0b101, 0b101], // 'N'
+    [0b010, 0b101, 0b101, 0b101, 0b010], // 'O'
+    [0b110, 0b101, 0b110, 0b100, 0b100], // 'P'
+    [0b010, 0b101, 0b101, 0b110, 0b011], // 'Q'
+    [0b110, 0b101, 0b110, 0b101, 0b101], // 'R'
+    [0b011, 0b100, 0b010, 0b001, 0b110], // 'S'
+    [0b111, 0b010, 0b010, 0b010, 0b010], // 'T'
+    [0b101, 0b101, 0b101, 0b101, 0b111], // 'U'
+    [0b101, 0b101, 0b101, 0b101, 0b010], // 'V'
+    [0b101, 0b101, 0b111, 0b111, 0b101], // 'W'
+    [0b101, 0b101, 0b010, 0b101, 0b101], // 'X'
+    [0b101, 0b101, 0b010, 0b010, 0b010], // 'Y'
+    [0b111, 0b001, 0b010, 0b100, 0b111], // 'Z'
+    [0b011, 0b010, 0b010, 0b010, 0b011], // '['
+    [0b100, 0b100, 0b010, 0b001, 0b001], // '\'
+    [0b110, 0b010, 0b010, 0b010, 0b110], // ']'
+    [0b010, 0b101, 0b000, 0b000, 0b000], // '^'
+    [0b000, 0b000, 0b000, 0b000, 0b111], // '_'
+];
+
+fn glyph(c: char) -> &'static [u8; 5] {
+    match c.to_ascii_uppercase() {
+        c @ ' '..='_' => &FONT[c as usize - 0x20],
+        _ => &FONT[(b'?' - 0x20) as usize],
+    }
+}
+
+// draws 'text' into the framebuffer with its top left corner at ('x', 'y'), with each
+// font pixel drawn as a 'scale' x 'scale' block. text beyond the edges is clipped
+pub fn draw_text(
+    framebuffer: &[Cell<u32>; 256 * 240],
+    x: usize,
+    y: usize,
+    scale: usize,
+    color: u32,
+    text: &str,
+) {
+    for (i, c) in text.chars().enumerate() {
+        let glyph_x = x + i * GLYPH_ADVANCE * scale;
+
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (0b100 >> col) == 0 {
+                    continue;
+                }
+
+                for pixel_y in y + row * scale..y + (row + 1) * scale {
+                    for pixel_x in glyph_x + col * scale..glyph_x + (col + 1) * scale {
+                        if pixel_x < 256 && pixel_y < 240 {
+                            framebuffer[pixel_y * 256 + pixel_x].set(color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_draw_text() {
+        let framebuffer = Cell::new([0u32; 256 * 240]);
+        let framebuffer: &[Cell<u32>; 256 * 240] =
+            unsafe { &*(&framebuffer as *const _ as *const _) };
+
+        draw_text(framebuffer, 10, 20, 2, 1, "t!");
+        let row = |y: usize| {
+            (10..24)
+                .map(|x| framebuffer[y * 256 + x].get())
+                .collect::<Vec<_>>()
+        };
+        // the top of 't' (3 pixels) and '!' (the middle pixel), at double size
+        assert_eq!(row(20), [1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 1, 1, 0, 0]);
+        assert_eq!(row(21), row(20));
+        assert_eq!(row(22), [0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0]);
+        assert_eq!(row(19), [0; 14]);
+
+        // clipped at the edges
+        draw_text(framebuffer, 254, 238, 1, 2, "MM");
+        assert_eq!(framebuffer[238 * 256 + 254].get(), 2);
+        assert_eq!(framebuffer[239 * 256 + 255].get(), 2);
+    }
+}
@@ -0,0 +1,278 @@
+use crate::parse::{self, ConsoleType, RomDescription, RomHeader, Timing};
+
+use std::borrow::Cow;
+
+// unif roms identify their board by name (the 'MAPR' chunk) rather than by mapper number.
+// these are the board names (without the 'NES-'/'HVC-'/... prefix) that map onto one of
+// the supported mappers, along with the mapper and submapper numbers they correspond to
+static BOARDS: &[(&str, u16, u8)] = &[
+    ("NROM", 0, 0),
+    ("NROM-128", 0, 0),
+    ("NROM-256", 0, 0),
+    ("HROM", 0, 0),
+    ("RROM", 0, 0),
+    ("RROM-128", 0, 0),
+    ("COLORDREAMS-74*377", 11, 0),
+    ("BANDAI-FCG-1", 16, 0),
+    ("BANDAI-FCG-2", 16, 0),
+    ("BANDAI-LZ93D50", 16, 0),
+    ("BANDAI-LZ93D50+24C02", 16, 0),
+    ("NAMCOT-163", 19, 0),
+    ("TBROM", 4, 0),
+    ("TEROM", 4, 0),
+    ("TFROM", 4, 0),
+    ("TGROM", 4, 0),
+    ("TKROM", 4, 0),
+    ("TLROM", 4, 0),
+    ("TNROM", 4, 0),
+    ("TR1ROM", 4, 0),
+    ("TSROM", 4, 0),
+    ("TVROM", 4, 0),
+    ("B4", 4, 0),
+    ("HKROM", 4, 1),
+    ("EKROM", 5, 0),
+    ("ELROM", 5, 0),
+    ("ETROM", 5, 0),
+    ("EWROM", 5, 0),
+    ("BNROM", 34, 2),
+    ("AVE-NINA-01", 34, 1),
+    ("AVE-NINA-02", 34, 1),
+    ("GNROM", 66, 0),
+    ("MHROM", 66, 0),
+    ("BTR", 69, 0),
+    ("JLROM", 69, 0),
+    ("JSROM", 69, 0),
+    ("CAMERICA-BF9093", 71, 0),
+    ("CAMERICA-BF9097", 71, 1),
+    ("AVE-NINA-03", 79, 0),
+    ("AVE-NINA-06", 79, 0),
+    ("KONAMI-VRC-7", 85, 0),
+    ("TKSROM", 118, 0),
+    ("TLSROM", 118, 0),
+    ("TQROM", 119, 0),
+    ("BANDAI-LZ93D50+SRAM", 153, 0),
+    ("BANDAI-LZ93D50+24C01", 159, 0),
+    ("DEROM", 206, 0),
+    ("DE1ROM", 206, 0),
+    ("DRROM", 206, 0),
+];
+
+// the manufacturer/region prefixes that board names may start with
+static BOARD_PREFIXES: &[&str] = &["NES-", "HVC-", "UNL-", "BTL-", "BMC-"];
+
+pub fn is_unif(rom: &[u8]) -> bool {
+    rom.starts_with(b"UNIF")
+}
+
+// returns the mapper and submapper that a unif board name corresponds to
+pub fn find_board(name: &str) -> Option<(u16, u8)> {
+    let name = BOARD_PREFIXES
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name);
+
+    BOARDS
+        .iter()
+        .find(|(board, _, _)| board.eq_ignore_ascii_case(name))
+        .map(|&(_, mapper, submapper)| (mapper, submapper))
+}
+
+// returns the string stored in a chunk (which should be null-terminated)
+fn parse_string(data: &[u8]) -> String {
+    let len = data
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..len]).into_owned()
+}
+
+// parses a unif rom: a 32 byte header ('UNIF', the revision and padding), followed
+// by chunks consisting of a 4 byte id, a 32 bit little-endian length and the data
+pub fn parse(rom: &[u8]) -> Result<RomDescription<'_>, String> {
+    if rom.len() < 0x20 || !is_unif(rom) {
+        return Err("invalid unif header information".to_string());
+    }
+
+    let mut board_name = None;
+    // the rom is split into (up to) 16 numbered chunks, which are concatenated in order
+    let mut prg_chunks: [&[u8]; 16] = [&[]; 16];
+    let mut chr_chunks: [&[u8]; 16] = [&[]; 16];
+    let mut mirroring = parse::MirroringType::Hor;
+    let mut has_battery = false;
+    let mut timing = Timing::Ntsc;
+
+    let mut remaining = &rom[0x20..];
+    while !remaining.is_empty() {
+        if remaining.len() < 8 {
+            return Err("unexpected end of file in unif chunk header".to_string());
+        }
+
+        let id = &remaining[0..4];
+        let len = u32::from_le_bytes([remaining[4], remaining[5], remaining[6], remaining[7]]);
+        let data = remaining.get(8..8 + len as usize).ok_or_else(|| {
+            format!(
+                "unif chunk '{}' is {} bytes long, but the file ends before that",
+                String::from_utf8_lossy(id),
+                len
+            )
+        })?;
+        remaining = &remaining[8 + len as usize..];
+
+        match id {
+            b"MAPR" => board_name = Some(parse_string(data)),
+            b"NAME" => logln!("unif game name: {}", parse_string(data)),
+            [b'P', b'R', b'G', idx] | [b'C', b'H', b'R', idx] if idx.is_ascii_hexdigit() => {
+                let idx = (*idx as char).to_digit(16).unwrap() as usize;
+                if id[0] == b'P' {
+                    prg_chunks[idx] = data;
+                } else {
+                    chr_chunks[idx] = data;
+                }
+            }
+            b"MIRR" => {
+                mirroring = match data.first() {
+                    Some(0) => parse::MirroringType::Hor,
+                    Some(1) => parse::MirroringType::Vert,
+                    Some(2) => parse::MirroringType::SingleScreenA,
+                    Some(3) => parse::MirroringType::SingleScreenB,
+                    Some(4) => parse::MirroringType::FourScreen,
+                    // mapper-controlled mirroring (5) is left to the mapper
+                    _ => parse::MirroringType::Hor,
+                }
+            }
+            b"BATR" => has_battery = data.first() != Some(&0),
+            b"TVCI" => {
+                timing = match data.first() {
+                    Some(1) => Timing::Pal,
+                    Some(2) => Timing::MultiRegion,
+                    _ => Timing::Ntsc,
+                }
+            }
+            // other chunks (checksums, dumper information, etc.) are ignored
+            _ => (),
+        }
+    }
+
+    let board_name = board_name.ok_or("unif file is missing a board name ('MAPR' chunk)")?;
+    logln!("unif board: {}", board_name);
+    let (mapper, submapper) = find_board(&board_name)
+        .ok_or_else(|| format!("unif board '{}' is not supported", board_name))?;
+
+    let prg_rom = prg_chunks.concat();
+    let chr_rom = chr_chunks.concat();
+    if prg_rom.is_empty() {
+        return Err("unif file doesn't contain any prg rom ('PRG0'-'PRGF' chunks)".to_string());
+    }
+
+    let header = RomHeader {
+        is_nes_2_format: false,
+        mapper,
+        submapper,
+        prg_rom_size: prg_rom.len(),
+        chr_rom_size: chr_rom.len(),
+        prg_ram_size: 0x2000,
+        prg_nvram_size: 0,
+        chr_ram_size: 0,
+        chr_nvram_size: 0,
+        mirroring,
+        has_battery,
+        has_trainer: false,
+        timing,
+        console_type: ConsoleType::Nes,
+        misc_rom_count: 0,
+        default_expansion_device: 0,
+    };
+
+    Ok(RomDescription {
+        header,
+        trainer: None,
+        prg_rom: Cow::Owned(prg_rom),
+        chr_rom: Cow::Owned(chr_rom),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn push_chunk(rom: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+        rom.extend_from_slice(id);
+        rom.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        rom.extend_from_slice(data);
+    }
+
+    fn new_unif() -> Vec<u8> {
+        let mut rom = b"UNIF".to_vec();
+        rom.extend_from_slice(&7u32.to_le_bytes());
+        rom.resize(0x20, 0);
+        rom
+    }
+
+    #[test]
+    fn test_find_board() {
+        assert_eq!(find_board("NES-TLROM"), Some((4, 0)));
+        assert_eq!(find_board("HVC-HKROM"), Some((4, 1)));
+        assert_eq!(find_board("TQROM"), Some((119, 0)));
+        assert_eq!(find_board("AVE-NINA-01"), Some((34, 1)));
+        assert_eq!(find_board("NES-BNROM"), Some((34, 2)));
+        assert_eq!(find_board("UNL-COLORDREAMS-74*377"), Some((11, 0)));
+        assert_eq!(find_board("KONAMI-VRC-7"), Some((85, 0)));
+        assert_eq!(find_board("BANDAI-LZ93D50+24C01"), Some((159, 0)));
+        assert_eq!(find_board("NES-UNROM"), None);
+        assert_eq!(find_board("UNL-SOMETHING"), None);
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut rom = new_unif();
+        push_chunk(&mut rom, b"MAPR", b"NES-TKROM\0");
+        push_chunk(&mut rom, b"NAME", b"test\0");
+        // chunks are concatenated by index, not by file order
+        push_chunk(&mut rom, b"PRG1", &[0x22; 0x4000]);
+        push_chunk(&mut rom, b"PRG0", &[0x11; 0x4000]);
+        push_chunk(&mut rom, b"CHR0", &[0x33; 0x2000]);
+        push_chunk(&mut rom, b"MIRR", &[1]);
+        push_chunk(&mut rom, b"BATR", &[1]);
+        push_chunk(&mut rom, b"TVCI", &[1]);
+        push_chunk(&mut rom, b"DINF", &[0; 204]);
+
+        let rom_description = parse(&rom).unwrap();
+        let header = &rom_description.header;
+        assert_eq!((header.mapper, header.submapper), (4, 0));
+        assert_eq!(header.prg_rom_size, 0x8000);
+        assert_eq!(header.chr_rom_size, 0x2000);
+        assert_eq!(header.mirroring, parse::MirroringType::Vert);
+        assert!(header.has_battery);
+        assert_eq!(header.timing, Timing::Pal);
+        assert_eq!(rom_description.prg_rom[0], 0x11);
+        assert_eq!(rom_description.prg_rom[0x4000], 0x22);
+        assert_eq!(rom_description.chr_rom[..], [0x33; 0x2000][..]);
+
+        // single-screen mirroring
+        push_chunk(&mut rom, b"MIRR", &[3]);
+        assert_eq!(
+            parse(&rom).unwrap().header.mirroring,
+            parse::MirroringType::SingleScreenB
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut rom = new_unif();
+        push_chunk(&mut rom, b"PRG0", &[0; 0x4000]);
+        assert!(parse(&rom).err().unwrap().contains("board name"));
+
+        push_chunk(&mut rom, b"MAPR", b"UNL-SOMETHING\0");
+        assert_eq!(
+            parse(&rom).err().unwrap(),
+            "unif board 'UNL-SOMETHING' is not supported"
+        );
+
+        // truncated chunk
+        let mut rom = new_unif();
+        push_chunk(&mut rom, b"MAPR", b"NES-NROM-256\0");
+        push_chunk(&mut rom, b"PRG0", &[0; 0x8000]);
+        rom.truncate(rom.len() - 1);
+        assert!(parse(&rom).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use crate::apu;
+
+use std::io::{Seek, SeekFrom, Write};
+use std::{fs, io};
+
+// the size of the RIFF and fmt chunk headers, plus the header of the data chunk
+const HEADER_SIZE: u32 = 44;
+
+// records the mixed output of the apu (see 'Apu::take_samples()') to a 16-bit mono
+// .wav file. the chunk sizes in the header are filled in by 'finish()'
+pub struct WavRecorder {
+    file: io::BufWriter<fs::File>,
+    n_samples: u32,
+}
+
+impl WavRecorder {
+    pub fn new(path: &std::path::Path) -> Result<Self, String> {
+        let file = fs::File::create(path).map_err(|e| e.to_string())?;
+        let mut recorder = Self {
+            file: io::BufWriter::new(file),
+            n_samples: 0,
+        };
+        recorder.write_header().map_err(|e| e.to_string())?;
+
+        Ok(recorder)
+    }
+
+    pub fn write_samples(&mut self, samples: &[i16]) -> Result<(), String> {
+        for sample in samples.iter() {
+            self.file
+                .write_all(&sample.to_le_bytes())
+                .map_err(|e| e.to_string())?;
+        }
+        self.n_samples += samples.len() as u32;
+
+        Ok(())
+    }
+
+    // rewrites the header with the final length of the recording
+    pub fn finish(&mut self) -> Result<(), String> {
+        self.file
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| self.write_header())
+            .and_then(|_| self.file.flush())
+            .map_err(|e| e.to_string())
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let data_size = self.n_samples * 2;
+
+        self.file.write_all(b"RIFF")?;
+        self.file
+            .write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        self.file.write_all(b"WAVE")?;
+
+        self.file.write_all(b"fmt ")?;
+        self.file.write_all(&16u32.to_le_bytes())?;
+        // pcm, 1 channel
+        self.file.write_all(&1u16.to_le_bytes())?;
+        self.file.write_all(&1u16.to_le_bytes())?;
+        self.file.write_all(&apu::SAMPLE_RATE.to_le_bytes())?;
+        // byte rate and block size
+        self.file.write_all(&(apu::SAMPLE_RATE * 2).to_le_bytes())?;
+        self.file.write_all(&2u16.to_le_bytes())?;
+        self.file.write_all(&16u16.to_le_bytes())?;
+
+        self.file.write_all(b"data")?;
+        self.file.write_all(&data_size.to_le_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wav_recorder() {
+        let path = std::env::temp_dir().join("nees_test_wav_recorder.wav");
+
+        let mut recorder = WavRecorder::new(&path).unwrap();
+        recorder.write_samples(&[0, 1, -1]).unwrap();
+        recorder.write_samples(&[0x1234]).unwrap();
+        recorder.finish().unwrap();
+        drop(recorder);
+
+        let wav = fs::read(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(wav.len(), HEADER_SIZE as usize + 8);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(wav[4..8], (HEADER_SIZE - 8 + 8).to_le_bytes());
+        assert_eq!(wav[24..28], 44_100u32.to_le_bytes());
+        assert_eq!(wav[40..44], 8u32.to_le_bytes());
+        assert_eq!(wav[44..], [0, 0, 1, 0, 0xff, 0xff, 0x34, 0x12]);
+    }
+}
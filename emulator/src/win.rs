@@ -8,13 +8,17 @@ impl Keys {
     pub const ESC: u32 = 0xff1b;
     pub const TAB: u32 = 0xff09;
     pub const SHIFT: u32 = 0xffe1;
+    pub const LEFT: u32 = 0xff51;
+    pub const RIGHT: u32 = 0xff53;
     pub const W: u32 = 0x77;
     pub const A: u32 = 0x61;
     pub const S: u32 = 0x73;
     pub const D: u32 = 0x64;
+    pub const E: u32 = 0x65;
     pub const F: u32 = 0x66;
     pub const P: u32 = 0x70;
     pub const Q: u32 = 0x71;
+    pub const X: u32 = 0x78;
 }
 
 pub struct XcbWindowWrapper {